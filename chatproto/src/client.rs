@@ -1,27 +1,211 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use async_std::stream::Stream;
+use async_trait::async_trait;
+
 use crate::{
-  core::WORKPROOF_STRENGTH,
-  messages::{ClientId, Sequence},
-  workproof::gen_workproof,
+  core::{now_millis, WORKPROOF_STRENGTH},
+  messages::{ClientId, ClientPollReply, Sequence, ServerId},
 };
 
 #[derive(Debug, Default)]
 pub struct Client {
   id: ClientId,
   curid: u128,
+  // last round trip time measured to each server
+  latencies: HashMap<ServerId, Duration>,
 }
 
 impl Client {
   pub fn new(id: ClientId) -> Self {
-    Client { id, curid: 0 }
+    Client {
+      id,
+      curid: 0,
+      latencies: HashMap::new(),
+    }
   }
   pub fn sequence<A>(&mut self, content: A) -> Sequence<A> {
     self.curid += 1;
-    let workproof = gen_workproof((&self.id).into(), WORKPROOF_STRENGTH, u128::MAX).unwrap();
-    Sequence {
-      seqid: self.curid,
-      src: self.id,
-      workproof,
+    Sequence::signed_at(
+      self.id,
+      self.curid,
       content,
+      WORKPROOF_STRENGTH,
+      now_millis(),
+    )
+  }
+  /// a sequence that does not take a sequence id, nor carries a workproof, for the queries the
+  /// server does not check, such as `ClientQuery::Heartbeat`
+  pub fn unsequenced<A>(&self, content: A) -> Sequence<A> {
+    Sequence::new(self.id, 0, 0, content)
+  }
+  /// records the round trip time measured to a server, replacing the previous one
+  pub fn record_latency(&mut self, server: ServerId, rtt: Duration) {
+    self.latencies.insert(server, rtt);
+  }
+  /// the servers a round trip time was measured to, with the last one, sorted by server
+  pub fn server_latencies(&self) -> Vec<(ServerId, Duration)> {
+    let mut latencies: Vec<_> = self.latencies.iter().map(|(s, d)| (*s, *d)).collect();
+    latencies.sort();
+    latencies
+  }
+}
+
+/// something that can be polled for the next message, the server in practice
+#[async_trait]
+pub trait PollSource {
+  async fn poll(&mut self) -> anyhow::Result<ClientPollReply>;
+}
+
+/// a message yielded by `incoming`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IncomingMessage {
+  pub src: ClientId,
+  pub srcsrv: Option<ServerId>,
+  pub content: String,
+}
+
+type Step<'a, S> = Pin<Box<dyn Future<Output = (S, anyhow::Result<IncomingMessage>)> + Send + 'a>>;
+
+/// the stream returned by `incoming`
+pub struct Incoming<'a, S> {
+  interval: Duration,
+  step: Step<'a, S>,
+}
+
+/// the messages of a source, as they arrive
+/// the source is polled every `interval` while it has nothing to give. Delayed errors are yielded
+/// as `DelayedError` errors, system notices and read receipts are skipped.
+pub fn incoming<'a, S: PollSource + Send + 'a>(source: S, interval: Duration) -> Incoming<'a, S> {
+  Incoming {
+    interval,
+    step: Box::pin(next_message(source, interval)),
+  }
+}
+
+async fn next_message<S: PollSource>(
+  mut source: S,
+  interval: Duration,
+) -> (S, anyhow::Result<IncomingMessage>) {
+  loop {
+    let item = match source.poll().await {
+      Ok(ClientPollReply::Message {
+        src,
+        srcsrv,
+        content,
+        ..
+      }) => Ok(IncomingMessage {
+        src,
+        srcsrv,
+        content,
+      }),
+      Ok(ClientPollReply::DelayedError(e)) => Err(e.into()),
+      Ok(ClientPollReply::Nothing) => {
+        async_std::task::sleep(interval).await;
+        continue;
+      }
+      Ok(ClientPollReply::System { .. }) | Ok(ClientPollReply::Receipts(_)) => continue,
+      Err(rr) => {
+        // the source might be unreachable for a while, it is not hammered meanwhile
+        async_std::task::sleep(interval).await;
+        Err(rr)
+      }
+    };
+    return (source, item);
+  }
+}
+
+impl<'a, S: PollSource + Send + 'a> Stream for Incoming<'a, S> {
+  type Item = anyhow::Result<IncomingMessage>;
+
+  fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+    match self.step.as_mut().poll(cx) {
+      Poll::Ready((source, item)) => {
+        let interval = self.interval;
+        self.step = Box::pin(next_message(source, interval));
+        Poll::Ready(Some(item))
+      }
+      Poll::Pending => Poll::Pending,
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::core::MessageServer;
+  use crate::messages::{ClientMessage, DelayedError};
+  use crate::solutions::sample::Server;
+  use async_std::prelude::StreamExt;
+
+  struct ServerPoll<'a> {
+    server: &'a Server,
+    client: ClientId,
+  }
+
+  #[async_trait]
+  impl PollSource for ServerPoll<'_> {
+    async fn poll(&mut self) -> anyhow::Result<ClientPollReply> {
+      Ok(self.server.client_poll(self.client).await)
     }
   }
+
+  #[test]
+  fn incoming_messages() {
+    async_std::task::block_on(async {
+      let server = Server::new(ServerId::default());
+      let c1 = server.register_local_client("c1".into()).await.unwrap();
+      let c2 = server.register_local_client("c2".into()).await.unwrap();
+      for content in ["one", "two"] {
+        let msg = ClientMessage::Text {
+          dest: c1,
+          content: content.into(),
+          attachments: Vec::new(),
+        };
+        server.handle_client_message(c2, msg).await;
+      }
+      let interval = Duration::from_millis(10);
+      let mut stream = incoming(
+        ServerPoll {
+          server: &server,
+          client: c1,
+        },
+        interval,
+      );
+      for content in ["one", "two"] {
+        let msg = stream.next().await.unwrap().unwrap();
+        assert_eq!((msg.src, msg.content.as_str()), (c2, content));
+      }
+      // nothing else is queued
+      let next = async_std::future::timeout(interval * 5, stream.next()).await;
+      assert!(next.is_err());
+    })
+  }
+
+  #[test]
+  fn delayed_errors() {
+    struct Delayed;
+
+    #[async_trait]
+    impl PollSource for Delayed {
+      async fn poll(&mut self) -> anyhow::Result<ClientPollReply> {
+        Ok(ClientPollReply::DelayedError(
+          DelayedError::UnknownRecipient(ClientId::from(7)),
+        ))
+      }
+    }
+
+    async_std::task::block_on(async {
+      let mut stream = incoming(Delayed, Duration::from_millis(10));
+      let rr = stream.next().await.unwrap().unwrap_err();
+      assert_eq!(
+        rr.downcast_ref::<DelayedError>(),
+        Some(&DelayedError::UnknownRecipient(ClientId::from(7)))
+      );
+    })
+  }
 }
@@ -1,49 +1,224 @@
 use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
 
 use async_trait::async_trait;
 
-use crate::messages::{ClientError, ClientId, ClientMessage, ClientPollReply, ClientReply, Sequence, ServerId};
+use crate::messages::{
+  AckStatus, ClientError, ClientId, ClientLocation, ClientMessage, ClientPollReply, ClientReply,
+  DeliveryOrder, Sequence, ServerId,
+};
 #[cfg(feature = "federation")]
 use crate::messages::{ServerMessage, ServerReply};
 
 pub const MAILBOX_SIZE: usize = 256;
+/// maximum number of content bytes waiting for a local client, whatever the number of messages
+pub const MAX_MAILBOX_BYTES: usize = 1 << 20;
 pub const WORKPROOF_STRENGTH: u32 = 8;
+/// default age after which a timestamped sequence is considered stale, in milliseconds
+pub const REPLAY_WINDOW: u64 = 5 * 60 * 1000;
+/// maximum length of a client name, in bytes
+pub const MAX_NAME_LEN: usize = 64;
+/// maximum number of local clients
+pub const MAX_CLIENTS: usize = 65536;
+/// maximum number of messages a client can have waiting for recipients that are not known yet
+pub const MAX_PENDING_PER_SENDER: usize = MAILBOX_SIZE;
+/// maximum number of servers in a route, longer routes are neither accepted nor used
+pub const MAX_ROUTE_HOPS: usize = 16;
+/// maximum number of servers routes are kept for, announces from other servers are rejected
+pub const MAX_PEERS: usize = 1024;
+/// maximum number of users in a ListUsersPage reply, so that it fits in a datagram
+pub const MAX_USER_PAGE: u64 = 64;
+
+/// current time, as the number of milliseconds since the unix epoch
+pub fn now_millis() -> u64 {
+  std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .map(|d| d.as_millis() as u64)
+    .unwrap_or(0)
+}
+
+/// trims the surrounding whitespace of a client name, and checks its length and that it is
+/// printable
+pub fn validate_name(name: &str) -> Result<&str, ClientError> {
+  let name = name.trim();
+  if name.is_empty() {
+    Err(ClientError::EmptyName)
+  } else if name.len() > MAX_NAME_LEN {
+    Err(ClientError::NameTooLong)
+  } else if name.chars().any(char::is_control) {
+    Err(ClientError::InvalidName)
+  } else {
+    Ok(name)
+  }
+}
+
+/// server statistics, for operators
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Metrics {
+  pub uptime: Duration,
+  /// local clients currently registered
+  pub clients: usize,
+  /// messages delivered to a mailbox, since the counters were last reset
+  pub delivered: u64,
+  /// messages that got an error reply, since the counters were last reset
+  pub errors: u64,
+  /// messages to remote clients, held until a route to their server is announced
+  pub pending_transfers: usize,
+}
 
 #[async_trait]
 pub trait MessageServer {
   /// group name
   const GROUP_NAME: &'static str;
 
+  #[cfg(feature = "debug-snapshot")]
+  /// the whole in-memory state of the server, as captured by `debug_snapshot`
+  type ServerState: Send;
+
   /// create a new server, this is the constructor function
   fn new(id: ServerId) -> Self;
 
+  /// the id the server was created with
+  fn id(&self) -> ServerId;
+
   /// register a new client, that will then be able to send and receive messages.
-  /// The first argument is the client screen name.
-  async fn register_local_client(&self, name: String) -> ClientId;
+  /// The first argument is the client screen name, that must be checked with `validate_name`.
+  /// Names are unique among local clients, and at most MAX_CLIENTS can be registered.
+  async fn register_local_client(&self, name: String) -> Result<ClientId, ClientError>;
+
+  /// registers a client on behalf of the source of a Register sequence
+  /// retransmissions from the same `nonce` get the id that was allocated the first time
+  async fn register_local_client_from(
+    &self,
+    nonce: ClientId,
+    name: String,
+  ) -> Result<ClientId, ClientError>;
+
+  /// registers a client with the same rules as `register_local_client_from`, and handles a
+  /// message it sends, as `handle_client_message` would
+  /// retransmissions from the same `nonce` get the id that was allocated the first time, and no
+  /// replies, as the message is not handled again
+  async fn register_and_message(
+    &self,
+    nonce: ClientId,
+    name: String,
+    msg: ClientMessage,
+  ) -> Result<(ClientId, Vec<ClientReply>), ClientError>;
+
+  /// unregisters a local client, the messages waiting in its mailbox are dropped
+  /// messages sent to it afterwards get a RecipientGone error
+  /// returns false if the client is not local
+  async fn unregister_local_client(&self, client: ClientId) -> bool;
+
+  /// registers several clients at once, with the same rules as `register_local_client`
+  /// a rejected name does not prevent the others from being registered
+  async fn register_local_clients(&self, names: Vec<String>) -> Vec<Result<ClientId, ClientError>>;
 
   /// list known users
   /// also lists known remote users if federation is enabled
   async fn list_users(&self) -> HashMap<ClientId, String>;
 
+  /// the server a remote client lives on
+  /// returns None for local clients, and for clients that are not known yet
+  async fn home_server(&self, client: ClientId) -> Option<ServerId>;
+
+  /// the clients a message can be delivered to right now: local clients, and remote clients the
+  /// server of which a route is known to
+  /// clients that are not known yet, and only have messages waiting for them, are not listed
+  async fn reachable_clients(&self) -> HashMap<ClientId, ClientLocation>;
+
   /// handles a sequenced message
   /// you must verify:
   ///  * the workproof first, and then,
+  ///  * that the timestamp, if any, is not stale
   ///  * that sequence numbers are increasing
-  async fn handle_sequenced_message<A: Send>(&self, msg: Sequence<A>) -> Result<A, ClientError>;
+  /// `peer` is the address the message came from, if known. When address checking is enabled and
+  /// the client is bound to another address, AddressMismatch must be returned.
+  async fn handle_sequenced_message<A: Send>(
+    &self,
+    msg: Sequence<A>,
+    peer: Option<SocketAddr>,
+  ) -> Result<A, ClientError>;
+
+  /// binds a local client to the address it registered from
+  async fn bind_address(&self, client: ClientId, addr: SocketAddr);
+
+  /// refreshes the liveness of a local client, without any other side effect
+  /// no workproof is checked, but when address checking is enabled the heartbeat must come from
+  /// the address the client is bound to
+  /// returns false if the client is not local, or the address does not match
+  async fn heartbeat(&self, client: ClientId, peer: Option<SocketAddr>) -> bool;
+
+  /// current statistics
+  async fn metrics(&self) -> Metrics;
+
+  /// resets the cumulative counters of the metrics, the gauges are left untouched
+  async fn reset_counters(&self);
+
+  /// the `n` local clients that sent the most messages since the counters were last reset, with
+  /// the number of messages they sent, busiest first
+  async fn top_senders(&self, n: usize) -> Vec<(ClientId, u64)>;
+
+  /// highest sequence id accepted from a local client, None if the client is not local
+  async fn last_accepted_seqid(&self, client: ClientId) -> Option<u128>;
+
+  /// the sequence ids accepted from a local client, None if the client is not local
+  async fn ack_status(&self, client: ClientId) -> Option<AckStatus>;
 
   /// pull function for the client
+  /// a message whose delivery completed before the poll started is visible to that poll, and a
+  /// delivery that is concurrent with the poll is either returned by it or kept for the next one
   async fn client_poll(&self, client: ClientId) -> ClientPollReply;
 
+  /// same as `client_poll`, but never waits: returns None if the poll could not be done right away
+  fn try_client_poll(&self, client: ClientId) -> Option<ClientPollReply>;
+
+  /// sets the order in which a local client polls its messages, the default being Fifo
+  /// returns false if the client is not local
+  async fn set_delivery_order(&self, client: ClientId, order: DeliveryOrder) -> bool;
+
+  /// pauses or resumes the delivery of the messages of a local client
+  /// while paused, polls return Nothing and messages keep accumulating in the mailbox
+  /// returns false if the client is not local
+  async fn set_delivery(&self, client: ClientId, enabled: bool) -> bool;
+
+  /// queues a system notice for every local client
+  /// notices are polled before the regular messages
+  async fn broadcast_system(&self, text: String);
+
   /// handles a client message
   /// * if the user is unknown, it might be that it is remote, so messages should be kept until the user becomes known
   ///   as a result, the "Delayed" message should be sent
   /// * until polled, messages are to be stored. There is a maximum mailbox size after which an error should be returned
   async fn handle_client_message(&self, src: ClientId, msg: ClientMessage) -> Vec<ClientReply>;
 
+  /// removes a message that has not been polled yet from the mailbox (or pending store) it sits in
+  /// * only messages originated by `src` can be recalled
+  /// * returns true if the message was found and removed
+  async fn recall(&self, src: ClientId, message_id: u128) -> bool;
+
+  /// subscribes the client to a topic, so that it receives what is published on it
+  /// * returns false if the client was already subscribed
+  async fn subscribe(&self, client: ClientId, topic: String) -> bool;
+
+  /// returns false if the client was not subscribed to the topic
+  async fn unsubscribe(&self, client: ClientId, topic: &str) -> bool;
+
+  #[cfg(feature = "debug-snapshot")]
+  /// captures all the in-memory state (clients, mailboxes, routes...), so that tests can compare
+  /// the state before and after an operation, or reset the server between assertions
+  async fn debug_snapshot(&self) -> Self::ServerState;
+
+  #[cfg(feature = "debug-snapshot")]
+  /// replaces the in-memory state of the server with one captured by `debug_snapshot`
+  async fn debug_restore(&self, state: Self::ServerState);
+
   #[cfg(feature = "federation")]
   /// handles a server message
   /// * might be an announce (which might trigger waiting messages to be sent)
   /// * might be a message for this server, or another
+  ///   a message that was entirely delivered locally is acknowledged with `Ack`
   async fn handle_server_message(&self, msg: ServerMessage) -> ServerReply;
 
   #[cfg(feature = "federation")]
@@ -2,7 +2,9 @@ pub mod client;
 pub mod core;
 pub mod messages;
 pub mod netproto;
+pub mod ratelimit;
+pub mod reorder;
 pub mod solutions;
-#[cfg(test)]
+#[cfg(any(test, feature = "self-test"))]
 pub mod testing;
 pub mod workproof;
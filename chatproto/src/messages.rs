@@ -3,6 +3,8 @@ use std::collections::HashMap;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use crate::workproof::gen_workproof;
+
 #[derive(
   Serialize, Deserialize, std::hash::Hash, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug,
 )]
@@ -12,6 +14,10 @@ pub struct ClientId(pub(crate) Uuid);
 )]
 pub struct ServerId(pub(crate) Uuid);
 
+/// reserved destination, servers running in echo mode send the messages addressed to it back to
+/// their sender
+pub const ECHO_CLIENT: ClientId = ClientId(Uuid::nil());
+
 impl From<u128> for ClientId {
   fn from(value: u128) -> Self {
     ClientId(Uuid::from_u128_le(value))
@@ -36,6 +42,62 @@ impl From<&ServerId> for u128 {
   }
 }
 
+/// a nonce of `N` little endian bytes, `N` is at most 16
+#[derive(
+  Serialize, Deserialize, std::hash::Hash, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug,
+)]
+pub struct Nonce<const N: usize>(u128);
+
+/// nonce exchanged during the authentication of servers
+pub type AuthNonce = Nonce<8>;
+/// nonce the workproofs are computed over, derived from the client id and the timestamp of the
+/// sequence, see `Sequence::workproof_nonce`
+pub type WorkproofNonce = Nonce<16>;
+
+impl<const N: usize> Nonce<N> {
+  // a larger nonce does not fit the u128, using it fails to compile
+  pub const SIZE: usize = {
+    assert!(N <= 16, "a nonce is at most 16 bytes");
+    N
+  };
+
+  pub fn from_bytes(bytes: [u8; N]) -> Self {
+    let mut buf = [0; 16];
+    buf[..Self::SIZE].copy_from_slice(&bytes);
+    Nonce(u128::from_le_bytes(buf))
+  }
+
+  pub fn to_bytes(&self) -> [u8; N] {
+    let mut out = [0; N];
+    out.copy_from_slice(&self.0.to_le_bytes()[..Self::SIZE]);
+    out
+  }
+}
+
+impl From<u128> for WorkproofNonce {
+  fn from(value: u128) -> Self {
+    Nonce(value)
+  }
+}
+
+impl<const N: usize> From<Nonce<N>> for u128 {
+  fn from(value: Nonce<N>) -> Self {
+    value.0
+  }
+}
+
+impl From<&ClientId> for WorkproofNonce {
+  fn from(value: &ClientId) -> Self {
+    Nonce(value.0.to_u128_le())
+  }
+}
+
+impl From<WorkproofNonce> for ClientId {
+  fn from(value: WorkproofNonce) -> Self {
+    ClientId(Uuid::from_u128_le(value.0))
+  }
+}
+
 impl Default for ClientId {
   fn default() -> ClientId {
     ClientId(Uuid::new_v4())
@@ -77,13 +139,76 @@ pub struct Sequence<A> {
   pub seqid: u128,
   pub src: ClientId,
   pub workproof: u128,
+  /// milliseconds since the unix epoch, when the message was sequenced
+  /// checked by the server, so that messages can't be replayed long after they were sent
+  /// the workproof covers it, so that it cannot be refreshed without computing a new proof
+  pub timestamp: Option<u64>,
   pub content: A,
 }
 
+impl<A> Sequence<A> {
+  /// a sequence with the given workproof, and no timestamp
+  pub fn new(src: ClientId, seqid: u128, workproof: u128, content: A) -> Self {
+    Sequence {
+      seqid,
+      src,
+      workproof,
+      timestamp: None,
+      content,
+    }
+  }
+
+  /// a sequence with no timestamp, and a workproof of the given strength computed for `src`
+  pub fn signed(src: ClientId, seqid: u128, content: A, strength: u32) -> Self {
+    let workproof = gen_workproof(workproof_nonce(&src, None), strength, u128::MAX).unwrap();
+    Sequence::new(src, seqid, workproof, content)
+  }
+
+  /// a sequence sent at `timestamp`, with a workproof of the given strength covering both `src`
+  /// and the timestamp
+  pub fn signed_at(src: ClientId, seqid: u128, content: A, strength: u32, timestamp: u64) -> Self {
+    let nonce = workproof_nonce(&src, Some(timestamp));
+    let workproof = gen_workproof(nonce, strength, u128::MAX).unwrap();
+    Sequence {
+      timestamp: Some(timestamp),
+      ..Sequence::new(src, seqid, workproof, content)
+    }
+  }
+
+  /// the nonce the workproof of this sequence is computed over
+  pub fn workproof_nonce(&self) -> WorkproofNonce {
+    workproof_nonce(&self.src, self.timestamp)
+  }
+}
+
+/// the client id, with the timestamp folded into its upper half when there is one
+pub fn workproof_nonce(src: &ClientId, timestamp: Option<u64>) -> WorkproofNonce {
+  let nonce = u128::from(WorkproofNonce::from(src));
+  match timestamp {
+    None => Nonce(nonce),
+    Some(timestamp) => Nonce(nonce ^ ((timestamp as u128) << 64)),
+  }
+}
+
+/// a sequenced query, along with an identifier chosen by the client
+/// the server echoes this identifier in its reply, so that replies can be matched with queries
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Request<A> {
+  pub request_id: u64,
+  pub sequence: Sequence<A>,
+}
+
+/// the server reply to a `Request`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Reply<A> {
+  pub request_id: u64,
+  pub payload: A,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub enum AuthMessage {
-  Hello { user: ClientId, nonce: [u8; 8] },
-  Nonce { server: ServerId, nonce: [u8; 8] },
+  Hello { user: ClientId, nonce: AuthNonce },
+  Nonce { server: ServerId, nonce: AuthNonce },
   Auth { response: [u8; 16] },
 }
 
@@ -93,17 +218,124 @@ pub enum ClientQuery {
   Message(ClientMessage),
   Poll,
   ListUsers,
+  /// removes a not yet delivered message that was sent by the requesting client
+  Recall {
+    message_id: u128,
+  },
+  /// receive every message published on the topic
+  Subscribe(String),
+  Unsubscribe(String),
+  /// asks which server hosts a remote client
+  HomeServer(ClientId),
+  /// registers, and sends a message right away, for clients that only send one
+  RegisterAndMessage {
+    name: String,
+    message: ClientMessage,
+  },
+  /// the order the requesting client polls its messages in
+  SetOrder(DeliveryOrder),
+  /// which server the client is talking to
+  ServerInfo,
+  /// which sequences of the requesting client were received, so that the missing ones can be sent
+  /// again
+  AckStatus,
+  /// resumes (true) or pauses (false) the delivery of the messages of the requesting client
+  SetDelivery(bool),
+  /// keeps the requesting client alive, and the NAT mappings on the way open
+  /// the workproof and the sequence id are not checked, the sequence id should be 0
+  Heartbeat,
+  /// at most `limit` users, starting at `offset` in the list sorted by id, so that large lists
+  /// are fetched in replies that fit in a datagram
+  ListUsersPage {
+    offset: u64,
+    limit: u64,
+  },
+  /// the clients the server can deliver to right now, with where they live
+  Reachable,
+}
+
+/// reply to `ClientQuery::ServerInfo`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ServerInfo {
+  pub server_id: ServerId,
+  /// the `GROUP_NAME` of the server implementation
+  pub group_name: String,
+  /// the protocol version the server speaks
+  pub version: u8,
+}
+
+/// reply to `ClientQuery::ListUsersPage`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct UserPage {
+  /// sorted by id
+  pub users: Vec<(ClientId, String)>,
+  /// number of users in the whole list
+  pub total: u64,
+  /// there are users after this page
+  pub has_more: bool,
+}
+
+impl UserPage {
+  /// the page of `users` starting at `offset`, with at most `limit` users
+  pub fn slice(users: HashMap<ClientId, String>, offset: u64, limit: u64) -> Self {
+    let mut users: Vec<_> = users.into_iter().collect();
+    users.sort_by_key(|(id, _)| *id);
+    let total = users.len() as u64;
+    let start = offset.min(total) as usize;
+    let end = offset.saturating_add(limit).min(total) as usize;
+    UserPage {
+      has_more: end < users.len(),
+      users: users.drain(start..end).collect(),
+      total,
+    }
+  }
+}
+
+/// where a client the server can deliver to lives
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClientLocation {
+  /// has a mailbox on this server
+  Local,
+  /// lives on another server, that a route is known to
+  Remote(ServerId),
+}
+
+/// order in which a client polls its mailbox
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DeliveryOrder {
+  /// oldest message first
+  #[default]
+  Fifo,
+  /// newest message first, for clients that only care about the latest messages
+  Lifo,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub enum ClientMessage {
   /// simple text message
-  Text { dest: ClientId, content: String },
+  /// the attachments are key-value metadata, such as a location or the message it replies to
+  Text {
+    dest: ClientId,
+    content: String,
+    #[serde(default)]
+    attachments: Vec<(String, String)>,
+  },
   /// multiple targets text message
   MText {
     dest: Vec<ClientId>,
     content: String,
   },
+  /// text message sent to every subscriber of the topic
+  Publish { topic: String, content: String },
+  /// replaces the content of a message that `dest` has not polled yet
+  Edit {
+    message_id: u128,
+    dest: ClientId,
+    content: String,
+  },
+  /// text message to the local client registered under `name`
+  /// when there is none, it is stored until a client registers with that name
+  ToName { name: String, content: String },
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
@@ -134,6 +366,75 @@ pub enum ClientError {
   SequenceError,  // sequence number not increasing
   BoxFull(ClientId),
   InternalError,
+  NameTooLong,     // name longer than MAX_NAME_LEN
+  EmptyName,       // name is empty, or only whitespace
+  StaleMessage,    // timestamp too old, the message might be a replay
+  AddressMismatch, // sent from another address than the one the client registered from
+  NameTaken,       // another local client already has this name
+  ServerFull,      // MAX_CLIENTS local clients are already registered
+  UnknownMessage,  // no such message waiting to be polled, or it was sent by someone else
+  ProtocolError,   // the server does not support this query
+  // the destination unregistered while the message was in flight
+  RecipientGone(ClientId),
+  // the sender has too many messages waiting for unknown recipients
+  TooManyPending,
+  // the server could not handle the message in time, it can be sent again
+  ServerBusy,
+  // the name has control characters, or the naming policy of the server rejects it
+  InvalidName,
+  // the server of the destination cannot be reached, and the message was not kept
+  NoRoute,
+}
+
+impl ClientError {
+  /// wire tag of the variant
+  pub fn tag(&self) -> u8 {
+    match self {
+      ClientError::WorkProofError => 0,
+      ClientError::UnknownClient => 1,
+      ClientError::SequenceError => 2,
+      ClientError::BoxFull(_) => 3,
+      ClientError::InternalError => 4,
+      ClientError::NameTooLong => 5,
+      ClientError::EmptyName => 6,
+      ClientError::StaleMessage => 7,
+      ClientError::AddressMismatch => 8,
+      ClientError::NameTaken => 9,
+      ClientError::ServerFull => 10,
+      ClientError::UnknownMessage => 11,
+      ClientError::ProtocolError => 12,
+      ClientError::RecipientGone(_) => 13,
+      ClientError::TooManyPending => 14,
+      ClientError::ServerBusy => 15,
+      ClientError::InvalidName => 16,
+      ClientError::NoRoute => 17,
+    }
+  }
+
+  /// variant corresponding to a wire tag, the fields are left to their default values
+  pub fn from_tag(tag: u8) -> Option<ClientError> {
+    match tag {
+      0 => Some(ClientError::WorkProofError),
+      1 => Some(ClientError::UnknownClient),
+      2 => Some(ClientError::SequenceError),
+      3 => Some(ClientError::BoxFull(ClientId::default())),
+      4 => Some(ClientError::InternalError),
+      5 => Some(ClientError::NameTooLong),
+      6 => Some(ClientError::EmptyName),
+      7 => Some(ClientError::StaleMessage),
+      8 => Some(ClientError::AddressMismatch),
+      9 => Some(ClientError::NameTaken),
+      10 => Some(ClientError::ServerFull),
+      11 => Some(ClientError::UnknownMessage),
+      12 => Some(ClientError::ProtocolError),
+      13 => Some(ClientError::RecipientGone(ClientId::default())),
+      14 => Some(ClientError::TooManyPending),
+      15 => Some(ClientError::ServerBusy),
+      16 => Some(ClientError::InvalidName),
+      17 => Some(ClientError::NoRoute),
+      _ => None,
+    }
+  }
 }
 
 impl std::fmt::Display for ClientError {
@@ -144,6 +445,19 @@ impl std::fmt::Display for ClientError {
       ClientError::InternalError => "InternalError".fmt(f),
       ClientError::WorkProofError => "WorkProofError".fmt(f),
       ClientError::UnknownClient => "UnknownClient".fmt(f),
+      ClientError::NameTooLong => "NameTooLong".fmt(f),
+      ClientError::EmptyName => "EmptyName".fmt(f),
+      ClientError::StaleMessage => "StaleMessage".fmt(f),
+      ClientError::AddressMismatch => "AddressMismatch".fmt(f),
+      ClientError::NameTaken => "NameTaken".fmt(f),
+      ClientError::ServerFull => "ServerFull".fmt(f),
+      ClientError::UnknownMessage => "UnknownMessage".fmt(f),
+      ClientError::ProtocolError => "ProtocolError".fmt(f),
+      ClientError::RecipientGone(clientid) => write!(f, "RecipientGone({})", clientid),
+      ClientError::TooManyPending => "TooManyPending".fmt(f),
+      ClientError::ServerBusy => "ServerBusy".fmt(f),
+      ClientError::InvalidName => "InvalidName".fmt(f),
+      ClientError::NoRoute => "NoRoute".fmt(f),
     }
   }
 }
@@ -164,35 +478,283 @@ impl std::error::Error for ClientError {
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub enum ClientReply {
-  Delivered,
+  /// the message is in the mailbox of its recipient, with the id the server gave it, that
+  /// `ClientQuery::Recall`, `ClientMessage::Edit` and read receipts refer to
+  Delivered(u128),
   Error(ClientError),
-  /// unknown recipient, no relays found
-  Delayed,
+  /// unknown recipient, no relays found, the message is kept under the given id
+  Delayed(u128),
   /// send to an external server
   Transfer(ServerId, ServerMessage),
+  /// `count` consecutive deliveries, whose ids follow each other from `first`, runs of `Delivered`
+  /// are sent this way and expanded back when decoded
+  DeliveredN {
+    first: u128,
+    count: u128,
+  },
+  /// reply to `ClientQuery::Heartbeat`
+  Heartbeat,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub enum ClientPollReply {
-  Message { src: ClientId, content: String },
+  /// `srcsrv` is the server a federated message comes from, None for local messages
+  /// `message_id` is the id the server gave the message, that the read receipts of its sender
+  /// carry
+  Message {
+    src: ClientId,
+    srcsrv: Option<ServerId>,
+    content: String,
+    #[serde(default)]
+    attachments: Vec<(String, String)>,
+    #[serde(default)]
+    message_id: u128,
+  },
   DelayedError(DelayedError),
   Nothing,
+  /// server wide notice, sent by the operator
+  System {
+    text: String,
+  },
+  /// ids of messages sent by the polling client that their destination read
+  Receipts(Vec<u128>),
+}
+
+/// the reply to a poll query, acknowledging the sequences accepted from the polling client
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct PollAck {
+  pub reply: ClientPollReply,
+  /// every sequence id up to this one was accepted, the client does not need to keep them
+  /// later ids might have been accepted too, but not all of them
+  pub contiguous_seqid: u128,
+}
+
+/// the sequence ids a server received from a client, over a window of 64 ids
+/// every id up to `contiguous` was received, the ids after it up to `forgotten` fell out of the
+/// window before they were, so whether they were received since is unknown
+/// bit `i` of `sparse` is set when the id `i + 1` after both of them was received
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AckStatus {
+  pub contiguous: u128,
+  pub forgotten: u128,
+  pub sparse: u64,
+}
+
+impl AckStatus {
+  // the id before the first one of the window
+  fn base(&self) -> u128 {
+    self.contiguous.max(self.forgotten)
+  }
+
+  /// records that `seqid` was received
+  /// when it is too far ahead for the window, the oldest ids are forgotten
+  pub fn record(&mut self, seqid: u128) {
+    if seqid <= self.contiguous {
+      return;
+    }
+    if seqid <= self.forgotten {
+      // the following forgotten ids might have been received, they stay unknown
+      if seqid == self.contiguous + 1 {
+        self.contiguous = seqid;
+        self.advance();
+      }
+      return;
+    }
+    let mut offset = seqid - self.base() - 1;
+    if offset >= 64 {
+      let shift = offset - 63;
+      self.forgotten = self.base() + shift;
+      self.sparse = self.sparse.checked_shr(shift as u32).unwrap_or(0);
+      offset = 63;
+    }
+    self.sparse |= 1 << offset;
+    self.advance();
+  }
+
+  // the received ids at the start of the window become contiguous, unless forgotten ids are before
+  fn advance(&mut self) {
+    while self.contiguous >= self.forgotten && self.sparse & 1 == 1 {
+      self.contiguous += 1;
+      self.sparse >>= 1;
+    }
+  }
+
+  /// whether `seqid` was received, None when it fell out of the window and it is unknown
+  pub fn received(&self, seqid: u128) -> Option<bool> {
+    if seqid <= self.contiguous {
+      return Some(true);
+    }
+    if seqid <= self.forgotten {
+      return None;
+    }
+    let offset = seqid - self.base() - 1;
+    Some(offset < 64 && self.sparse & (1 << offset) != 0)
+  }
+
+  /// the ids that were not received, while later ones were
+  /// the forgotten ids are not among them
+  pub fn missing(&self) -> Vec<u128> {
+    (0..64 - self.sparse.leading_zeros())
+      .filter(|i| self.sparse & (1 << i) == 0)
+      .map(|i| self.base() + 1 + i as u128)
+      .collect()
+  }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub enum DelayedError {
   UnknownRecipient(ClientId),
+  /// the route to the server hosting this client was lost, the message is held until it comes back
+  RouteLost(ClientId),
+}
+
+impl std::fmt::Display for DelayedError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      DelayedError::UnknownRecipient(clientid) => write!(f, "UnknownRecipient({})", clientid),
+      DelayedError::RouteLost(clientid) => write!(f, "RouteLost({})", clientid),
+    }
+  }
 }
 
+impl std::error::Error for DelayedError {}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct Outgoing<A> {
   pub nexthop: ServerId,
   pub message: A,
 }
 
+/// why a server message was refused
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum ServerError {
+  /// routes to MAX_PEERS servers are already known, and this one announced a new route
+  TooManyPeers(ServerId),
+  /// the route announced by this server has more than MAX_ROUTE_HOPS hops
+  RouteTooLong(ServerId),
+  /// none of the destinations of the message could be reached, for these reasons
+  Undeliverable(Vec<String>),
+}
+
+impl std::fmt::Display for ServerError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      ServerError::TooManyPeers(serverid) => write!(f, "TooManyPeers({})", serverid),
+      ServerError::RouteTooLong(serverid) => write!(f, "RouteTooLong({})", serverid),
+      ServerError::Undeliverable(failures) => write!(f, "Undeliverable({})", failures.join(", ")),
+    }
+  }
+}
+
+impl std::error::Error for ServerError {}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub enum ServerReply {
   Outgoing(Vec<Outgoing<FullyQualifiedMessage>>),
   EmptyRoute,
-  Error(String),
+  Error(ServerError),
+  /// the message was accepted, and there is nothing to forward
+  Ack,
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::core::WORKPROOF_STRENGTH;
+  use crate::workproof::verify_workproof;
+
+  #[test]
+  fn sequence_new() {
+    let src = ClientId(Uuid::new_v4());
+    let sq = Sequence::new(src, 7, 1234, "content");
+    assert_eq!(
+      sq,
+      Sequence {
+        seqid: 7,
+        src,
+        workproof: 1234,
+        timestamp: None,
+        content: "content",
+      }
+    );
+  }
+
+  #[test]
+  fn ack_status() {
+    let mut status = AckStatus::default();
+    for seqid in [1, 2, 4] {
+      status.record(seqid);
+    }
+    assert_eq!(
+      status,
+      AckStatus {
+        contiguous: 2,
+        forgotten: 0,
+        sparse: 0b10
+      }
+    );
+    assert_eq!(status.missing(), [3]);
+    assert_eq!(status.received(4), Some(true));
+    assert_eq!(status.received(3), Some(false));
+    assert_eq!(status.received(5), Some(false));
+    status.record(3);
+    assert_eq!(
+      status,
+      AckStatus {
+        contiguous: 4,
+        forgotten: 0,
+        sparse: 0
+      }
+    );
+    // 5 and 6 fall out of the window before they are received
+    status.record(8);
+    status.record(5 + 65);
+    assert_eq!(status.contiguous, 4);
+    assert_eq!(status.forgotten, 6);
+    assert_eq!(status.received(5), None);
+    assert_eq!(status.received(6), None);
+    assert_eq!(status.received(7), Some(false));
+    assert_eq!(status.received(8), Some(true));
+    assert_eq!(status.missing().len(), 62);
+    // a forgotten id arriving late still counts, the ones after it stay unknown
+    status.record(5);
+    assert_eq!(status.contiguous, 5);
+    assert_eq!(status.received(6), None);
+    status.record(6);
+    status.record(7);
+    assert_eq!(status.contiguous, 8);
+    assert_eq!(status.forgotten, 6);
+    assert_eq!(status.received(9), Some(false));
+  }
+
+  #[test]
+  fn sequence_signed() {
+    let src = ClientId(Uuid::new_v4());
+    let sq = Sequence::signed(src, 3, (), WORKPROOF_STRENGTH);
+    assert_eq!((sq.src, sq.seqid), (src, 3));
+    assert!(verify_workproof(
+      (&src).into(),
+      sq.workproof,
+      WORKPROOF_STRENGTH
+    ));
+  }
+
+  #[test]
+  fn sequence_signed_at() {
+    let src = ClientId(Uuid::new_v4());
+    let sq = Sequence::signed_at(src, 3, (), WORKPROOF_STRENGTH, 1_700_000_000_000);
+    assert_eq!(sq.timestamp, Some(1_700_000_000_000));
+    assert!(verify_workproof(
+      sq.workproof_nonce(),
+      sq.workproof,
+      WORKPROOF_STRENGTH
+    ));
+    // another timestamp needs another proof
+    let moved = Sequence {
+      timestamp: Some(1_700_000_000_001),
+      ..sq.clone()
+    };
+    assert_ne!(moved.workproof_nonce(), sq.workproof_nonce());
+    assert_eq!(workproof_nonce(&src, None), WorkproofNonce::from(&src));
+  }
 }
@@ -1,17 +1,79 @@
-use std::{any, collections::HashMap, io::Read, string, vec};
+use std::{
+  any,
+  collections::HashMap,
+  io::{Cursor, Read},
+  string, vec,
+};
 
 use anyhow::{anyhow, Error, Ok};
 use byteorder::{LittleEndian, ReadBytesExt};
 use uuid::Uuid;
 
+use super::{
+  FrameKind, ACK_FRAME_VERSION, ATTACHMENTS_VERSION, CHECKSUM_VERSION, COMPACT_UUID_VERSION,
+  COMPRESSED_USERLIST_VERSION, FRAME_KIND_VERSION, MAX_REPLIES, MESSAGE_ID_VERSION,
+  PROTOCOL_VERSION,
+};
 use crate::{
   client,
+  core::MAX_NAME_LEN,
   messages::{
-    AuthMessage, ClientId, ClientMessage, ClientPollReply, ClientQuery, ClientReply, Sequence,
-    ServerId, ServerMessage,
+    AckStatus, AuthMessage, ClientError, ClientId, ClientLocation, ClientMessage, ClientPollReply,
+    ClientQuery, ClientReply, DelayedError, DeliveryOrder, FullyQualifiedMessage, Nonce, PollAck,
+    Reply, Request, Sequence, ServerId, ServerInfo, ServerMessage, UserPage,
   },
 };
 
+/// maximum nesting of messages inside replies, deeper frames are rejected
+pub const MAX_DEPTH: usize = 4;
+
+/// decoding errors that callers might want to handle, wrapped in the anyhow errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+  /// a query tag this version does not know about, it might come from a newer client
+  UnknownQuery(u8),
+  /// messages are nested more than MAX_DEPTH times
+  MaxDepthExceeded,
+  /// the CRC32 trailer does not match the frame, it was corrupted on the way
+  ChecksumMismatch,
+  /// that many bytes were left after a complete frame, in strict mode
+  TrailingBytes(usize),
+}
+
+impl std::fmt::Display for DecodeError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      DecodeError::UnknownQuery(tag) => write!(f, "unknown client query tag {}", tag),
+      DecodeError::MaxDepthExceeded => write!(f, "messages nested more than {} times", MAX_DEPTH),
+      DecodeError::ChecksumMismatch => write!(f, "frame checksum mismatch"),
+      DecodeError::TrailingBytes(n) => write!(f, "{} bytes after the end of the frame", n),
+    }
+  }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// what to do with the bytes that follow a complete frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trailing {
+  /// they are ignored, datagrams might be padded
+  Lenient,
+  /// they are rejected with TrailingBytes, as they point to corruption or a framing bug
+  Strict,
+}
+
+/// checks that nothing is left in the reader, in strict mode
+pub fn end_of_frame<R: Read>(rd: &mut R, trailing: Trailing) -> anyhow::Result<()> {
+  if trailing == Trailing::Strict {
+    let mut rest = Vec::new();
+    rd.read_to_end(&mut rest)?;
+    if !rest.is_empty() {
+      return Err(DecodeError::TrailingBytes(rest.len()).into());
+    }
+  }
+  Ok(())
+}
+
 // look at the README.md for guidance on writing this function
 pub fn u128<R: Read>(rd: &mut R) -> anyhow::Result<u128> {
   let val = rd.read_u8()?;
@@ -23,12 +85,30 @@ pub fn u128<R: Read>(rd: &mut R) -> anyhow::Result<u128> {
       252 => Ok(rd.read_u32::<LittleEndian>()? as u128),
       253 => Ok(rd.read_u64::<LittleEndian>()? as u128),
       254 => Ok(rd.read_u128::<LittleEndian>()? as u128),
-      _ => todo!(),
+      _ => Err(anyhow!("invalid varint prefix {}", val)),
     }
   }
 }
 
+pub fn bool<R: Read>(rd: &mut R) -> anyhow::Result<bool> {
+  match rd.read_u8()? {
+    0 => Ok(false),
+    1 => Ok(true),
+    x => Err(anyhow!("invalid boolean value {}", x)),
+  }
+}
+
 fn uuid<R: Read>(rd: &mut R) -> anyhow::Result<Uuid> {
+  uuid_versioned(rd, PROTOCOL_VERSION)
+}
+
+pub fn uuid_versioned<R: Read>(rd: &mut R, version: u8) -> anyhow::Result<Uuid> {
+  if version >= COMPACT_UUID_VERSION {
+    let mut buffer = [0; 16];
+    rd.read_exact(&mut buffer)?;
+    return Ok(Uuid::from_bytes(buffer));
+  }
+
   let len = rd.read_u8()?;
   let mut buffer = vec![0; len as usize];
 
@@ -53,54 +133,109 @@ pub fn serverid<R: Read>(rd: &mut R) -> anyhow::Result<ServerId> {
 }
 
 pub fn string<R: Read>(rd: &mut R) -> anyhow::Result<String> {
-  let len = rd.read_u8()?;
-  let mut buffer = vec![0; len as usize];
-
-  rd.read_exact(&mut buffer)?;
+  let len = u64::try_from(u128(rd)?)?;
+  // the buffer only grows with the bytes actually read, a bogus length cannot make it huge
+  let mut buffer = Vec::new();
+  rd.take(len).read_to_end(&mut buffer)?;
+  if buffer.len() as u64 != len {
+    return Err(anyhow!(
+      "string of {} bytes, only {} available",
+      len,
+      buffer.len()
+    ));
+  }
 
   let res = String::from_utf8(buffer)?;
 
   Ok(res)
 }
 
+/// reads the kind of a server frame, earlier versions only carry server messages
+pub fn frame_kind<R: Read>(rd: &mut R, version: u8) -> anyhow::Result<FrameKind> {
+  if version < FRAME_KIND_VERSION {
+    return Ok(FrameKind::Server);
+  }
+  match rd.read_u8()? {
+    0 => Ok(FrameKind::Server),
+    1 => Ok(FrameKind::Auth),
+    2 => Ok(FrameKind::Ping),
+    3 if version >= ACK_FRAME_VERSION => Ok(FrameKind::Ack),
+    tag => Err(anyhow!("unknown frame kind {}", tag)),
+  }
+}
+
+pub fn option_serverid<R: Read>(rd: &mut R) -> anyhow::Result<Option<ServerId>> {
+  match rd.read_u8()? {
+    0 => Ok(None),
+    1 => Ok(Some(serverid(rd)?)),
+    x => Err(anyhow!("invalid server option tag {}", x)),
+  }
+}
+
+/// reads exactly `N` bytes, for the fixed size fields
+pub fn read_array<const N: usize, R: Read>(rd: &mut R) -> anyhow::Result<[u8; N]> {
+  let mut buf = [0; N];
+  rd.read_exact(&mut buf)?;
+  Ok(buf)
+}
+
+pub fn nonce<const N: usize, R: Read>(rd: &mut R) -> anyhow::Result<Nonce<N>> {
+  Ok(Nonce::from_bytes(read_array(rd)?))
+}
+
 pub fn auth<R: Read>(rd: &mut R) -> anyhow::Result<AuthMessage> {
-  let opt = u128(rd)?;
-  match opt as u8 {
+  match rd.read_u8()? {
     0 => {
       let client = clientid(rd)?;
-      let buf = rd.read_u64::<LittleEndian>()?;
       return Ok(AuthMessage::Hello {
         user: client,
-        nonce: buf.to_le_bytes(),
+        nonce: nonce(rd)?,
       });
     }
     1 => {
       let server = serverid(rd)?;
-      let buf = rd.read_u64::<LittleEndian>()?;
       return Ok(AuthMessage::Nonce {
         server: server,
-        nonce: buf.to_le_bytes(),
+        nonce: nonce(rd)?,
       });
     }
     2 => {
-      let buf: u128 = rd.read_u128::<LittleEndian>()?;
       return Ok(AuthMessage::Auth {
-        response: buf.to_le_bytes(),
+        response: read_array(rd)?,
       });
     }
-    _ => return Err(anyhow!("")),
+    tag => return Err(anyhow!("unknown auth tag {}", tag)),
   };
 }
 
+pub fn attachments<R: Read>(rd: &mut R) -> anyhow::Result<Vec<(String, String)>> {
+  let size = u128(rd)?;
+  let mut out = Vec::new();
+  for _ in 0..size {
+    out.push((string(rd)?, string(rd)?));
+  }
+  Ok(out)
+}
+
 pub fn client<R: Read>(rd: &mut R) -> anyhow::Result<ClientMessage> {
-  let opt = u128(rd)?;
-  match opt as u8 {
+  client_versioned(rd, PROTOCOL_VERSION)
+}
+
+// before ATTACHMENTS_VERSION, text messages have no attachments
+pub fn client_versioned<R: Read>(rd: &mut R, version: u8) -> anyhow::Result<ClientMessage> {
+  match rd.read_u8()? {
     0 => {
       let client = clientid(rd)?;
       let content = string(rd)?;
+      let attachments = if version >= ATTACHMENTS_VERSION {
+        attachments(rd)?
+      } else {
+        Vec::new()
+      };
       return Ok(ClientMessage::Text {
         dest: client,
         content: content,
+        attachments,
       });
     }
     1 => {
@@ -115,33 +250,475 @@ pub fn client<R: Read>(rd: &mut R) -> anyhow::Result<ClientMessage> {
         content: content,
       });
     }
-    _ => return Err(anyhow!("")),
+    2 => {
+      let topic = string(rd)?;
+      let content = string(rd)?;
+      return Ok(ClientMessage::Publish { topic, content });
+    }
+    3 => {
+      let message_id = u128(rd)?;
+      let dest = clientid(rd)?;
+      let content = string(rd)?;
+      return Ok(ClientMessage::Edit {
+        message_id,
+        dest,
+        content,
+      });
+    }
+    4 => {
+      let name = string(rd)?;
+      let content = string(rd)?;
+      return Ok(ClientMessage::ToName { name, content });
+    }
+    tag => return Err(anyhow!("unknown client message tag {}", tag)),
   };
 }
 
+pub fn client_error<R: Read>(rd: &mut R) -> anyhow::Result<ClientError> {
+  let tag = rd.read_u8()?;
+  let mut e =
+    ClientError::from_tag(tag).ok_or_else(|| anyhow!("unknown client error tag {}", tag))?;
+  if let ClientError::BoxFull(client) | ClientError::RecipientGone(client) = &mut e {
+    *client = clientid(rd)?;
+  }
+  Ok(e)
+}
+
 pub fn client_replies<R: Read>(rd: &mut R) -> anyhow::Result<Vec<ClientReply>> {
-  todo!()
+  client_replies_nested(rd, 0)
+}
+
+/// decodes replies that are nested `depth` times inside other messages
+pub fn client_replies_nested<R: Read>(
+  rd: &mut R,
+  depth: usize,
+) -> anyhow::Result<Vec<ClientReply>> {
+  client_replies_versioned(rd, depth, PROTOCOL_VERSION)
+}
+
+/// before MESSAGE_ID_VERSION, deliveries carry no message id, and are decoded with id 0
+pub fn client_replies_versioned<R: Read>(
+  rd: &mut R,
+  depth: usize,
+  version: u8,
+) -> anyhow::Result<Vec<ClientReply>> {
+  let message_id = |rd: &mut R| {
+    if version >= MESSAGE_ID_VERSION {
+      u128(rd)
+    } else {
+      Ok(0)
+    }
+  };
+  let len = u128(rd)?;
+  let mut replies = Vec::new();
+  for _ in 0..len {
+    let reply = match rd.read_u8()? {
+      0 => ClientReply::Delivered(message_id(rd)?),
+      4 => {
+        let n = u128(rd)?;
+        let first = message_id(rd)?;
+        if n > MAX_REPLIES.saturating_sub(replies.len()) as u128 {
+          return Err(anyhow!("too many replies, a run of {} deliveries", n));
+        }
+        let ids = (0..n).map(|i| {
+          if version >= MESSAGE_ID_VERSION {
+            first.wrapping_add(i)
+          } else {
+            0
+          }
+        });
+        replies.extend(ids.map(ClientReply::Delivered));
+        continue;
+      }
+      1 => ClientReply::Error(client_error(rd)?),
+      2 => ClientReply::Delayed(message_id(rd)?),
+      3 => {
+        let dest = serverid(rd)?;
+        ClientReply::Transfer(dest, server_nested(rd, depth + 1)?)
+      }
+      5 => ClientReply::Heartbeat,
+      tag => return Err(anyhow!("unknown client reply tag {}", tag)),
+    };
+    replies.push(reply);
+  }
+  Ok(replies)
+}
+
+pub fn delayed_error<R: Read>(rd: &mut R) -> anyhow::Result<DelayedError> {
+  match rd.read_u8()? {
+    0 => Ok(DelayedError::UnknownRecipient(clientid(rd)?)),
+    1 => Ok(DelayedError::RouteLost(clientid(rd)?)),
+    tag => Err(anyhow!("unknown delayed error tag {}", tag)),
+  }
 }
 
 pub fn client_poll_reply<R: Read>(rd: &mut R) -> anyhow::Result<ClientPollReply> {
-  todo!()
+  client_poll_reply_versioned(rd, PROTOCOL_VERSION)
+}
+
+pub fn client_poll_reply_versioned<R: Read>(
+  rd: &mut R,
+  version: u8,
+) -> anyhow::Result<ClientPollReply> {
+  match rd.read_u8()? {
+    0 => {
+      let src = clientid(rd)?;
+      let srcsrv = option_serverid(rd)?;
+      let content = string(rd)?;
+      let attachments = if version >= ATTACHMENTS_VERSION {
+        attachments(rd)?
+      } else {
+        Vec::new()
+      };
+      let message_id = if version >= MESSAGE_ID_VERSION {
+        u128(rd)?
+      } else {
+        0
+      };
+      Ok(ClientPollReply::Message {
+        src,
+        srcsrv,
+        content,
+        attachments,
+        message_id,
+      })
+    }
+    1 => Ok(ClientPollReply::DelayedError(delayed_error(rd)?)),
+    2 => Ok(ClientPollReply::Nothing),
+    3 => Ok(ClientPollReply::System { text: string(rd)? }),
+    4 => {
+      let len = u128(rd)?;
+      let mut ids = Vec::new();
+      for _ in 0..len {
+        ids.push(u128(rd)?);
+      }
+      Ok(ClientPollReply::Receipts(ids))
+    }
+    tag => Err(anyhow!("unknown poll reply tag {}", tag)),
+  }
+}
+
+pub fn poll_ack<R: Read>(rd: &mut R) -> anyhow::Result<PollAck> {
+  let reply = client_poll_reply(rd)?;
+  let contiguous_seqid = u128(rd)?;
+  Ok(PollAck {
+    reply,
+    contiguous_seqid,
+  })
+}
+
+pub fn ack_status<R: Read>(rd: &mut R) -> anyhow::Result<AckStatus> {
+  let contiguous = u128(rd)?;
+  let forgotten = u128(rd)?;
+  let sparse = u64::try_from(u128(rd)?)?;
+  Ok(AckStatus {
+    contiguous,
+    forgotten,
+    sparse,
+  })
 }
 
 pub fn server<R: Read>(rd: &mut R) -> anyhow::Result<ServerMessage> {
-  todo!()
+  server_nested(rd, 0)
+}
+
+/// decodes a server message that is nested `depth` times inside other messages
+pub fn server_nested<R: Read>(rd: &mut R, depth: usize) -> anyhow::Result<ServerMessage> {
+  if depth > MAX_DEPTH {
+    return Err(DecodeError::MaxDepthExceeded.into());
+  }
+  match rd.read_u8()? {
+    0 => {
+      let len = u128(rd)?;
+      let mut route = Vec::new();
+      for _ in 0..len {
+        route.push(serverid(rd)?);
+      }
+      let clients = userlist(rd)?;
+      Ok(ServerMessage::Announce { route, clients })
+    }
+    1 => {
+      let src = clientid(rd)?;
+      let srcsrv = serverid(rd)?;
+      let len = u128(rd)?;
+      let mut dsts = Vec::new();
+      for _ in 0..len {
+        let c = clientid(rd)?;
+        dsts.push((c, serverid(rd)?));
+      }
+      let content = string(rd)?;
+      Ok(ServerMessage::Message(FullyQualifiedMessage {
+        src,
+        srcsrv,
+        dsts,
+        content,
+      }))
+    }
+    tag => Err(anyhow!("unknown server message tag {}", tag)),
+  }
 }
 
 pub fn userlist<R: Read>(rd: &mut R) -> anyhow::Result<HashMap<ClientId, String>> {
-  todo!()
+  userlist_versioned(rd, PROTOCOL_VERSION)
+}
+
+pub fn userlist_versioned<R: Read>(
+  rd: &mut R,
+  version: u8,
+) -> anyhow::Result<HashMap<ClientId, String>> {
+  if version < COMPRESSED_USERLIST_VERSION {
+    return plain_userlist(rd);
+  }
+  match rd.read_u8()? {
+    0 => plain_userlist(rd),
+    1 => compressed_userlist(rd),
+    tag => Err(anyhow!("unknown user list flag {}", tag)),
+  }
+}
+
+fn plain_userlist<R: Read>(rd: &mut R) -> anyhow::Result<HashMap<ClientId, String>> {
+  let len = u128(rd)?;
+  let mut users = HashMap::new();
+  for _ in 0..len {
+    let id = clientid(rd)?;
+    let name = string(rd)?;
+    check_name_len(&name)?;
+    users.insert(id, name);
+  }
+  Ok(users)
+}
+
+// no client can register a longer name, a list carrying one is forged
+fn check_name_len(name: &str) -> anyhow::Result<()> {
+  if name.len() > MAX_NAME_LEN {
+    return Err(anyhow!(
+      "name of {} bytes, at most {} are allowed",
+      name.len(),
+      MAX_NAME_LEN
+    ));
+  }
+  Ok(())
+}
+
+// names are sent as the length of the prefix they share with the previous one, and the rest
+// each name is checked against MAX_NAME_LEN, a short list cannot expand to huge names
+fn compressed_userlist<R: Read>(rd: &mut R) -> anyhow::Result<HashMap<ClientId, String>> {
+  let len = u128(rd)?;
+  let mut users = HashMap::new();
+  let mut prev = String::new();
+  for _ in 0..len {
+    let id = clientid(rd)?;
+    let shared = usize::try_from(u128(rd)?)?;
+    if !prev.is_char_boundary(shared) {
+      return Err(anyhow!(
+        "shared prefix of {} bytes does not fit the previous name of {} bytes",
+        shared,
+        prev.len()
+      ));
+    }
+    prev.truncate(shared);
+    prev.push_str(&string(rd)?);
+    check_name_len(&prev)?;
+    users.insert(id, prev.clone());
+  }
+  Ok(users)
+}
+
+pub fn client_location<R: Read>(rd: &mut R) -> anyhow::Result<ClientLocation> {
+  match rd.read_u8()? {
+    0 => Ok(ClientLocation::Local),
+    1 => Ok(ClientLocation::Remote(serverid(rd)?)),
+    tag => Err(anyhow!("unknown client location {}", tag)),
+  }
+}
+
+pub fn reachable<R: Read>(rd: &mut R) -> anyhow::Result<HashMap<ClientId, ClientLocation>> {
+  let len = u128(rd)?;
+  let mut clients = HashMap::new();
+  for _ in 0..len {
+    let id = clientid(rd)?;
+    clients.insert(id, client_location(rd)?);
+  }
+  Ok(clients)
+}
+
+pub fn user_page<R: Read>(rd: &mut R) -> anyhow::Result<UserPage> {
+  let len = u128(rd)?;
+  let mut users = Vec::new();
+  for _ in 0..len {
+    let id = clientid(rd)?;
+    users.push((id, string(rd)?));
+  }
+  let total = u64::try_from(u128(rd)?)?;
+  let has_more = bool(rd)?;
+  Ok(UserPage {
+    users,
+    total,
+    has_more,
+  })
 }
 
 pub fn client_query<R: Read>(rd: &mut R) -> anyhow::Result<ClientQuery> {
-  todo!()
+  match rd.read_u8()? {
+    0 => Ok(ClientQuery::Register(string(rd)?)),
+    1 => Ok(ClientQuery::Message(client(rd)?)),
+    2 => Ok(ClientQuery::Poll),
+    3 => Ok(ClientQuery::ListUsers),
+    4 => Ok(ClientQuery::Recall {
+      message_id: u128(rd)?,
+    }),
+    5 => Ok(ClientQuery::Subscribe(string(rd)?)),
+    6 => Ok(ClientQuery::Unsubscribe(string(rd)?)),
+    7 => Ok(ClientQuery::HomeServer(clientid(rd)?)),
+    8 => Ok(ClientQuery::RegisterAndMessage {
+      name: string(rd)?,
+      message: client(rd)?,
+    }),
+    9 => Ok(ClientQuery::SetOrder(match rd.read_u8()? {
+      0 => DeliveryOrder::Fifo,
+      1 => DeliveryOrder::Lifo,
+      x => return Err(anyhow!("invalid delivery order {}", x)),
+    })),
+    10 => Ok(ClientQuery::ServerInfo),
+    11 => Ok(ClientQuery::AckStatus),
+    12 => Ok(ClientQuery::SetDelivery(bool(rd)?)),
+    13 => Ok(ClientQuery::Heartbeat),
+    14 => Ok(ClientQuery::ListUsersPage {
+      offset: u64::try_from(u128(rd)?)?,
+      limit: u64::try_from(u128(rd)?)?,
+    }),
+    15 => Ok(ClientQuery::Reachable),
+    tag => Err(DecodeError::UnknownQuery(tag).into()),
+  }
+}
+
+pub fn server_info<R: Read>(rd: &mut R) -> anyhow::Result<ServerInfo> {
+  Ok(ServerInfo {
+    server_id: serverid(rd)?,
+    group_name: string(rd)?,
+    version: rd.read_u8()?,
+  })
 }
 
 pub fn sequence<X, R: Read, DEC>(rd: &mut R, d: DEC) -> anyhow::Result<Sequence<X>>
 where
   DEC: FnOnce(&mut R) -> anyhow::Result<X>,
 {
-  todo!()
+  let seqid = u128(rd)?;
+  let src = clientid(rd)?;
+  let workproof = u128(rd)?;
+  let timestamp = match rd.read_u8()? {
+    0 => None,
+    1 => Some(u64::try_from(u128(rd)?)?),
+    x => return Err(anyhow!("invalid timestamp tag {}", x)),
+  };
+  let content = d(rd)?;
+  Ok(Sequence {
+    seqid,
+    src,
+    workproof,
+    timestamp,
+    content,
+  })
+}
+
+/// decodes a sequence that ends the frame, the bytes after it are handled according to `trailing`
+pub fn sequence_checked<X, R: Read, DEC>(
+  rd: &mut R,
+  d: DEC,
+  trailing: Trailing,
+) -> anyhow::Result<Sequence<X>>
+where
+  DEC: FnOnce(&mut R) -> anyhow::Result<X>,
+{
+  let sq = sequence(rd, d)?;
+  end_of_frame(rd, trailing)?;
+  Ok(sq)
+}
+
+/// decodes a server message that ends the frame, the bytes after it are handled according to
+/// `trailing`
+pub fn server_checked<R: Read>(rd: &mut R, trailing: Trailing) -> anyhow::Result<ServerMessage> {
+  let m = server(rd)?;
+  end_of_frame(rd, trailing)?;
+  Ok(m)
+}
+
+/// reads the rest of a frame, starting with CHECKSUM_VERSION its CRC32 trailer is checked and removed
+pub fn frame_versioned<R: Read>(rd: &mut R, version: u8) -> anyhow::Result<Cursor<Vec<u8>>> {
+  let mut frame = Vec::new();
+  rd.read_to_end(&mut frame)?;
+  if version >= CHECKSUM_VERSION {
+    if frame.len() < 4 {
+      return Err(anyhow!("frame too short for its checksum"));
+    }
+    let trailer = frame.split_off(frame.len() - 4);
+    let expected = u32::from_le_bytes(trailer.try_into().unwrap());
+    if crc32fast::hash(&frame) != expected {
+      return Err(DecodeError::ChecksumMismatch.into());
+    }
+  }
+  Ok(Cursor::new(frame))
+}
+
+/// decodes a sequence that ends the frame, checking its trailer if the version carries one
+pub fn sequence_versioned<X, R: Read, DEC>(
+  rd: &mut R,
+  d: DEC,
+  version: u8,
+) -> anyhow::Result<Sequence<X>>
+where
+  DEC: FnOnce(&mut Cursor<Vec<u8>>) -> anyhow::Result<X>,
+{
+  sequence(&mut frame_versioned(rd, version)?, d)
+}
+
+/// decodes a server message that ends the frame, checking its trailer if the version carries one
+pub fn server_versioned<R: Read>(rd: &mut R, version: u8) -> anyhow::Result<ServerMessage> {
+  server(&mut frame_versioned(rd, version)?)
+}
+
+/// decodes a request, that ends the frame, checking the checksum of its sequence
+pub fn request<X, R: Read, DEC>(rd: &mut R, d: DEC) -> anyhow::Result<Request<X>>
+where
+  DEC: FnOnce(&mut Cursor<Vec<u8>>) -> anyhow::Result<X>,
+{
+  let request_id = u64::try_from(u128(rd)?)?;
+  let sequence = sequence_versioned(rd, d, PROTOCOL_VERSION)?;
+  Ok(Request {
+    request_id,
+    sequence,
+  })
+}
+
+/// decodes the request id and the status of a reply, the error of a failed query included
+/// the reader is left at the start of the payload of a successful reply
+pub fn reply_header<R: Read>(rd: &mut R) -> anyhow::Result<Reply<Result<(), ClientError>>> {
+  let request_id = u64::try_from(u128(rd)?)?;
+  let payload = match rd.read_u8()? {
+    0 => Result::Ok(()),
+    1 => Err(client_error(rd)?),
+    status => return Err(anyhow!("unknown reply status {}", status)),
+  };
+  Ok(Reply {
+    request_id,
+    payload,
+  })
+}
+
+/// the error of a failed query is returned as a ClientError
+pub fn reply<X, R: Read, DEC>(rd: &mut R, d: DEC) -> anyhow::Result<Reply<X>>
+where
+  DEC: FnOnce(&mut R) -> anyhow::Result<X>,
+{
+  let Reply {
+    request_id,
+    payload,
+  } = reply_header(rd)?;
+  payload?;
+  let payload = d(rd)?;
+  Ok(Reply {
+    request_id,
+    payload,
+  })
 }
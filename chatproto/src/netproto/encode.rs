@@ -3,9 +3,15 @@ use std::{collections::HashMap, io::Write};
 use byteorder::{LittleEndian, WriteBytesExt};
 use uuid::Uuid;
 
+use super::{
+  FrameKind, ACK_FRAME_VERSION, ATTACHMENTS_VERSION, CHECKSUM_VERSION, COMPACT_UUID_VERSION,
+  COMPRESSED_USERLIST_VERSION, FRAME_KIND_VERSION, MESSAGE_ID_VERSION, PROTOCOL_VERSION,
+  USERLIST_COMPRESSION_THRESHOLD,
+};
 use crate::messages::{
-  AuthMessage, ClientId, ClientMessage, ClientPollReply, ClientQuery, ClientReply, Sequence,
-  ServerId, ServerMessage,
+  AckStatus, AuthMessage, ClientError, ClientId, ClientLocation, ClientMessage, ClientPollReply,
+  ClientQuery, ClientReply, DelayedError, DeliveryOrder, Nonce, PollAck, Reply, Request, Sequence,
+  ServerId, ServerInfo, ServerMessage, UserPage,
 };
 
 // look at the README.md for guidance on writing this function
@@ -31,6 +37,14 @@ where
   }
 }
 
+// booleans are a single byte, 0 or 1
+pub fn bool<W>(w: &mut W, m: bool) -> std::io::Result<()>
+where
+  W: Write,
+{
+  w.write_u8(m as u8)
+}
+
 /* UUIDs are 128bit values, but in the situation they are represented as [u8; 16]
   don't forget that arrays are encoded with their sizes first, and then their content
 */
@@ -38,7 +52,17 @@ fn uuid<W>(w: &mut W, m: &Uuid) -> std::io::Result<()>
 where
   W: Write,
 {
-  w.write_u8(m.as_bytes().len() as u8)?;
+  uuid_versioned(w, m, PROTOCOL_VERSION)
+}
+
+// starting with COMPACT_UUID_VERSION, the length is omitted, as it is always 16
+pub fn uuid_versioned<W>(w: &mut W, m: &Uuid, version: u8) -> std::io::Result<()>
+where
+  W: Write,
+{
+  if version < COMPACT_UUID_VERSION {
+    w.write_u8(m.as_bytes().len() as u8)?;
+  }
   w.write_all(m.as_bytes())
 }
 
@@ -68,7 +92,7 @@ where
   W: Write,
 {
   let bytes = m.as_bytes();
-  let _ = w.write_u8(bytes.len() as u8);
+  u128(w, bytes.len() as u128)?;
   w.write_all(bytes)
 }
 
@@ -99,6 +123,13 @@ where
 
  */
 
+pub fn nonce<W, const N: usize>(w: &mut W, m: &Nonce<N>) -> std::io::Result<()>
+where
+  W: Write,
+{
+  w.write_all(&m.to_bytes())
+}
+
 pub fn auth<W>(w: &mut W, m: &AuthMessage) -> std::io::Result<()>
 where
   W: Write,
@@ -107,12 +138,12 @@ where
     AuthMessage::Hello { user, nonce } => {
       w.write_u8(0)?;
       clientid(w, user)?;
-      w.write_all(nonce)
+      self::nonce(w, nonce)
     }
     AuthMessage::Nonce { server, nonce } => {
       w.write_u8(1)?;
       serverid(w, server)?;
-      w.write_all(nonce)
+      self::nonce(w, nonce)
     }
     AuthMessage::Auth { response } => {
       w.write_u8(2)?;
@@ -121,76 +152,339 @@ where
   }
 }
 
+// starting with FRAME_KIND_VERSION, server frames start with their kind, nothing is written before
+// acks only exist starting with ACK_FRAME_VERSION
+pub fn frame_kind<W>(w: &mut W, m: FrameKind, version: u8) -> std::io::Result<()>
+where
+  W: Write,
+{
+  if m == FrameKind::Ack && version < ACK_FRAME_VERSION {
+    return Err(std::io::Error::new(
+      std::io::ErrorKind::InvalidInput,
+      "no ack frames before ACK_FRAME_VERSION",
+    ));
+  }
+  if version < FRAME_KIND_VERSION {
+    return Ok(());
+  }
+  w.write_u8(match m {
+    FrameKind::Server => 0,
+    FrameKind::Auth => 1,
+    FrameKind::Ping => 2,
+    FrameKind::Ack => 3,
+  })
+}
+
+// 0 when there is no server, 1 followed by the server id otherwise
+pub fn option_serverid<W>(w: &mut W, m: &Option<ServerId>) -> std::io::Result<()>
+where
+  W: Write,
+{
+  match m {
+    None => w.write_u8(0),
+    Some(s) => {
+      w.write_u8(1)?;
+      serverid(w, s)
+    }
+  }
+}
+
 pub fn server<W>(w: &mut W, m: &ServerMessage) -> std::io::Result<()>
 where
   W: Write,
 {
   match m {
-    ServerMessage::Announce { route, clients } => todo!(),
+    ServerMessage::Announce { route, clients } => {
+      w.write_u8(0)?;
+      u128(w, route.len() as u128)?;
+      for s in route {
+        serverid(w, s)?;
+      }
+      userlist(w, clients)
+    }
     ServerMessage::Message(val) => {
-      // u128(w, 1)?;
-      // clientid(w, &val.src);
-      // serverid(w, &val.srcsrv);
-
-      todo!()
+      w.write_u8(1)?;
+      clientid(w, &val.src)?;
+      serverid(w, &val.srcsrv)?;
+      u128(w, val.dsts.len() as u128)?;
+      for (c, s) in &val.dsts {
+        clientid(w, c)?;
+        serverid(w, s)?;
+      }
+      string(w, &val.content)
     }
   }
 }
 
+pub fn attachments<W>(w: &mut W, m: &[(String, String)]) -> std::io::Result<()>
+where
+  W: Write,
+{
+  u128(w, m.len() as u128)?;
+  for (key, value) in m {
+    string(w, key)?;
+    string(w, value)?;
+  }
+  Ok(())
+}
+
 pub fn client<W>(w: &mut W, m: &ClientMessage) -> std::io::Result<()>
+where
+  W: Write,
+{
+  client_versioned(w, m, PROTOCOL_VERSION)
+}
+
+// starting with ATTACHMENTS_VERSION, text messages end with their attachments, that are dropped
+// by earlier versions
+pub fn client_versioned<W>(w: &mut W, m: &ClientMessage, version: u8) -> std::io::Result<()>
 where
   W: Write,
 {
   match m {
-    ClientMessage::Text { dest, content } => {
+    ClientMessage::Text {
+      dest,
+      content,
+      attachments,
+    } => {
       w.write_u8(0)?;
       clientid(w, dest)?;
-      string(w, content)
+      string(w, content)?;
+      if version >= ATTACHMENTS_VERSION {
+        self::attachments(w, attachments)?;
+      }
+      Ok(())
     }
     ClientMessage::MText { dest, content } => {
       w.write_u8(1)?;
       u128(w, dest.len() as u128)?;
-      let _ = dest.iter().for_each(|x| {
-        let _ = clientid(w, x);
-      });
+      for x in dest {
+        clientid(w, x)?;
+      }
+      string(w, content)
+    }
+    ClientMessage::Publish { topic, content } => {
+      w.write_u8(2)?;
+      string(w, topic)?;
+      string(w, content)
+    }
+    ClientMessage::Edit {
+      message_id,
+      dest,
+      content,
+    } => {
+      w.write_u8(3)?;
+      u128(w, *message_id)?;
+      clientid(w, dest)?;
+      string(w, content)
+    }
+    ClientMessage::ToName { name, content } => {
+      w.write_u8(4)?;
+      string(w, name)?;
       string(w, content)
     }
   }
 }
 
+pub fn client_error<W>(w: &mut W, m: &ClientError) -> std::io::Result<()>
+where
+  W: Write,
+{
+  w.write_u8(m.tag())?;
+  match m {
+    ClientError::BoxFull(x) | ClientError::RecipientGone(x) => clientid(w, x),
+    _ => Ok(()),
+  }
+}
+
 pub fn client_replies<W>(w: &mut W, m: &[ClientReply]) -> std::io::Result<()>
 where
   W: Write,
 {
-  u128(w, m.len() as u128);
-  m.iter().for_each(|x| {
-    match x {
-      ClientReply::Delivered => u128(w, 0),
-      ClientReply::Error(val) => Ok({
-        u128(w, 1);
-        match val {
-          crate::messages::ClientError::WorkProofError => u128(w, 0),
-          crate::messages::ClientError::UnknownClient => u128(w, 1),
-          crate::messages::ClientError::SequenceError => u128(w, 2),
-          crate::messages::ClientError::BoxFull(x) => {
-            u128(w, 3);
-            clientid(w, x)
-          }
-          crate::messages::ClientError::InternalError => u128(w, 4),
-        };
-      }),
-      ClientReply::Delayed => todo!(),
-      ClientReply::Transfer(v1, v2) => todo!(),
-    };
-  });
+  client_replies_versioned(w, m, PROTOCOL_VERSION)
+}
+
+// starting with MESSAGE_ID_VERSION, deliveries are followed by the id of their message, and a run
+// of deliveries by the id of its first message, the ids of a run following each other
+pub fn client_replies_versioned<W>(w: &mut W, m: &[ClientReply], version: u8) -> std::io::Result<()>
+where
+  W: Write,
+{
+  let with_ids = version >= MESSAGE_ID_VERSION;
+  let message_id = |w: &mut W, id: u128| if with_ids { u128(w, id) } else { Ok(()) };
+  // consecutive deliveries are collapsed into a single DeliveredN
+  let runs: Vec<&[ClientReply]> = m
+    .chunk_by(|a, b| match (a, b) {
+      (ClientReply::Delivered(a), ClientReply::Delivered(b)) => {
+        !with_ids || a.checked_add(1) == Some(*b)
+      }
+      _ => false,
+    })
+    .collect();
+  u128(w, runs.len() as u128)?;
+  for run in runs {
+    if let (true, ClientReply::Delivered(first)) = (run.len() > 1, &run[0]) {
+      w.write_u8(4)?;
+      u128(w, run.len() as u128)?;
+      message_id(w, *first)?;
+      continue;
+    }
+    match &run[0] {
+      ClientReply::Delivered(id) => {
+        w.write_u8(0)?;
+        message_id(w, *id)?
+      }
+      ClientReply::Error(val) => {
+        w.write_u8(1)?;
+        client_error(w, val)?
+      }
+      ClientReply::Delayed(id) => {
+        w.write_u8(2)?;
+        message_id(w, *id)?
+      }
+      ClientReply::Transfer(dest, msg) => {
+        w.write_u8(3)?;
+        serverid(w, dest)?;
+        server(w, msg)?
+      }
+      ClientReply::DeliveredN { first, count } => {
+        w.write_u8(4)?;
+        u128(w, *count)?;
+        message_id(w, *first)?
+      }
+      ClientReply::Heartbeat => w.write_u8(5)?,
+    }
+  }
   Ok(())
 }
 
+pub fn delayed_error<W>(w: &mut W, m: &DelayedError) -> std::io::Result<()>
+where
+  W: Write,
+{
+  match m {
+    DelayedError::UnknownRecipient(client) => {
+      w.write_u8(0)?;
+      clientid(w, client)
+    }
+    DelayedError::RouteLost(client) => {
+      w.write_u8(1)?;
+      clientid(w, client)
+    }
+  }
+}
+
 pub fn client_poll_reply<W>(w: &mut W, m: &ClientPollReply) -> std::io::Result<()>
 where
   W: Write,
 {
-  todo!()
+  client_poll_reply_versioned(w, m, PROTOCOL_VERSION)
+}
+
+// starting with ATTACHMENTS_VERSION, messages end with their attachments, and starting with
+// MESSAGE_ID_VERSION with their id
+pub fn client_poll_reply_versioned<W>(
+  w: &mut W,
+  m: &ClientPollReply,
+  version: u8,
+) -> std::io::Result<()>
+where
+  W: Write,
+{
+  match m {
+    ClientPollReply::Message {
+      src,
+      srcsrv,
+      content,
+      attachments,
+      message_id,
+    } => {
+      w.write_u8(0)?;
+      clientid(w, src)?;
+      option_serverid(w, srcsrv)?;
+      string(w, content)?;
+      if version >= ATTACHMENTS_VERSION {
+        self::attachments(w, attachments)?;
+      }
+      if version >= MESSAGE_ID_VERSION {
+        u128(w, *message_id)?;
+      }
+      Ok(())
+    }
+    ClientPollReply::DelayedError(e) => {
+      w.write_u8(1)?;
+      delayed_error(w, e)
+    }
+    ClientPollReply::Nothing => w.write_u8(2),
+    ClientPollReply::System { text } => {
+      w.write_u8(3)?;
+      string(w, text)
+    }
+    ClientPollReply::Receipts(ids) => {
+      w.write_u8(4)?;
+      u128(w, ids.len() as u128)?;
+      for id in ids {
+        u128(w, *id)?;
+      }
+      Ok(())
+    }
+  }
+}
+
+// the poll reply, followed by the acknowledged sequence id
+pub fn poll_ack<W>(w: &mut W, m: &PollAck) -> std::io::Result<()>
+where
+  W: Write,
+{
+  client_poll_reply(w, &m.reply)?;
+  u128(w, m.contiguous_seqid)
+}
+
+pub fn ack_status<W>(w: &mut W, m: &AckStatus) -> std::io::Result<()>
+where
+  W: Write,
+{
+  u128(w, m.contiguous)?;
+  u128(w, m.forgotten)?;
+  u128(w, m.sparse as u128)
+}
+
+pub fn client_location<W>(w: &mut W, m: &ClientLocation) -> std::io::Result<()>
+where
+  W: Write,
+{
+  match m {
+    ClientLocation::Local => w.write_u8(0),
+    ClientLocation::Remote(server) => {
+      w.write_u8(1)?;
+      serverid(w, server)
+    }
+  }
+}
+
+pub fn reachable<W>(w: &mut W, m: &HashMap<ClientId, ClientLocation>) -> std::io::Result<()>
+where
+  W: Write,
+{
+  u128(w, m.len() as u128)?;
+  for (k, v) in m {
+    clientid(w, k)?;
+    client_location(w, v)?;
+  }
+  Ok(())
+}
+
+pub fn user_page<W>(w: &mut W, m: &UserPage) -> std::io::Result<()>
+where
+  W: Write,
+{
+  u128(w, m.users.len() as u128)?;
+  for (id, name) in &m.users {
+    clientid(w, id)?;
+    string(w, name)?;
+  }
+  u128(w, m.total as u128)?;
+  bool(w, m.has_more)
 }
 
 // hashmaps are encoded by first writing the size (using u128), then each key and values
@@ -198,14 +492,122 @@ pub fn userlist<W>(w: &mut W, m: &HashMap<ClientId, String>) -> std::io::Result<
 where
   W: Write,
 {
-  todo!()
+  userlist_versioned(w, m, PROTOCOL_VERSION)
+}
+
+// starting with COMPRESSED_USERLIST_VERSION, lists start with a flag byte, 1 when compressed
+// compressed lists are sorted by name, and each name is sent as the length of the prefix it shares
+// with the previous name, followed by the rest of the name
+pub fn userlist_versioned<W>(
+  w: &mut W,
+  m: &HashMap<ClientId, String>,
+  version: u8,
+) -> std::io::Result<()>
+where
+  W: Write,
+{
+  let mut plain = Vec::new();
+  u128(&mut plain, m.len() as u128)?;
+  for (k, v) in m {
+    clientid(&mut plain, k)?;
+    string(&mut plain, v)?;
+  }
+  if version < COMPRESSED_USERLIST_VERSION {
+    return w.write_all(&plain);
+  }
+  if plain.len() <= USERLIST_COMPRESSION_THRESHOLD {
+    w.write_u8(0)?;
+    return w.write_all(&plain);
+  }
+  w.write_u8(1)?;
+  let mut users: Vec<_> = m.iter().collect();
+  users.sort_by(|a, b| a.1.cmp(b.1));
+  u128(w, users.len() as u128)?;
+  let mut prev = "";
+  for (id, name) in users {
+    let shared = shared_prefix(prev, name);
+    clientid(w, id)?;
+    u128(w, shared as u128)?;
+    string(w, &name[shared..])?;
+    prev = name;
+  }
+  Ok(())
+}
+
+// length in bytes of the longest prefix of both strings, that ends on a char boundary
+fn shared_prefix(a: &str, b: &str) -> usize {
+  a.char_indices()
+    .zip(b.chars())
+    .find(|((_, ca), cb)| ca != cb)
+    .map_or(a.len().min(b.len()), |((i, _), _)| i)
 }
 
 pub fn client_query<W>(w: &mut W, m: &ClientQuery) -> std::io::Result<()>
 where
   W: Write,
 {
-  todo!()
+  match m {
+    ClientQuery::Register(name) => {
+      w.write_u8(0)?;
+      string(w, name)
+    }
+    ClientQuery::Message(msg) => {
+      w.write_u8(1)?;
+      client(w, msg)
+    }
+    ClientQuery::Poll => w.write_u8(2),
+    ClientQuery::ListUsers => w.write_u8(3),
+    ClientQuery::Recall { message_id } => {
+      w.write_u8(4)?;
+      u128(w, *message_id)
+    }
+    ClientQuery::Subscribe(topic) => {
+      w.write_u8(5)?;
+      string(w, topic)
+    }
+    ClientQuery::HomeServer(client) => {
+      w.write_u8(7)?;
+      clientid(w, client)
+    }
+    ClientQuery::Unsubscribe(topic) => {
+      w.write_u8(6)?;
+      string(w, topic)
+    }
+    ClientQuery::RegisterAndMessage { name, message } => {
+      w.write_u8(8)?;
+      string(w, name)?;
+      client(w, message)
+    }
+    ClientQuery::SetOrder(order) => {
+      w.write_u8(9)?;
+      w.write_u8(match order {
+        DeliveryOrder::Fifo => 0,
+        DeliveryOrder::Lifo => 1,
+      })
+    }
+    ClientQuery::ServerInfo => w.write_u8(10),
+    ClientQuery::AckStatus => w.write_u8(11),
+    ClientQuery::SetDelivery(enabled) => {
+      w.write_u8(12)?;
+      bool(w, *enabled)
+    }
+    ClientQuery::Heartbeat => w.write_u8(13),
+    ClientQuery::ListUsersPage { offset, limit } => {
+      w.write_u8(14)?;
+      u128(w, *offset as u128)?;
+      u128(w, *limit as u128)
+    }
+    ClientQuery::Reachable => w.write_u8(15),
+  }
+}
+
+pub fn server_info<W>(w: &mut W, m: &ServerInfo) -> std::io::Result<()>
+where
+  W: Write,
+{
+  serverid(w, &m.server_id)?;
+  string(w, &m.group_name)?;
+  w.write_u8(m.version)
 }
 
 pub fn sequence<X, W, ENC>(w: &mut W, m: &Sequence<X>, f: ENC) -> std::io::Result<()>
@@ -214,5 +616,86 @@ where
   X: serde::Serialize,
   ENC: FnOnce(&mut W, &X) -> std::io::Result<()>,
 {
-  todo!()
+  u128(w, m.seqid)?;
+  clientid(w, &m.src)?;
+  u128(w, m.workproof)?;
+  match m.timestamp {
+    None => w.write_u8(0)?,
+    Some(t) => {
+      w.write_u8(1)?;
+      u128(w, t as u128)?
+    }
+  }
+  f(w, &m.content)
+}
+
+// writes an encoded frame, starting with CHECKSUM_VERSION it is followed by its CRC32
+fn checksummed<W>(w: &mut W, frame: &[u8], version: u8) -> std::io::Result<()>
+where
+  W: Write,
+{
+  w.write_all(frame)?;
+  if version >= CHECKSUM_VERSION {
+    w.write_u32::<LittleEndian>(crc32fast::hash(frame))?;
+  }
+  Ok(())
+}
+
+// the sequence, with a checksum trailer if the version carries one
+pub fn sequence_versioned<X, W, ENC>(
+  w: &mut W,
+  m: &Sequence<X>,
+  f: ENC,
+  version: u8,
+) -> std::io::Result<()>
+where
+  W: Write,
+  X: serde::Serialize,
+  ENC: FnOnce(&mut Vec<u8>, &X) -> std::io::Result<()>,
+{
+  let mut frame = Vec::new();
+  sequence(&mut frame, m, f)?;
+  checksummed(w, &frame, version)
+}
+
+// the server message, with a checksum trailer if the version carries one
+pub fn server_versioned<W>(w: &mut W, m: &ServerMessage, version: u8) -> std::io::Result<()>
+where
+  W: Write,
+{
+  let mut frame = Vec::new();
+  server(&mut frame, m)?;
+  checksummed(w, &frame, version)
+}
+
+// the request id, followed by the sequence and its checksum
+pub fn request<X, W, ENC>(w: &mut W, m: &Request<X>, f: ENC) -> std::io::Result<()>
+where
+  W: Write,
+  X: serde::Serialize,
+  ENC: FnOnce(&mut Vec<u8>, &X) -> std::io::Result<()>,
+{
+  u128(w, m.request_id as u128)?;
+  sequence_versioned(w, &m.sequence, f, PROTOCOL_VERSION)
+}
+
+// the request id, the status of a successful reply, then the payload
+pub fn reply<X, W, ENC>(w: &mut W, m: &Reply<X>, f: ENC) -> std::io::Result<()>
+where
+  W: Write,
+  ENC: FnOnce(&mut W, &X) -> std::io::Result<()>,
+{
+  u128(w, m.request_id as u128)?;
+  w.write_u8(0)?;
+  f(w, &m.payload)
+}
+
+// the request id, the status of a failed query, then the error it failed with
+pub fn error_reply<W>(w: &mut W, m: &Reply<ClientError>) -> std::io::Result<()>
+where
+  W: Write,
+{
+  u128(w, m.request_id as u128)?;
+  w.write_u8(1)?;
+  client_error(w, &m.payload)
 }
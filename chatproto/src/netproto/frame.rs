@@ -0,0 +1,446 @@
+use std::collections::HashMap;
+use std::io::{Cursor, Read};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+
+use super::{decode, encode};
+
+/// largest payload that can be split and reassembled
+pub const MAX_FRAMED_LEN: usize = 1 << 20;
+/// maximum number of partial payloads kept for a single peer, the oldest is dropped past it
+pub const MAX_PARTIAL_PER_PEER: usize = 8;
+/// maximum number of peers partial payloads are kept for, the peer whose oldest payload is the
+/// oldest is dropped past it
+pub const MAX_PEERS: usize = 1024;
+/// room taken by the header of a chunk at most, its four fields being varints
+pub const CHUNK_HEADER_LEN: usize = 4 * 17;
+/// smallest amount of data a chunk carries, but for the last one, so that a payload is split in
+/// at most MAX_FRAMED_LEN / MIN_CHUNK_LEN chunks
+pub const MIN_CHUNK_LEN: usize = 512;
+/// size of the datagrams the chunks are sent in
+pub const CHUNK_DATAGRAM_LEN: usize = 1400;
+
+/// a piece of a payload that is too large for a single datagram
+/// each chunk carries the id of its payload, the total length of the payload, its index, and the
+/// number of chunks, so that the receiver can reassemble the payload whatever the arrival order
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Chunk {
+  pub id: u64,
+  pub total: usize,
+  pub index: usize,
+  pub count: usize,
+  pub data: Vec<u8>,
+}
+
+/// splits `payload` into datagrams of at most `max_datagram` bytes, header included
+/// `id` tells the payloads of a peer apart, it should not be reused while a payload is in flight
+pub fn split(id: u64, payload: &[u8], max_datagram: usize) -> anyhow::Result<Vec<Vec<u8>>> {
+  if payload.len() > MAX_FRAMED_LEN {
+    return Err(anyhow!("payload of {} bytes is too large", payload.len()));
+  }
+  let room = max_datagram
+    .checked_sub(CHUNK_HEADER_LEN)
+    .filter(|r| *r >= MIN_CHUNK_LEN)
+    .ok_or_else(|| anyhow!("datagrams of {} bytes cannot carry chunks", max_datagram))?;
+  // an empty payload still takes a chunk
+  let count = payload.len().div_ceil(room).max(1);
+  let mut datagrams = Vec::with_capacity(count);
+  for index in 0..count {
+    let start = (index * room).min(payload.len());
+    let data = &payload[start..(start + room).min(payload.len())];
+    let mut w = Vec::with_capacity(CHUNK_HEADER_LEN + data.len());
+    encode::u128(&mut w, id as u128)?;
+    encode::u128(&mut w, payload.len() as u128)?;
+    encode::u128(&mut w, index as u128)?;
+    encode::u128(&mut w, count as u128)?;
+    w.extend_from_slice(data);
+    datagrams.push(w);
+  }
+  Ok(datagrams)
+}
+
+/// a message to be sent on a stream, preceded by its length as a varint
+pub fn delimit(payload: &[u8]) -> Vec<u8> {
+  let mut w = Vec::with_capacity(payload.len() + 17);
+  // writing to a vector cannot fail
+  let _ = encode::u128(&mut w, payload.len() as u128);
+  w.extend_from_slice(payload);
+  w
+}
+
+/// finds the first message delimited by `delimit` at the start of `buf`, that holds what was read
+/// from a stream so far
+/// returns the length of its header and of the message, or None when the message is not whole yet
+/// messages longer than `max_len` are an error
+pub fn delimited(buf: &[u8], max_len: usize) -> anyhow::Result<Option<(usize, usize)>> {
+  let header = match buf.first() {
+    None => return Ok(None),
+    Some(b) if *b < 251 => 1,
+    Some(251) => 3,
+    Some(252) => 5,
+    Some(253) => 9,
+    Some(254) => 17,
+    Some(b) => return Err(anyhow!("invalid varint prefix {}", b)),
+  };
+  if buf.len() < header {
+    return Ok(None);
+  }
+  let len = usize::try_from(decode::u128(&mut Cursor::new(&buf[..header]))?)?;
+  if len > max_len {
+    return Err(anyhow!("message of {} bytes is too large", len));
+  }
+  if buf.len() - header < len {
+    return Ok(None);
+  }
+  Ok(Some((header, len)))
+}
+
+/// decodes a datagram produced by `split`
+pub fn read_chunk(buf: &[u8]) -> anyhow::Result<Chunk> {
+  let mut rd = Cursor::new(buf);
+  let id = u64::try_from(decode::u128(&mut rd)?)?;
+  let total = usize::try_from(decode::u128(&mut rd)?)?;
+  let index = usize::try_from(decode::u128(&mut rd)?)?;
+  let count = usize::try_from(decode::u128(&mut rd)?)?;
+  if total > MAX_FRAMED_LEN {
+    return Err(anyhow!("payload of {} bytes is too large", total));
+  }
+  // only an empty payload has an empty chunk, and the count is checked before the chunks are
+  // allocated for
+  if index >= count || count > total.div_ceil(MIN_CHUNK_LEN).max(1) {
+    return Err(anyhow!(
+      "invalid chunk {} of {} for {} bytes",
+      index,
+      count,
+      total
+    ));
+  }
+  let mut data = Vec::new();
+  rd.read_to_end(&mut data)?;
+  if data.len() > total {
+    return Err(anyhow!("chunk of {} bytes for {} bytes", data.len(), total));
+  }
+  Ok(Chunk {
+    id,
+    total,
+    index,
+    count,
+    data,
+  })
+}
+
+// a payload some chunks of which were received
+struct Partial {
+  id: u64,
+  since: Instant,
+  total: usize,
+  chunks: Vec<Option<Vec<u8>>>,
+  received: usize,
+  // bytes received so far, that cannot exceed the announced total
+  len: usize,
+}
+
+/// puts the chunks received from each peer back together
+/// payloads that are not complete within `timeout` are dropped, and at most MAX_PARTIAL_PER_PEER
+/// partial payloads are kept for each peer
+pub struct Reassembler {
+  timeout: Duration,
+  // oldest first
+  partial: HashMap<SocketAddr, Vec<Partial>>,
+}
+
+impl Reassembler {
+  pub fn new(timeout: Duration) -> Self {
+    Reassembler {
+      timeout,
+      partial: HashMap::new(),
+    }
+  }
+
+  /// adds a chunk received from `peer` at `now`
+  /// returns the payload once all its chunks are received, duplicated chunks are ignored
+  pub fn push(
+    &mut self,
+    peer: SocketAddr,
+    chunk: Chunk,
+    now: Instant,
+  ) -> anyhow::Result<Option<Vec<u8>>> {
+    if chunk.count == 1 {
+      if chunk.data.len() != chunk.total {
+        return Err(anyhow!(
+          "single chunk of {} bytes for {} bytes",
+          chunk.data.len(),
+          chunk.total
+        ));
+      }
+      return Ok(Some(chunk.data));
+    }
+    if !self.partial.contains_key(&peer) && self.partial.len() >= MAX_PEERS {
+      let oldest = self
+        .partial
+        .iter()
+        .min_by_key(|(_, partials)| partials.first().map(|p| p.since))
+        .map(|(peer, _)| *peer);
+      if let Some(oldest) = oldest {
+        self.partial.remove(&oldest);
+        log::debug!("dropping the partial payloads of {}", oldest);
+      }
+    }
+    let partials = self.partial.entry(peer).or_default();
+    let pos = match partials.iter().position(|p| p.id == chunk.id) {
+      Some(pos) => pos,
+      None => {
+        if partials.len() >= MAX_PARTIAL_PER_PEER {
+          let dropped = partials.remove(0);
+          log::debug!("dropping partial payload {} from {}", dropped.id, peer);
+        }
+        partials.push(Partial {
+          id: chunk.id,
+          since: now,
+          total: chunk.total,
+          chunks: vec![None; chunk.count],
+          received: 0,
+          len: 0,
+        });
+        partials.len() - 1
+      }
+    };
+    let partial = &mut partials[pos];
+    if partial.total != chunk.total || partial.chunks.len() != chunk.count {
+      return Err(anyhow!(
+        "chunk of payload {} from {} does not match the previous ones",
+        chunk.id,
+        peer
+      ));
+    }
+    if partial.chunks[chunk.index].is_none() {
+      if partial.len + chunk.data.len() > partial.total {
+        return Err(anyhow!(
+          "payload {} from {} is larger than announced",
+          chunk.id,
+          peer
+        ));
+      }
+      partial.len += chunk.data.len();
+      partial.chunks[chunk.index] = Some(chunk.data);
+      partial.received += 1;
+    }
+    if partial.received < partial.chunks.len() {
+      return Ok(None);
+    }
+    let partial = partials.remove(pos);
+    if partials.is_empty() {
+      self.partial.remove(&peer);
+    }
+    if partial.len != partial.total {
+      return Err(anyhow!(
+        "payload {} from {} is {} bytes instead of {}",
+        partial.id,
+        peer,
+        partial.len,
+        partial.total
+      ));
+    }
+    Ok(Some(
+      partial.chunks.into_iter().flatten().flatten().collect(),
+    ))
+  }
+
+  /// drops the partial payloads that are not complete at `now`, returns how many were dropped
+  pub fn expire(&mut self, now: Instant) -> usize {
+    let mut dropped = 0;
+    for partials in self.partial.values_mut() {
+      let before = partials.len();
+      partials.retain(|p| now.duration_since(p.since) < self.timeout);
+      dropped += before - partials.len();
+    }
+    self.partial.retain(|_, partials| !partials.is_empty());
+    dropped
+  }
+
+  /// the next time a partial payload expires, if any is kept
+  pub fn next_expiry(&self) -> Option<Instant> {
+    self
+      .partial
+      .values()
+      .flatten()
+      .map(|p| p.since + self.timeout)
+      .min()
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn peer(port: u16) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], port))
+  }
+
+  fn payload_of(len: usize) -> Vec<u8> {
+    vec![0; len]
+  }
+
+  #[test]
+  fn split_and_reassemble() {
+    let payload: Vec<u8> = (0..20000).map(|i| (i % 251) as u8).collect();
+    let datagrams = split(7, &payload, 1400).unwrap();
+    assert_eq!(
+      datagrams.len(),
+      payload.len().div_ceil(1400 - CHUNK_HEADER_LEN)
+    );
+    assert!(datagrams.iter().all(|d| d.len() <= 1400));
+
+    let mut reassembler = Reassembler::new(Duration::from_secs(1));
+    let now = Instant::now();
+    // in reverse order, with a duplicate
+    let mut order: Vec<_> = datagrams.iter().rev().collect();
+    order.insert(1, &datagrams[datagrams.len() - 1]);
+    let mut out = None;
+    for d in order {
+      let chunk = read_chunk(d).unwrap();
+      assert_eq!(chunk.id, 7);
+      if let Some(p) = reassembler.push(peer(1), chunk, now).unwrap() {
+        out = Some(p);
+      }
+    }
+    assert_eq!(out, Some(payload));
+    assert_eq!(reassembler.next_expiry(), None);
+
+    // small and empty payloads take a single chunk
+    for payload in [&b"hello"[..], &[]] {
+      let datagrams = split(8, payload, 1400).unwrap();
+      assert_eq!(datagrams.len(), 1);
+      let chunk = read_chunk(&datagrams[0]).unwrap();
+      let out = reassembler.push(peer(1), chunk, now).unwrap();
+      assert_eq!(out.as_deref(), Some(payload));
+    }
+    assert!(split(9, &payload_of(MAX_FRAMED_LEN + 1), 1400).is_err());
+    assert!(split(9, b"hello", CHUNK_HEADER_LEN).is_err());
+  }
+
+  #[test]
+  fn stream_delimiting() {
+    let long = payload_of(300);
+    let mut stream = delimit(b"hello");
+    stream.extend(delimit(&long));
+    stream.extend(delimit(b""));
+    assert_eq!(delimited(&stream, 1000).unwrap(), Some((1, 5)));
+    assert_eq!(&stream[1..6], b"hello");
+    let rest = &stream[6..];
+    // the header and the message are read bit by bit
+    assert_eq!(delimited(&rest[..1], 1000).unwrap(), None);
+    assert_eq!(delimited(&rest[..3], 1000).unwrap(), None);
+    assert_eq!(delimited(&rest[..302], 1000).unwrap(), None);
+    assert_eq!(delimited(rest, 1000).unwrap(), Some((3, 300)));
+    assert_eq!(delimited(&rest[303..], 1000).unwrap(), Some((1, 0)));
+    assert!(delimited(rest, 299).is_err());
+    assert!(delimited(&[255], 1000).is_err());
+    assert_eq!(delimited(&[], 1000).unwrap(), None);
+  }
+
+  #[test]
+  fn bounded() {
+    let timeout = Duration::from_millis(100);
+    let mut reassembler = Reassembler::new(timeout);
+    let start = Instant::now();
+    let payload = payload_of(2000);
+    // only the first chunk of each payload arrives
+    for id in 0..=MAX_PARTIAL_PER_PEER as u64 {
+      let first = read_chunk(&split(id, &payload, 1000).unwrap()[0]).unwrap();
+      let now = start + Duration::from_millis(id);
+      assert_eq!(reassembler.push(peer(1), first, now).unwrap(), None);
+    }
+    let other = read_chunk(&split(0, &payload, 1000).unwrap()[0]).unwrap();
+    assert_eq!(reassembler.push(peer(2), other, start).unwrap(), None);
+    // the oldest payload of the first peer was dropped, its rest starts it over
+    let rest = split(0, &payload, 1000).unwrap();
+    let last = read_chunk(rest.last().unwrap()).unwrap();
+    assert_eq!(reassembler.push(peer(1), last, start).unwrap(), None);
+    assert_eq!(reassembler.next_expiry(), Some(start + timeout));
+
+    assert_eq!(reassembler.expire(start + timeout / 2), 0);
+    assert_eq!(
+      reassembler.expire(start + timeout * 2),
+      MAX_PARTIAL_PER_PEER + 1
+    );
+    assert_eq!(reassembler.next_expiry(), None);
+
+    // the peers are bounded too, the one that waits the longest is dropped first
+    let first = read_chunk(&split(0, &payload, 1000).unwrap()[0]).unwrap();
+    for port in 0..=MAX_PEERS as u16 {
+      let now = start + Duration::from_millis(port.into());
+      let pushed = reassembler.push(peer(port), first.clone(), now);
+      assert_eq!(pushed.unwrap(), None);
+    }
+    assert_eq!(reassembler.partial.len(), MAX_PEERS);
+    assert!(!reassembler.partial.contains_key(&peer(0)));
+    assert_eq!(
+      reassembler.next_expiry(),
+      Some(start + Duration::from_millis(1) + timeout)
+    );
+  }
+
+  #[test]
+  fn invalid_chunks() {
+    let header = |id: u128, total: u128, index: u128, count: u128| {
+      let mut w = Vec::new();
+      for v in [id, total, index, count] {
+        encode::u128(&mut w, v).unwrap();
+      }
+      w
+    };
+    // index out of range
+    assert!(read_chunk(&header(1, 10, 2, 2)).is_err());
+    // more chunks than bytes
+    assert!(read_chunk(&header(1, 2, 0, 3)).is_err());
+    // chunks smaller than MIN_CHUNK_LEN
+    assert!(read_chunk(&header(1, MIN_CHUNK_LEN as u128 + 1, 0, 2)).is_ok());
+    assert!(read_chunk(&header(
+      1,
+      MAX_FRAMED_LEN as u128,
+      0,
+      MAX_FRAMED_LEN as u128
+    ))
+    .is_err());
+    // too large
+    assert!(read_chunk(&header(1, MAX_FRAMED_LEN as u128 + 1, 0, 2)).is_err());
+    // more data than announced
+    let mut d = header(1, 2, 0, 1);
+    d.extend([1, 2, 3]);
+    assert!(read_chunk(&d).is_err());
+
+    let mut reassembler = Reassembler::new(Duration::from_secs(1));
+    let now = Instant::now();
+    let total = MIN_CHUNK_LEN as u128 + 4;
+    let mut d = header(1, total, 0, 2);
+    d.extend(payload_of(MIN_CHUNK_LEN));
+    assert_eq!(
+      reassembler
+        .push(peer(1), read_chunk(&d).unwrap(), now)
+        .unwrap(),
+      None
+    );
+    // announces another length than the first chunk
+    let mut d = header(1, total + 1, 1, 2);
+    d.extend([4]);
+    assert!(reassembler
+      .push(peer(1), read_chunk(&d).unwrap(), now)
+      .is_err());
+    // would exceed the announced length
+    let mut d = header(1, total, 1, 2);
+    d.extend([1, 2, 3, 4, 5]);
+    assert!(reassembler
+      .push(peer(1), read_chunk(&d).unwrap(), now)
+      .is_err());
+    // completes it
+    let mut d = header(1, total, 1, 2);
+    d.extend([1, 2, 3, 4]);
+    let out = reassembler.push(peer(1), read_chunk(&d).unwrap(), now);
+    let mut expected = payload_of(MIN_CHUNK_LEN);
+    expected.extend([1, 2, 3, 4]);
+    assert_eq!(out.unwrap(), Some(expected));
+  }
+}
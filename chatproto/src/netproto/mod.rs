@@ -1,16 +1,98 @@
 pub mod decode;
 pub mod encode;
+pub mod frame;
+
+/// version of the wire format spoken by this crate, every feature introduced up to it is used by
+/// the plain encoders and decoders
+pub const PROTOCOL_VERSION: u8 = 9;
+
+/// first protocol version where UUIDs are sent as their 16 raw bytes, without a length byte
+pub const COMPACT_UUID_VERSION: u8 = 2;
+
+/// first protocol version where sequences and server messages end with the CRC32 of their bytes
+pub const CHECKSUM_VERSION: u8 = 3;
+
+/// first protocol version where the frames exchanged by servers start with their FrameKind
+pub const FRAME_KIND_VERSION: u8 = 4;
+
+/// first protocol version where text messages, and the polled messages, carry their attachments
+pub const ATTACHMENTS_VERSION: u8 = 5;
+
+/// first protocol version where user lists start with a flag byte telling whether they are
+/// compressed, large lists being sent with the prefix each name shares with the previous one elided
+pub const COMPRESSED_USERLIST_VERSION: u8 = 6;
+
+/// first protocol version where deliveries and polled messages carry the id the server gave the
+/// message
+pub const MESSAGE_ID_VERSION: u8 = 7;
+
+/// first protocol version where servers acknowledge the messages they accepted with an Ack frame
+pub const ACK_FRAME_VERSION: u8 = 8;
+
+/// first protocol version where replies to clients carry a status byte after their request id, the
+/// failed queries being answered with the ClientError they failed with
+pub const REPLY_STATUS_VERSION: u8 = 9;
+
+/// user lists whose plain encoding is larger than this many bytes are compressed
+pub const USERLIST_COMPRESSION_THRESHOLD: usize = 1024;
+
+/// what a frame exchanged by servers carries, earlier versions only exchange server messages
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameKind {
+  Server,
+  Auth,
+  /// liveness check, nothing follows
+  Ping,
+  /// the server message received from the peer was accepted, and there is nothing to forward,
+  /// nothing follows
+  Ack,
+}
+
+/// how clients exchange messages with a server
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransportKind {
+  /// one message per datagram
+  Udp,
+  /// a stream, each message being preceded by its length, see `frame::delimit`
+  Tcp,
+  /// datagrams carrying the chunks of messages, see `frame::split`, so that a message can be
+  /// larger than a datagram
+  Chunked,
+}
+
+impl std::str::FromStr for TransportKind {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> anyhow::Result<Self> {
+    match s {
+      "udp" => Ok(TransportKind::Udp),
+      "tcp" => Ok(TransportKind::Tcp),
+      "chunked" => Ok(TransportKind::Chunked),
+      _ => Err(anyhow::anyhow!(
+        "unknown transport {}, expected udp, tcp or chunked",
+        s
+      )),
+    }
+  }
+}
+
+/// maximum number of replies a run of deliveries can be expanded to when decoding
+pub const MAX_REPLIES: usize = 65536;
 
 #[cfg(test)]
 mod test {
   use std::collections::HashMap;
   use std::io::Cursor;
-  use uuid::uuid;
+  use uuid::{uuid, Uuid};
 
   use crate::messages::*;
 
   use super::decode;
   use super::encode;
+  use super::{
+    FrameKind, ACK_FRAME_VERSION, ATTACHMENTS_VERSION, CHECKSUM_VERSION, COMPACT_UUID_VERSION,
+    COMPRESSED_USERLIST_VERSION, FRAME_KIND_VERSION, MAX_REPLIES, MESSAGE_ID_VERSION,
+  };
 
   fn servermessages() -> Vec<ServerMessage> {
     // large announce
@@ -61,9 +143,9 @@ mod test {
           )]),
         },
         vec![
-          0, 1, 16, 115, 32, 55, 175, 211, 132, 77, 147, 171, 78, 235, 175, 100, 222, 135, 27, 1,
-          16, 39, 41, 62, 160, 35, 197, 73, 227, 151, 186, 157, 147, 55, 193, 244, 20, 9, 104, 97,
-          114, 100, 99, 111, 100, 101, 100,
+          0, 1, 115, 32, 55, 175, 211, 132, 77, 147, 171, 78, 235, 175, 100, 222, 135, 27, 0, 1,
+          39, 41, 62, 160, 35, 197, 73, 227, 151, 186, 157, 147, 55, 193, 244, 20, 9, 104, 97, 114,
+          100, 99, 111, 100, 101, 100,
         ],
       ),
       (
@@ -83,12 +165,12 @@ mod test {
           content: "Yes!".into(),
         }),
         vec![
-          1, 16, 80, 6, 77, 218, 134, 93, 64, 112, 168, 67, 170, 202, 41, 44, 184, 94, 16, 149,
-          191, 12, 236, 188, 242, 74, 129, 182, 26, 83, 221, 179, 111, 20, 93, 2, 16, 167, 127,
-          119, 47, 112, 10, 64, 116, 155, 132, 226, 100, 5, 13, 171, 89, 16, 47, 6, 253, 122, 142,
-          123, 70, 134, 159, 125, 102, 168, 228, 232, 145, 82, 16, 91, 130, 107, 77, 243, 48, 75,
-          95, 131, 174, 198, 254, 5, 183, 247, 96, 16, 109, 26, 131, 191, 201, 1, 65, 108, 138,
-          179, 18, 64, 158, 9, 10, 15, 4, 89, 101, 115, 33,
+          1, 80, 6, 77, 218, 134, 93, 64, 112, 168, 67, 170, 202, 41, 44, 184, 94, 149, 191, 12,
+          236, 188, 242, 74, 129, 182, 26, 83, 221, 179, 111, 20, 93, 2, 167, 127, 119, 47, 112,
+          10, 64, 116, 155, 132, 226, 100, 5, 13, 171, 89, 47, 6, 253, 122, 142, 123, 70, 134, 159,
+          125, 102, 168, 228, 232, 145, 82, 91, 130, 107, 77, 243, 48, 75, 95, 131, 174, 198, 254,
+          5, 183, 247, 96, 109, 26, 131, 191, 201, 1, 65, 108, 138, 179, 18, 64, 158, 9, 10, 15, 4,
+          89, 101, 115, 33,
         ],
       ),
     ]
@@ -109,21 +191,21 @@ mod test {
       (
         AuthMessage::Hello {
           user: uuid!["45095b4e-549d-4fd9-b4d0-9aa4111c6324"].into(),
-          nonce: [160, 172, 206, 207, 7, 198, 123, 142],
+          nonce: Nonce::from_bytes([160, 172, 206, 207, 7, 198, 123, 142]),
         },
         vec![
-          0, 16, 69, 9, 91, 78, 84, 157, 79, 217, 180, 208, 154, 164, 17, 28, 99, 36, 160, 172,
-          206, 207, 7, 198, 123, 142,
+          0, 69, 9, 91, 78, 84, 157, 79, 217, 180, 208, 154, 164, 17, 28, 99, 36, 160, 172, 206,
+          207, 7, 198, 123, 142,
         ],
       ),
       (
         AuthMessage::Nonce {
           server: uuid!["2a1e715b-5a5e-406b-9046-7be132a8df27"].into(),
-          nonce: [185, 213, 83, 150, 85, 248, 241, 110],
+          nonce: Nonce::from_bytes([185, 213, 83, 150, 85, 248, 241, 110]),
         },
         vec![
-          1, 16, 42, 30, 113, 91, 90, 94, 64, 107, 144, 70, 123, 225, 50, 168, 223, 39, 185, 213,
-          83, 150, 85, 248, 241, 110,
+          1, 42, 30, 113, 91, 90, 94, 64, 107, 144, 70, 123, 225, 50, 168, 223, 39, 185, 213, 83,
+          150, 85, 248, 241, 110,
         ],
       ),
     ]
@@ -135,10 +217,11 @@ mod test {
         ClientMessage::Text {
           dest: uuid!["732037af-d384-4d93-ab4e-ebaf64de871b"].into(),
           content: "P2s6ERp2".into(),
+          attachments: Vec::new(),
         },
         vec![
-          0, 16, 115, 32, 55, 175, 211, 132, 77, 147, 171, 78, 235, 175, 100, 222, 135, 27, 8, 80,
-          50, 115, 54, 69, 82, 112, 50,
+          0, 115, 32, 55, 175, 211, 132, 77, 147, 171, 78, 235, 175, 100, 222, 135, 27, 8, 80, 50,
+          115, 54, 69, 82, 112, 50, 0,
         ],
       ),
       (
@@ -152,11 +235,11 @@ mod test {
           content: "g1tL1R58x5C05jc".into(),
         },
         vec![
-          1, 4, 16, 199, 112, 82, 11, 203, 32, 79, 72, 138, 82, 145, 212, 198, 252, 8, 34, 16, 39,
-          41, 62, 160, 35, 197, 73, 227, 151, 186, 157, 147, 55, 193, 244, 20, 16, 19, 202, 156,
-          201, 130, 223, 70, 228, 138, 16, 30, 50, 55, 146, 128, 240, 16, 48, 190, 73, 154, 77, 78,
-          69, 106, 147, 16, 64, 70, 121, 194, 3, 194, 15, 103, 49, 116, 76, 49, 82, 53, 56, 120,
-          53, 67, 48, 53, 106, 99,
+          1, 4, 199, 112, 82, 11, 203, 32, 79, 72, 138, 82, 145, 212, 198, 252, 8, 34, 39, 41, 62,
+          160, 35, 197, 73, 227, 151, 186, 157, 147, 55, 193, 244, 20, 19, 202, 156, 201, 130, 223,
+          70, 228, 138, 16, 30, 50, 55, 146, 128, 240, 48, 190, 73, 154, 77, 78, 69, 106, 147, 16,
+          64, 70, 121, 194, 3, 194, 15, 103, 49, 116, 76, 49, 82, 53, 56, 120, 53, 67, 48, 53, 106,
+          99,
         ],
       ),
     ]
@@ -207,6 +290,32 @@ mod test {
         encoded,
       );
     }
+    // 255 is not a valid prefix
+    assert!(decode::u128(&mut Cursor::new([0xff])).is_err());
+  }
+
+  #[test]
+  fn u128_boundaries() {
+    let two64 = 1u128 << 64;
+    let mut expected = vec![253];
+    expected.extend(u64::MAX.to_le_bytes());
+    let samples = [
+      (two64 - 1, expected),
+      (two64, [vec![254], two64.to_le_bytes().to_vec()].concat()),
+      (
+        u128::MAX,
+        [vec![254], u128::MAX.to_le_bytes().to_vec()].concat(),
+      ),
+    ];
+    for (raw, encoded) in &samples {
+      round_trip(
+        |w, x: &u128| encode::u128(w, *x),
+        decode::u128,
+        raw,
+        encoded,
+      );
+    }
+    assert_eq!(samples[1].1.len(), 17);
   }
 
   #[test]
@@ -216,15 +325,36 @@ mod test {
     encode::serverid(&mut wr, &source).unwrap();
     assert_eq!(
       wr.into_inner(),
-      &[16, 163, 182, 116, 162, 185, 80, 78, 68, 179, 43, 162, 147, 69, 227, 142, 54]
+      &[163, 182, 116, 162, 185, 80, 78, 68, 179, 43, 162, 147, 69, 227, 142, 54]
     )
   }
 
+  #[test]
+  fn compact_uuid() {
+    let source = uuid!["a3b674a2-b950-4e44-b32b-a29345e38e36"];
+    let mut wr = Cursor::new(Vec::new());
+    encode::uuid_versioned(&mut wr, &source, COMPACT_UUID_VERSION).unwrap();
+    let buf = wr.into_inner();
+    assert_eq!(buf.len(), 16);
+    assert_eq!(buf, source.as_bytes());
+    let decoded = decode::uuid_versioned(&mut Cursor::new(buf), COMPACT_UUID_VERSION).unwrap();
+    assert_eq!(decoded, source);
+
+    // the legacy form keeps its length byte
+    let mut wr = Cursor::new(Vec::new());
+    encode::uuid_versioned(&mut wr, &source, COMPACT_UUID_VERSION - 1).unwrap();
+    let buf = wr.into_inner();
+    assert_eq!(buf.len(), 17);
+    assert_eq!(buf[0], 16);
+    let decoded = decode::uuid_versioned(&mut Cursor::new(buf), COMPACT_UUID_VERSION - 1).unwrap();
+    assert_eq!(decoded, source);
+  }
+
   #[test]
   fn serverid_decode() {
     let expected = ServerId(uuid!["a3b674a2-b950-4e44-b32b-a29345e38e36"]);
     let mut rd = Cursor::new([
-      16, 163, 182, 116, 162, 185, 80, 78, 68, 179, 43, 162, 147, 69, 227, 142, 54,
+      163, 182, 116, 162, 185, 80, 78, 68, 179, 43, 162, 147, 69, 227, 142, 54,
     ]);
     let decoded = decode::serverid(&mut rd).unwrap();
     assert_eq!(decoded, expected);
@@ -277,6 +407,40 @@ mod test {
     }
   }
 
+  // the nonces are written as their AuthNonce::SIZE raw bytes, and read back as such
+  #[test]
+  fn auth_nonce_width() {
+    let bytes = [0xff, 1, 2, 3, 4, 5, 6, 0x80];
+    let nonce = Nonce::from_bytes(bytes);
+    let messages = [
+      AuthMessage::Hello {
+        user: ClientId::default(),
+        nonce,
+      },
+      AuthMessage::Nonce {
+        server: ServerId::default(),
+        nonce,
+      },
+    ];
+    for m in messages {
+      let mut wr = Vec::new();
+      encode::auth(&mut wr, &m).unwrap();
+      // tag, then the 16 bytes of the id
+      assert_eq!(wr.len(), 1 + 16 + AuthNonce::SIZE);
+      assert_eq!(wr[17..], bytes);
+      let decoded = decode::auth(&mut &wr[..]).unwrap();
+      match &decoded {
+        AuthMessage::Hello { nonce, .. } | AuthMessage::Nonce { nonce, .. } => {
+          assert_eq!(nonce.to_bytes(), bytes)
+        }
+        AuthMessage::Auth { .. } => panic!("decoded as {:?}", decoded),
+      }
+      assert_eq!(decoded, m);
+      // a truncated nonce is an error, not a shorter nonce
+      assert!(decode::auth(&mut &wr[..wr.len() - 1]).is_err());
+    }
+  }
+
   #[test]
   fn client_encode() {
     for (msg, expected) in client_hardcoded() {
@@ -299,6 +463,7 @@ mod test {
     let msg = ClientMessage::Text {
       dest: ClientId::from(126u128),
       content: "😘😙😚".to_string(),
+      attachments: Vec::new(),
     };
     let mut wr = Cursor::new(Vec::new());
     encode::client(&mut wr, &msg).unwrap();
@@ -341,6 +506,1083 @@ mod test {
     round_trip(encode::client_query, decode::client_query, &query, &[3]);
   }
 
+  #[test]
+  fn client_query_recall() {
+    let query = ClientQuery::Recall { message_id: 300 };
+    round_trip(
+      encode::client_query,
+      decode::client_query,
+      &query,
+      &[4, 251, 44, 1],
+    );
+  }
+
+  #[test]
+  fn client_query_subscribe() {
+    round_trip(
+      encode::client_query,
+      decode::client_query,
+      &ClientQuery::Subscribe("news".into()),
+      &[5, 4, 110, 101, 119, 115],
+    );
+    round_trip(
+      encode::client_query,
+      decode::client_query,
+      &ClientQuery::Unsubscribe("news".into()),
+      &[6, 4, 110, 101, 119, 115],
+    );
+  }
+
+  #[test]
+  fn client_publish() {
+    round_trip(
+      encode::client,
+      decode::client,
+      &ClientMessage::Publish {
+        topic: "news".into(),
+        content: "hi".into(),
+      },
+      &[2, 4, 110, 101, 119, 115, 2, 104, 105],
+    );
+  }
+
+  #[test]
+  fn client_edit() {
+    round_trip(
+      encode::client,
+      decode::client,
+      &ClientMessage::Edit {
+        message_id: 300,
+        dest: ClientId(uuid!["a3b674a2-b950-4e44-b32b-a29345e38e36"]),
+        content: "hi".into(),
+      },
+      &[
+        3, 251, 44, 1, 163, 182, 116, 162, 185, 80, 78, 68, 179, 43, 162, 147, 69, 227, 142, 54, 2,
+        104, 105,
+      ],
+    );
+  }
+
+  #[test]
+  fn client_to_name() {
+    round_trip(
+      encode::client,
+      decode::client,
+      &ClientMessage::ToName {
+        name: "dave".into(),
+        content: "hi".into(),
+      },
+      &[4, 4, 100, 97, 118, 101, 2, 104, 105],
+    );
+  }
+
+  #[test]
+  fn client_replies() {
+    let replies = vec![
+      ClientReply::Delivered(7),
+      ClientReply::Error(ClientError::BoxFull(ClientId(uuid![
+        "a3b674a2-b950-4e44-b32b-a29345e38e36"
+      ]))),
+      ClientReply::Error(ClientError::StaleMessage),
+      ClientReply::Delayed(9),
+    ];
+    round_trip(
+      |w, r: &Vec<ClientReply>| encode::client_replies(w, r),
+      decode::client_replies,
+      &replies,
+      &[
+        4, 0, 7, 1, 3, 163, 182, 116, 162, 185, 80, 78, 68, 179, 43, 162, 147, 69, 227, 142, 54, 1,
+        7, 2, 9,
+      ],
+    );
+  }
+
+  #[test]
+  fn delivered_runs() {
+    let run: Vec<ClientReply> = (5..105).map(ClientReply::Delivered).collect();
+    round_trip(
+      |w, r: &Vec<ClientReply>| encode::client_replies(w, r),
+      decode::client_replies,
+      &run,
+      &[1, 4, 100, 5],
+    );
+    // a single delivery keeps its own tag, and only consecutive ids form a run
+    round_trip(
+      |w, r: &Vec<ClientReply>| encode::client_replies(w, r),
+      decode::client_replies,
+      &vec![
+        ClientReply::Delivered(1),
+        ClientReply::Delayed(2),
+        ClientReply::Delivered(3),
+        ClientReply::Delivered(4),
+        ClientReply::Delivered(6),
+      ],
+      &[4, 0, 1, 2, 2, 4, 2, 3, 0, 6],
+    );
+
+    let mut wr = Cursor::new(Vec::new());
+    let too_many = ClientReply::DeliveredN {
+      first: 0,
+      count: MAX_REPLIES as u128 + 1,
+    };
+    encode::client_replies(&mut wr, &[too_many]).unwrap();
+    assert!(decode::client_replies(&mut Cursor::new(wr.into_inner())).is_err());
+  }
+
+  #[test]
+  fn transfer_depth() {
+    let replies = vec![ClientReply::Transfer(
+      ServerId::default(),
+      servermessages().remove(0),
+    )];
+    let mut wr = Cursor::new(Vec::new());
+    encode::client_replies(&mut wr, &replies).unwrap();
+    let buf = wr.into_inner();
+
+    let decoded = decode::client_replies(&mut Cursor::new(buf.clone())).unwrap();
+    assert_eq!(decoded, replies);
+
+    let decoded =
+      decode::client_replies_nested(&mut Cursor::new(buf.clone()), decode::MAX_DEPTH - 1);
+    assert_eq!(decoded.unwrap(), replies);
+    let rr = decode::client_replies_nested(&mut Cursor::new(buf), decode::MAX_DEPTH).unwrap_err();
+    assert_eq!(
+      rr.downcast_ref(),
+      Some(&decode::DecodeError::MaxDepthExceeded)
+    );
+  }
+
+  #[test]
+  fn frame_kind() {
+    for (kind, tag) in [
+      (FrameKind::Server, 0),
+      (FrameKind::Auth, 1),
+      (FrameKind::Ping, 2),
+      (FrameKind::Ack, 3),
+    ] {
+      let mut wr = Vec::new();
+      encode::frame_kind(&mut wr, kind, ACK_FRAME_VERSION).unwrap();
+      assert_eq!(wr, [tag]);
+      let decoded = decode::frame_kind(&mut Cursor::new(wr), ACK_FRAME_VERSION).unwrap();
+      assert_eq!(decoded, kind);
+    }
+    assert!(decode::frame_kind(&mut Cursor::new([4]), ACK_FRAME_VERSION).is_err());
+    // acks cannot be sent to the servers that do not know them
+    assert!(decode::frame_kind(&mut Cursor::new([3]), FRAME_KIND_VERSION).is_err());
+    assert!(encode::frame_kind(&mut Vec::new(), FrameKind::Ack, ACK_FRAME_VERSION - 1).is_err());
+
+    // earlier versions only have server messages, and no kind on the wire
+    let mut wr = Vec::new();
+    encode::frame_kind(&mut wr, FrameKind::Server, FRAME_KIND_VERSION - 1).unwrap();
+    assert!(wr.is_empty());
+    let mut rd = Cursor::new([1]);
+    let decoded = decode::frame_kind(&mut rd, FRAME_KIND_VERSION - 1).unwrap();
+    assert_eq!(decoded, FrameKind::Server);
+    assert_eq!(rd.position(), 0);
+  }
+
+  #[test]
+  fn checksum_trailer() {
+    let sq = Sequence {
+      seqid: 12,
+      src: ClientId::default(),
+      workproof: 3,
+      timestamp: None,
+      content: ClientQuery::Message(ClientMessage::Text {
+        dest: ClientId::default(),
+        content: "hello".into(),
+        attachments: Vec::new(),
+      }),
+    };
+    let mut plain = Vec::new();
+    encode::sequence(&mut plain, &sq, encode::client_query).unwrap();
+    // older versions have no trailer
+    let mut wr = Vec::new();
+    encode::sequence_versioned(&mut wr, &sq, encode::client_query, CHECKSUM_VERSION - 1).unwrap();
+    assert_eq!(wr, plain);
+
+    let mut wr = Vec::new();
+    encode::sequence_versioned(&mut wr, &sq, encode::client_query, CHECKSUM_VERSION).unwrap();
+    assert_eq!(wr.len(), plain.len() + 4);
+    let decoded = decode::sequence_versioned(
+      &mut Cursor::new(wr.clone()),
+      decode::client_query,
+      CHECKSUM_VERSION,
+    );
+    assert_eq!(decoded.unwrap(), sq);
+    // "hello" becomes "hellp", still a valid message
+    let idx = wr.len() - 5;
+    wr[idx] ^= 0x1f;
+    let rr =
+      decode::sequence_versioned(&mut Cursor::new(wr), decode::client_query, CHECKSUM_VERSION)
+        .unwrap_err();
+    assert_eq!(
+      rr.downcast_ref(),
+      Some(&decode::DecodeError::ChecksumMismatch)
+    );
+
+    for m in servermessages() {
+      let mut wr = Vec::new();
+      encode::server_versioned(&mut wr, &m, CHECKSUM_VERSION).unwrap();
+      let decoded = decode::server_versioned(&mut Cursor::new(wr.clone()), CHECKSUM_VERSION);
+      assert_eq!(decoded.unwrap(), m);
+      wr[1] ^= 1;
+      let rr = decode::server_versioned(&mut Cursor::new(wr), CHECKSUM_VERSION).unwrap_err();
+      assert_eq!(
+        rr.downcast_ref(),
+        Some(&decode::DecodeError::ChecksumMismatch)
+      );
+    }
+  }
+
+  #[test]
+  fn trailing_bytes() {
+    use decode::Trailing::{Lenient, Strict};
+    let sq = Sequence {
+      seqid: 12,
+      src: ClientId::default(),
+      workproof: 3,
+      timestamp: None,
+      content: ClientQuery::Poll,
+    };
+    let mut frame = Vec::new();
+    encode::sequence(&mut frame, &sq, encode::client_query).unwrap();
+    let mut padded = frame.clone();
+    padded.extend_from_slice(&[0, 0, 0]);
+    for trailing in [Lenient, Strict] {
+      let decoded = decode::sequence_checked(&mut &frame[..], decode::client_query, trailing);
+      assert_eq!(decoded.unwrap(), sq);
+    }
+    let decoded = decode::sequence_checked(&mut &padded[..], decode::client_query, Lenient);
+    assert_eq!(decoded.unwrap(), sq);
+    let rr = decode::sequence_checked(&mut &padded[..], decode::client_query, Strict).unwrap_err();
+    assert_eq!(
+      rr.downcast_ref(),
+      Some(&decode::DecodeError::TrailingBytes(3))
+    );
+
+    for m in servermessages() {
+      let mut frame = Vec::new();
+      encode::server(&mut frame, &m).unwrap();
+      let mut padded = frame.clone();
+      padded.push(0);
+      for trailing in [Lenient, Strict] {
+        assert_eq!(
+          decode::server_checked(&mut &frame[..], trailing).unwrap(),
+          m
+        );
+      }
+      assert_eq!(
+        decode::server_checked(&mut &padded[..], Lenient).unwrap(),
+        m
+      );
+      let rr = decode::server_checked(&mut &padded[..], Strict).unwrap_err();
+      assert_eq!(
+        rr.downcast_ref(),
+        Some(&decode::DecodeError::TrailingBytes(1))
+      );
+    }
+  }
+
+  // decodes each frame of vectors.bin, and compares it with the value vectors.json gives for it
+  // the frames are written by hand from the protocol description of PROTOCOL_VERSION, not by our
+  // encoders, so that other implementations can use them as they are
+  #[test]
+  fn interop_vectors() {
+    #[derive(serde::Deserialize)]
+    struct Vector {
+      name: String,
+      kind: String,
+      offset: usize,
+      len: usize,
+      value: serde_json::Value,
+    }
+
+    fn check<'a, X, DEC>(v: &Vector, frame: &'a [u8], d: DEC)
+    where
+      X: serde::de::DeserializeOwned + PartialEq + std::fmt::Debug,
+      DEC: FnOnce(&mut Cursor<&'a [u8]>) -> anyhow::Result<X>,
+    {
+      let mut rd = Cursor::new(frame);
+      let decoded = d(&mut rd).unwrap_or_else(|rr| panic!("{}: {}", v.name, rr));
+      assert_eq!(
+        rd.position() as usize,
+        frame.len(),
+        "{}: trailing bytes",
+        v.name
+      );
+      let expected: X = serde_json::from_value(v.value.clone())
+        .unwrap_or_else(|rr| panic!("{}: bad expected value: {}", v.name, rr));
+      assert_eq!(decoded, expected, "{}", v.name);
+    }
+
+    let bin = include_bytes!("vectors.bin");
+    let vectors: Vec<Vector> = serde_json::from_str(include_str!("vectors.json")).unwrap();
+    for v in &vectors {
+      let frame = &bin[v.offset..v.offset + v.len];
+      match v.kind.as_str() {
+        // as decimal strings, JSON numbers do not reach 128 bits everywhere
+        "u128" => check(v, frame, |rd| decode::u128(rd).map(|n| n.to_string())),
+        "string" => check(v, frame, decode::string),
+        "clientid" => check(v, frame, decode::clientid),
+        "auth" => check(v, frame, decode::auth),
+        "client_query" => check(v, frame, decode::client_query),
+        "request" => check(v, frame, |rd| decode::request(rd, decode::client_query)),
+        "client_replies" => check(v, frame, decode::client_replies),
+        "client_poll_reply" => check(v, frame, decode::client_poll_reply),
+        "poll_ack" => check(v, frame, decode::poll_ack),
+        "server" => check(v, frame, decode::server),
+        kind => panic!("{}: unknown kind {}", v.name, kind),
+      }
+    }
+    // no frame was forgotten in the table
+    assert_eq!(vectors.last().map(|v| v.offset + v.len), Some(bin.len()));
+  }
+
+  // checks that `value` starts with `tag`, written as a raw byte, and that the same tag written
+  // as a wide varint is rejected
+  fn raw_tag<T, ENC, DEC>(e: ENC, d: DEC, value: &T, tag: u8)
+  where
+    T: std::fmt::Debug,
+    ENC: FnOnce(&mut Cursor<Vec<u8>>, &T) -> std::io::Result<()>,
+    DEC: FnOnce(&mut Cursor<Vec<u8>>) -> anyhow::Result<T>,
+  {
+    let mut wr = Cursor::new(Vec::new());
+    e(&mut wr, value).unwrap();
+    let buf = wr.into_inner();
+    assert_eq!(buf[0], tag, "{:?}", value);
+    let mut wide = vec![251, tag, 0];
+    wide.extend(&buf[1..]);
+    assert!(d(&mut Cursor::new(wide)).is_err(), "{:?}", value);
+  }
+
+  #[test]
+  fn tags_are_raw_bytes() {
+    let client = ClientId(uuid!["a3b674a2-b950-4e44-b32b-a29345e38e36"]);
+    let server = ServerId(uuid!["2a1e715b-5a5e-406b-9046-7be132a8df27"]);
+    let auths = [
+      (
+        AuthMessage::Hello {
+          user: client,
+          nonce: Nonce::from_bytes([1; 8]),
+        },
+        0,
+      ),
+      (
+        AuthMessage::Nonce {
+          server,
+          nonce: Nonce::from_bytes([2; 8]),
+        },
+        1,
+      ),
+      (AuthMessage::Auth { response: [3; 16] }, 2),
+    ];
+    for (m, tag) in &auths {
+      raw_tag(encode::auth, decode::auth, m, *tag);
+    }
+    let messages = [
+      (
+        ClientMessage::Text {
+          dest: client,
+          content: "hi".into(),
+          attachments: Vec::new(),
+        },
+        0,
+      ),
+      (
+        ClientMessage::MText {
+          dest: vec![client],
+          content: "hi".into(),
+        },
+        1,
+      ),
+      (
+        ClientMessage::Publish {
+          topic: "news".into(),
+          content: "hi".into(),
+        },
+        2,
+      ),
+      (
+        ClientMessage::Edit {
+          message_id: 1,
+          dest: client,
+          content: "hi".into(),
+        },
+        3,
+      ),
+      (
+        ClientMessage::ToName {
+          name: "dave".into(),
+          content: "hi".into(),
+        },
+        4,
+      ),
+    ];
+    for (m, tag) in &messages {
+      raw_tag(encode::client, decode::client, m, *tag);
+    }
+    for e in [ClientError::WorkProofError, ClientError::BoxFull(client)] {
+      raw_tag(encode::client_error, decode::client_error, &e, e.tag());
+    }
+    raw_tag(
+      encode::client_query,
+      decode::client_query,
+      &ClientQuery::Poll,
+      2,
+    );
+    raw_tag(
+      encode::client_poll_reply,
+      decode::client_poll_reply,
+      &ClientPollReply::Nothing,
+      2,
+    );
+    raw_tag(
+      encode::delayed_error,
+      decode::delayed_error,
+      &DelayedError::RouteLost(client),
+      1,
+    );
+    raw_tag(encode::server, decode::server, &servermessages()[3], 1);
+    // replies come after their count
+    let replies = [
+      (vec![ClientReply::Delivered(0)], 0),
+      (vec![ClientReply::Error(ClientError::UnknownClient)], 1),
+      (vec![ClientReply::Delayed(0)], 2),
+      ((0..3).map(ClientReply::Delivered).collect(), 4),
+    ];
+    for (r, tag) in replies {
+      let mut buf = Vec::new();
+      encode::client_replies(&mut buf, &r).unwrap();
+      assert_eq!(buf[..2], [1, tag]);
+      let mut wide = vec![1, 251, tag, 0];
+      wide.extend(&buf[2..]);
+      assert!(decode::client_replies(&mut Cursor::new(wide)).is_err());
+    }
+  }
+
+  #[test]
+  fn client_error_tags() {
+    let client = ClientId(uuid!["a3b674a2-b950-4e44-b32b-a29345e38e36"]);
+    // these tags are part of the protocol, and must never change
+    let golden = [
+      (ClientError::WorkProofError, 0),
+      (ClientError::UnknownClient, 1),
+      (ClientError::SequenceError, 2),
+      (ClientError::BoxFull(client), 3),
+      (ClientError::InternalError, 4),
+      (ClientError::NameTooLong, 5),
+      (ClientError::EmptyName, 6),
+      (ClientError::StaleMessage, 7),
+      (ClientError::AddressMismatch, 8),
+      (ClientError::NameTaken, 9),
+      (ClientError::ServerFull, 10),
+      (ClientError::UnknownMessage, 11),
+      (ClientError::ProtocolError, 12),
+      (ClientError::RecipientGone(client), 13),
+      (ClientError::TooManyPending, 14),
+      (ClientError::ServerBusy, 15),
+      (ClientError::InvalidName, 16),
+      (ClientError::NoRoute, 17),
+    ];
+    for (e, tag) in &golden {
+      assert_eq!(e.tag(), *tag, "{:?}", e);
+      let mut expected = vec![*tag];
+      if let ClientError::BoxFull(c) | ClientError::RecipientGone(c) = e {
+        encode::clientid(&mut expected, c).unwrap();
+      }
+      round_trip(encode::client_error, decode::client_error, e, &expected);
+    }
+    // every tag known to from_tag is in the golden list
+    for tag in 0..=u8::MAX {
+      let known = golden.iter().find(|(_, t)| *t == tag);
+      assert_eq!(
+        ClientError::from_tag(tag).map(|e| e.tag()),
+        known.map(|(e, _)| e.tag()),
+        "tag {}",
+        tag
+      );
+    }
+  }
+
+  #[test]
+  fn userlist() {
+    let users = HashMap::from([(
+      ClientId(uuid!["a3b674a2-b950-4e44-b32b-a29345e38e36"]),
+      "bob".to_string(),
+    )]);
+    round_trip(
+      encode::userlist,
+      decode::userlist,
+      &users,
+      &[
+        0, 1, 163, 182, 116, 162, 185, 80, 78, 68, 179, 43, 162, 147, 69, 227, 142, 54, 3, 98, 111,
+        98,
+      ],
+    );
+  }
+
+  #[test]
+  fn user_page() {
+    round_trip(
+      encode::client_query,
+      decode::client_query,
+      &ClientQuery::ListUsersPage {
+        offset: 5,
+        limit: 64,
+      },
+      &[14, 5, 64],
+    );
+    let page = UserPage {
+      users: vec![(
+        ClientId(uuid!["a3b674a2-b950-4e44-b32b-a29345e38e36"]),
+        "bob".to_string(),
+      )],
+      total: 3,
+      has_more: true,
+    };
+    round_trip(
+      encode::user_page,
+      decode::user_page,
+      &page,
+      &[
+        1, 163, 182, 116, 162, 185, 80, 78, 68, 179, 43, 162, 147, 69, 227, 142, 54, 3, 98, 111,
+        98, 3, 1,
+      ],
+    );
+  }
+
+  #[test]
+  fn reachable() {
+    round_trip(
+      encode::client_query,
+      decode::client_query,
+      &ClientQuery::Reachable,
+      &[15],
+    );
+    let client = ClientId(uuid!["a3b674a2-b950-4e44-b32b-a29345e38e36"]);
+    let server = ServerId(uuid!["2a1e715b-5a5e-406b-9046-7be132a8df27"]);
+    let mut expected = vec![1];
+    encode::clientid(&mut expected, &client).unwrap();
+    expected.push(1);
+    encode::serverid(&mut expected, &server).unwrap();
+    round_trip(
+      encode::reachable,
+      decode::reachable,
+      &HashMap::from([(client, ClientLocation::Remote(server))]),
+      &expected,
+    );
+    round_trip(
+      encode::client_location,
+      decode::client_location,
+      &ClientLocation::Local,
+      &[0],
+    );
+    assert!(decode::client_location(&mut Cursor::new([2])).is_err());
+  }
+
+  #[test]
+  fn compressed_userlist() {
+    let users: HashMap<ClientId, String> = (0..1000)
+      .map(|i| (ClientId(Uuid::new_v4()), format!("user number {:04}", i)))
+      .collect();
+    let mut plain = Cursor::new(Vec::new());
+    encode::userlist_versioned(&mut plain, &users, COMPRESSED_USERLIST_VERSION - 1).unwrap();
+    let mut compressed = Cursor::new(Vec::new());
+    encode::userlist_versioned(&mut compressed, &users, COMPRESSED_USERLIST_VERSION).unwrap();
+    assert_eq!(compressed.get_ref()[0], 1);
+    assert!(compressed.get_ref().len() < plain.get_ref().len());
+    let mut rd = Cursor::new(compressed.into_inner());
+    let decoded = decode::userlist_versioned(&mut rd, COMPRESSED_USERLIST_VERSION).unwrap();
+    assert_eq!(decoded, users);
+    assert_eq!(rd.position() as usize, rd.get_ref().len());
+
+    // small lists are sent as they are, after the flag
+    let small = HashMap::from([
+      (ClientId(Uuid::new_v4()), "bob".to_string()),
+      (ClientId(Uuid::new_v4()), "bobby".to_string()),
+    ]);
+    let mut wr = Cursor::new(Vec::new());
+    encode::userlist_versioned(&mut wr, &small, COMPRESSED_USERLIST_VERSION).unwrap();
+    let mut expected = vec![0];
+    encode::userlist_versioned(&mut expected, &small, COMPRESSED_USERLIST_VERSION - 1).unwrap();
+    assert_eq!(wr.get_ref(), &expected);
+    let decoded = decode::userlist_versioned(
+      &mut Cursor::new(wr.into_inner()),
+      COMPRESSED_USERLIST_VERSION,
+    )
+    .unwrap();
+    assert_eq!(decoded, small);
+
+    // the shared prefix cannot be longer than the previous name
+    let mut wr = vec![1, 1];
+    encode::clientid(&mut wr, &ClientId::default()).unwrap();
+    wr.extend([3, 1, b'x']);
+    assert!(decode::userlist_versioned(&mut Cursor::new(wr), COMPRESSED_USERLIST_VERSION).is_err());
+
+    // every name shares the whole previous one and adds to it, growing past any name
+    let mut wr = vec![1];
+    encode::u128(&mut wr, 1000).unwrap();
+    for i in 0..1000u128 {
+      encode::clientid(&mut wr, &ClientId::default()).unwrap();
+      encode::u128(&mut wr, i * 8).unwrap();
+      encode::string(&mut wr, "xxxxxxxx").unwrap();
+    }
+    let rr = decode::userlist_versioned(&mut Cursor::new(wr), COMPRESSED_USERLIST_VERSION);
+    assert!(rr.unwrap_err().to_string().contains("at most"));
+    // and without the compression
+    let mut wr = Vec::new();
+    encode::u128(&mut wr, 1).unwrap();
+    encode::clientid(&mut wr, &ClientId::default()).unwrap();
+    encode::string(&mut wr, &"x".repeat(crate::core::MAX_NAME_LEN + 1)).unwrap();
+    assert!(
+      decode::userlist_versioned(&mut Cursor::new(wr), COMPRESSED_USERLIST_VERSION - 1).is_err()
+    );
+  }
+
+  #[test]
+  fn server_message_destinations() {
+    let src = ClientId(Uuid::from_u128(1));
+    let srcsrv = ServerId(Uuid::from_u128(2));
+    for count in [0, 1, 5] {
+      // every destination lives on its own server, in an order that is not sorted
+      let dsts: Vec<(ClientId, ServerId)> = (0..count)
+        .map(|i| {
+          (
+            ClientId(Uuid::from_u128(100 - i)),
+            ServerId(Uuid::from_u128(200 + i * 7 % 5)),
+          )
+        })
+        .collect();
+      let mut expected = vec![1];
+      encode::clientid(&mut expected, &src).unwrap();
+      encode::serverid(&mut expected, &srcsrv).unwrap();
+      encode::u128(&mut expected, count).unwrap();
+      for (client, server) in &dsts {
+        encode::clientid(&mut expected, client).unwrap();
+        encode::serverid(&mut expected, server).unwrap();
+      }
+      encode::string(&mut expected, "hi").unwrap();
+      round_trip(
+        encode::server,
+        decode::server,
+        &ServerMessage::Message(FullyQualifiedMessage {
+          src,
+          srcsrv,
+          dsts,
+          content: "hi".into(),
+        }),
+        &expected,
+      );
+    }
+  }
+
+  #[test]
+  fn bool() {
+    round_trip(|w, x: &bool| encode::bool(w, *x), decode::bool, &true, &[1]);
+    round_trip(
+      |w, x: &bool| encode::bool(w, *x),
+      decode::bool,
+      &false,
+      &[0],
+    );
+    assert!(decode::bool(&mut Cursor::new([2])).is_err());
+  }
+
+  #[test]
+  fn long_string() {
+    let src = "a".repeat(300);
+    let mut wr = Cursor::new(Vec::new());
+    encode::string(&mut wr, &src).unwrap();
+    let buf = wr.into_inner();
+    assert_eq!(&buf[..3], &[251, 44, 1]);
+    assert_eq!(decode::string(&mut Cursor::new(buf)).unwrap(), src);
+  }
+
+  #[test]
+  fn client_poll_reply_nothing() {
+    round_trip(
+      encode::client_poll_reply,
+      decode::client_poll_reply,
+      &ClientPollReply::Nothing,
+      &[2],
+    );
+  }
+
+  #[test]
+  fn delayed_error_unknown_recipient() {
+    let reply = ClientPollReply::DelayedError(DelayedError::UnknownRecipient(ClientId(uuid![
+      "a3b674a2-b950-4e44-b32b-a29345e38e36"
+    ])));
+    round_trip(
+      encode::client_poll_reply,
+      decode::client_poll_reply,
+      &reply,
+      &[
+        1, 0, 163, 182, 116, 162, 185, 80, 78, 68, 179, 43, 162, 147, 69, 227, 142, 54,
+      ],
+    );
+  }
+
+  #[test]
+  fn delayed_error_route_lost() {
+    let reply = ClientPollReply::DelayedError(DelayedError::RouteLost(ClientId(uuid![
+      "a3b674a2-b950-4e44-b32b-a29345e38e36"
+    ])));
+    round_trip(
+      encode::client_poll_reply,
+      decode::client_poll_reply,
+      &reply,
+      &[
+        1, 1, 163, 182, 116, 162, 185, 80, 78, 68, 179, 43, 162, 147, 69, 227, 142, 54,
+      ],
+    );
+  }
+
+  #[test]
+  fn client_query_register_and_message() {
+    let dest = ClientId(uuid!["a3b674a2-b950-4e44-b32b-a29345e38e36"]);
+    let mut expected = vec![8, 3, 98, 111, 116, 0];
+    expected.extend(dest.0.as_bytes());
+    expected.extend([2, 104, 105, 0]);
+    round_trip(
+      encode::client_query,
+      decode::client_query,
+      &ClientQuery::RegisterAndMessage {
+        name: "bot".into(),
+        message: ClientMessage::Text {
+          dest,
+          content: "hi".into(),
+          attachments: Vec::new(),
+        },
+      },
+      &expected,
+    );
+  }
+
+  #[test]
+  fn client_query_home_server() {
+    let client = ClientId(uuid!["a3b674a2-b950-4e44-b32b-a29345e38e36"]);
+    let mut expected = vec![7];
+    expected.extend(client.0.as_bytes());
+    round_trip(
+      encode::client_query,
+      decode::client_query,
+      &ClientQuery::HomeServer(client),
+      &expected,
+    );
+
+    round_trip(
+      encode::option_serverid,
+      decode::option_serverid,
+      &None,
+      &[0],
+    );
+    let server = ServerId(uuid!["2a1e715b-5a5e-406b-9046-7be132a8df27"]);
+    let mut expected = vec![1];
+    expected.extend(server.0.as_bytes());
+    round_trip(
+      encode::option_serverid,
+      decode::option_serverid,
+      &Some(server),
+      &expected,
+    );
+  }
+
+  #[test]
+  fn client_query_server_info() {
+    round_trip(
+      encode::client_query,
+      decode::client_query,
+      &ClientQuery::ServerInfo,
+      &[10],
+    );
+    let server = ServerId(uuid!["2a1e715b-5a5e-406b-9046-7be132a8df27"]);
+    let mut expected = server.0.as_bytes().to_vec();
+    expected.extend([2, b'u', b's', 4]);
+    round_trip(
+      encode::server_info,
+      decode::server_info,
+      &ServerInfo {
+        server_id: server,
+        group_name: "us".into(),
+        version: 4,
+      },
+      &expected,
+    );
+  }
+
+  #[test]
+  fn client_query_ack_status() {
+    round_trip(
+      encode::client_query,
+      decode::client_query,
+      &ClientQuery::AckStatus,
+      &[11],
+    );
+    round_trip(
+      encode::ack_status,
+      decode::ack_status,
+      &AckStatus {
+        contiguous: 2,
+        forgotten: 70,
+        sparse: 0b10,
+      },
+      &[2, 70, 2],
+    );
+    // the bitmap is 64 bits wide
+    assert!(decode::ack_status(&mut Cursor::new([
+      2, 0, 254, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0
+    ]))
+    .is_err());
+  }
+
+  #[test]
+  fn attachments() {
+    let msg = ClientMessage::Text {
+      dest: ClientId::from(126u128),
+      content: "hi".into(),
+      attachments: vec![
+        ("location".into(), "here".into()),
+        ("reply-to".into(), "42".into()),
+      ],
+    };
+    let mut wr = Cursor::new(Vec::new());
+    encode::client_versioned(&mut wr, &msg, ATTACHMENTS_VERSION).unwrap();
+    let buf = wr.into_inner();
+    // the number of pairs, and then the keys and values
+    let mut expected = vec![2, 8];
+    expected.extend(b"location\x04here\x08reply-to\x0242");
+    assert!(buf.ends_with(&expected));
+    let decoded = decode::client_versioned(&mut Cursor::new(&buf), ATTACHMENTS_VERSION).unwrap();
+    assert_eq!(decoded, msg);
+
+    let reply = ClientPollReply::Message {
+      src: ClientId::from(126u128),
+      srcsrv: None,
+      content: "hi".into(),
+      attachments: vec![("tag".into(), "a".into()), ("tag".into(), "b".into())],
+      // not carried before MESSAGE_ID_VERSION
+      message_id: 0,
+    };
+    let mut wr = Cursor::new(Vec::new());
+    encode::client_poll_reply_versioned(&mut wr, &reply, ATTACHMENTS_VERSION).unwrap();
+    let decoded =
+      decode::client_poll_reply_versioned(&mut Cursor::new(wr.into_inner()), ATTACHMENTS_VERSION)
+        .unwrap();
+    assert_eq!(decoded, reply);
+  }
+
+  #[test]
+  fn attachments_legacy() {
+    let msg = ClientMessage::Text {
+      dest: ClientId::from(126u128),
+      content: "hi".into(),
+      attachments: Vec::new(),
+    };
+    // earlier versions have no attachments at all
+    let mut legacy = Cursor::new(Vec::new());
+    encode::client_versioned(&mut legacy, &msg, ATTACHMENTS_VERSION - 1).unwrap();
+    let decoded =
+      decode::client_versioned(&mut Cursor::new(legacy.get_ref()), ATTACHMENTS_VERSION - 1)
+        .unwrap();
+    assert_eq!(decoded, msg);
+
+    // an empty list is a single byte, the current version carries it
+    let mut wr = Cursor::new(Vec::new());
+    encode::client_versioned(&mut wr, &msg, ATTACHMENTS_VERSION).unwrap();
+    let mut expected = legacy.into_inner();
+    expected.push(0);
+    assert_eq!(wr.get_ref(), &expected);
+    let mut current = Cursor::new(Vec::new());
+    encode::client(&mut current, &msg).unwrap();
+    assert_eq!(current.into_inner(), expected);
+  }
+
+  #[test]
+  fn message_id_legacy() {
+    // earlier versions carry no ids, any two deliveries form a run, and the ids decode as 0
+    let replies = vec![
+      ClientReply::Delivered(3),
+      ClientReply::Delivered(8),
+      ClientReply::Delayed(9),
+    ];
+    let mut wr = Vec::new();
+    encode::client_replies_versioned(&mut wr, &replies, MESSAGE_ID_VERSION - 1).unwrap();
+    assert_eq!(wr, [2, 4, 2, 2]);
+    let decoded =
+      decode::client_replies_versioned(&mut Cursor::new(wr), 0, MESSAGE_ID_VERSION - 1).unwrap();
+    assert_eq!(
+      decoded,
+      [
+        ClientReply::Delivered(0),
+        ClientReply::Delivered(0),
+        ClientReply::Delayed(0)
+      ]
+    );
+
+    let reply = ClientPollReply::Message {
+      src: ClientId::from(126u128),
+      srcsrv: None,
+      content: "hi".into(),
+      attachments: Vec::new(),
+      message_id: 42,
+    };
+    let mut legacy = Vec::new();
+    encode::client_poll_reply_versioned(&mut legacy, &reply, MESSAGE_ID_VERSION - 1).unwrap();
+    let mut current = Vec::new();
+    encode::client_poll_reply(&mut current, &reply).unwrap();
+    assert_eq!(current[..current.len() - 1], legacy[..]);
+    assert_eq!(current.last(), Some(&42));
+    let decoded =
+      decode::client_poll_reply_versioned(&mut Cursor::new(legacy), MESSAGE_ID_VERSION - 1)
+        .unwrap();
+    assert!(matches!(
+      decoded,
+      ClientPollReply::Message { message_id: 0, .. }
+    ));
+  }
+
+  #[test]
+  fn read_array() {
+    let bytes: Vec<u8> = (1..=16).collect();
+    let mut rd = Cursor::new(&bytes);
+    assert_eq!(
+      decode::read_array::<8, _>(&mut rd).unwrap(),
+      [1, 2, 3, 4, 5, 6, 7, 8]
+    );
+    // only the requested bytes are consumed
+    assert_eq!(rd.position(), 8);
+    let mut rd = Cursor::new(&bytes);
+    assert_eq!(decode::read_array::<16, _>(&mut rd).unwrap()[..], bytes[..]);
+    // truncated input
+    assert!(decode::read_array::<16, _>(&mut Cursor::new(&bytes[..15])).is_err());
+  }
+
+  #[test]
+  fn client_query_set_delivery() {
+    round_trip(
+      encode::client_query,
+      decode::client_query,
+      &ClientQuery::SetDelivery(false),
+      &[12, 0],
+    );
+    round_trip(
+      encode::client_query,
+      decode::client_query,
+      &ClientQuery::SetDelivery(true),
+      &[12, 1],
+    );
+  }
+
+  #[test]
+  fn heartbeat() {
+    round_trip(
+      encode::client_query,
+      decode::client_query,
+      &ClientQuery::Heartbeat,
+      &[13],
+    );
+    round_trip(
+      |w, r: &Vec<ClientReply>| encode::client_replies(w, r),
+      decode::client_replies,
+      &vec![ClientReply::Heartbeat],
+      &[1, 5],
+    );
+  }
+
+  #[test]
+  fn client_query_set_order() {
+    round_trip(
+      encode::client_query,
+      decode::client_query,
+      &ClientQuery::SetOrder(DeliveryOrder::Fifo),
+      &[9, 0],
+    );
+    round_trip(
+      encode::client_query,
+      decode::client_query,
+      &ClientQuery::SetOrder(DeliveryOrder::Lifo),
+      &[9, 1],
+    );
+    assert!(decode::client_query(&mut Cursor::new([9, 2])).is_err());
+  }
+
+  #[test]
+  fn client_poll_reply_receipts() {
+    round_trip(
+      encode::client_poll_reply,
+      decode::client_poll_reply,
+      &ClientPollReply::Receipts(vec![1, 300]),
+      &[4, 2, 1, 251, 44, 1],
+    );
+  }
+
+  #[test]
+  fn poll_ack() {
+    round_trip(
+      encode::poll_ack,
+      decode::poll_ack,
+      &PollAck {
+        reply: ClientPollReply::Nothing,
+        contiguous_seqid: 300,
+      },
+      &[2, 251, 44, 1],
+    );
+  }
+
+  #[test]
+  fn client_poll_reply_message() {
+    let src = ClientId(uuid!["a3b674a2-b950-4e44-b32b-a29345e38e36"]);
+    let mut local = vec![0];
+    local.extend(src.0.as_bytes());
+    local.extend([0, 2, 104, 105, 0, 42]);
+    round_trip(
+      encode::client_poll_reply,
+      decode::client_poll_reply,
+      &ClientPollReply::Message {
+        src,
+        srcsrv: None,
+        content: "hi".into(),
+        attachments: Vec::new(),
+        message_id: 42,
+      },
+      &local,
+    );
+
+    let srcsrv = ServerId(uuid!["2a1e715b-5a5e-406b-9046-7be132a8df27"]);
+    let mut federated = vec![0];
+    federated.extend(src.0.as_bytes());
+    federated.extend([1]);
+    federated.extend(srcsrv.0.as_bytes());
+    federated.extend([2, 104, 105, 0, 42]);
+    round_trip(
+      encode::client_poll_reply,
+      decode::client_poll_reply,
+      &ClientPollReply::Message {
+        src,
+        srcsrv: Some(srcsrv),
+        content: "hi".into(),
+        attachments: Vec::new(),
+        message_id: 42,
+      },
+      &federated,
+    );
+  }
+
+  #[test]
+  fn client_poll_reply_system() {
+    let reply = ClientPollReply::System {
+      text: "maintenance".into(),
+    };
+    round_trip(
+      encode::client_poll_reply,
+      decode::client_poll_reply,
+      &reply,
+      &[3, 11, 109, 97, 105, 110, 116, 101, 110, 97, 110, 99, 101],
+    );
+  }
+
   #[test]
   fn string_decode() {
     let mut cursor = Cursor::new([
@@ -348,6 +1590,36 @@ mod test {
     ]);
     let decoded = decode::string(&mut cursor).unwrap();
     assert_eq!(decoded, "Hello World ;)");
+
+    // the declared length is larger than what follows
+    let mut wr = Cursor::new(Vec::new());
+    encode::u128(&mut wr, 1 << 60).unwrap();
+    wr.get_mut().extend(b"short");
+    assert!(decode::string(&mut Cursor::new(wr.into_inner())).is_err());
+  }
+
+  #[test]
+  fn long_message() {
+    let content = "x".repeat(10 * 1024);
+    let rq = Request {
+      request_id: 3,
+      sequence: Sequence::new(
+        ClientId::default(),
+        1,
+        0,
+        ClientQuery::Message(ClientMessage::Text {
+          dest: ClientId::default(),
+          content: content.clone(),
+          attachments: Vec::new(),
+        }),
+      ),
+    };
+    let mut wr = Cursor::new(Vec::new());
+    encode::request(&mut wr, &rq, encode::client_query).unwrap();
+    let buf = wr.into_inner();
+    assert!(buf.len() > content.len());
+    let decoded = decode::request(&mut Cursor::new(buf), decode::client_query).unwrap();
+    assert_eq!(decoded, rq);
   }
 
   #[test]
@@ -356,11 +1628,12 @@ mod test {
       seqid: 12,
       src: uuid!["77ff529e-75bd-4832-bf0c-6db339022924"].into(),
       workproof: 161666813615,
+      timestamp: None,
       content: "Hello".to_string(),
     };
     let encoded = &[
-      12, 16, 119, 255, 82, 158, 117, 189, 72, 50, 191, 12, 109, 179, 57, 2, 41, 36, 253, 175, 206,
-      23, 164, 37, 0, 0, 0, 5, 72, 101, 108, 108, 111,
+      12, 119, 255, 82, 158, 117, 189, 72, 50, 191, 12, 109, 179, 57, 2, 41, 36, 253, 175, 206, 23,
+      164, 37, 0, 0, 0, 0, 5, 72, 101, 108, 108, 111,
     ];
     round_trip::<Sequence<String>, _, _>(
       |w, seq| encode::sequence(w, seq, |w2, st| encode::string(w2, st.as_str())),
@@ -369,4 +1642,146 @@ mod test {
       encoded,
     );
   }
+
+  #[test]
+  fn sequence_client_queries() {
+    let src: ClientId = uuid!["77ff529e-75bd-4832-bf0c-6db339022924"].into();
+    let queries = [
+      ClientQuery::Register("Bob".into()),
+      ClientQuery::Message(ClientMessage::Text {
+        dest: src,
+        content: "hi".into(),
+        attachments: Vec::new(),
+      }),
+      ClientQuery::Poll,
+      ClientQuery::ListUsers,
+    ];
+    for query in queries {
+      let sq = Sequence::new(src, 12, 161666813615, query);
+      let mut wr = Cursor::new(Vec::new());
+      encode::sequence(&mut wr, &sq, encode::client_query).unwrap();
+      let buf = wr.into_inner();
+      // the query comes right after the header
+      let mut payload = Cursor::new(Vec::new());
+      encode::client_query(&mut payload, &sq.content).unwrap();
+      assert!(buf.ends_with(payload.get_ref()));
+
+      let mut rd = Cursor::new(buf);
+      assert_eq!(decode::sequence(&mut rd, decode::client_query).unwrap(), sq);
+      assert_eq!(rd.position() as usize, rd.get_ref().len());
+    }
+  }
+
+  #[test]
+  fn request() {
+    let src = Request {
+      request_id: 7,
+      sequence: Sequence {
+        seqid: 12,
+        src: uuid!["77ff529e-75bd-4832-bf0c-6db339022924"].into(),
+        workproof: 161666813615,
+        timestamp: Some(1700000000000),
+        content: ClientQuery::Poll,
+      },
+    };
+    let encoded = &[
+      7, 12, 119, 255, 82, 158, 117, 189, 72, 50, 191, 12, 109, 179, 57, 2, 41, 36, 253, 175, 206,
+      23, 164, 37, 0, 0, 0, 1, 253, 0, 104, 229, 207, 139, 1, 0, 0, 2, 12, 232, 250, 41,
+    ];
+    round_trip::<Request<ClientQuery>, _, _>(
+      |w, rq| encode::request(w, rq, encode::client_query),
+      |rd| decode::request(rd, decode::client_query),
+      &src,
+      encoded,
+    );
+  }
+
+  #[test]
+  fn reply() {
+    let src = Reply {
+      request_id: 300,
+      payload: true,
+    };
+    round_trip::<Reply<bool>, _, _>(
+      |w, r| encode::reply(w, r, |w2, b| encode::bool(w2, *b)),
+      |rd| decode::reply(rd, decode::bool),
+      &src,
+      &[251, 44, 1, 0, 1],
+    );
+  }
+
+  #[test]
+  fn error_reply() {
+    let src = Reply {
+      request_id: 300,
+      payload: ClientError::ServerBusy,
+    };
+    round_trip::<Reply<ClientError>, _, _>(
+      encode::error_reply,
+      |rd| {
+        let header = decode::reply_header(rd)?;
+        Ok(Reply {
+          request_id: header.request_id,
+          payload: header.payload.unwrap_err(),
+        })
+      },
+      &src,
+      &[251, 44, 1, 1, 15],
+    );
+    let mut rd = Cursor::new(vec![251, 44, 1, 1, 12]);
+    let rr = decode::reply(&mut rd, decode::bool).unwrap_err();
+    assert_eq!(rr.downcast_ref(), Some(&ClientError::ProtocolError));
+    assert!(decode::reply_header(&mut Cursor::new(vec![1, 2])).is_err());
+  }
+
+  // a writer that accepts `limit` bytes, then fails
+  struct FailingWriter {
+    limit: usize,
+    written: Vec<u8>,
+  }
+
+  impl std::io::Write for FailingWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+      let n = buf.len().min(self.limit - self.written.len());
+      if n == 0 && !buf.is_empty() {
+        return Err(std::io::Error::new(
+          std::io::ErrorKind::WriteZero,
+          "writer is full",
+        ));
+      }
+      self.written.extend_from_slice(&buf[..n]);
+      Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+      Ok(())
+    }
+  }
+
+  #[test]
+  fn client_replies_write_failure() {
+    let replies = vec![
+      ClientReply::Delivered(1),
+      ClientReply::Error(ClientError::BoxFull(ClientId::default())),
+      ClientReply::Delayed(2),
+      ClientReply::Transfer(ServerId::default(), servermessages().remove(0)),
+    ];
+    let mut wr = Cursor::new(Vec::new());
+    encode::client_replies(&mut wr, &replies).unwrap();
+    let full = wr.into_inner();
+
+    for limit in 0..full.len() {
+      let mut wr = FailingWriter {
+        limit,
+        written: Vec::new(),
+      };
+      assert!(
+        encode::client_replies(&mut wr, &replies).is_err(),
+        "encoding succeeded with only {} bytes out of {}",
+        limit,
+        full.len()
+      );
+      assert_eq!(wr.written, &full[..limit]);
+    }
+  }
 }
@@ -0,0 +1,81 @@
+use std::time::{Duration, Instant};
+
+/// window over which the decode errors are counted
+pub const LOG_WINDOW: Duration = Duration::from_secs(1);
+
+/// samples a repetitive log line: the first `limit` occurrences of each window are logged, and
+/// the number of the suppressed ones is reported once the window is over
+pub struct LogLimiter {
+  limit: usize,
+  window: Duration,
+  started: Option<Instant>,
+  logged: usize,
+  suppressed: u64,
+}
+
+impl LogLimiter {
+  pub fn new(limit: usize, window: Duration) -> Self {
+    LogLimiter {
+      limit,
+      window,
+      started: None,
+      logged: 0,
+      suppressed: 0,
+    }
+  }
+
+  /// records an occurrence at `now`, returns whether it should be logged, and the number of
+  /// occurrences that were suppressed during the previous window, if it just ended
+  pub fn check(&mut self, now: Instant) -> (bool, u64) {
+    let mut ended = 0;
+    match self.started {
+      Some(started) if now.duration_since(started) < self.window => (),
+      _ => {
+        ended = std::mem::take(&mut self.suppressed);
+        self.started = Some(now);
+        self.logged = 0;
+      }
+    }
+    if self.logged < self.limit {
+      self.logged += 1;
+      (true, ended)
+    } else {
+      self.suppressed += 1;
+      (false, ended)
+    }
+  }
+
+  /// calls `log` if the occurrence is to be logged, preceded by the suppression summary
+  pub fn log<F: FnOnce()>(&mut self, what: &str, log: F) {
+    let (logged, suppressed) = self.check(Instant::now());
+    if suppressed > 0 {
+      log::warn!("{} similar {} were not logged", suppressed, what);
+    }
+    if logged {
+      log();
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn first_ones_logged() {
+    let mut limiter = LogLimiter::new(3, LOG_WINDOW);
+    let start = Instant::now();
+    let logged: Vec<bool> = (0..10).map(|_| limiter.check(start).0).collect();
+    assert_eq!(
+      logged,
+      [true, true, true, false, false, false, false, false, false, false]
+    );
+    // still within the window
+    assert_eq!(limiter.check(start + LOG_WINDOW / 2), (false, 0));
+    // the next window starts with the summary of the previous one
+    assert_eq!(limiter.check(start + LOG_WINDOW), (true, 8));
+    assert_eq!(limiter.check(start + LOG_WINDOW), (true, 0));
+    // nothing was suppressed during the second window
+    assert_eq!(limiter.check(start + LOG_WINDOW * 3), (true, 0));
+  }
+}
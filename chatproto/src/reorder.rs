@@ -0,0 +1,148 @@
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+use crate::messages::ClientId;
+
+/// maximum number of sequences held for a single client, they are all released when it is exceeded
+pub const MAX_HELD: usize = 32;
+
+/// holds the sequences that arrive ahead of their predecessors for a short grace period, so that
+/// they can be handled in order once the missing ones arrive
+/// sequences are released in order when the gap is filled, when their grace period is over, or
+/// when too many are held. Sequences that are not ahead are released right away, so that replays
+/// are still rejected by the server.
+pub struct ReorderBuffer<T> {
+  grace: Duration,
+  held: HashMap<ClientId, BTreeMap<u128, (Instant, T)>>,
+}
+
+impl<T> ReorderBuffer<T> {
+  pub fn new(grace: Duration) -> Self {
+    ReorderBuffer {
+      grace,
+      held: HashMap::new(),
+    }
+  }
+
+  /// adds the sequence `seqid` from `src` received at `now`, `last` being the highest sequence id
+  /// accepted from `src` so far
+  /// returns the items that can be handled now, in order
+  pub fn push(&mut self, src: ClientId, seqid: u128, last: u128, item: T, now: Instant) -> Vec<T> {
+    let held = self.held.entry(src).or_default();
+    let mut ready = Vec::new();
+    if seqid <= last.saturating_add(1) {
+      ready.push(item);
+      let mut next = seqid;
+      while let Some((_, item)) = next.checked_add(1).and_then(|n| held.remove(&n)) {
+        ready.push(item);
+        next += 1;
+      }
+    } else {
+      match held.entry(seqid) {
+        Entry::Occupied(_) => log::debug!(
+          "dropping a duplicate of the held sequence {} from {}",
+          seqid,
+          src
+        ),
+        Entry::Vacant(e) => {
+          e.insert((now, item));
+        }
+      }
+      if held.len() > MAX_HELD {
+        ready.extend(std::mem::take(held).into_values().map(|(_, item)| item));
+      }
+    }
+    if held.is_empty() {
+      self.held.remove(&src);
+    }
+    ready
+  }
+
+  /// releases the items whose grace period is over at `now`, with the items of the same client
+  /// that precede them, in order
+  pub fn expired(&mut self, now: Instant) -> Vec<T> {
+    let mut ready = Vec::new();
+    for held in self.held.values_mut() {
+      let last_expired = held
+        .iter()
+        .filter(|(_, (since, _))| now.duration_since(*since) >= self.grace)
+        .map(|(seqid, _)| *seqid)
+        .max();
+      if let Some(last) = last_expired {
+        let rest = match last.checked_add(1) {
+          Some(n) => held.split_off(&n),
+          None => BTreeMap::new(),
+        };
+        ready.extend(
+          std::mem::replace(held, rest)
+            .into_values()
+            .map(|(_, item)| item),
+        );
+      }
+    }
+    self.held.retain(|_, held| !held.is_empty());
+    ready
+  }
+
+  /// the next time an item expires, if any is held
+  pub fn next_expiry(&self) -> Option<Instant> {
+    self
+      .held
+      .values()
+      .flat_map(|held| held.values().map(|(since, _)| *since + self.grace))
+      .min()
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::client::Client;
+  use crate::core::MessageServer;
+  use crate::messages::ServerId;
+  use crate::solutions::sample::Server;
+
+  #[test]
+  fn handled_in_order() {
+    async_std::task::block_on(async {
+      let server = Server::new(ServerId::default());
+      let id = server.register_local_client("c1".into()).await.unwrap();
+      let mut client = Client::new(id);
+      let sequences: Vec<_> = (1..=3).map(|n| client.sequence(n)).collect();
+      let mut buffer = ReorderBuffer::new(Duration::from_secs(1));
+      let mut handled = Vec::new();
+      let now = Instant::now();
+      for i in [0, 2, 1] {
+        let seq = sequences[i].clone();
+        let last = server.last_accepted_seqid(id).await.unwrap();
+        for seq in buffer.push(id, seq.seqid, last, seq, now) {
+          handled.push(server.handle_sequenced_message(seq, None).await);
+        }
+      }
+      assert_eq!(handled, [Ok(1), Ok(2), Ok(3)]);
+      assert_eq!(buffer.next_expiry(), None);
+
+      // replays are not held
+      let replay = sequences[1].clone();
+      let ready = buffer.push(id, replay.seqid, 3, replay, now);
+      assert_eq!(ready.len(), 1);
+    })
+  }
+
+  #[test]
+  fn released_after_grace() {
+    let grace = Duration::from_millis(100);
+    let mut buffer = ReorderBuffer::new(grace);
+    let client = ClientId::default();
+    let start = Instant::now();
+    assert!(buffer.push(client, 3, 1, 3, start).is_empty());
+    assert!(buffer.push(client, 5, 1, 5, start + grace / 2).is_empty());
+    assert_eq!(buffer.next_expiry(), Some(start + grace));
+    assert!(buffer.expired(start + grace / 2).is_empty());
+    // 5 is still within its grace period
+    assert_eq!(buffer.expired(start + grace), [3]);
+    assert_eq!(buffer.expired(start + grace * 2), [5]);
+    assert_eq!(buffer.next_expiry(), None);
+  }
+}
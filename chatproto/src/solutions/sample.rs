@@ -1,46 +1,573 @@
-use async_std::sync::RwLock;
+use async_std::sync::{RwLock, RwLockWriteGuard};
 use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::Entry;
 use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 use uuid::Uuid;
 
 use crate::{
-  core::{MessageServer, MAILBOX_SIZE, WORKPROOF_STRENGTH},
+  core::{
+    now_millis, validate_name, MessageServer, Metrics, MAILBOX_SIZE, MAX_CLIENTS,
+    MAX_MAILBOX_BYTES, MAX_PENDING_PER_SENDER, REPLAY_WINDOW, WORKPROOF_STRENGTH,
+  },
   messages::{
-    ClientError, ClientId, ClientMessage, ClientPollReply, ClientReply, FullyQualifiedMessage,
-    Sequence, ServerId,
+    AckStatus, ClientError, ClientId, ClientLocation, ClientMessage, ClientPollReply, ClientReply,
+    DelayedError, DeliveryOrder, Sequence, ServerId, WorkproofNonce, ECHO_CLIENT,
   },
+  netproto::{decode, encode},
   workproof::verify_workproof,
 };
 
 #[cfg(feature = "federation")]
-use crate::messages::{Outgoing, ServerMessage, ServerReply};
+use crate::core::{MAX_PEERS, MAX_ROUTE_HOPS};
+#[cfg(feature = "federation")]
+use crate::messages::{FullyQualifiedMessage, Outgoing, ServerError, ServerMessage, ServerReply};
+
+// number of rejected workproofs that are remembered
+const REJECTED_PROOFS: usize = 1024;
+
+// number of read receipts in a batch, a full batch is sent without waiting for the end of the
+// window
+const RECEIPT_BATCH: usize = 64;
+
+// number of unregistered clients that are remembered, so that their senders get RecipientGone
+const UNREGISTERED: usize = 1024;
+
+// number of placeholders for unknown clients that are kept, the oldest ones are evicted first
+const MAX_PLACEHOLDERS: usize = 4096;
+
+// how long the messages sent to a name nobody registered with are kept, by default
+const NAME_HOLD_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+// delay between two attempts at taking the clients lock when its wait is bounded, doubled each time
+const LOCK_RETRY_DELAY: Duration = Duration::from_micros(100);
+const MAX_LOCK_RETRY_DELAY: Duration = Duration::from_millis(10);
+
+// number of times the clients lock was taken for writing
+#[cfg(test)]
+thread_local! {
+  static CLIENTS_LOCKS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+// a message waiting in a mailbox
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct MessageInfo {
+  id: u128,
+  src: ClientId,
+  // the server a federated message comes from
+  srcsrv: Option<ServerId>,
+  content: String,
+  attachments: Vec<(String, String)>,
+}
+
+// what we know about a local client
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct ClientInfo {
+  name: String,
+  // highest sequence id accepted from this client, the earlier ones are rejected from now on
+  last_accepted_seqid: u128,
+  mailbox: VecDeque<MessageInfo>,
+  // messages that did not fit in the mailbox, moved there as it empties
+  overflow: VecDeque<MessageInfo>,
+  // last workproof that was verified, with its nonce and the strength it was verified against
+  verified_proof: Option<(WorkproofNonce, u128, u32)>,
+  // system notices, polled before the mailbox
+  notices: VecDeque<String>,
+  // problems with messages this client sent, polled before the mailbox
+  errors: VecDeque<DelayedError>,
+  // address the client registered from, if it came from the network
+  address: Option<SocketAddr>,
+  // last time a message was accepted from this client
+  last_seen: Instant,
+  // source of the Register sequence this client was created by
+  registered_by: Option<ClientId>,
+  // ids of the messages sent by this client that were read, with the time the first one was
+  receipts: Option<(Instant, Vec<u128>)>,
+  // batches of RECEIPT_BATCH receipts, polled without waiting for the end of their window
+  full_receipts: VecDeque<Vec<u128>>,
+  // the order this client polls its messages in
+  order: DeliveryOrder,
+  // the sequence ids accepted from this client
+  acks: AckStatus,
+  // set while the client does not want its messages delivered
+  paused: bool,
+  // content bytes of the messages in the mailbox and the overflow
+  queued_bytes: usize,
+}
+
+// what happens to messages sent to a local client whose mailbox is full
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverflowMode {
+  // the message is dropped, and BoxFull is returned
+  Reject,
+  // the message is kept in a bounded overflow buffer, and Delayed is returned
+  Queue,
+}
+
+// what happens to messages sent to a remote client whose server cannot be reached
+#[cfg(feature = "federation")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnroutableMode {
+  // the message is held until a route is announced, the sender gets Delayed and a RouteLost
+  // delayed error
+  Hold,
+  // the message is dropped, and NoRoute is returned
+  Reject,
+}
+
+// size of the overflow buffer of each client, when messages are queued
+const OVERFLOW_SIZE: usize = MAILBOX_SIZE;
+
+// a change in the routing table, routes are in the same order as announces
+#[cfg(feature = "federation")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RouteChange {
+  Added(Vec<ServerId>),
+  Removed(Vec<ServerId>),
+}
+
+// called whenever the routing table changes, without any lock held
+#[cfg(feature = "federation")]
+pub type RouteObserver = Box<dyn Fn(RouteChange) + Send + Sync>;
+
+// tells whether a name can be registered, it is given names that passed `validate_name`
+pub type NamePolicy = Box<dyn Fn(&str) -> bool + Send + Sync>;
+
+// a significant server event, for debugging the message flow
+// events carry what `Server::replay` needs to apply them again
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+  Registered {
+    client: ClientId,
+    name: String,
+  },
+  // stored in the mailbox of a local client, or held until the destination is reachable
+  Queued {
+    message_id: u128,
+    src: ClientId,
+    dest: ClientId,
+    content: String,
+    attachments: Vec<(String, String)>,
+  },
+  Polled {
+    client: ClientId,
+    message_id: u128,
+  },
+  #[cfg(feature = "federation")]
+  Transferred {
+    src: ClientId,
+    dest: ClientId,
+    nexthop: ServerId,
+  },
+  #[cfg(feature = "federation")]
+  Routed(RouteChange),
+}
+
+#[derive(Clone, Debug)]
+pub struct LoggedEvent {
+  pub at: Instant,
+  pub event: Event,
+}
+
+// the last `capacity` events, oldest first
+pub struct EventLog {
+  capacity: usize,
+  events: VecDeque<LoggedEvent>,
+}
+
+impl EventLog {
+  pub fn new(capacity: usize) -> Self {
+    EventLog {
+      capacity,
+      events: VecDeque::new(),
+    }
+  }
+
+  pub fn push(&mut self, event: Event) {
+    if self.events.len() >= self.capacity {
+      self.events.pop_front();
+    }
+    self.events.push_back(LoggedEvent {
+      at: Instant::now(),
+      event,
+    });
+  }
+}
+
+// persisted state of a local client
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ClientSnapshot {
+  pub id: ClientId,
+  pub name: String,
+  pub last_sequence: u128,
+  // the messages waiting in the mailbox, oldest first
+  pub mailbox: Vec<MessageSnapshot>,
+}
+
+// persisted message, it gets a new id on the server it is imported into
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct MessageSnapshot {
+  pub src: ClientId,
+  pub srcsrv: Option<ServerId>,
+  pub content: String,
+  pub attachments: Vec<(String, String)>,
+}
+
+// persisted state of a server, only local clients are saved
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ServerSnapshot {
+  pub clients: Vec<ClientSnapshot>,
+}
+
+// a single local client, encoded with the netproto encoders, to move it to another server
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExportedClient(pub Vec<u8>);
+
+impl ClientSnapshot {
+  fn encode(&self, w: &mut Vec<u8>) -> std::io::Result<()> {
+    encode::clientid(w, &self.id)?;
+    encode::string(w, &self.name)?;
+    encode::u128(w, self.last_sequence)?;
+    encode::u128(w, self.mailbox.len() as u128)?;
+    for message in &self.mailbox {
+      encode::clientid(w, &message.src)?;
+      encode::option_serverid(w, &message.srcsrv)?;
+      encode::string(w, &message.content)?;
+      encode::attachments(w, &message.attachments)?;
+    }
+    Ok(())
+  }
+
+  fn decode<R: std::io::Read>(rd: &mut R) -> anyhow::Result<Self> {
+    let id = decode::clientid(rd)?;
+    let name = decode::string(rd)?;
+    let last_sequence = decode::u128(rd)?;
+    let count = decode::u128(rd)?;
+    let mut mailbox = Vec::new();
+    for _ in 0..count {
+      mailbox.push(MessageSnapshot {
+        src: decode::clientid(rd)?,
+        srcsrv: decode::option_serverid(rd)?,
+        content: decode::string(rd)?,
+        attachments: decode::attachments(rd)?,
+      });
+    }
+    Ok(ClientSnapshot {
+      id,
+      name,
+      last_sequence,
+      mailbox,
+    })
+  }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Stuff {
+  Local(Box<ClientInfo>),
+  // a client that is not known yet, the mailbox holds the delayed messages until it is announced
+  Pending {
+    mailbox: VecDeque<MessageInfo>,
+  },
+  // a client living on another server
+  // the mailbox holds the messages waiting for a route to its server
+  // only announces create them, so they do not exist without federation
+  #[cfg_attr(not(feature = "federation"), allow(dead_code))]
+  Remote {
+    name: String,
+    server: ServerId,
+    mailbox: VecDeque<MessageInfo>,
+  },
+}
+
+// number of messages of each sender that are waiting for their recipient
+// they are counted as they come and go, rather than by scanning every waiting message
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct SenderCounts(HashMap<ClientId, usize>);
+
+impl SenderCounts {
+  fn get(&self, src: ClientId) -> usize {
+    self.0.get(&src).copied().unwrap_or(0)
+  }
+
+  fn add(&mut self, src: ClientId) {
+    *self.0.entry(src).or_default() += 1;
+  }
+
+  // the messages are not waiting anymore, whether they were delivered, recalled or dropped
+  fn remove<'a>(&mut self, messages: impl IntoIterator<Item = &'a MessageInfo>) {
+    for message in messages {
+      if let Entry::Occupied(mut count) = self.0.entry(message.src) {
+        *count.get_mut() -= 1;
+        if *count.get() == 0 {
+          count.remove();
+        }
+      }
+    }
+  }
+}
+
+// the latest values, oldest first, along with a set so that they are looked up without scanning
+// them all
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Recent<T: std::hash::Hash + Eq> {
+  order: VecDeque<T>,
+  members: HashSet<T>,
+  capacity: usize,
+}
+
+impl<T: std::hash::Hash + Eq + Clone> Recent<T> {
+  fn new(capacity: usize) -> Self {
+    Self {
+      order: VecDeque::new(),
+      members: HashSet::new(),
+      capacity,
+    }
+  }
+
+  fn contains(&self, value: &T) -> bool {
+    self.members.contains(value)
+  }
+
+  // the oldest value is forgotten once `capacity` values are remembered
+  fn push(&mut self, value: T) {
+    if !self.members.insert(value.clone()) {
+      return;
+    }
+    if self.order.len() >= self.capacity {
+      if let Some(oldest) = self.order.pop_front() {
+        self.members.remove(&oldest);
+      }
+    }
+    self.order.push_back(value);
+  }
+}
+
+// messages sent to names no local client is registered with
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct HeldMessages {
+  // with the time they were sent, oldest first
+  by_name: HashMap<String, VecDeque<(Instant, MessageInfo)>>,
+  // the name of each held message, oldest first, so that they expire without scanning them all
+  // the messages released since are skipped
+  order: VecDeque<(Instant, String)>,
+  senders: SenderCounts,
+}
+
+impl HeldMessages {
+  fn push(&mut self, name: String, message: MessageInfo) {
+    let now = Instant::now();
+    self.senders.add(message.src);
+    self.order.push_back((now, name.clone()));
+    self
+      .by_name
+      .entry(name)
+      .or_default()
+      .push_back((now, message));
+  }
+
+  // drops the messages that were held for `ttl` or longer
+  fn expire(&mut self, ttl: Duration) {
+    while self
+      .order
+      .front()
+      .is_some_and(|(since, _)| since.elapsed() >= ttl)
+    {
+      let (since, name) = self.order.pop_front().unwrap();
+      if let Entry::Occupied(mut held) = self.by_name.entry(name) {
+        if held.get().front().is_some_and(|(sent, _)| *sent <= since) {
+          let (_, message) = held.get_mut().pop_front().unwrap();
+          self.senders.remove([&message]);
+          if held.get().is_empty() {
+            held.remove();
+          }
+        }
+      }
+    }
+  }
+
+  // removes the messages held for `name`
+  fn take(&mut self, name: &str) -> Option<VecDeque<(Instant, MessageInfo)>> {
+    let held = self.by_name.remove(name)?;
+    self.senders.remove(held.iter().map(|(_, message)| message));
+    Some(held)
+  }
+}
+
+/// the in-memory state of a `Server`, see `MessageServer::debug_snapshot`
+/// the configuration (replay window, overflow mode...) is not part of it
+#[cfg(feature = "debug-snapshot")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServerState {
+  clients: HashMap<ClientId, Stuff>,
+  #[cfg(feature = "federation")]
+  routes: HashMap<ServerId, Vec<ServerId>>,
+  #[cfg(feature = "federation")]
+  outbound: VecDeque<Outgoing<FullyQualifiedMessage>>,
+  topics: HashMap<String, HashSet<ClientId>>,
+  next_message_id: u64,
+  rejected_proofs: Recent<(WorkproofNonce, u128)>,
+  drain_cursor: Option<ClientId>,
+  delivered: u64,
+  errors: u64,
+  sent: HashMap<ClientId, u64>,
+  placeholders: VecDeque<ClientId>,
+  unregistered: Recent<ClientId>,
+  held_for_names: HeldMessages,
+  pending_senders: SenderCounts,
+}
 
 // this structure will contain the data you need to track in your server
 // this will include things like delivered messages, clients last seen sequence number, etc.
 pub struct Server {
   id: ServerId,
-  clients: RwLock<HashMap<ClientId, String>>,
+  clients: RwLock<HashMap<ClientId, Stuff>>,
+  // announced routes, indexed by the server that originated them
+  #[cfg(feature = "federation")]
+  routes: RwLock<HashMap<ServerId, Vec<ServerId>>>,
+  // transfers waiting to be sent, see `queue_transfers`
+  #[cfg(feature = "federation")]
+  outbound: RwLock<VecDeque<Outgoing<FullyQualifiedMessage>>>,
+  #[cfg(feature = "federation")]
+  route_observer: Option<RouteObserver>,
+  #[cfg(feature = "federation")]
+  unroutable_mode: UnroutableMode,
+  // subscribers of each topic
+  topics: RwLock<HashMap<String, HashSet<ClientId>>>,
+  // last allocated message id
+  next_message_id: AtomicU64,
+  // maximum age of a timestamped sequence, in milliseconds
+  replay_window: u64,
+  // recently rejected (nonce, workproof) pairs, oldest first
+  rejected_proofs: RwLock<Recent<(WorkproofNonce, u128)>>,
+  // reject messages that do not come from the address the client registered from
+  check_address: bool,
+  // local clients that have not been seen for that long are not listed anymore
+  liveness_window: Option<Duration>,
+  overflow_mode: OverflowMode,
+  // messages addressed to ECHO_CLIENT are sent back to their sender
+  echo: bool,
+  // names it rejects cannot be registered, see `with_name_policy`
+  name_policy: Option<NamePolicy>,
+  started_at: Instant,
+  // last client served by `drain`, the next pass starts after it
+  drain_cursor: RwLock<Option<ClientId>>,
+  // cumulative counters, see Metrics
+  delivered: AtomicU64,
+  errors: AtomicU64,
+  // number of messages sent by each local client
+  sent: RwLock<HashMap<ClientId, u64>>,
+  // read receipts are enabled, and batched over that window
+  receipt_window: Option<Duration>,
+  // unknown clients a placeholder was created for, oldest first
+  // entries that were announced since are skipped when evicting
+  placeholders: RwLock<VecDeque<ClientId>>,
+  // messages of each sender waiting in the placeholders
+  pending_senders: RwLock<SenderCounts>,
+  // recently unregistered local clients, oldest first
+  unregistered: RwLock<Recent<ClientId>>,
+  // messages sent to names no local client is registered with
+  held_for_names: RwLock<HeldMessages>,
+  // how long they are kept
+  name_hold_ttl: Duration,
+  // recent events, when enabled
+  event_log: Option<Mutex<EventLog>>,
+  // maximum wait for the clients lock when handling a sequence, None waits as long as needed
+  lock_timeout: Option<Duration>,
 }
 
 #[async_trait]
 impl MessageServer for Server {
   const GROUP_NAME: &'static str = "WRITE YOUR NAMES HERE, NOT YOUR TEAM NAME, YOUR ACTUAL NAMES!";
 
+  #[cfg(feature = "debug-snapshot")]
+  type ServerState = ServerState;
+
   fn new(id: ServerId) -> Self {
     Self {
-      id: id,
+      id,
       clients: RwLock::new(HashMap::new()),
+      #[cfg(feature = "federation")]
+      routes: RwLock::new(HashMap::new()),
+      #[cfg(feature = "federation")]
+      outbound: RwLock::new(VecDeque::new()),
+      #[cfg(feature = "federation")]
+      route_observer: None,
+      #[cfg(feature = "federation")]
+      unroutable_mode: UnroutableMode::Hold,
+      topics: RwLock::new(HashMap::new()),
+      next_message_id: AtomicU64::new(0),
+      replay_window: REPLAY_WINDOW,
+      rejected_proofs: RwLock::new(Recent::new(REJECTED_PROOFS)),
+      check_address: false,
+      liveness_window: None,
+      overflow_mode: OverflowMode::Reject,
+      echo: false,
+      name_policy: None,
+      started_at: Instant::now(),
+      drain_cursor: RwLock::new(None),
+      delivered: AtomicU64::new(0),
+      errors: AtomicU64::new(0),
+      sent: RwLock::new(HashMap::new()),
+      receipt_window: None,
+      placeholders: RwLock::new(VecDeque::new()),
+      pending_senders: RwLock::new(SenderCounts::default()),
+      unregistered: RwLock::new(Recent::new(UNREGISTERED)),
+      held_for_names: RwLock::new(HeldMessages::default()),
+      name_hold_ttl: NAME_HOLD_TTL,
+      event_log: None,
+      lock_timeout: None,
     }
   }
 
+  fn id(&self) -> ServerId {
+    self.id
+  }
+
   // note: you need to roll a Uuid, and then convert it into a ClientId
   // Uuid::new_v4() will generate such a value
   // you will most likely have to edit the Server struct as as to store information about the client
-  async fn register_local_client(&self, name: String) -> ClientId {
-    let user_id = ClientId(Uuid::new_v4());
-    let mut l = self.clients.write().await;
-    l.insert(user_id, name);
-    user_id
+  async fn register_local_client(&self, name: String) -> Result<ClientId, ClientError> {
+    let mut clients = self.clients_write().await;
+    let result = register(&mut clients, self.name_policy.as_ref(), None, name);
+    self.registered(&mut clients, result).await
+  }
+
+  async fn register_local_client_from(
+    &self,
+    nonce: ClientId,
+    name: String,
+  ) -> Result<ClientId, ClientError> {
+    let mut clients = self.clients_write().await;
+    let result = register(&mut clients, self.name_policy.as_ref(), Some(nonce), name);
+    self.registered(&mut clients, result).await
+  }
+
+  async fn unregister_local_client(&self, client: ClientId) -> bool {
+    let mut clients = self.clients_write().await;
+    if !matches!(clients.get(&client), Some(Stuff::Local(_))) {
+      return false;
+    }
+    clients.remove(&client);
+    self.unregistered.write().await.push(client);
+    self.sent.write().await.remove(&client);
+    for subscribers in self.topics.write().await.values_mut() {
+      subscribers.remove(&client);
+    }
+    true
+  }
+
+  async fn register_local_clients(&self, names: Vec<String>) -> Vec<Result<ClientId, ClientError>> {
+    let mut clients = self.clients_write().await;
+    let mut results = Vec::with_capacity(names.len());
+    for name in names {
+      let result = register(&mut clients, self.name_policy.as_ref(), None, name);
+      results.push(self.registered(&mut clients, result).await);
+    }
+    results
   }
 
   /*
@@ -52,8 +579,56 @@ impl MessageServer for Server {
   async fn handle_sequenced_message<A: Send>(
     &self,
     sequence: Sequence<A>,
+    peer: Option<SocketAddr>,
   ) -> Result<A, ClientError> {
-    todo!()
+    // the workproof must be checked before looking the client up: registration accepts
+    // UnknownClient, so checking it first would make registering free. Floods of bad proofs
+    // are mitigated by remembering the recently rejected ones instead.
+    let nonce = sequence.workproof_nonce();
+    let rejected = (nonce, sequence.workproof);
+    if self.rejected_proofs.read().await.contains(&rejected) {
+      return Err(ClientError::WorkProofError);
+    }
+    // without a timestamp, the nonce is stable for a given client, so a proof that was already
+    // verified is still valid
+    let proof = (nonce, sequence.workproof, WORKPROOF_STRENGTH);
+    // the lock is not waited for, at worst the proof is verified again
+    let cached = matches!(
+      self.clients.try_read().as_deref().and_then(|clients| clients.get(&sequence.src)),
+      Some(Stuff::Local(info)) if info.verified_proof == Some(proof)
+    );
+    if !cached && !verify_workproof(nonce, sequence.workproof, WORKPROOF_STRENGTH) {
+      self.rejected_proofs.write().await.push(rejected);
+      return Err(ClientError::WorkProofError);
+    }
+    // last_accepted_seqid is lost when the server restarts, so old messages could be replayed
+    if let Some(timestamp) = sequence.timestamp {
+      if timestamp.saturating_add(self.replay_window) < now_millis() {
+        return Err(ClientError::StaleMessage);
+      }
+    }
+    let mut clients = self.clients_write_bounded().await?;
+    match clients.get_mut(&sequence.src) {
+      Some(Stuff::Local(info)) => {
+        // the workproof only depends on the public client id, so it does not prevent spoofing
+        if self.check_address {
+          if let (Some(bound), Some(peer)) = (info.address, peer) {
+            if bound != peer {
+              return Err(ClientError::AddressMismatch);
+            }
+          }
+        }
+        if sequence.seqid <= info.last_accepted_seqid {
+          return Err(ClientError::SequenceError);
+        }
+        info.last_accepted_seqid = sequence.seqid;
+        info.acks.record(sequence.seqid);
+        info.verified_proof = Some(proof);
+        info.last_seen = Instant::now();
+        Ok(sequence.content)
+      }
+      _ => Err(ClientError::UnknownClient),
+    }
   }
 
   /* Here client messages are handled.
@@ -67,53 +642,3745 @@ impl MessageServer for Server {
     both ClientMessage variants.
   */
   async fn handle_client_message(&self, src: ClientId, msg: ClientMessage) -> Vec<ClientReply> {
-    todo!()
+    // an edit changes a message that was already counted, not a new delivery
+    let is_edit = matches!(msg, ClientMessage::Edit { .. });
+    if !is_edit {
+      *self.sent.write().await.entry(src).or_default() += 1;
+    }
+    let replies = match msg {
+      ClientMessage::Text {
+        dest,
+        content,
+        attachments,
+      } => {
+        let mut clients = self.clients_write().await;
+        vec![
+          self
+            .handle_single_message(&mut clients, src, dest, content, attachments)
+            .await,
+        ]
+      }
+      ClientMessage::MText { dest, content } => {
+        let mut clients = self.clients_write().await;
+        let mut replies = Vec::with_capacity(dest.len());
+        for d in dest {
+          replies.push(
+            self
+              .handle_single_message(&mut clients, src, d, content.clone(), Vec::new())
+              .await,
+          );
+        }
+        replies
+      }
+      ClientMessage::Publish { topic, content } => {
+        let subscribers: Vec<ClientId> = match self.topics.read().await.get(&topic) {
+          Some(s) => s.iter().filter(|c| **c != src).copied().collect(),
+          None => Vec::new(),
+        };
+        let mut clients = self.clients_write().await;
+        let mut replies = Vec::with_capacity(subscribers.len());
+        for d in subscribers {
+          replies.push(
+            self
+              .handle_single_message(&mut clients, src, d, content.clone(), Vec::new())
+              .await,
+          );
+        }
+        replies
+      }
+      ClientMessage::ToName { name, content } => {
+        vec![self.send_to_name(src, name, content).await]
+      }
+      ClientMessage::Edit {
+        message_id,
+        dest,
+        content,
+      } => {
+        let mut clients = self.clients_write().await;
+        let (mailboxes, queued_bytes) = match clients.get_mut(&dest) {
+          Some(Stuff::Local(info)) => (
+            vec![&mut info.mailbox, &mut info.overflow],
+            Some(&mut info.queued_bytes),
+          ),
+          Some(Stuff::Pending { mailbox }) | Some(Stuff::Remote { mailbox, .. }) => {
+            (vec![mailbox], None)
+          }
+          None => (Vec::new(), None),
+        };
+        let edited = mailboxes
+          .into_iter()
+          .flat_map(|mailbox| mailbox.iter_mut())
+          .find(|m| m.id == message_id && m.src == src);
+        vec![match (edited, queued_bytes) {
+          (None, _) => ClientReply::Error(ClientError::UnknownMessage),
+          (Some(m), Some(queued))
+            if *queued - m.content.len() + content.len() > MAX_MAILBOX_BYTES =>
+          {
+            ClientReply::Error(ClientError::BoxFull(dest))
+          }
+          (Some(m), queued_bytes) => {
+            if let Some(queued) = queued_bytes {
+              *queued = *queued - m.content.len() + content.len();
+            }
+            m.content = content;
+            ClientReply::Delivered(message_id)
+          }
+        }]
+      }
+    };
+    for reply in &replies {
+      let counter = match reply {
+        ClientReply::Delivered(_) if !is_edit => &self.delivered,
+        ClientReply::Error(_) => &self.errors,
+        _ => continue,
+      };
+      counter.fetch_add(1, Ordering::Relaxed);
+    }
+    replies
+  }
+
+  async fn last_accepted_seqid(&self, client: ClientId) -> Option<u128> {
+    match self.clients.read().await.get(&client)? {
+      Stuff::Local(info) => Some(info.last_accepted_seqid),
+      Stuff::Pending { .. } | Stuff::Remote { .. } => None,
+    }
+  }
+
+  async fn ack_status(&self, client: ClientId) -> Option<AckStatus> {
+    match self.clients.read().await.get(&client)? {
+      Stuff::Local(info) => Some(info.acks),
+      Stuff::Pending { .. } | Stuff::Remote { .. } => None,
+    }
   }
 
   /* for the given client, return the next message or error if available
    */
   async fn client_poll(&self, client: ClientId) -> ClientPollReply {
-    todo!()
+    let mut clients = self.clients_write().await;
+    self.poll_local(&mut clients, client)
+  }
+
+  fn try_client_poll(&self, client: ClientId) -> Option<ClientPollReply> {
+    let mut clients = self.clients.try_write()?;
+    Some(self.poll_local(&mut clients, client))
+  }
+
+  async fn broadcast_system(&self, text: String) {
+    let mut clients = self.clients_write().await;
+    for stuff in clients.values_mut() {
+      if let Stuff::Local(info) = stuff {
+        info.notices.push_back(text.clone());
+      }
+    }
+  }
+
+  async fn metrics(&self) -> Metrics {
+    let clients = self
+      .clients
+      .read()
+      .await
+      .values()
+      .filter(|s| matches!(s, Stuff::Local(_)))
+      .count();
+    Metrics {
+      uptime: self.uptime(),
+      clients,
+      delivered: self.delivered.load(Ordering::Relaxed),
+      errors: self.errors.load(Ordering::Relaxed),
+      pending_transfers: self
+        .pending_transfers()
+        .await
+        .iter()
+        .map(|(_, _, count)| count)
+        .sum(),
+    }
+  }
+
+  async fn reset_counters(&self) {
+    self.delivered.store(0, Ordering::Relaxed);
+    self.errors.store(0, Ordering::Relaxed);
+    self.sent.write().await.clear();
+  }
+
+  async fn top_senders(&self, n: usize) -> Vec<(ClientId, u64)> {
+    let mut top: Vec<(ClientId, u64)> = self
+      .sent
+      .read()
+      .await
+      .iter()
+      .map(|(id, count)| (*id, *count))
+      .collect();
+    top.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    top.truncate(n);
+    top
+  }
+
+  async fn set_delivery_order(&self, client: ClientId, order: DeliveryOrder) -> bool {
+    match self.clients_write().await.get_mut(&client) {
+      Some(Stuff::Local(info)) => {
+        info.order = order;
+        true
+      }
+      _ => false,
+    }
+  }
+
+  async fn set_delivery(&self, client: ClientId, enabled: bool) -> bool {
+    match self.clients_write().await.get_mut(&client) {
+      Some(Stuff::Local(info)) => {
+        info.paused = !enabled;
+        true
+      }
+      _ => false,
+    }
+  }
+
+  async fn recall(&self, src: ClientId, message_id: u128) -> bool {
+    let mut clients = self.clients_write().await;
+    for stuff in clients.values_mut() {
+      let pending = matches!(stuff, Stuff::Pending { .. });
+      let (mailboxes, queued_bytes) = match stuff {
+        Stuff::Local(info) => (
+          vec![&mut info.mailbox, &mut info.overflow],
+          Some(&mut info.queued_bytes),
+        ),
+        Stuff::Pending { mailbox } | Stuff::Remote { mailbox, .. } => (vec![mailbox], None),
+      };
+      for mailbox in mailboxes {
+        if let Some(pos) = mailbox
+          .iter()
+          .position(|m| m.id == message_id && m.src == src)
+        {
+          let removed = mailbox.remove(pos).unwrap();
+          if let Some(queued) = queued_bytes {
+            *queued -= removed.content.len();
+          }
+          if pending {
+            self.pending_senders.write().await.remove([&removed]);
+          }
+          return true;
+        }
+      }
+    }
+    false
+  }
+
+  async fn subscribe(&self, client: ClientId, topic: String) -> bool {
+    self
+      .topics
+      .write()
+      .await
+      .entry(topic)
+      .or_default()
+      .insert(client)
+  }
+
+  async fn unsubscribe(&self, client: ClientId, topic: &str) -> bool {
+    let mut topics = self.topics.write().await;
+    let subscribers = match topics.get_mut(topic) {
+      Some(s) => s,
+      None => return false,
+    };
+    let removed = subscribers.remove(&client);
+    if subscribers.is_empty() {
+      topics.remove(topic);
+    }
+    removed
+  }
+
+  #[cfg(feature = "debug-snapshot")]
+  async fn debug_snapshot(&self) -> ServerState {
+    // the clients are locked first, as everywhere else
+    let clients = self.clients.read().await;
+    ServerState {
+      clients: clients.clone(),
+      #[cfg(feature = "federation")]
+      routes: self.routes.read().await.clone(),
+      #[cfg(feature = "federation")]
+      outbound: self.outbound.read().await.clone(),
+      topics: self.topics.read().await.clone(),
+      next_message_id: self.next_message_id.load(Ordering::Relaxed),
+      rejected_proofs: self.rejected_proofs.read().await.clone(),
+      drain_cursor: *self.drain_cursor.read().await,
+      delivered: self.delivered.load(Ordering::Relaxed),
+      errors: self.errors.load(Ordering::Relaxed),
+      sent: self.sent.read().await.clone(),
+      placeholders: self.placeholders.read().await.clone(),
+      unregistered: self.unregistered.read().await.clone(),
+      held_for_names: self.held_for_names.read().await.clone(),
+      pending_senders: self.pending_senders.read().await.clone(),
+    }
+  }
+
+  #[cfg(feature = "debug-snapshot")]
+  async fn debug_restore(&self, state: ServerState) {
+    let mut clients = self.clients_write().await;
+    *clients = state.clients;
+    #[cfg(feature = "federation")]
+    {
+      *self.routes.write().await = state.routes;
+      *self.outbound.write().await = state.outbound;
+    }
+    *self.topics.write().await = state.topics;
+    self
+      .next_message_id
+      .store(state.next_message_id, Ordering::Relaxed);
+    *self.rejected_proofs.write().await = state.rejected_proofs;
+    *self.drain_cursor.write().await = state.drain_cursor;
+    self.delivered.store(state.delivered, Ordering::Relaxed);
+    self.errors.store(state.errors, Ordering::Relaxed);
+    *self.sent.write().await = state.sent;
+    *self.placeholders.write().await = state.placeholders;
+    *self.unregistered.write().await = state.unregistered;
+    *self.held_for_names.write().await = state.held_for_names;
+    *self.pending_senders.write().await = state.pending_senders;
   }
 
   /* For announces
      * if the route is empty, return EmptyRoute
+     * if it is longer than MAX_ROUTE_HOPS, drop it
+     * if it comes from a new server while routes to MAX_PEERS servers are known, drop it
      * if not, store the route in some way
      * also store the remote clients
      * if one of these remote clients has messages waiting, return them
+     * same for the clients of the other servers along the route, that might now be reachable
+     * the queued transfers follow the route if it is shorter than the one they were queued for
     For messages
      * if local, deliver them
      * if remote, forward them
   */
   #[cfg(feature = "federation")]
   async fn handle_server_message(&self, msg: ServerMessage) -> ServerReply {
-    todo!()
+    match msg {
+      ServerMessage::Announce { route, clients } => {
+        let origin = match route.first() {
+          Some(s) => *s,
+          None => return ServerReply::EmptyRoute,
+        };
+        if route.len() > MAX_ROUTE_HOPS {
+          return ServerReply::Error(ServerError::RouteTooLong(origin));
+        }
+        let previous = {
+          let mut routes = self.routes.write().await;
+          if routes.len() >= MAX_PEERS && !routes.contains_key(&origin) {
+            return ServerReply::Error(ServerError::TooManyPeers(origin));
+          }
+          routes.insert(origin, route.clone())
+        };
+        if previous.as_ref() != Some(&route) {
+          if let Some(previous) = previous {
+            self.notify_route(RouteChange::Removed(previous));
+          }
+          self.notify_route(RouteChange::Added(route.clone()));
+        }
+        self.reroute_transfers().await;
+
+        let mut outgoing = Vec::new();
+        let mut known = self.clients_write().await;
+        for (client, name) in clients {
+          if let Some(Stuff::Local(_)) = known.get(&client) {
+            continue;
+          }
+          let previous = known.insert(
+            client,
+            Stuff::Remote {
+              name,
+              server: origin,
+              mailbox: VecDeque::new(),
+            },
+          );
+          if let Some(Stuff::Pending { mailbox }) = &previous {
+            self.pending_senders.write().await.remove(mailbox);
+            self.forget_placeholder(client).await;
+          }
+          let waiting = match previous {
+            Some(Stuff::Pending { mailbox }) | Some(Stuff::Remote { mailbox, .. })
+              if !mailbox.is_empty() =>
+            {
+              mailbox
+            }
+            _ => continue,
+          };
+          match self.next_hop(origin).await {
+            Some(nexthop) => outgoing.extend(
+              waiting
+                .into_iter()
+                .map(|m| self.held_transfer(nexthop, client, origin, m)),
+            ),
+            None => log::error!("no route to {} after its announce", origin),
+          }
+        }
+        // the servers along the route might have become reachable, or closer, so the messages held
+        // for their clients leave now, along the best route known at this point
+        for (client, stuff) in known.iter_mut() {
+          if let Stuff::Remote {
+            server, mailbox, ..
+          } = stuff
+          {
+            if mailbox.is_empty() || !route.contains(server) {
+              continue;
+            }
+            if let Some(nexthop) = self.next_hop(*server).await {
+              let server = *server;
+              outgoing.extend(
+                mailbox
+                  .drain(..)
+                  .map(|m| self.held_transfer(nexthop, *client, server, m)),
+              );
+            }
+          }
+        }
+        ServerReply::Outgoing(outgoing)
+      }
+      ServerMessage::Message(msg) => {
+        let mut forward: HashMap<ServerId, Vec<(ClientId, ServerId)>> = HashMap::new();
+        let mut failures = Vec::new();
+        for (dest, server) in msg.dsts {
+          if server == self.id {
+            let id = self.alloc_message_id();
+            let mut clients = self.clients_write().await;
+            let message = MessageInfo {
+              id,
+              src: msg.src,
+              srcsrv: Some(msg.srcsrv),
+              content: msg.content.clone(),
+              attachments: Vec::new(),
+            };
+            let event = self.queued_event(dest, &message);
+            let queued = match self.entry_or_placeholder(&mut clients, dest).await {
+              Stuff::Local(info) => {
+                if let ClientReply::Error(_) = info.deliver(dest, message, self.overflow_mode) {
+                  failures.push(format!("mailbox of {} is full", dest));
+                  false
+                } else {
+                  true
+                }
+              }
+              Stuff::Pending { mailbox } => {
+                self.pending_senders.write().await.add(message.src);
+                mailbox.push_back(message);
+                true
+              }
+              Stuff::Remote { mailbox, .. } => {
+                mailbox.push_back(message);
+                true
+              }
+            };
+            if let (true, Some(event)) = (queued, event) {
+              self.record(event);
+            }
+          } else {
+            match self.next_hop(server).await {
+              Some(nexthop) => forward.entry(nexthop).or_default().push((dest, server)),
+              None => failures.push(format!("no route to {} for {}", server, dest)),
+            }
+          }
+        }
+        // forwarding the message implicitly acknowledges it
+        if !forward.is_empty() {
+          for failure in failures {
+            log::error!("dropping message from {}: {}", msg.src, failure);
+          }
+          ServerReply::Outgoing(
+            forward
+              .into_iter()
+              .map(|(nexthop, dsts)| Outgoing {
+                nexthop,
+                message: FullyQualifiedMessage {
+                  src: msg.src,
+                  srcsrv: msg.srcsrv,
+                  dsts,
+                  content: msg.content.clone(),
+                },
+              })
+              .collect(),
+          )
+        } else if !failures.is_empty() {
+          ServerReply::Error(ServerError::Undeliverable(failures))
+        } else {
+          ServerReply::Ack
+        }
+      }
+    }
+  }
+
+  async fn bind_address(&self, client: ClientId, addr: SocketAddr) {
+    if let Some(Stuff::Local(info)) = self.clients_write().await.get_mut(&client) {
+      info.address = Some(addr);
+    }
+  }
+
+  async fn heartbeat(&self, client: ClientId, peer: Option<SocketAddr>) -> bool {
+    match self.clients_write().await.get_mut(&client) {
+      Some(Stuff::Local(info)) => {
+        if let (true, Some(bound), Some(peer)) = (self.check_address, info.address, peer) {
+          if bound != peer {
+            return false;
+          }
+        }
+        info.last_seen = Instant::now();
+        true
+      }
+      _ => false,
+    }
+  }
+
+  async fn register_and_message(
+    &self,
+    nonce: ClientId,
+    name: String,
+    msg: ClientMessage,
+  ) -> Result<(ClientId, Vec<ClientReply>), ClientError> {
+    let mut clients = self.clients_write().await;
+    // the message was handled along with the first registration
+    let retransmitted = clients.iter().find_map(|(id, stuff)| match stuff {
+      Stuff::Local(info) if info.registered_by == Some(nonce) => Some(*id),
+      _ => None,
+    });
+    if let Some(id) = retransmitted {
+      return Ok((id, Vec::new()));
+    }
+    let result = register(&mut clients, self.name_policy.as_ref(), Some(nonce), name);
+    let id = self.registered(&mut clients, result).await?;
+    drop(clients);
+    // nobody knows the id yet, so nothing can happen to the client between the two steps
+    let replies = self.handle_client_message(id, msg).await;
+    Ok((id, replies))
   }
 
   async fn list_users(&self) -> HashMap<ClientId, String> {
-    todo!()
+    self
+      .clients
+      .read()
+      .await
+      .iter()
+      .filter_map(|(id, stuff)| match stuff {
+        // stale clients are kept, they reappear as soon as they send something
+        Stuff::Local(info)
+          if self
+            .liveness_window
+            .map_or(false, |w| info.last_seen.elapsed() > w) =>
+        {
+          None
+        }
+        Stuff::Local(info) => Some((*id, info.name.clone())),
+        Stuff::Remote { name, .. } => Some((*id, name.clone())),
+        Stuff::Pending { .. } => None,
+      })
+      .collect()
+  }
+
+  async fn home_server(&self, client: ClientId) -> Option<ServerId> {
+    match self.clients.read().await.get(&client)? {
+      Stuff::Remote { server, .. } => Some(*server),
+      Stuff::Local(_) | Stuff::Pending { .. } => None,
+    }
+  }
+
+  async fn reachable_clients(&self) -> HashMap<ClientId, ClientLocation> {
+    let clients = self.clients.read().await;
+    let mut reachable = HashMap::new();
+    // whether a route is known to each server, that many clients usually share
+    #[cfg(feature = "federation")]
+    let mut routed: HashMap<ServerId, bool> = HashMap::new();
+    for (id, stuff) in clients.iter() {
+      match stuff {
+        Stuff::Local(_) => {
+          reachable.insert(*id, ClientLocation::Local);
+        }
+        #[cfg(feature = "federation")]
+        Stuff::Remote { server, .. } => {
+          let has_route = match routed.get(server) {
+            Some(r) => *r,
+            None => {
+              let r = self.next_hop(*server).await.is_some();
+              routed.insert(*server, r);
+              r
+            }
+          };
+          if has_route {
+            reachable.insert(*id, ClientLocation::Remote(*server));
+          }
+        }
+        // without federation, there is no route to follow
+        #[cfg(not(feature = "federation"))]
+        Stuff::Remote { .. } => (),
+        Stuff::Pending { .. } => (),
+      }
+    }
+    reachable
   }
 
   // return a route to the target server
   // bonus points if it is the shortest route
   #[cfg(feature = "federation")]
   async fn route_to(&self, destination: ServerId) -> Option<Vec<ServerId>> {
-    todo!()
+    // every announced route is a chain of links, the closest server being linked to us
+    let mut links: HashMap<ServerId, HashSet<ServerId>> = HashMap::new();
+    for route in self.routes.read().await.values() {
+      let mut prev = self.id;
+      for hop in route.iter().rev() {
+        links.entry(prev).or_default().insert(*hop);
+        links.entry(*hop).or_default().insert(prev);
+        prev = *hop;
+      }
+    }
+
+    // breadth first search, so that the first path found is the shortest
+    let mut parents: HashMap<ServerId, ServerId> = HashMap::new();
+    let mut queue = VecDeque::from([self.id]);
+    while let Some(cur) = queue.pop_front() {
+      if cur == destination {
+        break;
+      }
+      for next in links.get(&cur).into_iter().flatten() {
+        if *next != self.id && !parents.contains_key(next) {
+          parents.insert(*next, cur);
+          queue.push_back(*next);
+        }
+      }
+    }
+
+    // same order as announces: the destination first, the next hop last
+    let mut route = vec![destination];
+    let mut cur = destination;
+    while let Some(parent) = parents.get(&cur) {
+      if *parent == self.id {
+        return Some(route);
+      }
+      if route.len() >= MAX_ROUTE_HOPS {
+        return None;
+      }
+      route.push(*parent);
+      cur = *parent;
+    }
+    None
   }
 }
 
-impl Server {
-  // write your own methods here
+fn register(
+  clients: &mut HashMap<ClientId, Stuff>,
+  policy: Option<&NamePolicy>,
+  nonce: Option<ClientId>,
+  name: String,
+) -> Result<ClientId, ClientError> {
+  register_as(clients, policy, ClientId(Uuid::new_v4()), nonce, name)
 }
 
-#[cfg(test)]
-mod test {
-  use crate::testing::test_message_server;
+// the checks a new local client goes through, however it arrives: its name must be valid, allowed
+// by the policy and not taken, and the server must not be full
+// returns the name the client is registered under
+fn check_new_client(
+  clients: &HashMap<ClientId, Stuff>,
+  policy: Option<&NamePolicy>,
+  name: &str,
+) -> Result<String, ClientError> {
+  let name = validate_name(name)?;
+  if let Some(allowed) = policy {
+    if !allowed(name) {
+      return Err(ClientError::InvalidName);
+    }
+  }
+  let mut locals = 0;
+  for stuff in clients.values() {
+    if let Stuff::Local(info) = stuff {
+      if info.name == name {
+        return Err(ClientError::NameTaken);
+      }
+      locals += 1;
+    }
+  }
+  if locals >= MAX_CLIENTS {
+    return Err(ClientError::ServerFull);
+  }
+  Ok(name.to_string())
+}
 
-  use super::*;
+// registers a client under `user_id`, an id that is already known is never overwritten
+fn register_as(
+  clients: &mut HashMap<ClientId, Stuff>,
+  policy: Option<&NamePolicy>,
+  user_id: ClientId,
+  nonce: Option<ClientId>,
+  name: String,
+) -> Result<ClientId, ClientError> {
+  // the reply to the first registration was lost
+  if nonce.is_some() {
+    let registered = clients.iter().find_map(|(id, stuff)| match stuff {
+      Stuff::Local(info) if info.registered_by == nonce => Some(*id),
+      _ => None,
+    });
+    if let Some(id) = registered {
+      return Ok(id);
+    }
+  }
+  let name = check_new_client(clients, policy, &name)?;
+  let entry = match clients.entry(user_id) {
+    Entry::Occupied(_) => {
+      log::error!(
+        "client id {} is already taken, not registering {}",
+        user_id,
+        name
+      );
+      return Err(ClientError::InternalError);
+    }
+    Entry::Vacant(entry) => entry,
+  };
+  entry.insert(Stuff::Local(Box::new(ClientInfo {
+    name,
+    last_accepted_seqid: 0,
+    mailbox: VecDeque::new(),
+    overflow: VecDeque::new(),
+    verified_proof: None,
+    notices: VecDeque::new(),
+    errors: VecDeque::new(),
+    address: None,
+    last_seen: Instant::now(),
+    registered_by: nonce,
+    receipts: None,
+    full_receipts: VecDeque::new(),
+    order: DeliveryOrder::default(),
+    acks: AckStatus::default(),
+    paused: false,
+    queued_bytes: 0,
+  })));
+  Ok(user_id)
+}
 
-  #[test]
-  fn tester() {
-    test_message_server::<Server>();
+impl ClientInfo {
+  // stores a message for this client, that is known as `dest`
+  // the mailbox is full when it holds MAILBOX_SIZE messages, or MAX_MAILBOX_BYTES of content
+  fn deliver(&mut self, dest: ClientId, msg: MessageInfo, mode: OverflowMode) -> ClientReply {
+    let (size, id) = (msg.content.len(), msg.id);
+    if self.queued_bytes + size > MAX_MAILBOX_BYTES {
+      return ClientReply::Error(ClientError::BoxFull(dest));
+    }
+    if self.mailbox.len() < MAILBOX_SIZE {
+      self.mailbox.push_back(msg);
+      self.queued_bytes += size;
+      return ClientReply::Delivered(id);
+    }
+    match mode {
+      OverflowMode::Queue if self.overflow.len() < OVERFLOW_SIZE => {
+        self.overflow.push_back(msg);
+        self.queued_bytes += size;
+        ClientReply::Delayed(id)
+      }
+      _ => ClientReply::Error(ClientError::BoxFull(dest)),
+    }
+  }
+
+  // the batch is flushed as soon as it is full, so that it never grows past RECEIPT_BATCH
+  fn add_receipt(&mut self, id: u128) {
+    let (_, ids) = self
+      .receipts
+      .get_or_insert_with(|| (Instant::now(), Vec::new()));
+    ids.push(id);
+    if ids.len() >= RECEIPT_BATCH {
+      if let Some((_, ids)) = self.receipts.take() {
+        self.full_receipts.push_back(ids);
+      }
+    }
+  }
+
+  // notices first, then errors, then the full batches of receipts and the others once their
+  // window is over, then the regular messages, whose id is also returned
+  fn poll(&mut self, receipt_window: Option<Duration>) -> (ClientPollReply, Option<u128>) {
+    if let Some(text) = self.notices.pop_front() {
+      return (ClientPollReply::System { text }, None);
+    }
+    if let Some(e) = self.errors.pop_front() {
+      return (ClientPollReply::DelayedError(e), None);
+    }
+    if let Some(ids) = self.full_receipts.pop_front() {
+      return (ClientPollReply::Receipts(ids), None);
+    }
+    if let Some(window) = receipt_window {
+      let due = |(since, _): &mut (Instant, Vec<u128>)| since.elapsed() >= window;
+      if let Some((_, ids)) = self.receipts.take_if(due) {
+        return (ClientPollReply::Receipts(ids), None);
+      }
+    }
+    let next = match self.order {
+      DeliveryOrder::Fifo => {
+        let next = self.mailbox.pop_front();
+        if let Some(msg) = self.overflow.pop_front() {
+          self.mailbox.push_back(msg);
+        }
+        next
+      }
+      // the overflow holds the most recent messages
+      DeliveryOrder::Lifo => self.overflow.pop_back().or_else(|| self.mailbox.pop_back()),
+    };
+    if let Some(msg) = &next {
+      self.queued_bytes -= msg.content.len();
+    }
+    match next {
+      Some(msg) => (
+        ClientPollReply::Message {
+          src: msg.src,
+          srcsrv: msg.srcsrv,
+          content: msg.content,
+          attachments: msg.attachments,
+          message_id: msg.id,
+        },
+        Some(msg.id),
+      ),
+      None => (ClientPollReply::Nothing, None),
+    }
+  }
+}
+
+impl ClientInfo {
+  fn snapshot(&self, id: ClientId) -> ClientSnapshot {
+    ClientSnapshot {
+      id,
+      name: self.name.clone(),
+      last_sequence: self.last_accepted_seqid,
+      mailbox: self
+        .mailbox
+        .iter()
+        .chain(self.overflow.iter())
+        .map(|m| MessageSnapshot {
+          src: m.src,
+          srcsrv: m.srcsrv,
+          content: m.content.clone(),
+          attachments: m.attachments.clone(),
+        })
+        .collect(),
+    }
+  }
+}
+
+impl Stuff {
+  fn unknown() -> Self {
+    Stuff::Pending {
+      mailbox: VecDeque::new(),
+    }
+  }
+}
+
+impl Server {
+  // sets the maximum age of a timestamped sequence, in milliseconds
+  pub fn with_replay_window(mut self, window: u64) -> Self {
+    self.replay_window = window;
+    self
+  }
+
+  pub async fn snapshot(&self) -> ServerSnapshot {
+    let clients = self.clients.read().await;
+    let mut snapshot: Vec<ClientSnapshot> = clients
+      .iter()
+      .filter_map(|(id, stuff)| match stuff {
+        Stuff::Local(info) => Some(info.snapshot(*id)),
+        Stuff::Pending { .. } | Stuff::Remote { .. } => None,
+      })
+      .collect();
+    snapshot.sort_by_key(|c| c.id);
+    ServerSnapshot { clients: snapshot }
+  }
+
+  /* merges the local clients of another server into this one, for failover
+   * when a client is already local here, the entries are merged: our name is kept, the
+     sequence number is the max of both (so that nothing already seen by either server can be
+     replayed), and the imported messages are appended to the mailbox, up to MAILBOX_SIZE
+   * when the client was known as remote, or had delayed messages waiting, it becomes local and
+     the delayed messages are delivered to it first
+   * the other clients go through the checks of a registration, and are not imported when they
+     fail them
+   * the imported messages count toward MAILBOX_SIZE and MAX_MAILBOX_BYTES, the ones past either
+     limit are dropped
+   * the imported messages get new ids, so their senders cannot recall or edit them anymore
+  */
+  pub async fn import_state(&self, snapshot: ServerSnapshot) -> Vec<Result<ClientId, ClientError>> {
+    let mut clients = self.clients_write().await;
+    let mut results = Vec::with_capacity(snapshot.clients.len());
+    for imported in snapshot.clients {
+      let name = match clients.get(&imported.id) {
+        Some(Stuff::Local(info)) => info.name.clone(),
+        _ => match check_new_client(&clients, self.name_policy.as_ref(), &imported.name) {
+          Ok(name) => name,
+          Err(rr) => {
+            log::warn!("not importing {}: {}", imported.id, rr);
+            results.push(Err(rr));
+            continue;
+          }
+        },
+      };
+      let mut info = match clients.remove(&imported.id) {
+        Some(Stuff::Local(mut info)) => {
+          info.last_accepted_seqid = info.last_accepted_seqid.max(imported.last_sequence);
+          info
+        }
+        previous => {
+          let mailbox = match previous {
+            Some(Stuff::Pending { mailbox }) => {
+              self.pending_senders.write().await.remove(&mailbox);
+              self.forget_placeholder(imported.id).await;
+              mailbox
+            }
+            Some(Stuff::Remote { mailbox, .. }) => mailbox,
+            Some(Stuff::Local(_)) | None => VecDeque::new(),
+          };
+          let queued_bytes = mailbox.iter().map(|m| m.content.len()).sum();
+          Box::new(ClientInfo {
+            name,
+            last_accepted_seqid: imported.last_sequence,
+            mailbox,
+            overflow: VecDeque::new(),
+            verified_proof: None,
+            notices: VecDeque::new(),
+            errors: VecDeque::new(),
+            address: None,
+            last_seen: Instant::now(),
+            registered_by: None,
+            receipts: None,
+            full_receipts: VecDeque::new(),
+            order: DeliveryOrder::default(),
+            acks: AckStatus::default(),
+            paused: false,
+            queued_bytes,
+          })
+        }
+      };
+      for message in imported.mailbox {
+        if info.mailbox.len() >= MAILBOX_SIZE
+          || info.queued_bytes + message.content.len() > MAX_MAILBOX_BYTES
+        {
+          log::error!(
+            "mailbox of {} is full, dropping imported messages",
+            imported.id
+          );
+          break;
+        }
+        info.queued_bytes += message.content.len();
+        info.mailbox.push_back(MessageInfo {
+          id: self.alloc_message_id(),
+          src: message.src,
+          srcsrv: message.srcsrv,
+          content: message.content,
+          attachments: message.attachments,
+        });
+      }
+      clients.insert(imported.id, Stuff::Local(info));
+      results.push(Ok(imported.id));
+    }
+    results
+  }
+
+  // encodes a local client and its mailbox, for `import_client` on another server
+  // the client is left in place, it is up to the caller to unregister it once moved
+  pub async fn export_client(&self, client: ClientId) -> Option<ExportedClient> {
+    let snapshot = match self.clients.read().await.get(&client)? {
+      Stuff::Local(info) => info.snapshot(client),
+      Stuff::Pending { .. } | Stuff::Remote { .. } => return None,
+    };
+    let mut out = Vec::new();
+    snapshot.encode(&mut out).ok()?;
+    Some(ExportedClient(out))
+  }
+
+  // recreates a client exported by `export_client`, it is merged and checked as `import_state` does
+  // and its messages get new ids
+  pub async fn import_client(&self, exported: &ExportedClient) -> anyhow::Result<ClientId> {
+    let snapshot = ClientSnapshot::decode(&mut exported.0.as_slice())?;
+    let mut results = self
+      .import_state(ServerSnapshot {
+        clients: vec![snapshot],
+      })
+      .await;
+    Ok(results.pop().unwrap()?)
+  }
+
+  // sequences wait at most `timeout` for the clients lock, and get ServerBusy past it
+  pub fn with_lock_timeout(mut self, timeout: Option<Duration>) -> Self {
+    self.lock_timeout = timeout;
+    self
+  }
+
+  pub fn with_overflow_mode(mut self, mode: OverflowMode) -> Self {
+    self.overflow_mode = mode;
+    self
+  }
+
+  // local clients that have not sent anything within the window are not listed anymore
+  pub fn with_liveness_window(mut self, window: Option<Duration>) -> Self {
+    self.liveness_window = window;
+    self
+  }
+
+  // takes up to `budget` pending replies out of the local mailboxes, for push delivery
+  // clients are served one message at a time in turn, so that a busy client does not starve the
+  // others, and the next call resumes after the last client that was served
+  pub async fn drain(&self, budget: usize) -> Vec<(ClientId, ClientPollReply)> {
+    let mut clients = self.clients_write().await;
+    let mut cursor = self.drain_cursor.write().await;
+    let mut ids: Vec<ClientId> = clients
+      .iter()
+      .filter(|(_, s)| matches!(s, Stuff::Local(_)))
+      .map(|(id, _)| *id)
+      .collect();
+    ids.sort();
+    if let Some(last) = *cursor {
+      let next = ids.partition_point(|id| *id <= last);
+      ids.rotate_left(next);
+    }
+    let mut out = Vec::new();
+    loop {
+      let before = out.len();
+      for id in &ids {
+        if out.len() >= budget {
+          return out;
+        }
+        match self.poll_local(&mut clients, *id) {
+          ClientPollReply::Nothing => (),
+          reply => {
+            out.push((*id, reply));
+            *cursor = Some(*id);
+          }
+        }
+      }
+      if out.len() == before {
+        return out;
+      }
+    }
+  }
+
+  // (destination, server, count) of the messages held because there is no route to the server of
+  // their destination, sorted by destination
+  pub async fn pending_transfers(&self) -> Vec<(ClientId, ServerId, usize)> {
+    let mut pending: Vec<_> = self
+      .clients
+      .read()
+      .await
+      .iter()
+      .filter_map(|(id, stuff)| match stuff {
+        Stuff::Remote {
+          server, mailbox, ..
+        } if !mailbox.is_empty() => Some((*id, *server, mailbox.len())),
+        _ => None,
+      })
+      .collect();
+    pending.sort();
+    pending
+  }
+
+  // sends the ids of the messages that were read back to their local sender, batched over `window`
+  pub fn with_receipt_window(mut self, window: Option<Duration>) -> Self {
+    self.receipt_window = window;
+    self
+  }
+
+  // polls a local client, the sender of the message that is read gets a receipt for it
+  fn poll_local(
+    &self,
+    clients: &mut HashMap<ClientId, Stuff>,
+    client: ClientId,
+  ) -> ClientPollReply {
+    let (reply, read) = match clients.get_mut(&client) {
+      Some(Stuff::Local(info)) if !info.paused => info.poll(self.receipt_window),
+      _ => return ClientPollReply::Nothing,
+    };
+    if let Some(message_id) = read {
+      self.record(Event::Polled { client, message_id });
+    }
+    if let (Some(_), ClientPollReply::Message { src, .. }, Some(id)) =
+      (self.receipt_window, &reply, read)
+    {
+      if let Some(Stuff::Local(sender)) = clients.get_mut(src) {
+        sender.add_receipt(id);
+      }
+    }
+    reply
+  }
+
+  pub fn uptime(&self) -> Duration {
+    self.started_at.elapsed()
+  }
+
+  // sends the messages addressed to ECHO_CLIENT back to their sender, to test connectivity
+  pub fn with_echo(mut self, echo: bool) -> Self {
+    self.echo = echo;
+    self
+  }
+
+  // how long the messages sent to a name nobody registered with are kept
+  pub fn with_name_hold_ttl(mut self, ttl: Duration) -> Self {
+    self.name_hold_ttl = ttl;
+    self
+  }
+
+  // only registers the names the policy accepts, the others get InvalidName
+  pub fn with_name_policy(mut self, policy: NamePolicy) -> Self {
+    self.name_policy = Some(policy);
+    self
+  }
+
+  // rejects sequenced messages coming from another address than the one the client is bound to
+  pub fn with_address_check(mut self, check: bool) -> Self {
+    self.check_address = check;
+    self
+  }
+
+  // forgets the route announced by `origin`, its clients are kept and will be delayed until
+  // a new route is announced
+  #[cfg(feature = "federation")]
+  pub async fn prune_route(&self, origin: ServerId) -> bool {
+    let removed = self.routes.write().await.remove(&origin);
+    match removed {
+      Some(route) => {
+        self.notify_route(RouteChange::Removed(route));
+        true
+      }
+      None => false,
+    }
+  }
+
+  // the neighbour the messages for `server` are handed to
+  // routes are listed from their destination to the server closest to us, so this is their last
+  // element, while the `dsts` of the messages always name the home server of their destination
+  #[cfg(feature = "federation")]
+  async fn next_hop(&self, server: ServerId) -> Option<ServerId> {
+    self.route_to(server).await.and_then(|r| r.last().copied())
+  }
+
+  // queues transfers until `take_transfers` hands them to the transport, they are sent along the
+  // shortest route known by then
+  #[cfg(feature = "federation")]
+  pub async fn queue_transfers(&self, transfers: Vec<Outgoing<FullyQualifiedMessage>>) {
+    self.outbound.write().await.extend(transfers);
+  }
+
+  #[cfg(feature = "federation")]
+  pub async fn take_transfers(&self) -> Vec<Outgoing<FullyQualifiedMessage>> {
+    self.outbound.write().await.drain(..).collect()
+  }
+
+  // moves the queued transfers onto the shortest routes known, the destinations of a transfer
+  // being split among the next hops that serve them best
+  // destinations without a route keep their next hop, it might still reach them
+  #[cfg(feature = "federation")]
+  async fn reroute_transfers(&self) {
+    let mut outbound = self.outbound.write().await;
+    let mut rerouted = VecDeque::with_capacity(outbound.len());
+    for mut transfer in outbound.drain(..) {
+      let mut by_hop: Vec<(ServerId, Vec<(ClientId, ServerId)>)> = Vec::new();
+      for (client, server) in std::mem::take(&mut transfer.message.dsts) {
+        let nexthop = self.next_hop(server).await.unwrap_or(transfer.nexthop);
+        match by_hop.iter_mut().find(|(hop, _)| *hop == nexthop) {
+          Some((_, dsts)) => dsts.push((client, server)),
+          None => by_hop.push((nexthop, vec![(client, server)])),
+        }
+      }
+      if by_hop.iter().any(|(hop, _)| *hop != transfer.nexthop) {
+        log::debug!("transfer from {} rerouted", transfer.message.src);
+      }
+      rerouted.extend(by_hop.into_iter().map(|(nexthop, dsts)| Outgoing {
+        nexthop,
+        message: FullyQualifiedMessage {
+          dsts,
+          ..transfer.message.clone()
+        },
+      }));
+    }
+    *outbound = rerouted;
+  }
+
+  // a message that was held for `client`, on its way to `server` through `nexthop`
+  #[cfg(feature = "federation")]
+  fn held_transfer(
+    &self,
+    nexthop: ServerId,
+    client: ClientId,
+    server: ServerId,
+    m: MessageInfo,
+  ) -> Outgoing<FullyQualifiedMessage> {
+    Outgoing {
+      nexthop,
+      message: FullyQualifiedMessage {
+        src: m.src,
+        srcsrv: self.id,
+        dsts: vec![(client, server)],
+        content: m.content,
+      },
+    }
+  }
+
+  #[cfg(feature = "federation")]
+  pub fn with_route_observer(mut self, observer: RouteObserver) -> Self {
+    self.route_observer = Some(observer);
+    self
+  }
+
+  #[cfg(feature = "federation")]
+  pub fn with_unroutable_mode(mut self, mode: UnroutableMode) -> Self {
+    self.unroutable_mode = mode;
+    self
+  }
+
+  #[cfg(feature = "federation")]
+  fn notify_route(&self, change: RouteChange) {
+    self.record(Event::Routed(change.clone()));
+    if let Some(observer) = &self.route_observer {
+      observer(change);
+    }
+  }
+
+  // keeps the last `capacity` events, see event_log_snapshot
+  pub fn with_event_log(mut self, capacity: Option<usize>) -> Self {
+    self.event_log = capacity.map(|c| Mutex::new(EventLog::new(c)));
+    self
+  }
+
+  // the logged events, oldest first, empty when the log is disabled
+  pub fn event_log_snapshot(&self) -> Vec<LoggedEvent> {
+    match &self.event_log {
+      Some(log) => log.lock().unwrap().events.iter().cloned().collect(),
+      None => Vec::new(),
+    }
+  }
+
+  fn record(&self, event: Event) {
+    if let Some(log) = &self.event_log {
+      log.lock().unwrap().push(event);
+    }
+  }
+
+  // the Queued event of a message, only built when the log is enabled as it copies the content
+  fn queued_event(&self, dest: ClientId, message: &MessageInfo) -> Option<Event> {
+    self.event_log.as_ref().map(|_| Event::Queued {
+      message_id: message.id,
+      src: message.src,
+      dest,
+      content: message.content.clone(),
+      attachments: message.attachments.clone(),
+    })
+  }
+
+  /* applies logged events again, to reproduce a run on a fresh server
+   * clients are registered with the ids they had, and messages are queued with their ids, so
+     that the mailboxes and the logs of both servers can be compared
+   * only local events are replayed: transfers and route changes are skipped, and queued messages
+     are handled as if they came from a local client
+  */
+  pub async fn replay(&self, events: &[Event]) {
+    let mut clients = self.clients_write().await;
+    for event in events {
+      match event {
+        Event::Registered { client, name } => {
+          let result = register_as(&mut clients, None, *client, None, name.clone());
+          if let Err(rr) = self.registered(&mut clients, result).await {
+            log::warn!("could not replay the registration of {}: {}", client, rr);
+          }
+        }
+        Event::Queued {
+          message_id,
+          src,
+          dest,
+          content,
+          attachments,
+        } => {
+          // the message gets the id it was logged with, and the ids allocated afterwards come after
+          // every replayed one, whatever the order of the events
+          // the allocator never gives out ids past u64::MAX, such an id was not logged by a server
+          let last = match u64::try_from(*message_id) {
+            Ok(last) => last,
+            Err(_) => {
+              log::warn!(
+                "could not replay message {}: the id is too large",
+                message_id
+              );
+              continue;
+            }
+          };
+          self.next_message_id.fetch_max(last, Ordering::Relaxed);
+          let reply = self
+            .handle_single_message_as(
+              &mut clients,
+              *message_id,
+              *src,
+              *dest,
+              content.clone(),
+              attachments.clone(),
+            )
+            .await;
+          if let ClientReply::Error(rr) = reply {
+            log::warn!("could not replay message {}: {}", message_id, rr);
+          }
+        }
+        Event::Polled { client, .. } => {
+          self.poll_local(&mut clients, *client);
+        }
+        #[cfg(feature = "federation")]
+        Event::Transferred { .. } | Event::Routed(_) => (),
+      }
+    }
+  }
+
+  // the messages stored for the name of a newly registered client are delivered to it
+  async fn registered(
+    &self,
+    clients: &mut HashMap<ClientId, Stuff>,
+    result: Result<ClientId, ClientError>,
+  ) -> Result<ClientId, ClientError> {
+    if let Ok(client) = result {
+      if let Some(Stuff::Local(info)) = clients.get(&client) {
+        self.record(Event::Registered {
+          client,
+          name: info.name.clone(),
+        });
+      }
+      self.release_held(clients, client).await;
+    }
+    result
+  }
+
+  // sends a message to the local client registered under `name`, or stores it until one registers
+  async fn send_to_name(&self, src: ClientId, name: String, content: String) -> ClientReply {
+    let name = match validate_name(&name) {
+      Ok(name) => name.to_string(),
+      Err(rr) => return ClientReply::Error(rr),
+    };
+    let mut clients = self.clients_write().await;
+    let registered = clients.iter().find_map(|(id, stuff)| match stuff {
+      Stuff::Local(info) if info.name == name => Some(*id),
+      _ => None,
+    });
+    if let Some(dest) = registered {
+      return self
+        .handle_single_message(&mut clients, src, dest, content, Vec::new())
+        .await;
+    }
+    let mut held = self.held_for_names.write().await;
+    held.expire(self.name_hold_ttl);
+    if held.senders.get(src) >= MAX_PENDING_PER_SENDER {
+      return ClientReply::Error(ClientError::TooManyPending);
+    }
+    let id = self.alloc_message_id();
+    let message = MessageInfo {
+      id,
+      src,
+      srcsrv: None,
+      content,
+      attachments: Vec::new(),
+    };
+    held.push(name, message);
+    ClientReply::Delayed(id)
+  }
+
+  // delivers the messages stored for the name of `client`, the expired ones are dropped
+  async fn release_held(&self, clients: &mut HashMap<ClientId, Stuff>, client: ClientId) {
+    let info = match clients.get_mut(&client) {
+      Some(Stuff::Local(info)) => info,
+      _ => return,
+    };
+    let held = match self.held_for_names.write().await.take(&info.name) {
+      Some(held) => held,
+      None => return,
+    };
+    for (since, message) in held {
+      if since.elapsed() >= self.name_hold_ttl {
+        continue;
+      }
+      let queued = self.queued_event(client, &message);
+      match info.deliver(client, message, self.overflow_mode) {
+        ClientReply::Error(rr) => log::warn!("dropping a message stored for {}: {}", info.name, rr),
+        _ => {
+          if let Some(event) = queued {
+            self.record(event);
+          }
+        }
+      }
+    }
+  }
+
+  // ids are unique for the lifetime of the server, and never 0
+  fn alloc_message_id(&self) -> u128 {
+    self.next_message_id.fetch_add(1, Ordering::Relaxed) as u128 + 1
+  }
+
+  // takes the clients lock for writing
+  async fn clients_write(&self) -> RwLockWriteGuard<'_, HashMap<ClientId, Stuff>> {
+    #[cfg(test)]
+    CLIENTS_LOCKS.with(|c| c.set(c.get() + 1));
+    self.clients.write().await
+  }
+
+  // same as clients_write, but gives up with ServerBusy after `lock_timeout`
+  // the lock is polled with an exponential backoff, as async-std has no timed lock
+  async fn clients_write_bounded(
+    &self,
+  ) -> Result<RwLockWriteGuard<'_, HashMap<ClientId, Stuff>>, ClientError> {
+    let timeout = match self.lock_timeout {
+      Some(timeout) => timeout,
+      None => return Ok(self.clients_write().await),
+    };
+    #[cfg(test)]
+    CLIENTS_LOCKS.with(|c| c.set(c.get() + 1));
+    let deadline = Instant::now() + timeout;
+    let mut delay = LOCK_RETRY_DELAY;
+    loop {
+      if let Some(clients) = self.clients.try_write() {
+        return Ok(clients);
+      }
+      let left = deadline.saturating_duration_since(Instant::now());
+      if left.is_zero() {
+        log::warn!("clients lock not acquired within {:?}", timeout);
+        return Err(ClientError::ServerBusy);
+      }
+      async_std::task::sleep(delay.min(left)).await;
+      delay = (delay * 2).min(MAX_LOCK_RETRY_DELAY);
+    }
+  }
+
+  // the entry of a client, a placeholder is created if it is unknown
+  // when there are more than MAX_PLACEHOLDERS, the oldest are evicted along with their messages
+  async fn entry_or_placeholder<'a>(
+    &self,
+    clients: &'a mut HashMap<ClientId, Stuff>,
+    client: ClientId,
+  ) -> &'a mut Stuff {
+    if !clients.contains_key(&client) {
+      let mut placeholders = self.placeholders.write().await;
+      placeholders.push_back(client);
+      while placeholders.len() > MAX_PLACEHOLDERS {
+        let old = placeholders.pop_front().unwrap();
+        if let Some(Stuff::Pending { mailbox }) = clients.get(&old) {
+          log::warn!(
+            "evicting the placeholder of {}, dropping {} messages",
+            old,
+            mailbox.len()
+          );
+          self.pending_senders.write().await.remove(mailbox);
+          clients.remove(&old);
+        }
+      }
+    }
+    clients.entry(client).or_insert_with(Stuff::unknown)
+  }
+
+  // the placeholder of a client became a real entry, so it must not count toward
+  // MAX_PLACEHOLDERS anymore, nor be evicted later on
+  async fn forget_placeholder(&self, client: ClientId) {
+    self.placeholders.write().await.retain(|c| *c != client);
+  }
+
+  async fn handle_single_message(
+    &self,
+    clients: &mut HashMap<ClientId, Stuff>,
+    src: ClientId,
+    dest: ClientId,
+    content: String,
+    attachments: Vec<(String, String)>,
+  ) -> ClientReply {
+    let id = self.alloc_message_id();
+    self
+      .handle_single_message_as(clients, id, src, dest, content, attachments)
+      .await
+  }
+
+  // same as handle_single_message, with the id the message was given already
+  async fn handle_single_message_as(
+    &self,
+    clients: &mut HashMap<ClientId, Stuff>,
+    id: u128,
+    src: ClientId,
+    dest: ClientId,
+    content: String,
+    attachments: Vec<(String, String)>,
+  ) -> ClientReply {
+    // the sender is a local client, so there is no source server
+    let message = MessageInfo {
+      id,
+      src,
+      srcsrv: None,
+      content,
+      attachments,
+    };
+    if self.echo && dest == ECHO_CLIENT {
+      return match clients.get_mut(&src) {
+        Some(Stuff::Local(info)) => info.deliver(src, message, self.overflow_mode),
+        _ => ClientReply::Error(ClientError::UnknownClient),
+      };
+    }
+    // a message that was in flight when its destination unregistered
+    if !clients.contains_key(&dest) && self.unregistered.read().await.contains(&dest) {
+      return ClientReply::Error(ClientError::RecipientGone(dest));
+    }
+    let known = match clients.get(&dest) {
+      Some(Stuff::Local(_)) | Some(Stuff::Remote { .. }) => true,
+      Some(Stuff::Pending { .. }) | None => false,
+    };
+    if !known && self.pending_senders.read().await.get(src) >= MAX_PENDING_PER_SENDER {
+      return ClientReply::Error(ClientError::TooManyPending);
+    }
+    let queued = self.queued_event(dest, &message);
+    let reply = match self.entry_or_placeholder(clients, dest).await {
+      Stuff::Local(info) => info.deliver(dest, message, self.overflow_mode),
+      #[cfg(feature = "federation")]
+      Stuff::Remote {
+        server, mailbox, ..
+      } => {
+        let server = *server;
+        match self.next_hop(server).await {
+          Some(nexthop) => ClientReply::Transfer(
+            nexthop,
+            ServerMessage::Message(FullyQualifiedMessage {
+              src,
+              srcsrv: self.id,
+              dsts: vec![(dest, server)],
+              content: message.content,
+            }),
+          ),
+          None if self.unroutable_mode == UnroutableMode::Reject => {
+            ClientReply::Error(ClientError::NoRoute)
+          }
+          // held messages are bounded like a local mailbox
+          None if mailbox.len() >= MAILBOX_SIZE => ClientReply::Error(ClientError::BoxFull(dest)),
+          None => {
+            // the sender is told once, not for every message held with this one
+            let first = mailbox.is_empty();
+            mailbox.push_back(message);
+            if let (true, Some(Stuff::Local(info))) = (first, clients.get_mut(&src)) {
+              info.errors.push_back(DelayedError::RouteLost(dest));
+            }
+            ClientReply::Delayed(id)
+          }
+        }
+      }
+      // without federation, there is no route to follow
+      #[cfg(not(feature = "federation"))]
+      Stuff::Remote { mailbox, .. } => {
+        mailbox.push_back(message);
+        ClientReply::Delayed(id)
+      }
+      Stuff::Pending { mailbox } => {
+        self.pending_senders.write().await.add(src);
+        mailbox.push_back(message);
+        ClientReply::Delayed(id)
+      }
+    };
+    match &reply {
+      ClientReply::Delivered(_) | ClientReply::Delayed(_) => {
+        if let Some(event) = queued {
+          self.record(event);
+        }
+      }
+      #[cfg(feature = "federation")]
+      ClientReply::Transfer(nexthop, _) => self.record(Event::Transferred {
+        src,
+        dest,
+        nexthop: *nexthop,
+      }),
+      _ => (),
+    }
+    reply
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use crate::{
+    client::Client,
+    testing::{invalid_workproof, test_message_server},
+    workproof::HASH_COUNT,
+  };
+
+  use super::*;
+
+  #[test]
+  fn tester() {
+    test_message_server::<Server>();
+  }
+
+  async fn pending_ids(server: &Server, dest: ClientId) -> Vec<u128> {
+    match server.clients.read().await.get(&dest) {
+      Some(Stuff::Local(info)) => info.mailbox.iter().map(|m| m.id).collect(),
+      Some(Stuff::Pending { mailbox }) | Some(Stuff::Remote { mailbox, .. }) => {
+        mailbox.iter().map(|m| m.id).collect()
+      }
+      None => Vec::new(),
+    }
+  }
+
+  #[test]
+  fn recall() {
+    async_std::task::block_on(async {
+      let server = Server::new(ServerId::default());
+      let c1 = server.register_local_client("user 1".into()).await.unwrap();
+      let c2 = server.register_local_client("user 2".into()).await.unwrap();
+      let unknown = ClientId::default();
+
+      // delayed message, recalled from the pending store
+      let r = server
+        .handle_client_message(
+          c1,
+          ClientMessage::Text {
+            dest: unknown,
+            content: "oops".into(),
+            attachments: Vec::new(),
+          },
+        )
+        .await;
+      assert!(matches!(r[..], [ClientReply::Delayed(_)]));
+      let ids = pending_ids(&server, unknown).await;
+      assert_eq!(ids.len(), 1);
+      assert!(
+        !server.recall(c2, ids[0]).await,
+        "only the sender can recall"
+      );
+      assert!(server.recall(c1, ids[0]).await);
+      assert!(!server.recall(c1, ids[0]).await);
+      assert!(pending_ids(&server, unknown).await.is_empty());
+
+      // local message, recalled before being polled
+      server
+        .handle_client_message(
+          c1,
+          ClientMessage::Text {
+            dest: c2,
+            content: "oops".into(),
+            attachments: Vec::new(),
+          },
+        )
+        .await;
+      let ids = pending_ids(&server, c2).await;
+      assert!(server.recall(c1, ids[0]).await);
+      assert_eq!(server.client_poll(c2).await, ClientPollReply::Nothing);
+    })
+  }
+
+  #[test]
+  fn edit() {
+    async_std::task::block_on(async {
+      let server = Server::new(ServerId::default());
+      let c1 = server.register_local_client("user 1".into()).await.unwrap();
+      let c2 = server.register_local_client("user 2".into()).await.unwrap();
+      server
+        .handle_client_message(
+          c1,
+          ClientMessage::Text {
+            dest: c2,
+            content: "helo".into(),
+            attachments: Vec::new(),
+          },
+        )
+        .await;
+      let ids = pending_ids(&server, c2).await;
+      let edit = |src, content: &str| {
+        server.handle_client_message(
+          src,
+          ClientMessage::Edit {
+            message_id: ids[0],
+            dest: c2,
+            content: content.into(),
+          },
+        )
+      };
+      assert_eq!(
+        edit(c2, "hijacked").await,
+        [ClientReply::Error(ClientError::UnknownMessage)],
+        "only the sender can edit"
+      );
+      assert!(matches!(
+        edit(c1, "hello").await[..],
+        [ClientReply::Delivered(_)]
+      ));
+      // the edit is neither a new delivery nor a new message sent
+      assert_eq!(server.metrics().await.delivered, 1);
+      assert_eq!(server.top_senders(1).await, [(c1, 1)]);
+      assert_eq!(
+        server.client_poll(c2).await,
+        ClientPollReply::Message {
+          src: c1,
+          srcsrv: None,
+          content: "hello".into(),
+          attachments: Vec::new(),
+          message_id: ids[0],
+        }
+      );
+      // polled messages can not be edited anymore
+      assert_eq!(
+        edit(c1, "hello!").await,
+        [ClientReply::Error(ClientError::UnknownMessage)]
+      );
+    })
+  }
+
+  #[test]
+  fn concurrent_message_ids() {
+    async_std::task::block_on(async {
+      let server = std::sync::Arc::new(Server::new(ServerId::default()));
+      let src = server.register_local_client("user 1".into()).await.unwrap();
+      let dests: Vec<ClientId> = (0..8).map(|_| ClientId(Uuid::new_v4())).collect();
+
+      // stays within the delayed messages a sender is allowed
+      let count = MAX_PENDING_PER_SENDER / dests.len();
+      let tasks: Vec<_> = (0..count)
+        .map(|i| {
+          let server = server.clone();
+          let dest = dests.clone();
+          async_std::task::spawn(async move {
+            server
+              .handle_client_message(
+                src,
+                ClientMessage::MText {
+                  dest,
+                  content: format!("message {}", i),
+                },
+              )
+              .await
+          })
+        })
+        .collect();
+      for t in tasks {
+        t.await;
+      }
+
+      let mut ids = Vec::new();
+      for dest in &dests {
+        ids.extend(pending_ids(&server, *dest).await);
+      }
+      assert_eq!(ids.len(), count * dests.len());
+      let distinct: std::collections::HashSet<u128> = ids.iter().copied().collect();
+      assert_eq!(distinct.len(), ids.len());
+    })
+  }
+
+  #[test]
+  fn mtext_single_lock() {
+    async_std::task::block_on(async {
+      let server = Server::new(ServerId::default());
+      let src = server.register_local_client("sender".into()).await.unwrap();
+      let mut dest = Vec::new();
+      for i in 0..99 {
+        dest.push(
+          server
+            .register_local_client(format!("user {}", i))
+            .await
+            .unwrap(),
+        );
+      }
+      let unknown = ClientId(Uuid::new_v4());
+      dest.insert(50, unknown);
+      let full = dest[10];
+      for _ in 0..MAILBOX_SIZE {
+        server
+          .handle_client_message(
+            src,
+            ClientMessage::Text {
+              dest: full,
+              content: "filler".into(),
+              attachments: Vec::new(),
+            },
+          )
+          .await;
+      }
+
+      CLIENTS_LOCKS.with(|c| c.set(0));
+      let replies = server
+        .handle_client_message(
+          src,
+          ClientMessage::MText {
+            dest: dest.clone(),
+            content: "hello".into(),
+          },
+        )
+        .await;
+      assert_eq!(CLIENTS_LOCKS.with(|c| c.get()), 1);
+
+      assert_eq!(replies.len(), 100);
+      for (d, r) in dest.iter().zip(replies) {
+        let expected = match r {
+          ClientReply::Error(ClientError::BoxFull(c)) => c == full,
+          ClientReply::Delayed(_) => *d == unknown,
+          ClientReply::Delivered(_) => *d != full && *d != unknown,
+          _ => false,
+        };
+        assert!(expected, "reply {:?} for {}", r, d);
+      }
+    })
+  }
+
+  #[test]
+  fn recent_values() {
+    let mut recent = Recent::new(2);
+    // a value that is already remembered is not pushed again
+    for value in [1, 2, 2, 3] {
+      recent.push(value);
+    }
+    assert!(!recent.contains(&1));
+    assert!(recent.contains(&2) && recent.contains(&3));
+    assert_eq!(recent.order, [2, 3]);
+    assert_eq!(recent.members.len(), 2);
+  }
+
+  #[test]
+  fn workproof_cache() {
+    async_std::task::block_on(async {
+      let server = Server::new(ServerId::default());
+      let c1 = server.register_local_client("user 1".into()).await.unwrap();
+      // timestamped sequences all have their own nonce, so only the others are cached
+      let seq1 = Sequence::signed(c1, 1, (), WORKPROOF_STRENGTH);
+      let seq2 = Sequence::signed(c1, 2, (), WORKPROOF_STRENGTH);
+      let mut forged = Sequence::signed(c1, 3, (), WORKPROOF_STRENGTH);
+      forged.workproof = invalid_workproof(forged.workproof_nonce());
+
+      HASH_COUNT.with(|c| c.set(0));
+      assert_eq!(server.handle_sequenced_message(seq1, None).await, Ok(()));
+      assert_eq!(server.handle_sequenced_message(seq2, None).await, Ok(()));
+      assert_eq!(HASH_COUNT.with(|c| c.get()), 1);
+
+      // a different proof is verified again
+      assert_eq!(
+        server.handle_sequenced_message(forged, None).await,
+        Err(ClientError::WorkProofError)
+      );
+      assert_eq!(HASH_COUNT.with(|c| c.get()), 2);
+    })
+  }
+
+  #[test]
+  fn unregistered_sender() {
+    async_std::task::block_on(async {
+      let server = Server::new(ServerId::default());
+      let mut client = Client::new(ClientId(Uuid::new_v4()));
+
+      // the workproof comes first, even for unknown clients
+      let valid = client.sequence(());
+      HASH_COUNT.with(|c| c.set(0));
+      assert_eq!(
+        server.handle_sequenced_message(valid, None).await,
+        Err(ClientError::UnknownClient)
+      );
+      assert_eq!(HASH_COUNT.with(|c| c.get()), 1);
+
+      // a rejected proof is not hashed again
+      let mut forged = client.sequence(());
+      forged.workproof = invalid_workproof(forged.workproof_nonce());
+      HASH_COUNT.with(|c| c.set(0));
+      for _ in 0..10 {
+        assert_eq!(
+          server.handle_sequenced_message(forged.clone(), None).await,
+          Err(ClientError::WorkProofError)
+        );
+      }
+      assert_eq!(HASH_COUNT.with(|c| c.get()), 1);
+    })
+  }
+
+  #[test]
+  fn address_mismatch() {
+    async_std::task::block_on(async {
+      let server = Server::new(ServerId::default()).with_address_check(true);
+      let c1 = server.register_local_client("user 1".into()).await.unwrap();
+      let registered: SocketAddr = "192.0.2.1:4000".parse().unwrap();
+      let spoofer: SocketAddr = "198.51.100.7:4000".parse().unwrap();
+      server.bind_address(c1, registered).await;
+      let mut client1 = Client::new(c1);
+
+      assert_eq!(
+        server
+          .handle_sequenced_message(client1.sequence(()), Some(spoofer))
+          .await,
+        Err(ClientError::AddressMismatch)
+      );
+      assert_eq!(
+        server
+          .handle_sequenced_message(client1.sequence(()), Some(registered))
+          .await,
+        Ok(())
+      );
+
+      // without the check, the address is ignored
+      let server = Server::new(ServerId::default());
+      let c2 = server.register_local_client("user 2".into()).await.unwrap();
+      server.bind_address(c2, registered).await;
+      let mut client2 = Client::new(c2);
+      assert_eq!(
+        server
+          .handle_sequenced_message(client2.sequence(()), Some(spoofer))
+          .await,
+        Ok(())
+      );
+    })
+  }
+
+  #[test]
+  fn import_state() {
+    async_std::task::block_on(async {
+      let old = Server::new(ServerId::default());
+      let a = old.register_local_client("alice".into()).await.unwrap();
+      let b = old.register_local_client("bob".into()).await.unwrap();
+      let mut alice = Client::new(a);
+      for _ in 0..3 {
+        old
+          .handle_sequenced_message(alice.sequence(()), None)
+          .await
+          .unwrap();
+      }
+      let attached = vec![("file.txt".to_string(), "contents".to_string())];
+      for (content, attachments) in [("one", Vec::new()), ("two", attached.clone())] {
+        old
+          .handle_client_message(
+            a,
+            ClientMessage::Text {
+              dest: b,
+              content: content.into(),
+              attachments,
+            },
+          )
+          .await;
+      }
+      let ids = pending_ids(&old, b).await;
+      let snapshot = old.snapshot().await;
+
+      let server = Server::new(ServerId::default());
+      let c = server.register_local_client("carol".into()).await.unwrap();
+      let imported: Vec<_> = snapshot.clients.iter().map(|c| Ok(c.id)).collect();
+      assert_eq!(server.import_state(snapshot).await, imported);
+
+      let users = server.list_users().await;
+      assert_eq!(users.len(), 3);
+      assert_eq!(users[&a], "alice");
+      assert_eq!(users[&b], "bob");
+      assert_eq!(users[&c], "carol");
+      for ((content, attachments), message_id) in [("one", Vec::new()), ("two", attached)]
+        .into_iter()
+        .zip(ids)
+      {
+        assert_eq!(
+          server.client_poll(b).await,
+          ClientPollReply::Message {
+            src: a,
+            srcsrv: None,
+            content: content.into(),
+            attachments,
+            message_id,
+          }
+        );
+      }
+      assert_eq!(server.client_poll(b).await, ClientPollReply::Nothing);
+
+      // the sequence numbers seen by the old server cannot be replayed
+      let mut replayed = Client::new(a);
+      assert_eq!(
+        server
+          .handle_sequenced_message(replayed.sequence(()), None)
+          .await,
+        Err(ClientError::SequenceError)
+      );
+      assert_eq!(
+        server
+          .handle_sequenced_message(alice.sequence(()), None)
+          .await,
+        Ok(())
+      );
+    })
+  }
+
+  #[test]
+  fn import_byte_quota() {
+    async_std::task::block_on(async {
+      let id = ClientId::default();
+      let message = MessageSnapshot {
+        src: ClientId::default(),
+        srcsrv: None,
+        content: "x".repeat(MAX_MAILBOX_BYTES / 2 + 1),
+        attachments: Vec::new(),
+      };
+      let snapshot = ServerSnapshot {
+        clients: vec![ClientSnapshot {
+          id,
+          name: "bob".into(),
+          last_sequence: 0,
+          mailbox: vec![message.clone(), message],
+        }],
+      };
+      let server = Server::new(ServerId::default());
+      assert_eq!(server.import_state(snapshot).await, [Ok(id)]);
+      // the second message does not fit
+      assert_eq!(pending_ids(&server, id).await.len(), 1);
+    })
+  }
+
+  #[test]
+  fn import_checked() {
+    async_std::task::block_on(async {
+      let old = Server::new(ServerId::default());
+      for name in ["alice", "bob", "mallory"] {
+        old.register_local_client(name.into()).await.unwrap();
+      }
+      let snapshot = old.snapshot().await;
+      let id = |name: &str| {
+        snapshot
+          .clients
+          .iter()
+          .find(|c| c.name == name)
+          .map(|c| c.id)
+          .unwrap()
+      };
+
+      let server =
+        Server::new(ServerId::default()).with_name_policy(Box::new(|name| name != "mallory"));
+      let other = server.register_local_client("bob".into()).await.unwrap();
+      let results = server.import_state(snapshot.clone()).await;
+      let expected: Vec<_> = snapshot
+        .clients
+        .iter()
+        .map(|c| match c.name.as_str() {
+          "bob" => Err(ClientError::NameTaken),
+          "mallory" => Err(ClientError::InvalidName),
+          _ => Ok(c.id),
+        })
+        .collect();
+      assert_eq!(results, expected);
+      let users = server.list_users().await;
+      assert_eq!(users.len(), 2);
+      assert_eq!(users[&id("alice")], "alice");
+      assert_eq!(users[&other], "bob");
+
+      // importing twice merges, and does not take the name again
+      assert_eq!(
+        server.import_state(snapshot.clone()).await[..],
+        expected[..]
+      );
+      assert_eq!(server.list_users().await.len(), 2);
+
+      let full = Server::new(ServerId::default());
+      let someone = full.register_local_client("someone".into()).await.unwrap();
+      let mut clients = full.clients.write().await;
+      let stuff = clients[&someone].clone();
+      clients.extend((1..MAX_CLIENTS).map(|_| (ClientId::default(), stuff.clone())));
+      drop(clients);
+      let exported = old.export_client(id("alice")).await.unwrap();
+      assert_eq!(
+        full
+          .import_client(&exported)
+          .await
+          .unwrap_err()
+          .downcast::<ClientError>()
+          .unwrap(),
+        ClientError::ServerFull
+      );
+    })
+  }
+
+  #[test]
+  fn liveness_window() {
+    async_std::task::block_on(async {
+      let server =
+        Server::new(ServerId::default()).with_liveness_window(Some(Duration::from_millis(50)));
+      let c1 = server.register_local_client("user 1".into()).await.unwrap();
+      let mut client1 = Client::new(c1);
+      assert!(server.list_users().await.contains_key(&c1));
+
+      async_std::task::sleep(Duration::from_millis(100)).await;
+      assert!(!server.list_users().await.contains_key(&c1));
+
+      server
+        .handle_sequenced_message(client1.sequence(()), None)
+        .await
+        .unwrap();
+      assert!(server.list_users().await.contains_key(&c1));
+    })
+  }
+
+  #[test]
+  fn overflow_modes() {
+    async_std::task::block_on(async {
+      for mode in [OverflowMode::Reject, OverflowMode::Queue] {
+        let server = Server::new(ServerId::default()).with_overflow_mode(mode);
+        let c1 = server.register_local_client("user 1".into()).await.unwrap();
+        let c2 = server.register_local_client("user 2".into()).await.unwrap();
+        let send = |content: String| {
+          server.handle_client_message(
+            c1,
+            ClientMessage::Text {
+              dest: c2,
+              content,
+              attachments: Vec::new(),
+            },
+          )
+        };
+        for i in 0..MAILBOX_SIZE {
+          assert!(matches!(
+            send(i.to_string()).await[..],
+            [ClientReply::Delivered(_)]
+          ));
+        }
+
+        let reply = send("overflow".into()).await;
+        match mode {
+          OverflowMode::Reject => assert_eq!(reply, [ClientReply::Error(ClientError::BoxFull(c2))]),
+          OverflowMode::Queue => assert!(matches!(reply[..], [ClientReply::Delayed(_)])),
+        }
+
+        let mut received = Vec::new();
+        loop {
+          match server.client_poll(c2).await {
+            ClientPollReply::Message { content, .. } => received.push(content),
+            ClientPollReply::Nothing => break,
+            r => panic!("unexpected {:?}", r),
+          }
+        }
+        let queued = mode == OverflowMode::Queue;
+        assert_eq!(received.len(), MAILBOX_SIZE + queued as usize);
+        assert_eq!(received.last() == Some(&"overflow".to_string()), queued);
+      }
+    })
+  }
+
+  #[cfg(feature = "federation")]
+  #[test]
+  fn route_observer() {
+    async_std::task::block_on(async {
+      let changes = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+      let recorded = changes.clone();
+      let server = Server::new(ServerId::default())
+        .with_route_observer(Box::new(move |c| recorded.lock().unwrap().push(c)));
+      let s1 = ServerId(Uuid::new_v4());
+      let s2 = ServerId(Uuid::new_v4());
+      for route in [vec![s1], vec![s2, s1]] {
+        server
+          .handle_server_message(ServerMessage::Announce {
+            route,
+            clients: HashMap::new(),
+          })
+          .await;
+      }
+      assert_eq!(
+        *changes.lock().unwrap(),
+        [
+          RouteChange::Added(vec![s1]),
+          RouteChange::Added(vec![s2, s1])
+        ]
+      );
+
+      server.prune_route(s2).await;
+      assert_eq!(
+        changes.lock().unwrap().last(),
+        Some(&RouteChange::Removed(vec![s2, s1]))
+      );
+    })
+  }
+
+  #[cfg(feature = "federation")]
+  #[test]
+  fn home_server() {
+    async_std::task::block_on(async {
+      let server = Server::new(ServerId::default());
+      let local = server.register_local_client("local".into()).await.unwrap();
+      let remote = ClientId::default();
+      let s1 = ServerId(Uuid::new_v4());
+      let s2 = ServerId(Uuid::new_v4());
+      assert_eq!(server.home_server(remote).await, None);
+      server
+        .handle_server_message(ServerMessage::Announce {
+          route: vec![s2, s1],
+          clients: HashMap::from([(remote, "remote".to_string())]),
+        })
+        .await;
+      assert_eq!(server.home_server(remote).await, Some(s2));
+      assert_eq!(server.home_server(local).await, None);
+    })
+  }
+
+  #[test]
+  fn echo() {
+    async_std::task::block_on(async {
+      let server = Server::new(ServerId::default()).with_echo(true);
+      let c1 = server.register_local_client("c1".into()).await.unwrap();
+      let reply = server
+        .handle_client_message(
+          c1,
+          ClientMessage::Text {
+            dest: ECHO_CLIENT,
+            content: "ping".into(),
+            attachments: Vec::new(),
+          },
+        )
+        .await;
+      let message_id = match reply[..] {
+        [ClientReply::Delivered(id)] => id,
+        _ => panic!("unexpected {:?}", reply),
+      };
+      assert_eq!(
+        server.client_poll(c1).await,
+        ClientPollReply::Message {
+          src: c1,
+          srcsrv: None,
+          content: "ping".into(),
+          attachments: Vec::new(),
+          message_id,
+        }
+      );
+
+      // without echo mode, the reserved id is an ordinary unknown recipient
+      let server = Server::new(ServerId::default());
+      let c1 = server.register_local_client("c1".into()).await.unwrap();
+      let reply = server
+        .handle_client_message(
+          c1,
+          ClientMessage::Text {
+            dest: ECHO_CLIENT,
+            content: "ping".into(),
+            attachments: Vec::new(),
+          },
+        )
+        .await;
+      assert!(matches!(reply[..], [ClientReply::Delayed(_)]));
+      assert_eq!(server.client_poll(c1).await, ClientPollReply::Nothing);
+    })
+  }
+
+  #[test]
+  fn metrics() {
+    async_std::task::block_on(async {
+      let server = Server::new(ServerId::default());
+      let c1 = server.register_local_client("c1".into()).await.unwrap();
+      let c2 = server.register_local_client("c2".into()).await.unwrap();
+      async_std::task::sleep(Duration::from_millis(10)).await;
+      server
+        .handle_client_message(
+          c1,
+          ClientMessage::MText {
+            dest: vec![c2, c2],
+            content: "hi".into(),
+          },
+        )
+        .await;
+      server
+        .handle_client_message(
+          c1,
+          ClientMessage::Edit {
+            message_id: 0,
+            dest: c2,
+            content: "hi".into(),
+          },
+        )
+        .await;
+      let metrics = server.metrics().await;
+      assert!(metrics.uptime >= Duration::from_millis(10));
+      assert_eq!(
+        (metrics.clients, metrics.delivered, metrics.errors),
+        (2, 2, 1)
+      );
+
+      server.reset_counters().await;
+      let reset = server.metrics().await;
+      assert_eq!((reset.clients, reset.delivered, reset.errors), (2, 0, 0));
+      assert!(reset.uptime >= metrics.uptime);
+    })
+  }
+
+  #[test]
+  fn drain_round_robin() {
+    async_std::task::block_on(async {
+      let server = Server::new(ServerId::default());
+      let src = server.register_local_client("src".into()).await.unwrap();
+      let mut dests = Vec::new();
+      for n in 0..3 {
+        dests.push(
+          server
+            .register_local_client(format!("dest {n}"))
+            .await
+            .unwrap(),
+        );
+      }
+      for content in ["1", "2", "3"] {
+        for d in &dests {
+          server
+            .handle_client_message(
+              src,
+              ClientMessage::Text {
+                dest: *d,
+                content: content.into(),
+                attachments: Vec::new(),
+              },
+            )
+            .await;
+        }
+      }
+      dests.sort();
+
+      // the budget stops the first pass in the middle of the second round
+      let first = server.drain(5).await;
+      let rest = server.drain(100).await;
+      assert!(server.drain(100).await.is_empty());
+      let order: Vec<ClientId> = first.iter().chain(rest.iter()).map(|(d, _)| *d).collect();
+      assert_eq!(order, [dests.as_slice(); 3].concat());
+      for (n, (_, reply)) in first.iter().chain(rest.iter()).enumerate() {
+        match reply {
+          ClientPollReply::Message { content, .. } => {
+            assert_eq!(*content, (n / 3 + 1).to_string())
+          }
+          r => panic!("unexpected reply {:?}", r),
+        }
+      }
+    })
+  }
+
+  #[test]
+  fn stale_message() {
+    async_std::task::block_on(async {
+      let server = Server::new(ServerId::default()).with_replay_window(60_000);
+      let c1 = server.register_local_client("user 1".into()).await.unwrap();
+      let mut client1 = Client::new(c1);
+
+      let old = Sequence::signed_at(c1, 1, (), WORKPROOF_STRENGTH, now_millis() - 120_000);
+      assert_eq!(
+        server.handle_sequenced_message(old.clone(), None).await,
+        Err(ClientError::StaleMessage)
+      );
+      // the proof covers the timestamp, so it cannot be refreshed
+      // a timestamp the proof happens to be valid for is skipped
+      let refreshed = (now_millis()..)
+        .map(|timestamp| Sequence {
+          timestamp: Some(timestamp),
+          ..old.clone()
+        })
+        .find(|sq| !verify_workproof(sq.workproof_nonce(), sq.workproof, WORKPROOF_STRENGTH))
+        .unwrap();
+      assert_eq!(
+        server.handle_sequenced_message(refreshed, None).await,
+        Err(ClientError::WorkProofError)
+      );
+
+      let fresh = client1.sequence(());
+      assert_eq!(server.handle_sequenced_message(fresh, None).await, Ok(()));
+    })
+  }
+
+  #[cfg(feature = "federation")]
+  #[test]
+  fn pending_transfers() {
+    async_std::task::block_on(async {
+      let server = Server::new(ServerId::default());
+      let c1 = server.register_local_client("user 1".into()).await.unwrap();
+      let remote_server = ServerId(Uuid::new_v4());
+      let remote = ClientId(Uuid::new_v4());
+      let announce = ServerMessage::Announce {
+        route: vec![remote_server],
+        clients: HashMap::from([(remote, "remote".to_string())]),
+      };
+      server.handle_server_message(announce.clone()).await;
+      server.prune_route(remote_server).await;
+      assert!(server.pending_transfers().await.is_empty());
+
+      for content in ["first", "second"] {
+        let msg = ClientMessage::Text {
+          dest: remote,
+          content: content.into(),
+          attachments: Vec::new(),
+        };
+        server.handle_client_message(c1, msg).await;
+      }
+      assert_eq!(
+        server.pending_transfers().await,
+        [(remote, remote_server, 2)]
+      );
+      assert_eq!(server.metrics().await.pending_transfers, 2);
+
+      server.handle_server_message(announce).await;
+      assert!(server.pending_transfers().await.is_empty());
+    })
+  }
+
+  #[cfg(feature = "federation")]
+  #[test]
+  fn route_lost() {
+    async_std::task::block_on(async {
+      let server = Server::new(ServerId::default());
+      let c1 = server.register_local_client("user 1".into()).await.unwrap();
+      let remote_server = ServerId(Uuid::new_v4());
+      let remote = ClientId(Uuid::new_v4());
+      let announce = ServerMessage::Announce {
+        route: vec![remote_server],
+        clients: HashMap::from([(remote, "remote".to_string())]),
+      };
+      server.handle_server_message(announce.clone()).await;
+      assert!(server.prune_route(remote_server).await);
+
+      let msg = ClientMessage::Text {
+        dest: remote,
+        content: "hello".into(),
+        attachments: Vec::new(),
+      };
+      assert!(matches!(
+        server.handle_client_message(c1, msg).await[..],
+        [ClientReply::Delayed(_)]
+      ));
+      assert_eq!(
+        server.client_poll(c1).await,
+        ClientPollReply::DelayedError(DelayedError::RouteLost(remote))
+      );
+      assert_eq!(server.client_poll(c1).await, ClientPollReply::Nothing);
+
+      // the held message leaves once the route is back
+      match server.handle_server_message(announce).await {
+        ServerReply::Outgoing(out) => {
+          assert_eq!(out.len(), 1);
+          assert_eq!(out[0].nexthop, remote_server);
+          assert_eq!(out[0].message.dsts, [(remote, remote_server)]);
+        }
+        r => panic!("unexpected reply {:?}", r),
+      }
+    })
+  }
+
+  #[cfg(feature = "federation")]
+  #[test]
+  fn route_lost_bounded() {
+    async_std::task::block_on(async {
+      let server = Server::new(ServerId::default());
+      let c1 = server.register_local_client("user 1".into()).await.unwrap();
+      let remote_server = ServerId(Uuid::new_v4());
+      let remote = ClientId(Uuid::new_v4());
+      server
+        .handle_server_message(ServerMessage::Announce {
+          route: vec![remote_server],
+          clients: HashMap::from([(remote, "remote".to_string())]),
+        })
+        .await;
+      assert!(server.prune_route(remote_server).await);
+
+      let msg = ClientMessage::Text {
+        dest: remote,
+        content: "hello".into(),
+        attachments: Vec::new(),
+      };
+      for _ in 0..MAILBOX_SIZE {
+        assert!(matches!(
+          server.handle_client_message(c1, msg.clone()).await[..],
+          [ClientReply::Delayed(_)]
+        ));
+      }
+      assert_eq!(
+        server.handle_client_message(c1, msg).await,
+        [ClientReply::Error(ClientError::BoxFull(remote))]
+      );
+      assert_eq!(pending_ids(&server, remote).await.len(), MAILBOX_SIZE);
+      // a single RouteLost for all the held messages
+      assert_eq!(
+        server.client_poll(c1).await,
+        ClientPollReply::DelayedError(DelayedError::RouteLost(remote))
+      );
+      assert_eq!(server.client_poll(c1).await, ClientPollReply::Nothing);
+    })
+  }
+
+  #[cfg(feature = "federation")]
+  #[test]
+  fn reachable_clients() {
+    async_std::task::block_on(async {
+      let server = Server::new(ServerId::default());
+      let remote_server = ServerId(Uuid::new_v4());
+      let remote = ClientId(Uuid::new_v4());
+      server
+        .handle_server_message(ServerMessage::Announce {
+          route: vec![remote_server],
+          clients: HashMap::from([(remote, "remote".to_string())]),
+        })
+        .await;
+      let local = server.register_local_client("local".into()).await.unwrap();
+      // a placeholder is created for it
+      let unknown = ClientId(Uuid::new_v4());
+      let msg = ClientMessage::Text {
+        dest: unknown,
+        content: "hello".into(),
+        attachments: Vec::new(),
+      };
+      assert!(matches!(
+        server.handle_client_message(local, msg).await[..],
+        [ClientReply::Delayed(_)]
+      ));
+      assert_eq!(
+        server.reachable_clients().await,
+        HashMap::from([
+          (local, ClientLocation::Local),
+          (remote, ClientLocation::Remote(remote_server)),
+        ])
+      );
+
+      // remote clients are not reachable anymore once their route is lost
+      assert!(server.prune_route(remote_server).await);
+      assert_eq!(
+        server.reachable_clients().await,
+        HashMap::from([(local, ClientLocation::Local)])
+      );
+    })
+  }
+
+  #[cfg(feature = "federation")]
+  #[test]
+  fn unroutable_modes() {
+    async_std::task::block_on(async {
+      for (mode, held) in [(UnroutableMode::Hold, 1), (UnroutableMode::Reject, 0)] {
+        let server = Server::new(ServerId::default()).with_unroutable_mode(mode);
+        let c1 = server.register_local_client("user 1".into()).await.unwrap();
+        let remote_server = ServerId(Uuid::new_v4());
+        let remote = ClientId(Uuid::new_v4());
+        let announce = ServerMessage::Announce {
+          route: vec![remote_server],
+          clients: HashMap::from([(remote, "remote".to_string())]),
+        };
+        server.handle_server_message(announce).await;
+        assert!(server.prune_route(remote_server).await);
+
+        let msg = ClientMessage::Text {
+          dest: remote,
+          content: "hello".into(),
+          attachments: Vec::new(),
+        };
+        let reply = server.handle_client_message(c1, msg).await;
+        assert_eq!(server.metrics().await.pending_transfers, held, "{:?}", mode);
+        if mode == UnroutableMode::Hold {
+          assert!(matches!(reply[..], [ClientReply::Delayed(_)]));
+        } else {
+          assert_eq!(reply, [ClientReply::Error(ClientError::NoRoute)]);
+          // no delayed error either
+          assert_eq!(server.client_poll(c1).await, ClientPollReply::Nothing);
+        }
+      }
+    })
+  }
+
+  #[test]
+  fn poll_sees_delivery() {
+    async_std::task::block_on(async {
+      let server = std::sync::Arc::new(Server::new(ServerId::default()));
+      let src = server.register_local_client("src".into()).await.unwrap();
+      let dest = server.register_local_client("dest".into()).await.unwrap();
+      let message = move |n: usize| ClientMessage::Text {
+        dest,
+        content: format!("message {n}"),
+        attachments: Vec::new(),
+      };
+      let expected = move |n: usize, replies: Vec<ClientReply>| match replies[..] {
+        [ClientReply::Delivered(message_id)] => ClientPollReply::Message {
+          src,
+          srcsrv: None,
+          content: format!("message {n}"),
+          attachments: Vec::new(),
+          message_id,
+        },
+        _ => panic!("unexpected {:?}", replies),
+      };
+
+      // the delivery happens before the poll, that must see it
+      for n in 0..50 {
+        let (done_tx, done_rx) = async_std::channel::bounded(1);
+        let srv = server.clone();
+        let delivery = async_std::task::spawn(async move {
+          let replies = srv.handle_client_message(src, message(n)).await;
+          done_tx.send(()).await.unwrap();
+          replies
+        });
+        let srv = server.clone();
+        let poll = async_std::task::spawn(async move {
+          done_rx.recv().await.unwrap();
+          srv.client_poll(dest).await
+        });
+        let replies = delivery.await;
+        assert_eq!(poll.await, expected(n, replies));
+      }
+
+      // racing with the delivery, the poll either sees it or leaves it for the next poll
+      for n in 0..50 {
+        let srv = server.clone();
+        let delivery =
+          async_std::task::spawn(async move { srv.handle_client_message(src, message(n)).await });
+        let srv = server.clone();
+        let poll = async_std::task::spawn(async move { srv.client_poll(dest).await });
+        let replies = delivery.await;
+        match poll.await {
+          ClientPollReply::Nothing => {
+            assert_eq!(server.client_poll(dest).await, expected(n, replies))
+          }
+          reply => assert_eq!(reply, expected(n, replies)),
+        }
+        assert_eq!(server.client_poll(dest).await, ClientPollReply::Nothing);
+      }
+    })
+  }
+
+  #[test]
+  fn last_accepted_seqid() {
+    async_std::task::block_on(async {
+      let server = Server::new(ServerId::default());
+      let id = server.register_local_client("c1".into()).await.unwrap();
+      assert_eq!(server.last_accepted_seqid(id).await, Some(0));
+      let mut client = Client::new(id);
+      let sequences = (0..5).map(|_| client.sequence(())).collect::<Vec<_>>();
+      for sq in &sequences {
+        server
+          .handle_sequenced_message(sq.clone(), None)
+          .await
+          .unwrap();
+      }
+      assert_eq!(server.last_accepted_seqid(id).await, Some(5));
+      // a replay is rejected, and does not move the acknowledgement
+      let replayed = server
+        .handle_sequenced_message(sequences[2].clone(), None)
+        .await;
+      assert_eq!(replayed, Err(ClientError::SequenceError));
+      assert_eq!(server.last_accepted_seqid(id).await, Some(5));
+      assert_eq!(server.last_accepted_seqid(ClientId::default()).await, None);
+    })
+  }
+
+  #[test]
+  fn receipts_batched() {
+    async_std::task::block_on(async {
+      let window = Duration::from_secs(60);
+      let server = Server::new(ServerId::default()).with_receipt_window(Some(window));
+      let c1 = server.register_local_client("c1".into()).await.unwrap();
+      let c2 = server.register_local_client("c2".into()).await.unwrap();
+      let count = RECEIPT_BATCH + 2;
+      for n in 0..count {
+        let msg = ClientMessage::Text {
+          dest: c2,
+          content: format!("message {n}"),
+          attachments: Vec::new(),
+        };
+        server.handle_client_message(c1, msg).await;
+      }
+      let ids = match server.clients.read().await.get(&c2) {
+        Some(Stuff::Local(info)) => info.mailbox.iter().map(|m| m.id).collect::<Vec<_>>(),
+        _ => panic!("c2 is not local"),
+      };
+      for _ in 0..count {
+        assert!(matches!(
+          server.client_poll(c2).await,
+          ClientPollReply::Message { .. }
+        ));
+      }
+      // a full batch does not wait for the end of the window, the others do
+      assert_eq!(
+        server.client_poll(c1).await,
+        ClientPollReply::Receipts(ids[..RECEIPT_BATCH].to_vec())
+      );
+      assert_eq!(server.client_poll(c1).await, ClientPollReply::Nothing);
+      match server.clients.write().await.get_mut(&c1) {
+        Some(Stuff::Local(info)) => {
+          let (since, batch) = info.receipts.as_mut().unwrap();
+          assert_eq!(batch.len(), 2);
+          *since = Instant::now().checked_sub(window).unwrap();
+        }
+        _ => panic!("c1 is not local"),
+      }
+      assert_eq!(
+        server.client_poll(c1).await,
+        ClientPollReply::Receipts(ids[RECEIPT_BATCH..].to_vec())
+      );
+      assert_eq!(server.client_poll(c1).await, ClientPollReply::Nothing);
+    })
+  }
+
+  #[test]
+  fn register_and_message() {
+    async_std::task::block_on(async {
+      let server = Server::new(ServerId::default());
+      let dest = server.register_local_client("dest".into()).await.unwrap();
+      let msg = ClientMessage::Text {
+        dest,
+        content: "one shot".into(),
+        attachments: Vec::new(),
+      };
+      let nonce = ClientId::default();
+      let (bot, replies) = server
+        .register_and_message(nonce, "bot".into(), msg.clone())
+        .await
+        .unwrap();
+      let message_id = match replies[..] {
+        [ClientReply::Delivered(id)] => id,
+        _ => panic!("unexpected {:?}", replies),
+      };
+      assert_eq!(
+        server.list_users().await.get(&bot).map(String::as_str),
+        Some("bot")
+      );
+      assert_eq!(
+        server.client_poll(dest).await,
+        ClientPollReply::Message {
+          src: bot,
+          srcsrv: None,
+          content: "one shot".into(),
+          attachments: Vec::new(),
+          message_id,
+        }
+      );
+      // a retransmission gets the same id, and the message is not sent again
+      assert_eq!(
+        server
+          .register_and_message(nonce, "bot".into(), msg.clone())
+          .await,
+        Ok((bot, Vec::new()))
+      );
+      assert_eq!(server.client_poll(dest).await, ClientPollReply::Nothing);
+      // the name is checked before anything is sent
+      assert_eq!(
+        server
+          .register_and_message(ClientId::default(), "bot".into(), msg)
+          .await,
+        Err(ClientError::NameTaken)
+      );
+      assert_eq!(server.client_poll(dest).await, ClientPollReply::Nothing);
+    })
+  }
+
+  #[test]
+  fn placeholders_evicted() {
+    async_std::task::block_on(async {
+      let server = Server::new(ServerId::default());
+      let unknown: Vec<ClientId> = (0..=MAX_PLACEHOLDERS)
+        .map(|_| ClientId::default())
+        .collect();
+      // each sender can only have so many delayed messages
+      let mut senders = Vec::new();
+      for (i, dest) in unknown.chunks(MAX_PENDING_PER_SENDER).enumerate() {
+        let sender = server
+          .register_local_client(format!("sender {}", i))
+          .await
+          .unwrap();
+        let msg = ClientMessage::MText {
+          dest: dest.to_vec(),
+          content: "hello?".into(),
+        };
+        server.handle_client_message(sender, msg).await;
+        senders.push(sender);
+      }
+      let local = senders[0];
+      let placeholders = |clients: &HashMap<ClientId, Stuff>| {
+        clients
+          .values()
+          .filter(|s| matches!(s, Stuff::Pending { .. }))
+          .count()
+      };
+      let clients = server.clients.read().await;
+      // the oldest placeholder is gone, with its message
+      assert!(!clients.contains_key(&unknown[0]));
+      assert!(unknown[1..].iter().all(|c| clients.contains_key(c)));
+      assert!(senders
+        .iter()
+        .all(|c| matches!(clients.get(c), Some(Stuff::Local(_)))));
+      assert_eq!(placeholders(&clients), MAX_PLACEHOLDERS);
+      drop(clients);
+      // the evicted message is not counted against its sender anymore
+      assert_eq!(
+        server.pending_senders.read().await.get(senders[0]),
+        MAX_PENDING_PER_SENDER - 1
+      );
+      // sending to a local client does not create a placeholder, nor evict one
+      server
+        .handle_client_message(
+          local,
+          ClientMessage::Text {
+            dest: local,
+            content: "hello".into(),
+            attachments: Vec::new(),
+          },
+        )
+        .await;
+      let clients = server.clients.read().await;
+      assert_eq!(placeholders(&clients), MAX_PLACEHOLDERS);
+      assert!(clients.contains_key(&unknown[1]));
+    })
+  }
+
+  #[test]
+  fn placeholders_forgotten() {
+    async_std::task::block_on(async {
+      let old = Server::new(ServerId::default());
+      let alice = old.register_local_client("alice".into()).await.unwrap();
+      let snapshot = old.snapshot().await;
+
+      let server = Server::new(ServerId::default());
+      let first = ClientId::default();
+      let unknown: Vec<ClientId> = (1..MAX_PLACEHOLDERS).map(|_| ClientId::default()).collect();
+      let send = |sender, dest: Vec<ClientId>| {
+        server.handle_client_message(
+          sender,
+          ClientMessage::MText {
+            dest,
+            content: "hello?".into(),
+          },
+        )
+      };
+      let sender = server.register_local_client("sender".into()).await.unwrap();
+      send(sender, vec![first, alice]).await;
+      // alice is not a placeholder anymore once imported
+      server.import_state(snapshot).await;
+      assert!(!server.placeholders.read().await.contains(&alice));
+      for (i, dest) in unknown.chunks(MAX_PENDING_PER_SENDER).enumerate() {
+        let sender = server
+          .register_local_client(format!("sender {}", i))
+          .await
+          .unwrap();
+        send(sender, dest.to_vec()).await;
+      }
+      // so she does not take the room of the oldest placeholder
+      assert_eq!(server.placeholders.read().await.len(), MAX_PLACEHOLDERS);
+      let clients = server.clients.read().await;
+      assert!(matches!(clients.get(&first), Some(Stuff::Pending { .. })));
+      assert!(matches!(clients.get(&alice), Some(Stuff::Local(_))));
+    })
+  }
+
+  #[test]
+  fn event_log() {
+    async_std::task::block_on(async {
+      let server = Server::new(ServerId::default()).with_event_log(Some(16));
+      let c1 = server.register_local_client("c1".into()).await.unwrap();
+      let c2 = server.register_local_client("c2".into()).await.unwrap();
+      let unknown = ClientId::default();
+      server
+        .handle_client_message(
+          c1,
+          ClientMessage::MText {
+            dest: vec![c2, unknown],
+            content: "hello".into(),
+          },
+        )
+        .await;
+      server.client_poll(c2).await;
+      let log = server.event_log_snapshot();
+      let events: Vec<Event> = log.iter().map(|e| e.event.clone()).collect();
+      assert_eq!(
+        events,
+        [
+          Event::Registered {
+            client: c1,
+            name: "c1".into()
+          },
+          Event::Registered {
+            client: c2,
+            name: "c2".into()
+          },
+          Event::Queued {
+            message_id: 1,
+            src: c1,
+            dest: c2,
+            content: "hello".into(),
+            attachments: Vec::new()
+          },
+          Event::Queued {
+            message_id: 2,
+            src: c1,
+            dest: unknown,
+            content: "hello".into(),
+            attachments: Vec::new()
+          },
+          Event::Polled {
+            client: c2,
+            message_id: 1
+          },
+        ]
+      );
+      assert!(log.windows(2).all(|w| w[0].at <= w[1].at));
+      // disabled by default
+      let server = Server::new(ServerId::default());
+      server.register_local_client("c1".into()).await.unwrap();
+      assert!(server.event_log_snapshot().is_empty());
+    })
+  }
+
+  #[test]
+  fn replay() {
+    async_std::task::block_on(async {
+      let server = Server::new(ServerId::default()).with_event_log(Some(64));
+      let c1 = server.register_local_client("c1".into()).await.unwrap();
+      let c2 = server.register_local_client("c2".into()).await.unwrap();
+      for content in ["one", "two"] {
+        let msg = ClientMessage::Text {
+          dest: c2,
+          content: content.into(),
+          attachments: vec![("a.txt".into(), "data".into())],
+        };
+        server.handle_client_message(c1, msg).await;
+      }
+      let msg = ClientMessage::MText {
+        dest: vec![c1, ClientId::default()],
+        content: "three".into(),
+      };
+      server.handle_client_message(c2, msg).await;
+      let msg = ClientMessage::ToName {
+        name: "c3".into(),
+        content: "four".into(),
+      };
+      server.handle_client_message(c1, msg).await;
+      server.register_local_client("c3".into()).await.unwrap();
+      server.client_poll(c2).await;
+      let events: Vec<Event> = server
+        .event_log_snapshot()
+        .into_iter()
+        .map(|e| e.event)
+        .collect();
+
+      let replayed = Server::new(ServerId::default()).with_event_log(Some(64));
+      replayed.replay(&events).await;
+      assert_eq!(replayed.snapshot().await, server.snapshot().await);
+      let again: Vec<Event> = replayed
+        .event_log_snapshot()
+        .into_iter()
+        .map(|e| e.event)
+        .collect();
+      assert_eq!(again, events);
+      assert_eq!(replayed.client_poll(c2).await, server.client_poll(c2).await);
+    })
+  }
+
+  #[test]
+  fn replay_ids() {
+    async_std::task::block_on(async {
+      let c1 = ClientId::default();
+      let c2 = ClientId::default();
+      let queued = |message_id: u128| Event::Queued {
+        message_id,
+        src: c1,
+        dest: c2,
+        content: format!("message {}", message_id),
+        attachments: Vec::new(),
+      };
+      let registered = |client, name: &str| Event::Registered {
+        client,
+        name: name.into(),
+      };
+      let server = Server::new(ServerId::default());
+      server
+        .replay(&[
+          registered(c1, "c1"),
+          registered(c2, "c2"),
+          queued(5),
+          queued(3),
+        ])
+        .await;
+      assert_eq!(pending_ids(&server, c2).await, [5, 3]);
+      let msg = ClientMessage::Text {
+        dest: c2,
+        content: "after".into(),
+        attachments: Vec::new(),
+      };
+      // the next id is not one of the replayed ones, although they came out of order
+      assert_eq!(
+        server.handle_client_message(c1, msg).await,
+        [ClientReply::Delivered(6)]
+      );
+
+      // the allocator could not follow an id past what it gives out
+      server.replay(&[queued(u64::MAX as u128 + 1)]).await;
+      assert_eq!(pending_ids(&server, c2).await, [5, 3, 6]);
+    })
+  }
+
+  #[test]
+  fn recipient_gone() {
+    async_std::task::block_on(async {
+      let server = Server::new(ServerId::default());
+      let src = server.register_local_client("src".into()).await.unwrap();
+      let dest = server.register_local_client("dest".into()).await.unwrap();
+      let text = |content: &str| ClientMessage::Text {
+        dest,
+        content: content.into(),
+        attachments: Vec::new(),
+      };
+      assert!(matches!(
+        server.handle_client_message(src, text("first")).await[..],
+        [ClientReply::Delivered(_)]
+      ));
+      assert!(server.unregister_local_client(dest).await);
+      assert!(!server.unregister_local_client(dest).await);
+      assert_eq!(
+        server.handle_client_message(src, text("second")).await,
+        [ClientReply::Error(ClientError::RecipientGone(dest))]
+      );
+      // the first message went away with the mailbox, and no placeholder was created
+      assert!(!server.clients.read().await.contains_key(&dest));
+      assert_eq!(server.client_poll(dest).await, ClientPollReply::Nothing);
+      assert!(!server.list_users().await.contains_key(&dest));
+    })
+  }
+
+  #[cfg(feature = "federation")]
+  #[test]
+  fn max_route_hops() {
+    async_std::task::block_on(async {
+      let server = Server::new(ServerId::default());
+      let hops: Vec<ServerId> = (0..=MAX_ROUTE_HOPS).map(|_| ServerId::default()).collect();
+      // origin first, the server next to us last
+      let at_limit = hops[1..].to_vec();
+      let over_limit = hops.clone();
+      let announce = |route| ServerMessage::Announce {
+        route,
+        clients: HashMap::new(),
+      };
+      assert_eq!(
+        server.handle_server_message(announce(over_limit)).await,
+        ServerReply::Error(ServerError::RouteTooLong(hops[0]))
+      );
+      assert_eq!(server.route_to(hops[0]).await, None);
+      assert_ne!(
+        server
+          .handle_server_message(announce(at_limit.clone()))
+          .await,
+        ServerReply::EmptyRoute
+      );
+      assert_eq!(server.route_to(hops[1]).await, Some(at_limit));
+      // nothing was kept from the rejected route
+      assert_eq!(server.route_to(hops[0]).await, None);
+      for hop in &hops[1..] {
+        let route = server.route_to(*hop).await.unwrap();
+        assert!(route.len() <= MAX_ROUTE_HOPS);
+      }
+    })
+  }
+
+  #[cfg(feature = "federation")]
+  #[test]
+  fn max_peers() {
+    async_std::task::block_on(async {
+      let server = Server::new(ServerId::default());
+      let announce = |origin| ServerMessage::Announce {
+        route: vec![origin],
+        clients: HashMap::new(),
+      };
+      let peers: Vec<ServerId> = (0..MAX_PEERS).map(|_| ServerId(Uuid::new_v4())).collect();
+      for peer in &peers {
+        assert_eq!(
+          server.handle_server_message(announce(*peer)).await,
+          ServerReply::Outgoing(Vec::new())
+        );
+      }
+      let extra = ServerId(Uuid::new_v4());
+      assert_eq!(
+        server.handle_server_message(announce(extra)).await,
+        ServerReply::Error(ServerError::TooManyPeers(extra))
+      );
+      assert_eq!(server.route_to(extra).await, None);
+      // known peers can still update their route
+      let relayed = ServerMessage::Announce {
+        route: vec![peers[0], peers[1]],
+        clients: HashMap::new(),
+      };
+      assert_eq!(
+        server.handle_server_message(relayed).await,
+        ServerReply::Outgoing(Vec::new())
+      );
+    })
+  }
+
+  #[test]
+  fn export_client() {
+    async_std::task::block_on(async {
+      let origin = Server::new(ServerId::default());
+      let c1 = origin.register_local_client("c1".into()).await.unwrap();
+      let c2 = origin.register_local_client("c2".into()).await.unwrap();
+      let attachments = vec![("name".to_string(), "value".to_string())];
+      for content in ["one", "two", "three"] {
+        let msg = ClientMessage::Text {
+          dest: c1,
+          content: content.into(),
+          attachments: attachments.clone(),
+        };
+        origin.handle_client_message(c2, msg).await;
+      }
+      let mut client = Client::new(c1);
+      for _ in 0..3 {
+        let sq = client.sequence(());
+        origin.handle_sequenced_message(sq, None).await.unwrap();
+      }
+      assert_eq!(origin.export_client(ClientId::default()).await, None);
+      let ids = pending_ids(&origin, c1).await;
+      let exported = origin.export_client(c1).await.unwrap();
+
+      let target = Server::new(ServerId::default());
+      assert_eq!(target.import_client(&exported).await.unwrap(), c1);
+      assert_eq!(target.last_accepted_seqid(c1).await, Some(3));
+      for (content, message_id) in ["one", "two", "three"].into_iter().zip(ids) {
+        assert_eq!(
+          target.client_poll(c1).await,
+          ClientPollReply::Message {
+            src: c2,
+            srcsrv: None,
+            content: content.into(),
+            attachments: attachments.clone(),
+            message_id,
+          }
+        );
+      }
+      assert_eq!(target.client_poll(c1).await, ClientPollReply::Nothing);
+      assert!(target
+        .import_client(&ExportedClient(exported.0[..5].to_vec()))
+        .await
+        .is_err());
+    })
+  }
+
+  #[test]
+  fn too_many_pending() {
+    async_std::task::block_on(async {
+      let server = Server::new(ServerId::default());
+      let flooder = server
+        .register_local_client("flooder".into())
+        .await
+        .unwrap();
+      let sender = server.register_local_client("sender".into()).await.unwrap();
+      let text = |dest| ClientMessage::Text {
+        dest,
+        content: "anyone?".into(),
+        attachments: Vec::new(),
+      };
+      let mut delayed = Vec::new();
+      for _ in 0..MAX_PENDING_PER_SENDER {
+        match server
+          .handle_client_message(flooder, text(ClientId::default()))
+          .await[..]
+        {
+          [ClientReply::Delayed(id)] => delayed.push(id),
+          ref r => panic!("unexpected {:?}", r),
+        }
+      }
+      let unknown = ClientId::default();
+      assert_eq!(
+        server.handle_client_message(flooder, text(unknown)).await,
+        [ClientReply::Error(ClientError::TooManyPending)]
+      );
+      // no placeholder was created for the rejected message
+      assert!(!server.clients.read().await.contains_key(&unknown));
+      // the other senders, and the local recipients, are not affected
+      assert!(matches!(
+        server.handle_client_message(sender, text(unknown)).await[..],
+        [ClientReply::Delayed(_)]
+      ));
+      assert!(matches!(
+        server.handle_client_message(flooder, text(sender)).await[..],
+        [ClientReply::Delivered(_)]
+      ));
+      // a recalled message makes room for another one
+      assert!(server.recall(flooder, delayed[0]).await);
+      assert!(matches!(
+        server.handle_client_message(flooder, text(unknown)).await[..],
+        [ClientReply::Delayed(_)]
+      ));
+      assert_eq!(
+        server.pending_senders.read().await.get(flooder),
+        MAX_PENDING_PER_SENDER
+      );
+    })
+  }
+
+  #[test]
+  fn top_senders() {
+    async_std::task::block_on(async {
+      let server = Server::new(ServerId::default());
+      let mut clients = Vec::new();
+      for name in ["quiet", "chatty", "busy"] {
+        clients.push(server.register_local_client(name.into()).await.unwrap());
+      }
+      let (quiet, chatty, busy) = (clients[0], clients[1], clients[2]);
+      for (src, count) in [(quiet, 1), (chatty, 3), (busy, 5)] {
+        for _ in 0..count {
+          let msg = ClientMessage::Text {
+            dest: quiet,
+            content: "hi".into(),
+            attachments: Vec::new(),
+          };
+          server.handle_client_message(src, msg).await;
+        }
+      }
+      assert_eq!(server.top_senders(2).await, [(busy, 5), (chatty, 3)]);
+      assert_eq!(server.top_senders(10).await.len(), 3);
+      server.reset_counters().await;
+      assert!(server.top_senders(2).await.is_empty());
+    })
+  }
+
+  #[test]
+  fn delivery_order() {
+    async fn next(server: &Server, client: ClientId) -> String {
+      match server.client_poll(client).await {
+        ClientPollReply::Message { content, .. } => content,
+        r => panic!("unexpected poll reply {:?}", r),
+      }
+    }
+
+    async_std::task::block_on(async {
+      let server = Server::new(ServerId::default());
+      let c1 = server.register_local_client("c1".into()).await.unwrap();
+      let c2 = server.register_local_client("c2".into()).await.unwrap();
+      for content in ["one", "two", "three", "four"] {
+        let msg = ClientMessage::Text {
+          dest: c1,
+          content: content.into(),
+          attachments: Vec::new(),
+        };
+        server.handle_client_message(c2, msg).await;
+      }
+      assert!(server.set_delivery_order(c1, DeliveryOrder::Lifo).await);
+      assert_eq!(next(&server, c1).await, "four");
+      assert_eq!(next(&server, c1).await, "three");
+      assert!(server.set_delivery_order(c1, DeliveryOrder::Fifo).await);
+      assert_eq!(next(&server, c1).await, "one");
+      assert_eq!(next(&server, c1).await, "two");
+      assert!(
+        !server
+          .set_delivery_order(ClientId::default(), DeliveryOrder::Lifo)
+          .await
+      );
+    })
+  }
+
+  #[cfg(feature = "federation")]
+  #[test]
+  fn held_transfer_rerouted() {
+    async_std::task::block_on(async {
+      let server = Server::new(ServerId::default());
+      let c1 = server.register_local_client("user 1".into()).await.unwrap();
+      let (far, relay, near) = (
+        ServerId(Uuid::new_v4()),
+        ServerId(Uuid::new_v4()),
+        ServerId(Uuid::new_v4()),
+      );
+      let remote = ClientId(Uuid::new_v4());
+      // far was first reached through relay
+      let announce = ServerMessage::Announce {
+        route: vec![far, relay],
+        clients: HashMap::from([(remote, "remote".to_string())]),
+      };
+      server.handle_server_message(announce).await;
+      server.prune_route(far).await;
+      let msg = ClientMessage::Text {
+        dest: remote,
+        content: "hello".into(),
+        attachments: Vec::new(),
+      };
+      server.handle_client_message(c1, msg).await;
+      assert_eq!(server.pending_transfers().await, [(remote, far, 1)]);
+
+      // near is announced through far, which turns out to be a direct neighbour
+      let announce = ServerMessage::Announce {
+        route: vec![near, far],
+        clients: HashMap::new(),
+      };
+      match server.handle_server_message(announce).await {
+        ServerReply::Outgoing(out) => {
+          assert_eq!(out.len(), 1);
+          assert_eq!(out[0].nexthop, far);
+          assert_eq!(out[0].message.dsts, [(remote, far)]);
+        }
+        r => panic!("unexpected reply {:?}", r),
+      }
+      assert!(server.pending_transfers().await.is_empty());
+    })
+  }
+
+  #[cfg(feature = "federation")]
+  #[test]
+  fn queued_transfer_rerouted() {
+    async_std::task::block_on(async {
+      let server = Server::new(ServerId::default());
+      let c1 = server.register_local_client("user 1".into()).await.unwrap();
+      let (far, other, relay, near) = (
+        ServerId(Uuid::new_v4()),
+        ServerId(Uuid::new_v4()),
+        ServerId(Uuid::new_v4()),
+        ServerId(Uuid::new_v4()),
+      );
+      let (remote, distant) = (ClientId(Uuid::new_v4()), ClientId(Uuid::new_v4()));
+      // both servers are reached through relay
+      for (origin, client) in [(far, remote), (other, distant)] {
+        let announce = ServerMessage::Announce {
+          route: vec![origin, relay],
+          clients: HashMap::from([(client, "remote".to_string())]),
+        };
+        server.handle_server_message(announce).await;
+      }
+      let msg = ClientMessage::MText {
+        dest: vec![remote, distant],
+        content: "hello".into(),
+      };
+      let transfers = server
+        .handle_client_message(c1, msg)
+        .await
+        .into_iter()
+        .map(|reply| match reply {
+          ClientReply::Transfer(nexthop, ServerMessage::Message(message)) => {
+            Outgoing { nexthop, message }
+          }
+          r => panic!("unexpected reply {:?}", r),
+        })
+        .collect::<Vec<_>>();
+      assert!(transfers.iter().all(|t| t.nexthop == relay));
+      server.queue_transfers(transfers).await;
+
+      // near is announced through far, which turns out to be a direct neighbour, so the transfer
+      // to far takes the shorter route, the one to other keeps going through relay
+      let announce = ServerMessage::Announce {
+        route: vec![near, far],
+        clients: HashMap::new(),
+      };
+      server.handle_server_message(announce).await;
+      let mut hops: Vec<_> = server
+        .take_transfers()
+        .await
+        .into_iter()
+        .map(|t| (t.nexthop, t.message.dsts))
+        .collect();
+      hops.sort();
+      let mut expected = vec![(far, vec![(remote, far)]), (relay, vec![(distant, other)])];
+      expected.sort();
+      assert_eq!(hops, expected);
+      assert!(server.take_transfers().await.is_empty());
+    })
+  }
+
+  #[test]
+  fn try_client_poll() {
+    async_std::task::block_on(async {
+      let server = Server::new(ServerId::default());
+      let c1 = server.register_local_client("c1".into()).await.unwrap();
+      let c2 = server.register_local_client("c2".into()).await.unwrap();
+      for content in ["one", "two"] {
+        let msg = ClientMessage::Text {
+          dest: c1,
+          content: content.into(),
+          attachments: Vec::new(),
+        };
+        server.handle_client_message(c2, msg).await;
+      }
+      assert!(matches!(
+        server.try_client_poll(c1),
+        Some(ClientPollReply::Message { content, .. }) if content == "one"
+      ));
+      // a reader is enough to prevent the poll, that gives up instead of waiting
+      let guard = server.clients.read().await;
+      assert_eq!(server.try_client_poll(c1), None);
+      drop(guard);
+      assert!(matches!(
+        server.try_client_poll(c1),
+        Some(ClientPollReply::Message { content, .. }) if content == "two"
+      ));
+    })
+  }
+
+  #[test]
+  fn ack_status() {
+    async_std::task::block_on(async {
+      let server = Server::new(ServerId::default());
+      let c1 = server.register_local_client("c1".into()).await.unwrap();
+      for seqid in [1, 2, 4] {
+        let sq = Sequence::signed(c1, seqid, (), WORKPROOF_STRENGTH);
+        assert_eq!(server.handle_sequenced_message(sq, None).await, Ok(()));
+      }
+      let status = server.ack_status(c1).await.unwrap();
+      assert_eq!(status.contiguous, 2);
+      assert_eq!(status.received(4), Some(true));
+      assert_eq!(status.missing(), [3]);
+      assert_eq!(server.ack_status(ClientId::default()).await, None);
+    })
+  }
+
+  #[test]
+  fn paused_delivery() {
+    async_std::task::block_on(async {
+      let server = Server::new(ServerId::default());
+      let c1 = server.register_local_client("c1".into()).await.unwrap();
+      let c2 = server.register_local_client("c2".into()).await.unwrap();
+      assert!(server.set_delivery(c1, false).await);
+      for content in ["one", "two"] {
+        let msg = ClientMessage::Text {
+          dest: c1,
+          content: content.into(),
+          attachments: Vec::new(),
+        };
+        let replies = server.handle_client_message(c2, msg).await;
+        assert!(matches!(replies[..], [ClientReply::Delivered(_)]));
+      }
+      assert_eq!(server.client_poll(c1).await, ClientPollReply::Nothing);
+      assert!(server.set_delivery(c1, true).await);
+      for content in ["one", "two"] {
+        match server.client_poll(c1).await {
+          ClientPollReply::Message { content: c, .. } => assert_eq!(c, content),
+          r => panic!("unexpected poll reply {:?}", r),
+        }
+      }
+      assert!(!server.set_delivery(ClientId::default(), false).await);
+    })
+  }
+
+  #[test]
+  fn colliding_id() {
+    async_std::task::block_on(async {
+      let server = Server::new(ServerId::default());
+      let c1 = server.register_local_client("c1".into()).await.unwrap();
+      let c2 = server.register_local_client("c2".into()).await.unwrap();
+      let msg = ClientMessage::Text {
+        dest: c1,
+        content: "kept".into(),
+        attachments: Vec::new(),
+      };
+      server.handle_client_message(c2, msg).await;
+      let collision = register_as(
+        &mut *server.clients_write().await,
+        None,
+        c1,
+        None,
+        "c3".into(),
+      );
+      assert_eq!(collision, Err(ClientError::InternalError));
+      match server.client_poll(c1).await {
+        ClientPollReply::Message { content, .. } => assert_eq!(content, "kept"),
+        r => panic!("unexpected poll reply {:?}", r),
+      }
+      assert_eq!(server.list_users().await.get(&c1), Some(&"c1".to_string()));
+    })
+  }
+
+  #[test]
+  fn attachments_delivered() {
+    async_std::task::block_on(async {
+      let server = Server::new(ServerId::default());
+      let c1 = server.register_local_client("c1".into()).await.unwrap();
+      let c2 = server.register_local_client("c2".into()).await.unwrap();
+      let attachments = vec![("reply-to".to_string(), "1".to_string())];
+      let msg = ClientMessage::Text {
+        dest: c1,
+        content: "hi".into(),
+        attachments: attachments.clone(),
+      };
+      let message_id = match server.handle_client_message(c2, msg).await[..] {
+        [ClientReply::Delivered(id)] => id,
+        ref r => panic!("unexpected {:?}", r),
+      };
+      assert_eq!(
+        server.client_poll(c1).await,
+        ClientPollReply::Message {
+          src: c2,
+          srcsrv: None,
+          content: "hi".into(),
+          attachments,
+          message_id,
+        }
+      );
+    })
+  }
+
+  #[cfg(feature = "debug-snapshot")]
+  #[test]
+  fn debug_snapshot() {
+    async_std::task::block_on(async {
+      let server = Server::new(ServerId::default());
+      let c1 = server.register_local_client("c1".into()).await.unwrap();
+      let c2 = server.register_local_client("c2".into()).await.unwrap();
+      server.subscribe(c1, "news".into()).await;
+      for dest in [c1, ClientId::default()] {
+        let msg = ClientMessage::Text {
+          dest,
+          content: "hello".into(),
+          attachments: Vec::new(),
+        };
+        server.handle_client_message(c2, msg).await;
+      }
+      let before = server.debug_snapshot().await;
+
+      assert!(matches!(
+        server.client_poll(c1).await,
+        ClientPollReply::Message { .. }
+      ));
+      server.register_local_client("c3".into()).await.unwrap();
+      server.unsubscribe(c1, "news").await;
+      server.unregister_local_client(c2).await;
+      assert_ne!(server.debug_snapshot().await, before);
+
+      server.debug_restore(before.clone()).await;
+      assert_eq!(server.debug_snapshot().await, before);
+      match server.client_poll(c1).await {
+        ClientPollReply::Message { src, content, .. } => {
+          assert_eq!((src, content.as_str()), (c2, "hello"))
+        }
+        r => panic!("unexpected poll reply {:?}", r),
+      }
+    })
+  }
+
+  #[test]
+  fn lock_timeout() {
+    async_std::task::block_on(async {
+      let timeout = Duration::from_millis(50);
+      let server =
+        std::sync::Arc::new(Server::new(ServerId::default()).with_lock_timeout(Some(timeout)));
+      let id = server.register_local_client("c1".into()).await.unwrap();
+      let sq = Client::new(id).sequence(());
+      let (locked_tx, locked_rx) = async_std::channel::bounded(1);
+      let (release_tx, release_rx) = async_std::channel::bounded::<()>(1);
+      let holder = {
+        let server = server.clone();
+        async_std::task::spawn(async move {
+          let _clients = server.clients.write().await;
+          locked_tx.send(()).await.unwrap();
+          let _ = release_rx.recv().await;
+        })
+      };
+      locked_rx.recv().await.unwrap();
+      let start = Instant::now();
+      let handled = async_std::future::timeout(
+        timeout * 10,
+        server.handle_sequenced_message(sq.clone(), None),
+      )
+      .await;
+      assert_eq!(handled, Ok(Err(ClientError::ServerBusy)));
+      assert!(start.elapsed() >= timeout);
+      release_tx.send(()).await.unwrap();
+      holder.await;
+      // the message was not accepted, so it can be sent again
+      assert_eq!(server.handle_sequenced_message(sq, None).await, Ok(()));
+    })
+  }
+
+  #[test]
+  fn byte_quota() {
+    async_std::task::block_on(async {
+      let server = Server::new(ServerId::default());
+      let c1 = server.register_local_client("c1".into()).await.unwrap();
+      let c2 = server.register_local_client("c2".into()).await.unwrap();
+      let large = |fill: char| ClientMessage::Text {
+        dest: c1,
+        content: fill.to_string().repeat(MAX_MAILBOX_BYTES / 4),
+        attachments: Vec::new(),
+      };
+      for fill in ['a', 'b', 'c', 'd'] {
+        assert!(matches!(
+          server.handle_client_message(c2, large(fill)).await[..],
+          [ClientReply::Delivered(_)]
+        ));
+      }
+      // far from MAILBOX_SIZE messages, but the quota is reached
+      let small = ClientMessage::Text {
+        dest: c1,
+        content: "x".into(),
+        attachments: Vec::new(),
+      };
+      assert_eq!(
+        server.handle_client_message(c2, small.clone()).await,
+        [ClientReply::Error(ClientError::BoxFull(c1))]
+      );
+      // polling frees the bytes of the message
+      assert!(matches!(
+        server.client_poll(c1).await,
+        ClientPollReply::Message { .. }
+      ));
+      assert!(matches!(
+        server.handle_client_message(c2, small).await[..],
+        [ClientReply::Delivered(_)]
+      ));
+    })
+  }
+
+  #[test]
+  fn heartbeat() {
+    async_std::task::block_on(async {
+      let server = Server::new(ServerId::default())
+        .with_liveness_window(Some(Duration::from_millis(50)))
+        .with_address_check(true);
+      let c1 = server.register_local_client("user 1".into()).await.unwrap();
+      let bound: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+      server.bind_address(c1, bound).await;
+      async_std::task::sleep(Duration::from_millis(100)).await;
+      assert!(!server.list_users().await.contains_key(&c1));
+
+      let elsewhere: SocketAddr = "127.0.0.1:4001".parse().unwrap();
+      assert!(!server.heartbeat(c1, Some(elsewhere)).await);
+      assert!(!server.list_users().await.contains_key(&c1));
+      assert!(server.heartbeat(c1, Some(bound)).await);
+      assert!(server.list_users().await.contains_key(&c1));
+      // nothing else changed
+      assert_eq!(server.last_accepted_seqid(c1).await, Some(0));
+      assert_eq!(server.client_poll(c1).await, ClientPollReply::Nothing);
+      assert!(!server.heartbeat(ClientId::default(), None).await);
+    })
+  }
+
+  #[cfg(feature = "federation")]
+  #[test]
+  fn delivery_states() {
+    async_std::task::block_on(async {
+      let server = Server::new(ServerId::default());
+      let c1 = server.register_local_client("user 1".into()).await.unwrap();
+      let local = server.register_local_client("user 2".into()).await.unwrap();
+      let remote = ClientId(Uuid::new_v4());
+      let remote_server = ServerId(Uuid::new_v4());
+      let text = |dest, content: &str| ClientMessage::Text {
+        dest,
+        content: content.into(),
+        attachments: Vec::new(),
+      };
+
+      let reply = server.handle_client_message(c1, text(local, "local")).await;
+      assert!(matches!(reply[..], [ClientReply::Delivered(_)]));
+      assert_eq!(pending_ids(&server, local).await.len(), 1);
+
+      // not known yet
+      let reply = server
+        .handle_client_message(c1, text(remote, "early"))
+        .await;
+      assert!(matches!(reply[..], [ClientReply::Delayed(_)]));
+      assert!(matches!(
+        server.clients.read().await.get(&remote),
+        Some(Stuff::Pending { mailbox }) if mailbox.len() == 1
+      ));
+      assert!(!server.list_users().await.contains_key(&remote));
+      assert_eq!(server.home_server(remote).await, None);
+
+      // the announce turns it into a remote client, and the held message leaves
+      let announce = ServerMessage::Announce {
+        route: vec![remote_server],
+        clients: HashMap::from([(remote, "remote".to_string())]),
+      };
+      match server.handle_server_message(announce).await {
+        ServerReply::Outgoing(out) => {
+          assert_eq!(out.len(), 1);
+          assert_eq!(out[0].message.content, "early");
+          assert_eq!(out[0].message.dsts, [(remote, remote_server)]);
+        }
+        other => panic!("unexpected reply {:?}", other),
+      }
+      assert!(matches!(
+        server.clients.read().await.get(&remote),
+        Some(Stuff::Remote { server: s, mailbox, .. }) if *s == remote_server && mailbox.is_empty()
+      ));
+      assert_eq!(server.home_server(remote).await, Some(remote_server));
+      assert!(server.list_users().await.contains_key(&remote));
+
+      let reply = server.handle_client_message(c1, text(remote, "late")).await;
+      assert!(matches!(
+        reply.as_slice(),
+        [ClientReply::Transfer(nexthop, _)] if *nexthop == remote_server
+      ));
+      assert!(pending_ids(&server, remote).await.is_empty());
+    })
+  }
+
+  #[test]
+  fn name_policy() {
+    async_std::task::block_on(async {
+      let server = Server::new(ServerId::default())
+        .with_name_policy(Box::new(|name| !name.to_lowercase().starts_with("admin")));
+      assert!(server.register_local_client(" alice ".into()).await.is_ok());
+      assert_eq!(
+        server.register_local_client("Admin Bob".into()).await,
+        Err(ClientError::InvalidName)
+      );
+      assert_eq!(
+        server
+          .register_local_client("bad\u{1b}[2Jname".into())
+          .await,
+        Err(ClientError::InvalidName)
+      );
+      assert_eq!(
+        server
+          .register_local_client("x".repeat(crate::core::MAX_NAME_LEN + 1))
+          .await,
+        Err(ClientError::NameTooLong)
+      );
+      assert_eq!(server.list_users().await.len(), 1);
+    })
+  }
+
+  #[cfg(feature = "federation")]
+  #[test]
+  fn flushed_like_fresh() {
+    async_std::task::block_on(async {
+      let server = Server::new(ServerId::default());
+      let c1 = server.register_local_client("user 1".into()).await.unwrap();
+      let (home, relay) = (ServerId(Uuid::new_v4()), ServerId(Uuid::new_v4()));
+      let remote = ClientId(Uuid::new_v4());
+      let text = |content: &str| ClientMessage::Text {
+        dest: remote,
+        content: content.into(),
+        attachments: Vec::new(),
+      };
+      // held until remote is announced
+      server.handle_client_message(c1, text("hello")).await;
+      let announce = ServerMessage::Announce {
+        route: vec![home, relay],
+        clients: HashMap::from([(remote, "remote".to_string())]),
+      };
+      let flushed = match server.handle_server_message(announce).await {
+        ServerReply::Outgoing(mut out) => out.pop().unwrap(),
+        other => panic!("unexpected reply {:?}", other),
+      };
+      let fresh = match server.handle_client_message(c1, text("hello")).await.pop() {
+        Some(ClientReply::Transfer(nexthop, ServerMessage::Message(message))) => {
+          Outgoing { nexthop, message }
+        }
+        other => panic!("unexpected reply {:?}", other),
+      };
+      assert_eq!(fresh, flushed);
+      assert_eq!(fresh.nexthop, relay);
+      assert_eq!(fresh.message.dsts, [(remote, home)]);
+    })
+  }
+
+  #[test]
+  fn held_for_name() {
+    async_std::task::block_on(async {
+      let server = Server::new(ServerId::default());
+      let c1 = server.register_local_client("user 1".into()).await.unwrap();
+      let to_dave = |content: &str| ClientMessage::ToName {
+        name: " dave ".into(),
+        content: content.into(),
+      };
+      let first = match server.handle_client_message(c1, to_dave("first")).await[..] {
+        [ClientReply::Delayed(id)] => id,
+        ref r => panic!("unexpected {:?}", r),
+      };
+      let dave = server.register_local_client("dave".into()).await.unwrap();
+      let second = match server.handle_client_message(c1, to_dave("second")).await[..] {
+        [ClientReply::Delivered(id)] => id,
+        ref r => panic!("unexpected {:?}", r),
+      };
+      for (content, message_id) in [("first", first), ("second", second)] {
+        assert_eq!(
+          server.client_poll(dave).await,
+          ClientPollReply::Message {
+            src: c1,
+            srcsrv: None,
+            content: content.into(),
+            attachments: Vec::new(),
+            message_id,
+          }
+        );
+      }
+      // the released messages are not counted against their sender anymore
+      assert_eq!(server.held_for_names.read().await.senders.get(c1), 0);
+
+      // expired messages are not delivered
+      let server = Server::new(ServerId::default()).with_name_hold_ttl(Duration::from_millis(20));
+      let c1 = server.register_local_client("user 1".into()).await.unwrap();
+      server.handle_client_message(c1, to_dave("late")).await;
+      async_std::task::sleep(Duration::from_millis(50)).await;
+      let dave = server.register_local_client("dave".into()).await.unwrap();
+      assert_eq!(server.client_poll(dave).await, ClientPollReply::Nothing);
+
+      // nor counted against their sender
+      let to_erin = ClientMessage::ToName {
+        name: "erin".into(),
+        content: "hi".into(),
+      };
+      for _ in 0..MAX_PENDING_PER_SENDER {
+        server.handle_client_message(c1, to_erin.clone()).await;
+      }
+      assert_eq!(
+        server.handle_client_message(c1, to_erin.clone()).await,
+        [ClientReply::Error(ClientError::TooManyPending)]
+      );
+      async_std::task::sleep(Duration::from_millis(50)).await;
+      assert!(matches!(
+        server.handle_client_message(c1, to_erin).await[..],
+        [ClientReply::Delayed(_)]
+      ));
+      assert_eq!(server.held_for_names.read().await.senders.get(c1), 1);
+    })
   }
 }
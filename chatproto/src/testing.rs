@@ -1,14 +1,24 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::time::Duration;
 
 use anyhow::Context;
+use async_trait::async_trait;
 
-use crate::{client::Client, core::*, messages::*};
+use crate::{client::Client, core::*, messages::*, workproof::verify_workproof};
+
+// a fixed value would be a valid proof for some ids
+pub(crate) fn invalid_workproof(nonce: WorkproofNonce) -> u128 {
+  (0..)
+    .find(|w| !verify_workproof(nonce, *w, WORKPROOF_STRENGTH))
+    .unwrap()
+}
 
 async fn sequence_correct<M: MessageServer>() -> Result<(), ClientError> {
   let sid = ServerId::default();
   let server: M = MessageServer::new(sid);
-  let c1 = server.register_local_client("user1".to_string()).await;
-  let c2 = server.register_local_client("user2".to_string()).await;
+  let c1 = server.register_local_client("user1".to_string()).await?;
+  let c2 = server.register_local_client("user2".to_string()).await?;
   let mut client1 = Client::new(c1);
   let mut client2 = Client::new(c2);
 
@@ -19,7 +29,7 @@ async fn sequence_correct<M: MessageServer>() -> Result<(), ClientError> {
     } else {
       client2.sequence(())
     };
-    server.handle_sequenced_message(message).await?;
+    server.handle_sequenced_message(message, None).await?;
   }
   Ok(())
 }
@@ -31,7 +41,7 @@ async fn sequence_unknown_user<M: MessageServer>() -> anyhow::Result<()> {
   let mut client1 = Client::new(c1);
 
   let message = client1.sequence(());
-  match server.handle_sequenced_message(message).await {
+  match server.handle_sequenced_message(message, None).await {
     Err(ClientError::UnknownClient) => Ok(()),
     r => anyhow::bail!("Expected Err(UnknownClient), but got {:?}", r),
   }
@@ -40,13 +50,13 @@ async fn sequence_unknown_user<M: MessageServer>() -> anyhow::Result<()> {
 async fn sequence_bad<M: MessageServer>() -> anyhow::Result<()> {
   let sid = ServerId::default();
   let server: M = MessageServer::new(sid);
-  let c1 = server.register_local_client("user 1".to_string()).await;
+  let c1 = server.register_local_client("user 1".to_string()).await?;
   let mut client1 = Client::new(c1);
   let seq1 = client1.sequence(());
   let mut seq2 = client1.sequence(());
   seq2.seqid = seq1.seqid;
-  server.handle_sequenced_message(seq1).await?;
-  match server.handle_sequenced_message(seq2).await {
+  server.handle_sequenced_message(seq1, None).await?;
+  match server.handle_sequenced_message(seq2, None).await {
     Err(ClientError::SequenceError) => Ok(()),
     t => anyhow::bail!("expected a sequence error, got {:?}", t),
   }
@@ -55,14 +65,12 @@ async fn sequence_bad<M: MessageServer>() -> anyhow::Result<()> {
 async fn workproof_bad<M: MessageServer>() -> anyhow::Result<()> {
   let sid = ServerId::default();
   let server: M = MessageServer::new(sid);
-  let c1 = server.register_local_client("user 1".to_string()).await;
+  let c1 = server.register_local_client("user 1".to_string()).await?;
   let r = server
-    .handle_sequenced_message(Sequence {
-      seqid: 1,
-      src: c1,
-      workproof: 0,
-      content: (),
-    })
+    .handle_sequenced_message(
+      Sequence::new(c1, 1, invalid_workproof((&c1).into()), ()),
+      None,
+    )
     .await;
   match r {
     Err(ClientError::WorkProofError) => Ok(()),
@@ -75,12 +83,10 @@ async fn sequence_multiple_problems<M: MessageServer>() -> anyhow::Result<()> {
   let server: M = MessageServer::new(sid);
   let c1 = ClientId::default();
   let r = server
-    .handle_sequenced_message(Sequence {
-      seqid: 1,
-      src: c1,
-      workproof: 0,
-      content: (),
-    })
+    .handle_sequenced_message(
+      Sequence::new(c1, 1, invalid_workproof((&c1).into()), ()),
+      None,
+    )
     .await;
   match r {
     Err(ClientError::WorkProofError) => Ok(()),
@@ -92,24 +98,30 @@ async fn simple_client_test<M: MessageServer>() -> anyhow::Result<()> {
   let sid = ServerId::default();
   let server: M = MessageServer::new(sid);
 
-  let c1 = server.register_local_client("user 1".to_string()).await;
-  let c2 = server.register_local_client("user 2".to_string()).await;
+  let c1 = server.register_local_client("user 1".to_string()).await?;
+  let c2 = server.register_local_client("user 2".to_string()).await?;
   let r = server
     .handle_client_message(
       c1,
       ClientMessage::Text {
         dest: c2,
         content: "hello".into(),
+        attachments: Vec::new(),
       },
     )
     .await;
-  if r != &[ClientReply::Delivered] {
-    anyhow::bail!("expected a single delivered message, got {:?}", r)
-  }
+  let message_id = match r[..] {
+    [ClientReply::Delivered(id)] => id,
+    _ => anyhow::bail!("expected a single delivered message, got {:?}", r),
+  };
   let reply = server.client_poll(c2).await;
+  // the message is polled with the id its sender was given
   let expected = ClientPollReply::Message {
     src: c1,
+    srcsrv: None,
     content: "hello".into(),
+    attachments: Vec::new(),
+    message_id,
   };
   if reply != expected {
     anyhow::bail!(
@@ -127,7 +139,7 @@ async fn list_users_test<M: MessageServer>() -> anyhow::Result<()> {
   let mut usermap = HashMap::new();
   for n in 0..100_u32 {
     let username = format!("user {n}");
-    let id = server.register_local_client(username.clone()).await;
+    let id = server.register_local_client(username.clone()).await?;
     usermap.insert(id, username);
   }
   let actual = server.list_users().await;
@@ -143,9 +155,11 @@ async fn multiple_client_messages_test<M: MessageServer>() -> anyhow::Result<()>
   let sid = ServerId::default();
   let server: M = MessageServer::new(sid);
 
-  let c1 = server.register_local_client("user 1".to_string()).await;
-  let c2 = server.register_local_client("user 2".to_string()).await;
-  let c3 = server.register_local_client("user 3".to_string()).await;
+  let c1 = server.register_local_client("user 1".to_string()).await?;
+  let c2 = server.register_local_client("user 2".to_string()).await?;
+  let c3 = server.register_local_client("user 3".to_string()).await?;
+  let mut ids2 = Vec::new();
+  let mut ids3 = Vec::new();
   for i in 0..100 {
     let r = server
       .handle_client_message(
@@ -153,11 +167,13 @@ async fn multiple_client_messages_test<M: MessageServer>() -> anyhow::Result<()>
         ClientMessage::Text {
           dest: c2,
           content: i.to_string(),
+          attachments: Vec::new(),
         },
       )
       .await;
-    if r != [ClientReply::Delivered] {
-      anyhow::bail!("A> Could not deliver message {}, got {:?}", i, r);
+    match r[..] {
+      [ClientReply::Delivered(id)] => ids2.push(id),
+      _ => anyhow::bail!("A> Could not deliver message {}, got {:?}", i, r),
     }
   }
   for i in 0..100 {
@@ -170,16 +186,23 @@ async fn multiple_client_messages_test<M: MessageServer>() -> anyhow::Result<()>
         },
       )
       .await;
-    if r != [ClientReply::Delivered, ClientReply::Delivered] {
-      anyhow::bail!("B> Could not deliver message {}, got {:?}", i, r);
+    match r[..] {
+      [ClientReply::Delivered(id2), ClientReply::Delivered(id3)] => {
+        ids2.push(id2);
+        ids3.push(id3);
+      }
+      _ => anyhow::bail!("B> Could not deliver message {}, got {:?}", i, r),
     }
   }
 
-  for i in 0..200 {
+  for (i, message_id) in ids2.into_iter().enumerate() {
     let reply = server.client_poll(c2).await;
     let expected_reply = ClientPollReply::Message {
       src: c1,
+      srcsrv: None,
       content: i.to_string(),
+      attachments: Vec::new(),
+      message_id,
     };
     if reply != expected_reply {
       anyhow::bail!(
@@ -189,11 +212,14 @@ async fn multiple_client_messages_test<M: MessageServer>() -> anyhow::Result<()>
       );
     }
   }
-  for i in 100..200 {
+  for (i, message_id) in (100..200).zip(ids3) {
     let reply = server.client_poll(c3).await;
     let expected_reply = ClientPollReply::Message {
       src: c1,
+      srcsrv: None,
       content: i.to_string(),
+      attachments: Vec::new(),
+      message_id,
     };
     if reply != expected_reply {
       anyhow::bail!(
@@ -224,8 +250,8 @@ async fn mixed_results_client_message<M: MessageServer>() -> anyhow::Result<()>
   let sid = ServerId::default();
   let server: M = MessageServer::new(sid);
 
-  let c1 = server.register_local_client("user 1".to_string()).await;
-  let c2 = server.register_local_client("user 2".to_string()).await;
+  let c1 = server.register_local_client("user 1".to_string()).await?;
+  let c2 = server.register_local_client("user 2".to_string()).await?;
   let c3 = ClientId::default();
 
   let m = server
@@ -237,7 +263,7 @@ async fn mixed_results_client_message<M: MessageServer>() -> anyhow::Result<()>
       },
     )
     .await;
-  if m != [ClientReply::Delivered, ClientReply::Delayed] {
+  if !matches!(m[..], [ClientReply::Delivered(_), ClientReply::Delayed(_)]) {
     anyhow::bail!("Expected Delivered/Delayed, but got {:?}", m)
   }
   Ok(())
@@ -247,8 +273,8 @@ async fn mailbox_full<M: MessageServer>() -> anyhow::Result<()> {
   let sid = ServerId::default();
   let server: M = MessageServer::new(sid);
 
-  let c1 = server.register_local_client("user 1".to_string()).await;
-  let c2 = server.register_local_client("user 2".to_string()).await;
+  let c1 = server.register_local_client("user 1".to_string()).await?;
+  let c2 = server.register_local_client("user 2".to_string()).await?;
 
   for n in 0..MAILBOX_SIZE {
     let m = server
@@ -257,10 +283,11 @@ async fn mailbox_full<M: MessageServer>() -> anyhow::Result<()> {
         ClientMessage::Text {
           dest: c2,
           content: format!("{n}"),
+          attachments: Vec::new(),
         },
       )
       .await;
-    if m != [ClientReply::Delivered] {
+    if !matches!(m[..], [ClientReply::Delivered(_)]) {
       anyhow::bail!("Expected Delivered, but got {:?}", m)
     }
   }
@@ -270,6 +297,7 @@ async fn mailbox_full<M: MessageServer>() -> anyhow::Result<()> {
       ClientMessage::Text {
         dest: c2,
         content: "FULL".into(),
+        attachments: Vec::new(),
       },
     )
     .await;
@@ -279,12 +307,129 @@ async fn mailbox_full<M: MessageServer>() -> anyhow::Result<()> {
   Ok(())
 }
 
+async fn broadcast_system_test<M: MessageServer>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let server: M = MessageServer::new(sid);
+
+  let c1 = server.register_local_client("user 1".to_string()).await?;
+  let c2 = server.register_local_client("user 2".to_string()).await?;
+  let c3 = server.register_local_client("user 3".to_string()).await?;
+  let sent = server
+    .handle_client_message(
+      c1,
+      ClientMessage::Text {
+        dest: c2,
+        content: "hello".into(),
+        attachments: Vec::new(),
+      },
+    )
+    .await;
+  let message_id = match sent[..] {
+    [ClientReply::Delivered(id)] => id,
+    _ => anyhow::bail!("Expected a single delivery, got {:?}", sent),
+  };
+  server.broadcast_system("maintenance".to_string()).await;
+
+  let notice = ClientPollReply::System {
+    text: "maintenance".to_string(),
+  };
+  for c in [c1, c2, c3] {
+    let reply = server.client_poll(c).await;
+    if reply != notice {
+      anyhow::bail!("Expected {:?} for {}, got {:?}", notice, c, reply);
+    }
+  }
+  // regular messages are still there
+  let reply = server.client_poll(c2).await;
+  if reply
+    != (ClientPollReply::Message {
+      src: c1,
+      srcsrv: None,
+      content: "hello".into(),
+      attachments: Vec::new(),
+      message_id,
+    })
+  {
+    anyhow::bail!("Expected the pending message, got {:?}", reply);
+  }
+  Ok(())
+}
+
+async fn topics_test<M: MessageServer>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let server: M = MessageServer::new(sid);
+
+  let c1 = server.register_local_client("user 1".to_string()).await?;
+  let c2 = server.register_local_client("user 2".to_string()).await?;
+  let c3 = server.register_local_client("user 3".to_string()).await?;
+  for c in [c1, c2] {
+    if !server.subscribe(c, "news".to_string()).await {
+      anyhow::bail!("{} could not subscribe", c);
+    }
+  }
+  if server.subscribe(c1, "news".to_string()).await {
+    anyhow::bail!("Subscribing twice should return false");
+  }
+
+  let replies = server
+    .handle_client_message(
+      c1,
+      ClientMessage::Publish {
+        topic: "news".into(),
+        content: "extra!".into(),
+      },
+    )
+    .await;
+  let message_id = match replies[..] {
+    [ClientReply::Delivered(id)] => id,
+    _ => anyhow::bail!("Expected a single delivery, got {:?}", replies),
+  };
+  let expected = ClientPollReply::Message {
+    src: c1,
+    srcsrv: None,
+    content: "extra!".into(),
+    attachments: Vec::new(),
+    message_id,
+  };
+  let reply = server.client_poll(c2).await;
+  if reply != expected {
+    anyhow::bail!("Expected {:?}, got {:?}", expected, reply);
+  }
+  // neither the publisher nor non subscribers receive it
+  for c in [c1, c3] {
+    let reply = server.client_poll(c).await;
+    if reply != ClientPollReply::Nothing {
+      anyhow::bail!("Expected nothing for {}, got {:?}", c, reply);
+    }
+  }
+
+  if !server.unsubscribe(c2, "news").await {
+    anyhow::bail!("{} should have been subscribed", c2);
+  }
+  if server.unsubscribe(c3, "news").await {
+    anyhow::bail!("{} was never subscribed", c3);
+  }
+  let replies = server
+    .handle_client_message(
+      c1,
+      ClientMessage::Publish {
+        topic: "news".into(),
+        content: "nobody listens".into(),
+      },
+    )
+    .await;
+  if !replies.is_empty() {
+    anyhow::bail!("Expected no delivery, got {:?}", replies);
+  }
+  Ok(())
+}
+
 #[cfg(feature = "federation")]
 async fn message_to_outer_user<M: MessageServer>() -> anyhow::Result<()> {
   let sid = ServerId::default();
   let server: M = MessageServer::new(sid);
 
-  let c1 = server.register_local_client("user 1".to_string()).await;
+  let c1 = server.register_local_client("user 1".to_string()).await?;
   let s1 = ServerId::default();
   let s2 = ServerId::default();
   let s3 = ServerId::default();
@@ -308,6 +453,7 @@ async fn message_to_outer_user<M: MessageServer>() -> anyhow::Result<()> {
       ClientMessage::Text {
         dest: euuid,
         content: "Hello".to_string(),
+        attachments: Vec::new(),
       },
     )
     .await;
@@ -333,7 +479,7 @@ async fn message_to_outer_user_delayed<M: MessageServer>() -> anyhow::Result<()>
   let sid = ServerId::default();
   let server: M = MessageServer::new(sid);
 
-  let c1 = server.register_local_client("user 1".to_string()).await;
+  let c1 = server.register_local_client("user 1".to_string()).await?;
   let s1 = ServerId::default();
   let s2 = ServerId::default();
   let s3 = ServerId::default();
@@ -347,10 +493,11 @@ async fn message_to_outer_user_delayed<M: MessageServer>() -> anyhow::Result<()>
       ClientMessage::Text {
         dest: euuid,
         content: "Hello".to_string(),
+        attachments: Vec::new(),
       },
     )
     .await;
-  if r != [ClientReply::Delayed] {
+  if !matches!(r[..], [ClientReply::Delayed(_)]) {
     anyhow::bail!("Expected a delayed message first, but got {:?}", r);
   }
   let r = server
@@ -380,7 +527,7 @@ async fn routing_test<M: MessageServer>() -> anyhow::Result<()> {
   let sid = ServerId::default();
   let server: M = MessageServer::new(sid);
 
-  let c1 = server.register_local_client("user 1".to_string()).await;
+  let c1 = server.register_local_client("user 1".to_string()).await?;
   /* map:
 
         us - s1 - s2
@@ -411,6 +558,7 @@ async fn routing_test<M: MessageServer>() -> anyhow::Result<()> {
       ClientMessage::Text {
         dest: s4_user,
         content: "Hello".to_string(),
+        attachments: Vec::new(),
       },
     )
     .await;
@@ -442,6 +590,7 @@ async fn routing_test<M: MessageServer>() -> anyhow::Result<()> {
       ClientMessage::Text {
         dest: s4_user,
         content: "Hello 2".to_string(),
+        attachments: Vec::new(),
       },
     )
     .await;
@@ -460,7 +609,247 @@ async fn routing_test<M: MessageServer>() -> anyhow::Result<()> {
   Ok(())
 }
 
-async fn all_tests<M: MessageServer>(counter: &mut usize) -> anyhow::Result<()> {
+async fn register_names<M: MessageServer>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let server: M = MessageServer::new(sid);
+
+  let at_limit = "x".repeat(MAX_NAME_LEN);
+  let c1 = server.register_local_client(at_limit.clone()).await?;
+  let c2 = server
+    .register_local_client("  padded\t".to_string())
+    .await?;
+  let users = server.list_users().await;
+  if users.get(&c1) != Some(&at_limit) || users.get(&c2).map(|s| s.as_str()) != Some("padded") {
+    anyhow::bail!("Unexpected user list {:?}", users);
+  }
+
+  let r = server
+    .register_local_client("x".repeat(MAX_NAME_LEN + 1))
+    .await;
+  if r != Err(ClientError::NameTooLong) {
+    anyhow::bail!("Expected NameTooLong, got {:?}", r);
+  }
+  for empty in ["", " \t "] {
+    let r = server.register_local_client(empty.to_string()).await;
+    if r != Err(ClientError::EmptyName) {
+      anyhow::bail!("Expected EmptyName for {:?}, got {:?}", empty, r);
+    }
+  }
+  let r = server.register_local_client("bell\u{7}".to_string()).await;
+  if r != Err(ClientError::InvalidName) {
+    anyhow::bail!("Expected InvalidName, got {:?}", r);
+  }
+  if server.list_users().await.len() != 2 {
+    anyhow::bail!("Rejected names should not be registered");
+  }
+  Ok(())
+}
+
+async fn register_bulk<M: MessageServer>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let server: M = MessageServer::new(sid);
+
+  let names: Vec<String> = (0..50).map(|i| format!("user {}", i)).collect();
+  let ids = server
+    .register_local_clients(names)
+    .await
+    .into_iter()
+    .collect::<Result<HashSet<_>, _>>()?;
+  if ids.len() != 50 {
+    anyhow::bail!("Expected 50 distinct ids, got {}", ids.len());
+  }
+
+  let r = server
+    .register_local_clients(vec![
+      "fresh 1".to_string(),
+      "user 7".to_string(),
+      "fresh 2".to_string(),
+      "fresh 2".to_string(),
+    ])
+    .await;
+  let rejected: Vec<_> = r.iter().map(|x| x.as_ref().err()).collect();
+  if rejected
+    != [
+      None,
+      Some(&ClientError::NameTaken),
+      None,
+      Some(&ClientError::NameTaken),
+    ]
+  {
+    anyhow::bail!("Unexpected bulk registration result {:?}", r);
+  }
+  let r = server.register_local_client("fresh 1".to_string()).await;
+  if r != Err(ClientError::NameTaken) {
+    anyhow::bail!("Expected NameTaken, got {:?}", r);
+  }
+  if server.list_users().await.len() != 52 {
+    anyhow::bail!("Expected 52 users");
+  }
+  Ok(())
+}
+
+#[cfg(feature = "federation")]
+async fn message_from_outer_user_ack<M: MessageServer>() -> anyhow::Result<()> {
+  let sid = ServerId::default();
+  let server: M = MessageServer::new(sid);
+
+  let c1 = server.register_local_client("user 1".to_string()).await?;
+  let s1 = ServerId::default();
+  let euuid = ClientId::default();
+
+  let r = server
+    .handle_server_message(ServerMessage::Message(FullyQualifiedMessage {
+      src: euuid,
+      srcsrv: s1,
+      dsts: vec![(c1, sid)],
+      content: "Hello".to_string(),
+    }))
+    .await;
+  if r != ServerReply::Ack {
+    anyhow::bail!("Expected Ack, got {:?}", r);
+  }
+  let reply = server.client_poll(c1).await;
+  // the id of a message that came from another server is only known to its recipient
+  let message_id = match reply {
+    ClientPollReply::Message { message_id, .. } => message_id,
+    _ => 0,
+  };
+  let expected = ClientPollReply::Message {
+    src: euuid,
+    srcsrv: Some(s1),
+    content: "Hello".to_string(),
+    attachments: Vec::new(),
+    message_id,
+  };
+  if reply != expected {
+    anyhow::bail!("Expected {:?}\n,    got {:?}", expected, reply);
+  }
+  Ok(())
+}
+
+/// a backend that does nothing, every stage of the suite fails against it
+pub struct Stub;
+
+#[async_trait]
+impl MessageServer for Stub {
+  const GROUP_NAME: &'static str = "stub";
+
+  #[cfg(feature = "debug-snapshot")]
+  type ServerState = ();
+
+  fn new(_id: ServerId) -> Self {
+    Stub
+  }
+  fn id(&self) -> ServerId {
+    ServerId::default()
+  }
+  async fn register_local_client(&self, _name: String) -> Result<ClientId, ClientError> {
+    Err(ClientError::InternalError)
+  }
+  async fn register_local_client_from(
+    &self,
+    _nonce: ClientId,
+    _name: String,
+  ) -> Result<ClientId, ClientError> {
+    Err(ClientError::InternalError)
+  }
+  async fn register_and_message(
+    &self,
+    _nonce: ClientId,
+    _name: String,
+    _msg: ClientMessage,
+  ) -> Result<(ClientId, Vec<ClientReply>), ClientError> {
+    Err(ClientError::InternalError)
+  }
+  async fn unregister_local_client(&self, _client: ClientId) -> bool {
+    false
+  }
+  async fn register_local_clients(&self, names: Vec<String>) -> Vec<Result<ClientId, ClientError>> {
+    names
+      .iter()
+      .map(|_| Err(ClientError::InternalError))
+      .collect()
+  }
+  async fn list_users(&self) -> HashMap<ClientId, String> {
+    HashMap::new()
+  }
+  async fn home_server(&self, _client: ClientId) -> Option<ServerId> {
+    None
+  }
+  async fn reachable_clients(&self) -> HashMap<ClientId, ClientLocation> {
+    HashMap::new()
+  }
+  async fn handle_sequenced_message<A: Send>(
+    &self,
+    _msg: Sequence<A>,
+    _peer: Option<SocketAddr>,
+  ) -> Result<A, ClientError> {
+    Err(ClientError::InternalError)
+  }
+  async fn bind_address(&self, _client: ClientId, _addr: SocketAddr) {}
+  async fn heartbeat(&self, _client: ClientId, _peer: Option<SocketAddr>) -> bool {
+    false
+  }
+  async fn metrics(&self) -> Metrics {
+    Metrics {
+      uptime: Duration::ZERO,
+      clients: 0,
+      delivered: 0,
+      errors: 0,
+      pending_transfers: 0,
+    }
+  }
+  async fn reset_counters(&self) {}
+  async fn top_senders(&self, _n: usize) -> Vec<(ClientId, u64)> {
+    Vec::new()
+  }
+  async fn last_accepted_seqid(&self, _client: ClientId) -> Option<u128> {
+    None
+  }
+  async fn ack_status(&self, _client: ClientId) -> Option<AckStatus> {
+    None
+  }
+  async fn client_poll(&self, _client: ClientId) -> ClientPollReply {
+    ClientPollReply::Nothing
+  }
+  fn try_client_poll(&self, _client: ClientId) -> Option<ClientPollReply> {
+    None
+  }
+  async fn set_delivery_order(&self, _client: ClientId, _order: DeliveryOrder) -> bool {
+    false
+  }
+  async fn set_delivery(&self, _client: ClientId, _enabled: bool) -> bool {
+    false
+  }
+  async fn broadcast_system(&self, _text: String) {}
+  async fn handle_client_message(&self, _src: ClientId, _msg: ClientMessage) -> Vec<ClientReply> {
+    Vec::new()
+  }
+  async fn recall(&self, _src: ClientId, _message_id: u128) -> bool {
+    false
+  }
+  async fn subscribe(&self, _client: ClientId, _topic: String) -> bool {
+    false
+  }
+  async fn unsubscribe(&self, _client: ClientId, _topic: &str) -> bool {
+    false
+  }
+  #[cfg(feature = "debug-snapshot")]
+  async fn debug_snapshot(&self) {}
+  #[cfg(feature = "debug-snapshot")]
+  async fn debug_restore(&self, _state: ()) {}
+  #[cfg(feature = "federation")]
+  async fn handle_server_message(&self, _msg: ServerMessage) -> ServerReply {
+    ServerReply::EmptyRoute
+  }
+  #[cfg(feature = "federation")]
+  async fn route_to(&self, _destination: ServerId) -> Option<Vec<ServerId>> {
+    None
+  }
+}
+
+/// runs the whole suite against `M`, `counter` being the number of stages that passed
+pub async fn all_tests<M: MessageServer>(counter: &mut usize) -> anyhow::Result<()> {
   sequence_correct::<M>()
     .await
     .with_context(|| "sequence_correct")?;
@@ -497,6 +886,20 @@ async fn all_tests<M: MessageServer>(counter: &mut usize) -> anyhow::Result<()>
   *counter += 1;
   mailbox_full::<M>().await.with_context(|| "mailbox_full")?;
   *counter += 1;
+  register_names::<M>()
+    .await
+    .with_context(|| "register_names")?;
+  *counter += 1;
+  register_bulk::<M>()
+    .await
+    .with_context(|| "register_bulk")?;
+  *counter += 1;
+  broadcast_system_test::<M>()
+    .await
+    .with_context(|| "broadcast_system_test")?;
+  *counter += 1;
+  topics_test::<M>().await.with_context(|| "topics_test")?;
+  *counter += 1;
   #[cfg(feature = "federation")]
   {
     message_to_outer_user::<M>()
@@ -509,10 +912,15 @@ async fn all_tests<M: MessageServer>(counter: &mut usize) -> anyhow::Result<()>
     *counter += 1;
     routing_test::<M>().await.with_context(|| "routing")?;
     *counter += 1;
+    message_from_outer_user_ack::<M>()
+      .await
+      .with_context(|| "message_from_outer_user_ack")?;
+    *counter += 1;
   }
   Ok(())
 }
 
+#[cfg(test)]
 pub(crate) fn test_message_server<M: MessageServer>() {
   pretty_env_logger::init();
   async_std::task::block_on(async {
@@ -1,9 +1,19 @@
 use byteorder::{LittleEndian, WriteBytesExt};
 use crypto_hash::{digest, Algorithm, Hasher};
 
+use crate::messages::WorkproofNonce;
+
 const LOOPS: usize = 16;
 
+#[cfg(test)]
+thread_local! {
+    // number of times the hash was computed on the current thread
+    pub(crate) static HASH_COUNT: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
 fn hashing(nonce: u128, start: u128) -> Vec<u8> {
+    #[cfg(test)]
+    HASH_COUNT.with(|c| c.set(c.get() + 1));
     let mut hasher = Hasher::new(Algorithm::SHA1);
     hasher.write_u128::<LittleEndian>(nonce).unwrap();
     hasher.write_u128::<LittleEndian>(start).unwrap();
@@ -27,18 +37,19 @@ fn get_leading(bytes: &[u8]) -> u32 {
     zeros
 }
 
-pub fn verify_workproof(nonce: u128, start: u128, strength: u32) -> bool {
-    let hashed = hashing(nonce, start);
+pub fn verify_workproof(nonce: WorkproofNonce, start: u128, strength: u32) -> bool {
+    let hashed = hashing(nonce.into(), start);
     get_leading(&hashed) >= strength
 }
 
-pub fn gen_workproof(nonce: u128, strength: u32, limit: u128) -> Option<u128> {
+pub fn gen_workproof(nonce: WorkproofNonce, strength: u32, limit: u128) -> Option<u128> {
     (0..limit).find(|&start| verify_workproof(nonce, start, strength))
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::messages::ClientId;
 
     #[test]
     fn leading_zeroes() {
@@ -52,11 +63,22 @@ mod test {
 
     #[test]
     fn find_workproof_easy() {
-        assert_eq!(gen_workproof(161566988, 8, u128::MAX), Some(186));
+        assert_eq!(gen_workproof(161566988.into(), 8, u128::MAX), Some(186));
     }
 
     #[test]
     fn find_workproof_impossible() {
-        assert_eq!(gen_workproof(161566988, 8, 100), None);
+        assert_eq!(gen_workproof(161566988.into(), 8, 100), None);
+    }
+
+    #[test]
+    fn client_nonce() {
+        let client = ClientId::default();
+        let nonce = WorkproofNonce::from(&client);
+        assert_eq!(ClientId::from(nonce), client);
+        assert_eq!(WorkproofNonce::from_bytes(nonce.to_bytes()), nonce);
+
+        let proof = gen_workproof(nonce, 8, u128::MAX).unwrap();
+        assert!(verify_workproof((&client).into(), proof, 8));
     }
 }
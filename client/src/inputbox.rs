@@ -1,8 +1,17 @@
 /// abstract input box implementation
 
+/// number of submitted lines kept in the history
+pub const HISTORY_SIZE: usize = 100;
+
 pub struct IBox {
   input: String,
   cursor_position: usize,
+  /// submitted lines, oldest first
+  history: Vec<String>,
+  /// the history entry being shown, None when editing a new line
+  browsing: Option<usize>,
+  /// the new line, kept while browsing the history
+  draft: String,
 }
 
 impl IBox {
@@ -10,6 +19,9 @@ impl IBox {
     Self {
       input: String::new(),
       cursor_position: 0,
+      history: Vec::new(),
+      browsing: None,
+      draft: String::new(),
     }
   }
 
@@ -51,6 +63,88 @@ impl IBox {
     }
   }
 
+  /// deletes the character under the cursor
+  pub fn delete_char_forward(&mut self) {
+    if self.cursor_position < self.input.chars().count() {
+      self.move_cursor_right();
+      self.delete_char();
+    }
+  }
+
+  /// deletes everything before the cursor
+  pub fn kill_to_start(&mut self) {
+    self.input = self.input.chars().skip(self.cursor_position).collect();
+    self.cursor_position = 0;
+  }
+
+  /// deletes everything from the cursor to the end of the line
+  pub fn kill_to_end(&mut self) {
+    self.input = self.input.chars().take(self.cursor_position).collect();
+  }
+
+  /// deletes the word before the cursor, along with the whitespace between them
+  pub fn delete_word(&mut self) {
+    let chars: Vec<char> = self.input.chars().collect();
+    let end = self.cursor_position.min(chars.len());
+    let mut start = end;
+    while start > 0 && chars[start - 1].is_whitespace() {
+      start -= 1;
+    }
+    while start > 0 && !chars[start - 1].is_whitespace() {
+      start -= 1;
+    }
+    self.input = chars[..start].iter().chain(&chars[end..]).collect();
+    self.cursor_position = start;
+  }
+
+  /// shows the previous line of the history, the line being edited is kept for `history_next`
+  pub fn history_prev(&mut self) {
+    let pos = match self.browsing {
+      None if self.history.is_empty() => return,
+      None => {
+        self.draft = self.input.clone();
+        self.history.len() - 1
+      }
+      Some(0) => return,
+      Some(pos) => pos - 1,
+    };
+    self.browsing = Some(pos);
+    self.set_line(self.history[pos].clone());
+  }
+
+  /// shows the next line of the history, or the line that was being edited after the last one
+  pub fn history_next(&mut self) {
+    match self.browsing {
+      None => (),
+      Some(pos) if pos + 1 < self.history.len() => {
+        self.browsing = Some(pos + 1);
+        self.set_line(self.history[pos + 1].clone());
+      }
+      Some(_) => {
+        self.browsing = None;
+        let draft = std::mem::take(&mut self.draft);
+        self.set_line(draft);
+      }
+    }
+  }
+
+  /// records the line in the history, and clears it
+  /// blank lines and repeats of the previous line are not recorded
+  pub fn commit(&mut self) {
+    if !self.input.trim().is_empty() && self.history.last() != Some(&self.input) {
+      if self.history.len() >= HISTORY_SIZE {
+        self.history.remove(0);
+      }
+      self.history.push(std::mem::take(&mut self.input));
+    }
+    self.reset();
+  }
+
+  fn set_line(&mut self, line: String) {
+    self.input = line;
+    self.move_cursor_end();
+  }
+
   pub fn clamp_cursor(&self, new_cursor_pos: usize) -> usize {
     new_cursor_pos.clamp(0, self.input.len())
   }
@@ -59,6 +153,10 @@ impl IBox {
     self.cursor_position = 0;
   }
 
+  pub fn move_cursor_end(&mut self) {
+    self.cursor_position = self.input.chars().count();
+  }
+
   pub fn message(&self) -> &str {
     &self.input
   }
@@ -66,6 +164,7 @@ impl IBox {
   pub fn reset(&mut self) {
     self.input = String::new();
     self.cursor_position = 0;
+    self.browsing = None;
   }
 
   pub fn cursor_pos(&self) -> u16 {
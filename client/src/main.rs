@@ -1,14 +1,21 @@
+use anyhow::Context;
 use async_std::channel::{Receiver, Sender};
-use async_std::net::UdpSocket;
+use async_std::io::prelude::{BufReadExt, ReadExt, WriteExt};
+use async_std::io::BufReader;
+use async_std::net::{TcpStream, UdpSocket};
+use async_std::stream::StreamExt;
 use async_std::sync::RwLock;
-use chatproto::client::Client;
-use chatproto::core::WORKPROOF_STRENGTH;
+use async_trait::async_trait;
+use chatproto::client::{Client, PollSource};
+use chatproto::core::{now_millis, validate_name, MAX_USER_PAGE, WORKPROOF_STRENGTH};
 use chatproto::messages::{
-  ClientId, ClientMessage, ClientPollReply, ClientQuery, ClientReply, Sequence,
+  ClientError, ClientId, ClientMessage, ClientPollReply, ClientQuery, ClientReply, Request,
+  Sequence, ServerId,
 };
-use chatproto::netproto::{decode, encode};
-use chatproto::workproof::gen_workproof;
-use crossterm::event::KeyEventKind;
+use chatproto::netproto::frame::{self, MAX_FRAMED_LEN};
+use chatproto::netproto::{decode, encode, TransportKind};
+use chatproto::ratelimit::{LogLimiter, LOG_WINDOW};
+use crossterm::event::{KeyEvent, KeyEventKind, KeyModifiers};
 use crossterm::{
   event::{DisableMouseCapture, EnableMouseCapture, KeyCode},
   execute,
@@ -26,9 +33,12 @@ use ratatui::{
   widgets::{Block, Borders},
   Terminal,
 };
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, VecDeque};
 use std::io::Cursor;
 use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use structopt::StructOpt;
 
 mod inputbox;
@@ -46,34 +56,727 @@ struct Opt {
   #[structopt(long, default_value = "127.0.0.1")]
   /// address to connect to
   host: IpAddr,
+
+  #[structopt(long, default_value = "1000")]
+  /// milliseconds to wait for the registration reply before sending it again
+  register_timeout: u64,
+
+  #[structopt(long, default_value = "5")]
+  /// number of times the registration is sent before giving up
+  register_attempts: u32,
+
+  #[structopt(long, default_value = "1000")]
+  /// milliseconds to wait for the reply to a query before sending it again, doubled each time
+  query_timeout: u64,
+
+  #[structopt(long, default_value = "3")]
+  /// number of times a query is sent before giving up
+  query_attempts: u32,
+
+  #[structopt(long, default_value = "8")]
+  /// maximum number of queries waiting for their reply at the same time
+  max_inflight: usize,
+
+  #[structopt(long, default_value = "10")]
+  /// number of undecodable datagrams logged per second, the others are only counted
+  decode_log_limit: usize,
+
+  #[structopt(long)]
+  /// reads the commands from the standard input, one per line, instead of showing the UI
+  /// no user can be selected, so messages are sent with `/msg <names> <text>`, and the errors and
+  /// incoming messages are printed
+  no_interactive: bool,
+
+  #[structopt(long, default_value = "udp")]
+  /// how to reach the server, udp, tcp, or chunked for udp with messages larger than a datagram
+  transport: TransportKind,
+
+  #[structopt(long, default_value = "5")]
+  /// messages to more recipients than this are only sent once confirmed with /yes
+  confirm_threshold: usize,
+
+  #[structopt(long)]
+  /// send the messages to many recipients without asking for a confirmation
+  yes: bool,
+}
+
+// a connection to the server, that sends and receives whole messages
+#[async_trait]
+trait Transport {
+  async fn send(&self, buf: &[u8]) -> std::io::Result<usize>;
+  async fn recv(&self, buf: &mut [u8]) -> std::io::Result<usize>;
+}
+
+#[async_trait]
+impl Transport for UdpSocket {
+  async fn send(&self, buf: &[u8]) -> std::io::Result<usize> {
+    UdpSocket::send(self, buf).await
+  }
+
+  async fn recv(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+    UdpSocket::recv(self, buf).await
+  }
 }
 
-struct Network {
+// a stream to the server, each message being preceded by its length
+struct TcpTransport {
+  stream: TcpStream,
+  // a message is only sent in one go
+  writer: async_std::sync::Mutex<()>,
+  // what was read of the messages that are not whole yet
+  pending: async_std::sync::Mutex<Vec<u8>>,
+}
+
+impl TcpTransport {
+  async fn connect(target: SocketAddr) -> std::io::Result<Self> {
+    let stream = TcpStream::connect(target).await?;
+    stream.set_nodelay(true)?;
+    Ok(TcpTransport {
+      stream,
+      writer: async_std::sync::Mutex::new(()),
+      pending: async_std::sync::Mutex::new(Vec::new()),
+    })
+  }
+}
+
+#[async_trait]
+impl Transport for TcpTransport {
+  async fn send(&self, buf: &[u8]) -> std::io::Result<usize> {
+    let _writer = self.writer.lock().await;
+    (&self.stream).write_all(&frame::delimit(buf)).await?;
+    Ok(buf.len())
+  }
+
+  // the bytes are only consumed once a whole message is read, so that a receive can be abandoned
+  // like a datagram that is too large, a message that does not fit in `buf` is truncated
+  async fn recv(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut pending = self.pending.lock().await;
+    let mut chunk = [0u8; 4096];
+    loop {
+      let found = frame::delimited(&pending, MAX_FRAMED_LEN)
+        .map_err(|rr| std::io::Error::new(std::io::ErrorKind::InvalidData, rr))?;
+      if let Some((header, len)) = found {
+        let n = len.min(buf.len());
+        buf[..n].copy_from_slice(&pending[header..header + n]);
+        pending.drain(..header + len);
+        return Ok(n);
+      }
+      let n = (&self.stream).read(&mut chunk).await?;
+      if n == 0 {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+      }
+      pending.extend_from_slice(&chunk[..n]);
+    }
+  }
+}
+
+// datagrams carrying the chunks of the messages, that can then be larger than a datagram
+struct ChunkedTransport {
   socket: UdpSocket,
+  server: SocketAddr,
+  last_id: AtomicU64,
+  // kept across receives, so that a receive can be abandoned without losing the chunks
+  reassembler: async_std::sync::Mutex<frame::Reassembler>,
+}
+
+impl ChunkedTransport {
+  fn new(socket: UdpSocket, server: SocketAddr) -> Self {
+    ChunkedTransport {
+      socket,
+      server,
+      last_id: AtomicU64::new(0),
+      reassembler: async_std::sync::Mutex::new(frame::Reassembler::new(CHUNK_TIMEOUT)),
+    }
+  }
+}
+
+#[async_trait]
+impl Transport for ChunkedTransport {
+  async fn send(&self, buf: &[u8]) -> std::io::Result<usize> {
+    let id = self.last_id.fetch_add(1, Ordering::Relaxed) + 1;
+    let datagrams = frame::split(id, buf, frame::CHUNK_DATAGRAM_LEN)
+      .map_err(|rr| std::io::Error::new(std::io::ErrorKind::InvalidInput, rr))?;
+    for datagram in datagrams {
+      self.socket.send(&datagram).await?;
+    }
+    Ok(buf.len())
+  }
+
+  // like a datagram that is too large, a message that does not fit in `buf` is truncated
+  async fn recv(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut reassembler = self.reassembler.lock().await;
+    let mut datagram = [0u8; frame::CHUNK_DATAGRAM_LEN];
+    loop {
+      let n = self.socket.recv(&mut datagram).await?;
+      let now = Instant::now();
+      reassembler.expire(now);
+      let pushed = frame::read_chunk(&datagram[..n])
+        .and_then(|chunk| reassembler.push(self.server, chunk, now));
+      match pushed {
+        Ok(Some(payload)) => {
+          let n = payload.len().min(buf.len());
+          buf[..n].copy_from_slice(&payload[..n]);
+          return Ok(n);
+        }
+        Ok(None) => (),
+        Err(rr) => log::debug!("dropping a chunk: {}", rr),
+      }
+    }
+  }
+}
+
+type AnyTransport = Box<dyn Transport + Send + Sync>;
+
+#[async_trait]
+impl Transport for AnyTransport {
+  async fn send(&self, buf: &[u8]) -> std::io::Result<usize> {
+    self.as_ref().send(buf).await
+  }
+
+  async fn recv(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+    self.as_ref().recv(buf).await
+  }
+}
+
+// how a query is sent again when its reply does not arrive
+// the very same datagram is retransmitted, so the sequence id and the request id do not change
+#[derive(Clone, Copy, Debug)]
+struct RetryPolicy {
+  attempts: u32,
+  // time waited for the reply to the first attempt, doubled after each attempt
+  timeout: Duration,
+}
+
+impl Default for RetryPolicy {
+  fn default() -> Self {
+    Self {
+      attempts: 3,
+      timeout: Duration::from_secs(1),
+    }
+  }
+}
+
+struct Network<T = AnyTransport> {
+  socket: T,
+  retry: RetryPolicy,
+  next_request_id: AtomicU64,
+  // one token per query in flight, a query waits until it can push its own
+  slots: (Sender<()>, Receiver<()>),
+  // requests waiting for their reply, with the reply if another query received it for them
+  waiting: std::sync::Mutex<HashMap<u64, Option<Vec<u8>>>>,
+  // only one query reads from the socket at a time
+  reader: async_std::sync::Mutex<()>,
+  // undecodable datagrams are dropped, and only some of them are logged
+  decode_errors: std::sync::Mutex<LogLimiter>,
+  // largest reply received
+  recv_buffer: usize,
 }
 
 impl Network {
-  async fn new(target: SocketAddr) -> anyhow::Result<Self> {
-    let socket = UdpSocket::bind("127.0.0.1:0").await?;
-    socket.connect(target).await?;
-    Ok(Self { socket })
+  async fn new(
+    transport: TransportKind,
+    target: SocketAddr,
+    max_inflight: usize,
+  ) -> anyhow::Result<Self> {
+    let socket: AnyTransport = match transport {
+      TransportKind::Udp => {
+        let socket = UdpSocket::bind("127.0.0.1:0").await?;
+        socket.connect(target).await?;
+        Box::new(socket)
+      }
+      TransportKind::Tcp => Box::new(TcpTransport::connect(target).await?),
+      TransportKind::Chunked => {
+        let socket = UdpSocket::bind("127.0.0.1:0").await?;
+        socket.connect(target).await?;
+        Box::new(ChunkedTransport::new(socket, target))
+      }
+    };
+    let network = Self::with_transport(socket, max_inflight);
+    Ok(match transport {
+      TransportKind::Udp => network,
+      // a stream loses nothing, a query sent again would only be a duplicate
+      TransportKind::Tcp => network
+        .with_recv_buffer(MAX_FRAMED_LEN)
+        .with_retry(RetryPolicy {
+          attempts: 1,
+          timeout: MAX_RETRY_DELAY,
+        }),
+      // the replies are not bounded by the size of a datagram
+      TransportKind::Chunked => network.with_recv_buffer(MAX_FRAMED_LEN),
+    })
+  }
+}
+
+// a query slot, given back when the query is done or abandoned
+struct Slot<'a>(&'a Receiver<()>);
+
+impl Drop for Slot<'_> {
+  fn drop(&mut self) {
+    let _ = self.0.try_recv();
   }
+}
+
+// removes the request from the waiting list when its reply was received, or when it is abandoned
+struct Waiting<'a> {
+  waiting: &'a std::sync::Mutex<HashMap<u64, Option<Vec<u8>>>>,
+  request_id: u64,
+}
 
-  async fn send(&self, sq: &Sequence<ClientQuery>) -> anyhow::Result<()> {
+impl Drop for Waiting<'_> {
+  fn drop(&mut self) {
+    self.waiting.lock().unwrap().remove(&self.request_id);
+  }
+}
+
+impl<T: Transport> Network<T> {
+  fn with_transport(socket: T, max_inflight: usize) -> Self {
+    Self {
+      socket,
+      retry: RetryPolicy::default(),
+      next_request_id: AtomicU64::new(0),
+      slots: async_std::channel::bounded(max_inflight.max(1)),
+      waiting: std::sync::Mutex::new(HashMap::new()),
+      reader: async_std::sync::Mutex::new(()),
+      decode_errors: std::sync::Mutex::new(LogLimiter::new(10, LOG_WINDOW)),
+      recv_buffer: RECV_BUFFER,
+    }
+  }
+
+  fn with_recv_buffer(mut self, recv_buffer: usize) -> Self {
+    self.recv_buffer = recv_buffer;
+    self
+  }
+
+  fn with_decode_log_limit(self, limit: usize) -> Self {
+    *self.decode_errors.lock().unwrap() = LogLimiter::new(limit, LOG_WINDOW);
+    self
+  }
+
+  fn with_retry(mut self, retry: RetryPolicy) -> Self {
+    self.retry = retry;
+    self
+  }
+
+  // sends the query, and waits for the reply carrying the same request id, sending it again
+  // according to the retry policy
+  // at most `max_inflight` queries wait for their reply at the same time, the others wait for a slot
+  async fn query<X, F>(&self, sq: Sequence<ClientQuery>, f: F) -> anyhow::Result<X>
+  where
+    F: FnOnce(&mut Cursor<Vec<u8>>) -> anyhow::Result<X>,
+  {
+    let _slot = self.slot().await?;
+    let mut payload = self.exchange(sq, self.retry).await?;
+    f(&mut payload)
+  }
+
+  // waits until fewer than `max_inflight` queries wait for their reply
+  async fn slot(&self) -> anyhow::Result<Slot<'_>> {
+    self.slots.0.send(()).await?;
+    Ok(Slot(&self.slots.1))
+  }
+
+  fn max_inflight(&self) -> usize {
+    self.slots.0.capacity().unwrap_or(1)
+  }
+
+  // sends the query until its reply arrives, and returns the payload of the reply
+  async fn exchange(
+    &self,
+    sq: Sequence<ClientQuery>,
+    retry: RetryPolicy,
+  ) -> anyhow::Result<Cursor<Vec<u8>>> {
+    let (request_id, datagram) = self.request(sq)?;
+    let mut timeout = retry.timeout;
+    for attempt in 1..=retry.attempts {
+      self.transmit(request_id, &datagram).await?;
+      match async_std::future::timeout(timeout, self.reply_payload(request_id)).await {
+        Ok(payload) => return payload,
+        Err(_) => log::warn!(
+          "no reply to request {} (attempt {}/{})",
+          request_id,
+          attempt,
+          retry.attempts
+        ),
+      }
+      timeout = (timeout * 2).min(MAX_RETRY_DELAY);
+    }
+    Err(NoReply(retry.attempts).into())
+  }
+
+  // sends the query, and returns its request id
+  // the request must then be waited for with `reply`
+  async fn send(&self, sq: Sequence<ClientQuery>) -> std::io::Result<u64> {
+    let (request_id, datagram) = self.request(sq)?;
+    self.transmit(request_id, &datagram).await?;
+    Ok(request_id)
+  }
+
+  // encodes the query with a new request id
+  fn request(&self, sq: Sequence<ClientQuery>) -> std::io::Result<(u64, Vec<u8>)> {
+    let request_id = self.next_request_id.fetch_add(1, Ordering::Relaxed);
     let mut wr = Cursor::new(Vec::new());
-    encode::sequence(&mut wr, sq, encode::client_query)?;
-    self.socket.send(&wr.into_inner()).await?;
+    encode::request(
+      &mut wr,
+      &Request {
+        request_id,
+        sequence: sq,
+      },
+      encode::client_query,
+    )?;
+    Ok((request_id, wr.into_inner()))
+  }
+
+  async fn transmit(&self, request_id: u64, datagram: &[u8]) -> std::io::Result<()> {
+    // registered before sending, the reply might be received by another query
+    self.waiting.lock().unwrap().insert(request_id, None);
+    if let Err(rr) = self.socket.send(datagram).await {
+      self.waiting.lock().unwrap().remove(&request_id);
+      return Err(rr);
+    }
     Ok(())
   }
 
-  async fn get<X, F>(&self, f: F) -> anyhow::Result<X>
+  // takes the reply another query received on our behalf
+  fn stashed(&self, request_id: u64) -> Option<Vec<u8>> {
+    self
+      .waiting
+      .lock()
+      .unwrap()
+      .get_mut(&request_id)
+      .and_then(Option::take)
+  }
+
+  async fn reply<X, F>(&self, request_id: u64, f: F) -> anyhow::Result<X>
   where
     F: FnOnce(&mut Cursor<Vec<u8>>) -> anyhow::Result<X>,
   {
-    let mut buf = vec![0u8; 8192];
-    let n = self.socket.recv(&mut buf).await?;
-    let mut cursor = Cursor::new(buf[..n].to_vec());
-    f(&mut cursor)
+    f(&mut self.reply_payload(request_id).await?)
+  }
+
+  // waits for the reply carrying the given request id, and returns it positioned on its payload
+  // replies to other waiting requests are kept for them, the others (late or duplicated datagrams)
+  // are dropped
+  async fn reply_payload(&self, request_id: u64) -> anyhow::Result<Cursor<Vec<u8>>> {
+    let _waiting = Waiting {
+      waiting: &self.waiting,
+      request_id,
+    };
+    let mut buf = vec![0u8; self.recv_buffer];
+    loop {
+      let datagram = {
+        let _reader = self.reader.lock().await;
+        // the reply might have been received while waiting for the socket
+        match self.stashed(request_id) {
+          Some(datagram) => datagram,
+          None => {
+            let n = self.socket.recv(&mut buf).await?;
+            buf[..n].to_vec()
+          }
+        }
+      };
+      let mut cursor = Cursor::new(datagram);
+      // only decode the header, the cursor is then left at the start of the payload
+      let header = match decode::reply_header(&mut cursor) {
+        Ok(header) => header,
+        Err(rr) => {
+          let mut decode_errors = self.decode_errors.lock().unwrap();
+          decode_errors.log("undecodable replies", || {
+            log::error!("could not decode a reply: {}", rr)
+          });
+          continue;
+        }
+      };
+      if header.request_id == request_id {
+        // the ClientError of a failed query can be told apart by the caller
+        header.payload?;
+        return Ok(cursor);
+      }
+      let stashed = match self.waiting.lock().unwrap().get_mut(&header.request_id) {
+        Some(stash) => {
+          *stash = Some(cursor.into_inner());
+          true
+        }
+        None => false,
+      };
+      if stashed {
+        // lets the query waiting for the socket pick its reply up
+        async_std::task::yield_now().await;
+      } else {
+        log::debug!(
+          "dropping reply to request {} while waiting for {}",
+          header.request_id,
+          request_id
+        );
+      }
+    }
+  }
+}
+
+// a query that got no reply after all its attempts
+#[derive(Debug)]
+struct NoReply(u32);
+
+impl std::fmt::Display for NoReply {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "no reply after {} attempts", self.0)
+  }
+}
+
+impl std::error::Error for NoReply {}
+
+// a busy server, or a query without a reply, is reported and the query can be sent again later
+// the other errors end the network task
+async fn report_temporary(what: &str, rr: anyhow::Error) -> anyhow::Result<()> {
+  if rr.downcast_ref() == Some(&ClientError::ServerBusy) || rr.is::<NoReply>() {
+    ERRORS.write().await.push(format!("[{}] {}", what, rr));
+    return Ok(());
+  }
+  Err(rr)
+}
+
+// sends the registration until a reply arrives, the same sequence is retransmitted so that the
+// server recognizes it, and does not register the client twice
+async fn register<T: Transport>(
+  network: &Network<T>,
+  sq: Sequence<ClientQuery>,
+  timeout: Duration,
+  attempts: u32,
+) -> anyhow::Result<ClientId> {
+  let retry = RetryPolicy { attempts, timeout };
+  let mut payload = network
+    .exchange(sq, retry)
+    .await
+    .context("registration failed")?;
+  decode::clientid(&mut payload)
+}
+
+// delay before retrying to send, doubled after each failure
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+// after this long without a reply, a message is checked with the ack status of the server
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+// size of the buffer replies are received in over udp
+const RECV_BUFFER: usize = 8192;
+// how long the chunks of a reply are kept while the others are missing
+const CHUNK_TIMEOUT: Duration = Duration::from_secs(5);
+
+// messages that could not be sent yet, in order
+// they are sequenced when they are sent, a sequence id taken when they were queued would be
+// rejected by the server once a later query, such as a poll, is accepted
+// each message is queued with its recipients, the server replies for each of them in order
+struct Outbox {
+  pending: VecDeque<(Vec<ClientId>, ClientQuery)>,
+  failures: u32,
+  next_attempt: Instant,
+  delay: Duration,
+  // messages that were sent, but got no reply or the server was too busy to handle them
+  unacked: Vec<(Vec<ClientId>, Sequence<ClientQuery>)>,
+  reply_timeout: Duration,
+}
+
+impl Outbox {
+  fn new(delay: Duration) -> Self {
+    Self {
+      pending: VecDeque::new(),
+      failures: 0,
+      next_attempt: Instant::now(),
+      delay,
+      unacked: Vec::new(),
+      reply_timeout: REPLY_TIMEOUT,
+    }
+  }
+
+  // queues the message behind the ones waiting for a retry, so that order is kept
+  async fn send<T: Transport>(
+    &mut self,
+    network: &Network<T>,
+    client: &mut Client,
+    targets: Vec<ClientId>,
+    query: ClientQuery,
+  ) -> anyhow::Result<()> {
+    self.pending.push_back((targets, query));
+    if self.failures == 0 && self.unacked.is_empty() {
+      self.flush(network, client).await?;
+    }
+    Ok(())
+  }
+
+  // the messages without a reply are sent again before the pending ones, so that order is kept
+  fn is_due(&self) -> bool {
+    !self.pending.is_empty() && self.unacked.is_empty() && Instant::now() >= self.next_attempt
+  }
+
+  // sends the pending messages in order, and stops at the first one that can't be sent
+  // up to `max_inflight` messages are sent before waiting for the reply to the oldest one
+  // nothing more is sent once a message is not handled, it waits for the ack status with the
+  // messages sent behind it, and they are all sent again in order
+  // only errors that are not send failures are returned
+  async fn flush<T: Transport>(
+    &mut self,
+    network: &Network<T>,
+    client: &mut Client,
+  ) -> anyhow::Result<()> {
+    let mut inflight = VecDeque::new();
+    let mut stopped = false;
+    loop {
+      while !stopped && inflight.len() < network.max_inflight() {
+        let (targets, query) = match self.pending.front() {
+          Some(message) => message,
+          None => break,
+        };
+        let slot = network.slot().await?;
+        let sq = client.sequence(query.clone());
+        match network.send(sq.clone()).await {
+          Ok(request_id) => {
+            let (targets, _) = self.pending.pop_front().unwrap();
+            self.failures = 0;
+            inflight.push_back((targets, sq, request_id, slot));
+          }
+          Err(rr) => {
+            let error = format!(
+              "message to {} not sent ({} attempts): {}",
+              recipients(targets),
+              self.failures + 1,
+              rr
+            );
+            self.back_off();
+            ERRORS.write().await.push(error);
+            stopped = true;
+          }
+        }
+      }
+      let (targets, sq, request_id, _slot) = match inflight.pop_front() {
+        Some(message) => message,
+        None => break,
+      };
+      let reply = network.reply(request_id, decode::client_replies);
+      let repls = match async_std::future::timeout(self.reply_timeout, reply).await {
+        Ok(repls) => repls,
+        // either the message or its reply was lost, the ack status tells which
+        Err(_) => {
+          self.unacked.push((targets, sq));
+          stopped = true;
+          continue;
+        }
+      };
+      match repls {
+        Ok(repls) => report_replies(&targets, repls).await,
+        // the server did not handle the message, so the ack status does not list it, and it is
+        // sent again later
+        Err(rr) if rr.downcast_ref() == Some(&ClientError::ServerBusy) => {
+          let error = format!(
+            "message to {} delayed, the server is busy",
+            recipients(&targets)
+          );
+          self.unacked.push((targets, sq));
+          if self.failures == 0 {
+            self.back_off();
+          }
+          stopped = true;
+          ERRORS.write().await.push(error);
+        }
+        // the server refused the message, sending it again would not help
+        Err(rr) => match rr.downcast::<ClientError>() {
+          Ok(error) => ERRORS.write().await.push(format!(
+            "message to {} refused: {}",
+            recipients(&targets),
+            error
+          )),
+          Err(rr) => return Err(rr),
+        },
+      }
+    }
+    Ok(())
+  }
+
+  // the next attempt is delayed twice as long after each failure
+  fn back_off(&mut self) {
+    self.failures += 1;
+    let delay = self.delay * 2u32.pow(self.failures.min(16) - 1);
+    self.next_attempt = Instant::now() + delay.min(MAX_RETRY_DELAY);
+  }
+
+  // the server accepted every sequence up to `seqid`, the messages among them need no ack status
+  fn acknowledge(&mut self, seqid: u128) {
+    self.unacked.retain(|(_, sq)| sq.seqid > seqid);
+  }
+
+  // asks the server which of the messages without a reply it received, and sends the others again
+  // the server might have forgotten some of them, sending them again could deliver them twice
+  async fn retransmit<T: Transport>(
+    &mut self,
+    network: &Network<T>,
+    client: &mut Client,
+  ) -> anyhow::Result<()> {
+    if self.unacked.is_empty() {
+      return Ok(());
+    }
+    let query = client.sequence(ClientQuery::AckStatus);
+    let status = network.query(query, decode::ack_status).await?;
+    let mut lost = Vec::new();
+    for (targets, sq) in std::mem::take(&mut self.unacked) {
+      match status.received(sq.seqid) {
+        Some(true) => (),
+        Some(false) => {
+          log::info!(
+            "message {} to {} was lost, sending it again",
+            sq.seqid,
+            recipients(&targets)
+          );
+          lost.push((targets, sq.content));
+        }
+        None => ERRORS.write().await.push(format!(
+          "message to {} might not have been delivered",
+          recipients(&targets)
+        )),
+      }
+    }
+    // they were sent before the pending messages
+    for message in lost.into_iter().rev() {
+      self.pending.push_front(message);
+    }
+    if self.failures == 0 {
+      self.flush(network, client).await?;
+    }
+    Ok(())
+  }
+}
+
+// the recipients of a message, for the error pane
+fn recipients(targets: &[ClientId]) -> String {
+  match targets {
+    [target] => target.to_string(),
+    _ => format!("{} recipients", targets.len()),
+  }
+}
+
+// the replies are in the order of the recipients, a run of deliveries stands for `count` of them
+async fn report_replies(targets: &[ClientId], repls: Vec<ClientReply>) {
+  let mut next = 0;
+  for repl in repls {
+    let target = match targets.get(next) {
+      Some(target) => *target,
+      None => {
+        log::warn!("unexpected reply {:?}", repl);
+        continue;
+      }
+    };
+    next += match repl {
+      ClientReply::DeliveredN { count, .. } => count as usize,
+      _ => 1,
+    };
+    match repl {
+      ClientReply::Delivered(_) | ClientReply::DeliveredN { .. } | ClientReply::Heartbeat => (),
+      ClientReply::Delayed(_) => ERRORS
+        .write()
+        .await
+        .push(format!("message to {} delayed ...", target)),
+      ClientReply::Error(rr) => ERRORS
+        .write()
+        .await
+        .push(format!("message to {}: {}", target, rr)),
+      ClientReply::Transfer(_, _) => todo!(),
+    }
   }
 }
 
@@ -83,6 +786,34 @@ enum Command {
   ListUsers,
   SendMessage { message: String },
   Poll,
+  // polls until a message arrives, or the timeout elapses
+  Wait { timeout: Duration },
+  // shows which server the client is talking to
+  Info,
+  // measures the round trip to the server, and shows the latencies measured so far
+  Ping,
+  // sends a message to several users, given by name
+  MultiMessage { names: Vec<String>, message: String },
+  // answers the confirmation asked before sending a message to many recipients
+  Confirm { yes: bool },
+}
+
+// a message to many recipients, waiting for /yes or /no
+#[derive(Debug)]
+struct Unconfirmed {
+  dest: Vec<ClientId>,
+  message: String,
+}
+
+// whether a message to `recipient_count` users can be sent without asking for a confirmation
+fn confirm_send(recipient_count: usize, threshold: usize, auto_yes: bool) -> bool {
+  auto_yes || recipient_count <= threshold
+}
+
+// the message to send once the confirmation is answered, None when it was declined or when no
+// message was waiting for one
+fn answer(unconfirmed: &mut Option<Unconfirmed>, yes: bool) -> Option<Unconfirmed> {
+  unconfirmed.take().filter(|_| yes)
 }
 
 enum Source {
@@ -105,21 +836,177 @@ struct Users {
   sorted: Vec<ClientId>,
 }
 
+impl Users {
+  // the ids of the users with these names, or the first name that is not known
+  fn ids_of(&self, names: &[String]) -> Result<Vec<ClientId>, String> {
+    names
+      .iter()
+      .map(|name| {
+        self
+          .userlist
+          .iter()
+          .find(|(_, info)| info.name == *name)
+          .map(|(id, _)| *id)
+          .ok_or_else(|| name.clone())
+      })
+      .collect()
+  }
+
+  // merges the reply to ListUsers, returns false if nothing changed
+  // users that disappeared are kept, but marked as inactive. The users a message came from before
+  // they were listed get their name.
+  fn merge(&mut self, mut list: HashMap<ClientId, String>) -> bool {
+    let mut changed = false;
+    for (id, info) in self.userlist.iter_mut() {
+      match list.remove(id) {
+        Some(name) if !info.active || info.name != name => {
+          info.active = true;
+          info.name = name;
+          changed = true;
+        }
+        Some(_) => (),
+        None if info.active => {
+          info.active = false;
+          changed = true;
+        }
+        None => (),
+      }
+    }
+    for (id, name) in list {
+      self.userlist.insert(
+        id,
+        UserInfo {
+          active: true,
+          messages: Vec::new(),
+          name,
+          unread: 0,
+        },
+      );
+      changed = true;
+    }
+    changed
+  }
+}
+
 lazy_static! {
   static ref USERS: RwLock<Users> = RwLock::new(Users::default());
   static ref ERRORS: RwLock<Vec<String>> = RwLock::new(Vec::new());
 }
 
 enum UIEvent {
-  Key(KeyCode),
+  Key(KeyEvent),
   UsersUpdated,
+  // the terminal input was closed, nothing more can be typed
+  InputClosed,
 }
 
-async fn handle_input(tx: Sender<UIEvent>) -> anyhow::Result<()> {
-  loop {
-    if let crossterm::event::Event::Key(k) = crossterm::event::read()? {
+// how often the poller queries the server
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+// how long /wait waits when no timeout is given
+const DEFAULT_WAIT: Duration = Duration::from_secs(30);
+// delay between the polls of /wait, doubled after each empty poll
+const WAIT_DELAY: Duration = Duration::from_millis(50);
+const MAX_WAIT_DELAY: Duration = Duration::from_millis(800);
+
+struct ServerPoll<'a, T> {
+  network: &'a Network<T>,
+  client: &'a mut Client,
+}
+
+#[async_trait]
+impl<T: Transport + Sync> PollSource for ServerPoll<'_, T> {
+  async fn poll(&mut self) -> anyhow::Result<ClientPollReply> {
+    let msg = self.client.sequence(ClientQuery::Poll);
+    let ack = self.network.query(msg, decode::poll_ack).await?;
+    Ok(ack.reply)
+  }
+}
+
+// an ongoing /wait
+struct Wait {
+  timeout: Duration,
+  deadline: Instant,
+  delay: Duration,
+}
+
+#[derive(Debug)]
+enum WaitEvent {
+  // a poll returned something, the wait is over if it is a message
+  Reply(ClientPollReply),
+  // a command arrived, it is handled before waiting again
+  Command(Command),
+  TimedOut,
+}
+
+impl Wait {
+  fn new(timeout: Duration) -> Self {
+    Wait {
+      timeout,
+      deadline: Instant::now() + timeout,
+      delay: WAIT_DELAY,
+    }
+  }
+
+  // polls until something else than Nothing arrives, the deadline passes, or a command arrives
+  // listening to the commands meanwhile means the input is never held back by the wait
+  async fn next<P: PollSource>(
+    &mut self,
+    source: &mut P,
+    commands: &Receiver<Command>,
+  ) -> anyhow::Result<WaitEvent> {
+    loop {
+      let remaining = self.deadline.saturating_duration_since(Instant::now());
+      if remaining.is_zero() {
+        return Ok(WaitEvent::TimedOut);
+      }
+      if let Ok(cmd) = async_std::future::timeout(self.delay.min(remaining), commands.recv()).await
+      {
+        return Ok(WaitEvent::Command(cmd?));
+      }
+      match source.poll().await? {
+        ClientPollReply::Nothing => self.delay = (self.delay * 2).min(MAX_WAIT_DELAY),
+        reply => return Ok(WaitEvent::Reply(reply)),
+      }
+    }
+  }
+}
+
+async fn handle_input(tx: Sender<UIEvent>, shutdown: Arc<AtomicBool>) -> anyhow::Result<()> {
+  forward_input(tx, shutdown, || {
+    // read() blocks, so only call it when an event is ready, and check for shutdown meanwhile
+    if crossterm::event::poll(Duration::from_millis(100))? {
+      crossterm::event::read().map(Some)
+    } else {
+      Ok(None)
+    }
+  })
+  .await
+}
+
+// forwards the key presses given by `next_event` to the UI, until Esc is pressed or the input is
+// closed, `next_event` returns None when no event is ready yet
+async fn forward_input<E>(
+  tx: Sender<UIEvent>,
+  shutdown: Arc<AtomicBool>,
+  mut next_event: E,
+) -> anyhow::Result<()>
+where
+  E: FnMut() -> std::io::Result<Option<crossterm::event::Event>>,
+{
+  while !shutdown.load(Ordering::Relaxed) {
+    let event = match next_event() {
+      Ok(Some(event)) => event,
+      Ok(None) => continue,
+      Err(rr) => {
+        // retrying would spin, the UI quits instead
+        log::error!("could not read the input: {}", rr);
+        tx.send(UIEvent::InputClosed).await?;
+        break;
+      }
+    };
+    if let crossterm::event::Event::Key(k) = event {
       if k.kind == KeyEventKind::Press {
-        tx.send(UIEvent::Key(k.code)).await?;
+        tx.send(UIEvent::Key(k)).await?;
         if k.code == KeyCode::Esc {
           break;
         }
@@ -129,6 +1016,156 @@ async fn handle_input(tx: Sender<UIEvent>) -> anyhow::Result<()> {
   Ok(())
 }
 
+// parses "/wait [seconds]"
+fn parse_wait(line: &str) -> Option<Duration> {
+  let mut words = line.split_whitespace();
+  if words.next() != Some("/wait") {
+    return None;
+  }
+  let timeout = match words.next() {
+    None => DEFAULT_WAIT,
+    Some(secs) => Duration::from_secs(secs.parse().ok()?),
+  };
+  words.next().is_none().then_some(timeout)
+}
+
+// parses "/msg name1,name2,... message", called once the first word is known to be /msg
+fn parse_msg(line: &str) -> Option<Command> {
+  let rest = line.trim().strip_prefix("/msg")?;
+  let (names, message) = rest.trim_start().split_once(char::is_whitespace)?;
+  let names: Vec<String> = names
+    .split(',')
+    .filter(|n| !n.is_empty())
+    .map(str::to_string)
+    .collect();
+  let message = message.trim();
+  (!names.is_empty() && !message.is_empty()).then(|| Command::MultiMessage {
+    names,
+    message: message.to_string(),
+  })
+}
+
+// the command typed on a line, None for blank lines and malformed commands
+fn parse_command(line: &str) -> Option<Command> {
+  if line.trim().is_empty() {
+    None
+  } else if line.split_whitespace().next() == Some("/wait") {
+    parse_wait(line).map(|timeout| Command::Wait { timeout })
+  } else if line.trim() == "/info" {
+    Some(Command::Info)
+  } else if line.trim() == "/ping" {
+    Some(Command::Ping)
+  } else if line.trim() == "/yes" || line.trim() == "/no" {
+    Some(Command::Confirm {
+      yes: line.trim() == "/yes",
+    })
+  } else if line.split_whitespace().next() == Some("/msg") {
+    parse_msg(line)
+  } else {
+    Some(Command::SendMessage {
+      message: line.to_string(),
+    })
+  }
+}
+
+// the command sent when Enter is pressed, blank input is ignored
+// a malformed /wait is left in the input box, so that it can be fixed
+fn submit(inputbox: &mut inputbox::IBox) -> Option<Command> {
+  let command = parse_command(inputbox.message());
+  if command.is_none() && !inputbox.message().trim().is_empty() {
+    return None;
+  }
+  inputbox.commit();
+  command
+}
+
+// readline-style editing of the input box
+// the arrows select the users, so the history is browsed with ctrl-p and ctrl-n
+fn edit_line(inputbox: &mut inputbox::IBox, key: KeyEvent) {
+  let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+  match key.code {
+    KeyCode::Char('a') if ctrl => inputbox.reset_cursor(),
+    KeyCode::Char('e') if ctrl => inputbox.move_cursor_end(),
+    KeyCode::Char('b') if ctrl => inputbox.move_cursor_left(),
+    KeyCode::Char('f') if ctrl => inputbox.move_cursor_right(),
+    KeyCode::Char('d') if ctrl => inputbox.delete_char_forward(),
+    KeyCode::Char('u') if ctrl => inputbox.kill_to_start(),
+    KeyCode::Char('k') if ctrl => inputbox.kill_to_end(),
+    KeyCode::Char('w') if ctrl => inputbox.delete_word(),
+    KeyCode::Char('p') if ctrl => inputbox.history_prev(),
+    KeyCode::Char('n') if ctrl => inputbox.history_next(),
+    KeyCode::Char(_) if ctrl => (),
+    KeyCode::Char(to_insert) => inputbox.enter_char(to_insert),
+    KeyCode::Backspace => inputbox.delete_char(),
+    KeyCode::Delete => inputbox.delete_char_forward(),
+    KeyCode::Left => inputbox.move_cursor_left(),
+    KeyCode::Right => inputbox.move_cursor_right(),
+    KeyCode::Home => inputbox.reset_cursor(),
+    KeyCode::End => inputbox.move_cursor_end(),
+    _ => (),
+  }
+}
+
+// reads the commands one per line, for when the input is not a terminal, the end of the input quits
+// malformed commands are skipped
+async fn read_commands<R>(input: R, tx: Sender<Command>) -> anyhow::Result<()>
+where
+  R: async_std::io::BufRead + Unpin,
+{
+  let mut lines = input.lines();
+  while let Some(line) = lines.next().await {
+    let line = line?;
+    match parse_command(&line) {
+      Some(command) => tx.send(command).await?,
+      None if line.trim().is_empty() => (),
+      None => log::warn!("ignoring the malformed command {:?}", line),
+    }
+  }
+  tx.send(Command::Quit).await?;
+  Ok(())
+}
+
+// the messages received since the last call, one line each, `printed` counts them per sender
+fn received_lines(users: &Users, printed: &mut HashMap<ClientId, usize>) -> Vec<String> {
+  let mut lines = Vec::new();
+  for (id, uinfo) in &users.userlist {
+    let seen = printed.entry(*id).or_default();
+    let from = if uinfo.name.is_empty() {
+      id.to_string()
+    } else {
+      uinfo.name.clone()
+    };
+    for (source, msg) in &uinfo.messages[*seen..] {
+      if let Source::Other = source {
+        lines.push(format!("< {}: {}", from, msg));
+      }
+    }
+    *seen = uinfo.messages.len();
+  }
+  lines
+}
+
+// prints what the UI would show in its error pane, and the messages received, until the network
+// task is gone
+async fn print_feed(rx: Receiver<UIEvent>) -> anyhow::Result<()> {
+  let mut printed = 0;
+  let mut received = HashMap::new();
+  loop {
+    let closed = rx.recv().await.is_err();
+    let errors = ERRORS.read().await;
+    for line in &errors[printed..] {
+      println!("{}", line);
+    }
+    printed = errors.len();
+    for line in received_lines(&*USERS.read().await, &mut received) {
+      println!("{}", line);
+    }
+    if closed {
+      return Ok(());
+    }
+  }
+}
+
 async fn show_ui(rx: Receiver<UIEvent>, tx: Sender<Command>) -> anyhow::Result<()> {
   enable_raw_mode()?;
   let mut stdout = std::io::stdout();
@@ -180,25 +1217,11 @@ async fn show_ui(rx: Receiver<UIEvent>, tx: Sender<Command>) -> anyhow::Result<(
     // handle events
     let event = rx.recv().await?;
     match event {
-      UIEvent::Key(k) => match k {
+      UIEvent::Key(k) => match k.code {
         KeyCode::Enter => {
-          tx.send(Command::SendMessage {
-            message: inputbox.message().to_string(),
-          })
-          .await?;
-          inputbox.reset()
-        }
-        KeyCode::Char(to_insert) => {
-          inputbox.enter_char(to_insert);
-        }
-        KeyCode::Backspace => {
-          inputbox.delete_char();
-        }
-        KeyCode::Left => {
-          inputbox.move_cursor_left();
-        }
-        KeyCode::Right => {
-          inputbox.move_cursor_right();
+          if let Some(command) = submit(&mut inputbox) {
+            tx.send(command).await?;
+          }
         }
         KeyCode::Up => {
           move_selected(true).await;
@@ -209,9 +1232,10 @@ async fn show_ui(rx: Receiver<UIEvent>, tx: Sender<Command>) -> anyhow::Result<(
         KeyCode::Esc => {
           break;
         }
-        _ => (),
+        _ => edit_line(&mut inputbox, k),
       },
       UIEvent::UsersUpdated => (),
+      UIEvent::InputClosed => break,
     }
   }
   disable_raw_mode()?;
@@ -305,53 +1329,114 @@ fn ui(f: &mut Frame, input: &inputbox::IBox, users: &Users, errors: &[String]) {
   f.render_widget(messages, chunks[1]);
 }
 
+// number of polled message ids remembered to recognize duplicates
+const RECENT_MESSAGES: usize = 64;
+
+// ids of the recently polled messages, oldest first
+// a duplicated poll reply carries the id of a message that was already shown, while the same text
+// sent twice is two messages with their own ids
+struct RecentMessages(VecDeque<u128>);
+
+impl RecentMessages {
+  fn new() -> Self {
+    RecentMessages(VecDeque::new())
+  }
+
+  // records the message id, returns true if it was polled already
+  // servers that predate message ids send 0, their messages are never taken for duplicates
+  fn duplicate(&mut self, message_id: u128) -> bool {
+    if message_id == 0 {
+      return false;
+    }
+    if self.0.contains(&message_id) {
+      return true;
+    }
+    if self.0.len() >= RECENT_MESSAGES {
+      self.0.pop_front();
+    }
+    self.0.push_back(message_id);
+    false
+  }
+}
+
 async fn handle_network(
   client: Client,
   network: Network,
   event_tx: Sender<UIEvent>,
   rx: Receiver<Command>,
+  confirm_threshold: usize,
+  auto_yes: bool,
 ) -> anyhow::Result<()> {
   let mut client = client;
+  let mut outbox = Outbox::new(RETRY_DELAY);
+  let mut wait: Option<Wait> = None;
+  let mut recent = RecentMessages::new();
+  // learnt with the first /ping
+  let mut server: Option<ServerId> = None;
+  let mut unconfirmed: Option<Unconfirmed> = None;
 
   loop {
     log::debug!("waiting for command");
-    let cmd = rx.recv().await?;
+    let cmd = match wait.as_mut() {
+      None => rx.recv().await?,
+      Some(w) => {
+        let mut source = ServerPoll {
+          network: &network,
+          client: &mut client,
+        };
+        let event = match w.next(&mut source, &rx).await {
+          Ok(event) => event,
+          // the wait goes on, the next poll might get through
+          Err(rr) => {
+            report_temporary("WAIT", rr).await?;
+            continue;
+          }
+        };
+        match event {
+          WaitEvent::Command(cmd) => cmd,
+          WaitEvent::Reply(reply) => {
+            report_poll_reply(reply, &mut wait, &mut recent).await;
+            event_tx.send(UIEvent::UsersUpdated).await?;
+            continue;
+          }
+          WaitEvent::TimedOut => {
+            ERRORS
+              .write()
+              .await
+              .push(format!("[WAIT] no message after {}s", w.timeout.as_secs()));
+            wait = None;
+            event_tx.send(UIEvent::UsersUpdated).await?;
+            continue;
+          }
+        }
+      }
+    };
     log::debug!("recv command: {:?}", cmd);
     event_tx.send(UIEvent::UsersUpdated).await?;
+    if outbox.is_due() {
+      outbox.flush(&network, &mut client).await?;
+    }
     match cmd {
-      Command::Quit => break,
+      Command::Quit => {
+        // last chance for the messages that could not be sent
+        outbox.flush(&network, &mut client).await?;
+        if !outbox.pending.is_empty() {
+          log::warn!("abandoning {} unsent messages", outbox.pending.len());
+        }
+        break;
+      }
       Command::ListUsers => {
-        let msg = client.sequence(ClientQuery::ListUsers);
-        network.send(&msg).await?;
-        let list = network.get(decode::userlist).await?;
+        let list = match fetch_users(&network, &mut client).await {
+          Ok(list) => list,
+          Err(rr) => {
+            report_temporary("USERS", rr).await?;
+            continue;
+          }
+        };
         let mut lk = USERS.write().await;
-        let known_users = lk
-          .userlist
-          .iter()
-          .map(|u| (*u.0, u.1.name.clone()))
-          .collect::<HashMap<_, _>>();
-        if list == known_users {
+        if !lk.merge(list) {
           continue;
         }
-        let known_userids = known_users.keys().copied().collect::<HashSet<_>>();
-        let new_userids = list.keys().copied().collect::<HashSet<_>>();
-        // do not remove users that disappeared, but mark them as inactive
-        for disappeared_user in known_userids.difference(&new_userids) {
-          lk.userlist
-            .entry(*disappeared_user)
-            .and_modify(|e| e.active = false);
-        }
-        for new_user in new_userids.difference(&known_userids) {
-          lk.userlist.insert(
-            *new_user,
-            UserInfo {
-              active: true,
-              messages: Vec::new(),
-              name: list.get(new_user).unwrap().clone(),
-              unread: 0,
-            },
-          );
-        }
         let mut sref = lk.userlist.iter().collect::<Vec<_>>();
         sref.sort_by_key(|f| &f.1.name);
         lk.sorted = sref.iter().map(|f| f.0).copied().collect();
@@ -363,20 +1448,61 @@ async fn handle_network(
       }
       Command::Poll => {
         let msg = client.sequence(ClientQuery::Poll);
-        network.send(&msg).await?;
-        let reply = network.get(decode::client_poll_reply).await?;
-        let mut lk = USERS.write().await;
-        let selected = lk.selected.clone();
-        match reply {
-          ClientPollReply::Nothing => continue,
-          ClientPollReply::DelayedError(msg) => ERRORS.write().await.push(format!("{:?}", msg)),
-          ClientPollReply::Message { src, content } => {
-            let uinfo = lk.userlist.entry(src).or_default();
-            uinfo.messages.push((Source::Other, content));
-            if selected != Some(src) {
-              uinfo.unread += 1;
+        let ack = match network.query(msg, decode::poll_ack).await {
+          Ok(ack) => ack,
+          Err(rr) => {
+            report_temporary("POLL", rr).await?;
+            continue;
+          }
+        };
+        outbox.acknowledge(ack.contiguous_seqid);
+        // the messages stay unacknowledged, the next poll checks them again
+        if let Err(rr) = outbox.retransmit(&network, &mut client).await {
+          report_temporary("POLL", rr).await?;
+        }
+        report_poll_reply(ack.reply, &mut wait, &mut recent).await;
+      }
+      Command::Wait { timeout } => wait = Some(Wait::new(timeout)),
+      Command::Info => {
+        let msg = client.sequence(ClientQuery::ServerInfo);
+        let info = match network.query(msg, decode::server_info).await {
+          Ok(info) => info,
+          Err(rr) => {
+            report_temporary("INFO", rr).await?;
+            continue;
+          }
+        };
+        ERRORS.write().await.push(format!(
+          "[INFO] {} run by {}, protocol version {}",
+          info.server_id, info.group_name, info.version
+        ));
+      }
+      Command::Ping => {
+        let server = match server {
+          Some(server) => server,
+          None => {
+            let msg = client.sequence(ClientQuery::ServerInfo);
+            match network.query(msg, decode::server_info).await {
+              Ok(info) => *server.insert(info.server_id),
+              Err(rr) => {
+                ERRORS
+                  .write()
+                  .await
+                  .push(format!("[PING] no server info: {}", rr));
+                continue;
+              }
             }
           }
+        };
+        if let Err(rr) = ping(&network, &mut client, server).await {
+          ERRORS
+            .write()
+            .await
+            .push(format!("[PING] {}: {}", server, rr));
+        }
+        let mut errors = ERRORS.write().await;
+        for (server, rtt) in client.server_latencies() {
+          errors.push(format!("[PING] {}: {} ms", server, rtt.as_millis()));
         }
       }
       Command::SendMessage { message } => {
@@ -396,25 +1522,57 @@ async fn handle_network(
           .or_default()
           .messages
           .push((Source::Me, message.clone()));
-        let msg = client.sequence(ClientQuery::Message(ClientMessage::Text {
+        let msg = ClientQuery::Message(ClientMessage::Text {
           dest: target,
           content: message,
-        }));
-        network.send(&msg).await?;
-        let repls = network.get(decode::client_replies).await?;
-        for repl in repls {
-          match repl {
-            ClientReply::Delivered => (),
-            ClientReply::Delayed => ERRORS
-              .write()
-              .await
-              .push(format!("message to {} delayed ...", target)),
-            ClientReply::Error(rr) => ERRORS
+          attachments: Vec::new(),
+        });
+        outbox
+          .send(&network, &mut client, vec![target], msg)
+          .await?;
+      }
+      Command::MultiMessage { names, message } => {
+        let dest = match USERS.read().await.ids_of(&names) {
+          Ok(dest) => dest,
+          Err(name) => {
+            ERRORS
               .write()
               .await
-              .push(format!("message to {}: {}", target, rr)),
-            ClientReply::Transfer(_, _) => todo!(),
+              .push(format!("[MSG] unknown user {}", name));
+            continue;
           }
+        };
+        if confirm_send(dest.len(), confirm_threshold, auto_yes) {
+          send_multi(&network, &mut outbox, &mut client, dest, message).await?;
+          continue;
+        }
+        let mut errors = ERRORS.write().await;
+        if let Some(previous) = unconfirmed.replace(Unconfirmed {
+          dest: dest.clone(),
+          message,
+        }) {
+          errors.push(format!(
+            "[MSG] message to {} recipients not sent",
+            previous.dest.len()
+          ));
+        }
+        errors.push(format!(
+          "[MSG] send to {} recipients? /yes to confirm, /no to abort",
+          dest.len()
+        ));
+      }
+      Command::Confirm { yes } => {
+        let waiting = unconfirmed.as_ref().map(|u| u.dest.len());
+        match (answer(&mut unconfirmed, yes), waiting) {
+          (Some(u), _) => send_multi(&network, &mut outbox, &mut client, u.dest, u.message).await?,
+          (None, Some(n)) => ERRORS
+            .write()
+            .await
+            .push(format!("[MSG] message to {} recipients not sent", n)),
+          (None, None) => ERRORS
+            .write()
+            .await
+            .push("[MSG] no message to confirm".to_string()),
         }
       }
     }
@@ -423,6 +1581,132 @@ async fn handle_network(
   Ok(())
 }
 
+// sends a message to several users at once, through the outbox like the other messages
+async fn send_multi<T: Transport>(
+  network: &Network<T>,
+  outbox: &mut Outbox,
+  client: &mut Client,
+  dest: Vec<ClientId>,
+  message: String,
+) -> anyhow::Result<()> {
+  {
+    let mut lk = USERS.write().await;
+    for target in &dest {
+      lk.userlist
+        .entry(*target)
+        .or_default()
+        .messages
+        .push((Source::Me, message.clone()));
+    }
+  }
+  let msg = ClientQuery::Message(ClientMessage::MText {
+    dest: dest.clone(),
+    content: message,
+  });
+  outbox.send(network, client, dest, msg).await
+}
+
+// measures the round trip to `server` with a heartbeat, that has no other effect, and records it
+async fn ping<T: Transport>(
+  network: &Network<T>,
+  client: &mut Client,
+  server: ServerId,
+) -> anyhow::Result<Duration> {
+  let start = Instant::now();
+  let sq = client.unsequenced(ClientQuery::Heartbeat);
+  let replies = network.query(sq, decode::client_replies).await?;
+  if replies != [ClientReply::Heartbeat] {
+    anyhow::bail!("unexpected reply to a heartbeat: {:?}", replies);
+  }
+  let rtt = start.elapsed();
+  client.record_latency(server, rtt);
+  Ok(rtt)
+}
+
+// the whole user list, fetched one page at a time so that each reply fits in a datagram
+async fn fetch_users<T: Transport>(
+  network: &Network<T>,
+  client: &mut Client,
+) -> anyhow::Result<HashMap<ClientId, String>> {
+  let mut users = HashMap::new();
+  loop {
+    let query = ClientQuery::ListUsersPage {
+      offset: users.len() as u64,
+      limit: MAX_USER_PAGE,
+    };
+    let page = network
+      .query(client.sequence(query), decode::user_page)
+      .await?;
+    // an empty page would never end the loop
+    let done = !page.has_more || page.users.is_empty();
+    users.extend(page.users);
+    if done {
+      return Ok(users);
+    }
+  }
+}
+
+// shows what a poll returned, a message ends the ongoing /wait
+async fn report_poll_reply(
+  reply: ClientPollReply,
+  wait: &mut Option<Wait>,
+  recent: &mut RecentMessages,
+) {
+  let mut lk = USERS.write().await;
+  let selected = lk.selected;
+  match reply {
+    ClientPollReply::Nothing => (),
+    ClientPollReply::DelayedError(msg) => ERRORS.write().await.push(format!("{:?}", msg)),
+    ClientPollReply::System { text } => ERRORS.write().await.push(format!("[SYSTEM] {}", text)),
+    ClientPollReply::Receipts(ids) => ERRORS
+      .write()
+      .await
+      .push(format!("[READ] {} of your messages were read", ids.len())),
+    ClientPollReply::Message {
+      src,
+      content,
+      message_id,
+      ..
+    } => {
+      if recent.duplicate(message_id) {
+        log::debug!("dropping a duplicate of a message from {}", src);
+        return;
+      }
+      let uinfo = lk.userlist.entry(src).or_default();
+      if wait.take().is_some() {
+        let from = if uinfo.name.is_empty() {
+          src.to_string()
+        } else {
+          uinfo.name.clone()
+        };
+        ERRORS
+          .write()
+          .await
+          .push(format!("[WAIT] {}: {}", from, content));
+      }
+      uinfo.messages.push((Source::Other, content));
+      if selected != Some(src) {
+        uinfo.unread += 1;
+      }
+    }
+  }
+}
+
+// periodically asks for new messages and users, until shutdown is set or the network task is gone
+async fn poller(tx: Sender<Command>, shutdown: Arc<AtomicBool>, interval: Duration) {
+  log::info!("entering main poller loop");
+  loop {
+    async_std::task::sleep(interval).await;
+    if shutdown.load(Ordering::Relaxed) {
+      break;
+    }
+    log::debug!("POLL");
+    if tx.send(Command::Poll).await.is_err() || tx.send(Command::ListUsers).await.is_err() {
+      break;
+    }
+  }
+}
+
 fn main() -> anyhow::Result<()> {
   async_std::task::block_on(async { main_task().await })
 }
@@ -431,51 +1715,1107 @@ async fn main_task() -> anyhow::Result<()> {
   pretty_env_logger::init();
 
   let opt = Opt::from_args();
-  let network = Network::new((opt.host, opt.port).into()).await?;
+  // the server would refuse it, and registration replies carry no error
+  let name = validate_name(&opt.name)?.to_string();
+  let network = Network::new(opt.transport, (opt.host, opt.port).into(), opt.max_inflight)
+    .await?
+    .with_decode_log_limit(opt.decode_log_limit)
+    .with_retry(RetryPolicy {
+      attempts: opt.query_attempts,
+      timeout: Duration::from_millis(opt.query_timeout),
+    });
   let tempid = ClientId::default();
-  let workproof = gen_workproof((&tempid).into(), WORKPROOF_STRENGTH, u128::MAX).unwrap();
-
   let sq = Sequence {
-    seqid: 0,
-    src: tempid,
-    workproof,
-    content: ClientQuery::Register(opt.name),
+    timestamp: Some(now_millis()),
+    ..Sequence::signed(tempid, 0, ClientQuery::Register(name), WORKPROOF_STRENGTH)
   };
 
-  network.send(&sq).await?;
-  let id = network.get(decode::clientid).await?;
+  let id = register(
+    &network,
+    sq,
+    Duration::from_millis(opt.register_timeout),
+    opt.register_attempts,
+  )
+  .await?;
   log::info!("registered as {}", id);
   let client = Client::new(id);
 
   let (tx, rx) = async_std::channel::bounded::<Command>(16);
   let (event_tx, event_rx) = async_std::channel::bounded::<UIEvent>(32);
 
-  let ievent_tx = event_tx.clone();
-  let t_input = async_std::task::Builder::new()
-    .name("input".to_string())
-    .spawn(async move { handle_input(ievent_tx).await })?;
+  let shutdown = Arc::new(AtomicBool::new(false));
 
   let itx = tx.clone();
-  let t_ui = async_std::task::Builder::new()
-    .name("ui".to_string())
-    .spawn(async move { show_ui(event_rx, itx).await })?;
+  let (t_input, t_ui) = if opt.no_interactive {
+    let t_input = async_std::task::Builder::new()
+      .name("input".to_string())
+      .spawn(async move { read_commands(BufReader::new(async_std::io::stdin()), itx).await })?;
+    let t_ui = async_std::task::Builder::new()
+      .name("ui".to_string())
+      .spawn(async move { print_feed(event_rx).await })?;
+    (t_input, t_ui)
+  } else {
+    let ievent_tx = event_tx.clone();
+    let ishutdown = shutdown.clone();
+    let t_input = async_std::task::Builder::new()
+      .name("input".to_string())
+      .spawn(async move { handle_input(ievent_tx, ishutdown).await })?;
+    let t_ui = async_std::task::Builder::new()
+      .name("ui".to_string())
+      .spawn(async move { show_ui(event_rx, itx).await })?;
+    (t_input, t_ui)
+  };
 
+  let pshutdown = shutdown.clone();
   let tpoll = async_std::task::Builder::new()
     .name("poller".to_string())
-    .spawn(async move {
-      log::info!("entering main poller loop");
-      loop {
-        async_std::task::sleep(std::time::Duration::from_secs(1)).await;
-        log::debug!("POLL");
-        tx.send(Command::Poll).await.unwrap();
-        tx.send(Command::ListUsers).await.unwrap();
-      }
-    })?;
+    .spawn(async move { poller(tx, pshutdown, POLL_INTERVAL).await })?;
 
-  handle_network(client, network, event_tx, rx).await?;
+  let r = handle_network(
+    client,
+    network,
+    event_tx,
+    rx,
+    opt.confirm_threshold,
+    opt.yes,
+  )
+  .await;
+  shutdown.store(true, Ordering::Relaxed);
   tpoll.await;
+  // a read of the standard input cannot be interrupted, that task is left behind
+  if !opt.no_interactive {
+    t_input.await?;
+  }
   t_ui.await?;
-  t_input.await?;
 
-  Ok(())
+  r
+}
+
+#[cfg(test)]
+mod test {
+  use std::sync::Mutex;
+
+  use super::*;
+  use chatproto::messages::AckStatus;
+
+  // what the scripted transport does with a request
+  enum Step {
+    Answer,
+    // the send fails, the request does not leave the client
+    Fail,
+    // the request is received, but its reply is lost
+    Lose,
+    // the request is answered with this error
+    Refuse(ClientError),
+  }
+
+  type Answer = Box<dyn Fn(&Sequence<ClientQuery>) -> Vec<u8> + Send + Sync>;
+
+  // a server following a script, one step per request, the requests past its end being answered
+  // ack status queries are answered with the sequence ids it received, the others with `answer`
+  struct ScriptedTransport {
+    script: Mutex<VecDeque<Step>>,
+    answer: Answer,
+    // replies are received after that long
+    delay: Duration,
+    // the latest reply is received first
+    lifo: bool,
+    sent: Mutex<Vec<Request<ClientQuery>>>,
+    received: Mutex<AckStatus>,
+    replies: Mutex<VecDeque<Vec<u8>>>,
+    // replies that were not received yet, and the most there were at once
+    outstanding: Mutex<usize>,
+    max_outstanding: Mutex<usize>,
+  }
+
+  impl ScriptedTransport {
+    fn new(answer: impl Fn(&Sequence<ClientQuery>) -> Vec<u8> + Send + Sync + 'static) -> Self {
+      Self {
+        script: Mutex::new(VecDeque::new()),
+        answer: Box::new(answer),
+        delay: Duration::ZERO,
+        lifo: false,
+        sent: Mutex::new(Vec::new()),
+        received: Mutex::new(AckStatus::default()),
+        replies: Mutex::new(VecDeque::new()),
+        outstanding: Mutex::new(0),
+        max_outstanding: Mutex::new(0),
+      }
+    }
+
+    fn with_script(self, script: impl IntoIterator<Item = Step>) -> Self {
+      self.script.lock().unwrap().extend(script);
+      self
+    }
+
+    fn with_delay(mut self, delay: Duration) -> Self {
+      self.delay = delay;
+      self
+    }
+
+    fn lifo(mut self) -> Self {
+      self.lifo = true;
+      self
+    }
+
+    // the sequences of the requests that left the client
+    fn sequences(&self) -> Vec<Sequence<ClientQuery>> {
+      let sent = self.sent.lock().unwrap();
+      sent.iter().map(|rq| rq.sequence.clone()).collect()
+    }
+  }
+
+  #[async_trait]
+  impl Transport for ScriptedTransport {
+    async fn send(&self, buf: &[u8]) -> std::io::Result<usize> {
+      let step = self.script.lock().unwrap().pop_front();
+      if let Some(Step::Fail) = step {
+        return Err(std::io::ErrorKind::NetworkUnreachable.into());
+      }
+      let rq = decode::request(&mut Cursor::new(buf.to_vec()), decode::client_query).unwrap();
+      self.sent.lock().unwrap().push(rq.clone());
+      let mut wr = Cursor::new(Vec::new());
+      match step {
+        Some(Step::Lose) => return Ok(buf.len()),
+        Some(Step::Refuse(error)) => encode::error_reply(
+          &mut wr,
+          &chatproto::messages::Reply {
+            request_id: rq.request_id,
+            payload: error,
+          },
+        ),
+        _ => {
+          let mut received = self.received.lock().unwrap();
+          received.record(rq.sequence.seqid);
+          let payload = match rq.sequence.content {
+            ClientQuery::AckStatus => payload(&*received, encode::ack_status),
+            _ => (self.answer)(&rq.sequence),
+          };
+          encode::reply(
+            &mut wr,
+            &chatproto::messages::Reply {
+              request_id: rq.request_id,
+              payload,
+            },
+            |w, p| std::io::Write::write_all(w, p),
+          )
+        }
+      }
+      .unwrap();
+      let mut outstanding = self.outstanding.lock().unwrap();
+      *outstanding += 1;
+      let mut max_outstanding = self.max_outstanding.lock().unwrap();
+      *max_outstanding = (*max_outstanding).max(*outstanding);
+      self.replies.lock().unwrap().push_back(wr.into_inner());
+      Ok(buf.len())
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+      async_std::task::sleep(self.delay).await;
+      loop {
+        let reply = {
+          let mut replies = self.replies.lock().unwrap();
+          match self.lifo {
+            true => replies.pop_back(),
+            false => replies.pop_front(),
+          }
+        };
+        if let Some(reply) = reply {
+          *self.outstanding.lock().unwrap() -= 1;
+          buf[..reply.len()].copy_from_slice(&reply);
+          return Ok(reply.len());
+        }
+        async_std::task::sleep(Duration::from_millis(1)).await;
+      }
+    }
+  }
+
+  // the payload of a reply
+  fn payload<X>(x: &X, f: impl FnOnce(&mut Vec<u8>, &X) -> std::io::Result<()>) -> Vec<u8> {
+    let mut out = Vec::new();
+    f(&mut out, x).unwrap();
+    out
+  }
+
+  // delivers the messages
+  fn delivered(sq: &Sequence<ClientQuery>) -> Vec<u8> {
+    payload(&vec![ClientReply::Delivered(sq.seqid)], |w, r| {
+      encode::client_replies(w, r)
+    })
+  }
+
+  #[test]
+  fn query_retry() {
+    async_std::task::block_on(async {
+      let network = Network::with_transport(
+        ScriptedTransport::new(|_| payload(&HashMap::new(), encode::userlist))
+          .with_script([Step::Lose, Step::Lose]),
+        1,
+      )
+      .with_retry(RetryPolicy {
+        attempts: 3,
+        timeout: Duration::from_millis(20),
+      });
+      let mut client = Client::new(ClientId::default());
+      let sq = client.sequence(ClientQuery::ListUsers);
+      let users = network.query(sq.clone(), decode::userlist).await.unwrap();
+      assert!(users.is_empty());
+      // the very same request was sent three times
+      let sent = network.socket.sent.lock().unwrap();
+      assert_eq!(sent.len(), 3);
+      assert!(sent
+        .iter()
+        .all(|rq| rq.request_id == sent[0].request_id && rq.sequence == sq));
+      assert!(network.waiting.lock().unwrap().is_empty());
+    })
+  }
+
+  #[test]
+  fn ping_latency() {
+    async_std::task::block_on(async {
+      let delay = Duration::from_millis(50);
+      let network = Network::with_transport(
+        ScriptedTransport::new(|_| {
+          payload(&vec![ClientReply::Heartbeat], |w, r| {
+            encode::client_replies(w, r)
+          })
+        })
+        .with_delay(delay),
+        1,
+      );
+      let mut client = Client::new(ClientId::default());
+      let server = ServerId::default();
+      assert!(client.server_latencies().is_empty());
+      let rtt = ping(&network, &mut client, server).await.unwrap();
+      assert!(rtt >= delay && rtt < delay * 10, "{:?}", rtt);
+      assert_eq!(client.server_latencies(), [(server, rtt)]);
+    })
+  }
+
+  #[test]
+  fn tcp_transport() {
+    async_std::task::block_on(async {
+      let listener = async_std::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap();
+      let addr = listener.local_addr().unwrap();
+      // answers two heartbeats, the replies being written a few bytes at a time
+      let server = async_std::task::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut pending = Vec::new();
+        let mut chunk = [0u8; 512];
+        for _ in 0..2 {
+          let (header, len) = loop {
+            if let Some(found) = frame::delimited(&pending, 8192).unwrap() {
+              break found;
+            }
+            let n = stream.read(&mut chunk).await.unwrap();
+            pending.extend_from_slice(&chunk[..n]);
+          };
+          let rq = decode::request(
+            &mut Cursor::new(pending[header..header + len].to_vec()),
+            decode::client_query,
+          )
+          .unwrap();
+          pending.drain(..header + len);
+          let mut wr = Cursor::new(Vec::new());
+          encode::reply(
+            &mut wr,
+            &chatproto::messages::Reply {
+              request_id: rq.request_id,
+              payload: vec![ClientReply::Heartbeat],
+            },
+            |w, r: &Vec<ClientReply>| encode::client_replies(w, r),
+          )
+          .unwrap();
+          for part in frame::delimit(wr.get_ref()).chunks(3) {
+            stream.write_all(part).await.unwrap();
+            stream.flush().await.unwrap();
+            async_std::task::sleep(Duration::from_millis(1)).await;
+          }
+        }
+      });
+      let network = Network::new(TransportKind::Tcp, addr, 1).await.unwrap();
+      assert_eq!(network.retry.attempts, 1);
+      let mut client = Client::new(ClientId::default());
+      for _ in 0..2 {
+        ping(&network, &mut client, ServerId::default())
+          .await
+          .unwrap();
+      }
+      server.await;
+    })
+  }
+
+  #[test]
+  fn chunked_transport() {
+    async_std::task::block_on(async {
+      let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+      let addr = socket.local_addr().unwrap();
+      let replies: Vec<_> = (0..3000)
+        .map(|_| ClientReply::Error(ClientError::BoxFull(ClientId::default())))
+        .collect();
+      let expected = replies.clone();
+      // answers a request larger than a datagram with a reply larger than a datagram
+      let server = async_std::task::spawn(async move {
+        let mut reassembler = frame::Reassembler::new(CHUNK_TIMEOUT);
+        let mut buf = [0u8; frame::CHUNK_DATAGRAM_LEN];
+        let (request, peer) = loop {
+          let (n, peer) = socket.recv_from(&mut buf).await.unwrap();
+          let chunk = frame::read_chunk(&buf[..n]).unwrap();
+          if let Some(request) = reassembler.push(peer, chunk, Instant::now()).unwrap() {
+            break (request, peer);
+          }
+        };
+        let rq = decode::request(&mut Cursor::new(request), decode::client_query).unwrap();
+        let mut wr = Cursor::new(Vec::new());
+        encode::reply(
+          &mut wr,
+          &chatproto::messages::Reply {
+            request_id: rq.request_id,
+            payload: replies,
+          },
+          |w, r: &Vec<ClientReply>| encode::client_replies(w, r),
+        )
+        .unwrap();
+        assert!(wr.get_ref().len() > RECV_BUFFER);
+        for datagram in frame::split(1, wr.get_ref(), frame::CHUNK_DATAGRAM_LEN).unwrap() {
+          socket.send_to(&datagram, peer).await.unwrap();
+        }
+        rq.sequence.content
+      });
+      let network = Network::new(TransportKind::Chunked, addr, 1).await.unwrap();
+      let mut client = Client::new(ClientId::default());
+      let query = ClientQuery::Message(ClientMessage::Text {
+        dest: ClientId::default(),
+        content: "x".repeat(20000),
+        attachments: Vec::new(),
+      });
+      let received = network
+        .query(client.sequence(query.clone()), decode::client_replies)
+        .await
+        .unwrap();
+      assert_eq!(received, expected);
+      assert_eq!(server.await, query);
+    })
+  }
+
+  #[test]
+  fn register_retry() {
+    async_std::task::block_on(async {
+      let id = ClientId::default();
+      let network = Network::with_transport(
+        ScriptedTransport::new(move |_| payload(&id, encode::clientid)).with_script([Step::Lose]),
+        1,
+      );
+      let sq = Client::new(ClientId::default()).sequence(ClientQuery::Register("bob".into()));
+      let registered = register(&network, sq.clone(), Duration::from_millis(50), 3)
+        .await
+        .unwrap();
+      assert_eq!(registered, id);
+      // the retransmission is the very same sequence
+      assert_eq!(network.socket.sequences(), [sq.clone(), sq.clone()]);
+
+      network
+        .socket
+        .script
+        .lock()
+        .unwrap()
+        .extend([Step::Lose, Step::Lose, Step::Lose]);
+      assert!(register(&network, sq, Duration::from_millis(50), 3)
+        .await
+        .is_err());
+    })
+  }
+
+  #[test]
+  fn input_closed() {
+    async_std::task::block_on(async {
+      let (tx, rx) = async_std::channel::bounded::<UIEvent>(16);
+      let key = crossterm::event::Event::Key(KeyCode::Char('a').into());
+      let mut events = VecDeque::from([Ok(Some(key)), Ok(None)]);
+      forward_input(tx, Arc::new(AtomicBool::new(false)), || {
+        events
+          .pop_front()
+          .unwrap_or_else(|| Err(std::io::ErrorKind::UnexpectedEof.into()))
+      })
+      .await
+      .unwrap();
+      assert!(matches!(
+        rx.recv().await,
+        Ok(UIEvent::Key(k)) if k.code == KeyCode::Char('a')
+      ));
+      assert!(matches!(rx.recv().await, Ok(UIEvent::InputClosed)));
+      assert!(rx.recv().await.is_err());
+    })
+  }
+
+  #[test]
+  fn blank_input() {
+    let mut inputbox = inputbox::IBox::new();
+    for c in "  ".chars() {
+      inputbox.enter_char(c);
+    }
+    assert!(submit(&mut inputbox).is_none());
+    assert_eq!(inputbox.message(), "");
+
+    for c in "hi".chars() {
+      inputbox.enter_char(c);
+    }
+    assert!(
+      matches!(submit(&mut inputbox), Some(Command::SendMessage { message }) if message == "hi")
+    );
+    assert_eq!(inputbox.message(), "");
+  }
+
+  #[test]
+  fn duplicate_poll_reply() {
+    async_std::task::block_on(async {
+      let src = ClientId::from(1216u128);
+      let reply = |message_id| ClientPollReply::Message {
+        src,
+        srcsrv: None,
+        content: "hello".into(),
+        attachments: Vec::new(),
+        message_id,
+      };
+      let mut recent = RecentMessages::new();
+      report_poll_reply(reply(7), &mut None, &mut recent).await;
+      report_poll_reply(reply(7), &mut None, &mut recent).await;
+      assert_eq!(USERS.read().await.userlist[&src].messages.len(), 1);
+      // the same text sent again is another message
+      report_poll_reply(reply(8), &mut None, &mut recent).await;
+      assert_eq!(USERS.read().await.userlist[&src].messages.len(), 2);
+
+      // only the last ids are remembered, and messages without an id are all shown
+      for id in 9..9 + RECENT_MESSAGES as u128 {
+        assert!(!recent.duplicate(id));
+      }
+      assert!(!recent.duplicate(7));
+      assert!(recent.duplicate(9 + RECENT_MESSAGES as u128 - 1));
+      assert!(!recent.duplicate(0));
+      assert!(!recent.duplicate(0));
+    })
+  }
+
+  #[test]
+  fn received_printed() {
+    let (carol, dave) = (ClientId::default(), ClientId::default());
+    let mut users = Users::default();
+    users.userlist.insert(
+      carol,
+      UserInfo {
+        name: "carol".into(),
+        messages: vec![(Source::Other, "hi".into()), (Source::Me, "hello".into())],
+        ..UserInfo::default()
+      },
+    );
+    let mut printed = HashMap::new();
+    // what was sent is not printed again
+    assert_eq!(received_lines(&users, &mut printed), ["< carol: hi"]);
+    assert!(received_lines(&users, &mut printed).is_empty());
+
+    // senders that are not listed yet are shown by id
+    let uinfo = users.userlist.entry(dave).or_default();
+    uinfo.messages.push((Source::Other, "anyone?".into()));
+    assert_eq!(
+      received_lines(&users, &mut printed),
+      [format!("< {}: anyone?", dave)]
+    );
+  }
+
+  #[test]
+  fn names_filled_in() {
+    async_std::task::block_on(async {
+      let carol = ClientId::default();
+      let message = |content: &str, message_id| ClientPollReply::Message {
+        src: carol,
+        srcsrv: None,
+        content: content.into(),
+        attachments: Vec::new(),
+        message_id,
+      };
+      let mut recent = RecentMessages::new();
+      // the first message arrives before carol is listed
+      report_poll_reply(message("first", 1), &mut None, &mut recent).await;
+      assert_eq!(USERS.read().await.userlist[&carol].name, "");
+
+      let list = HashMap::from([(carol, "carol".to_string())]);
+      assert!(USERS.write().await.merge(list.clone()));
+      assert!(!USERS.write().await.merge(list));
+      assert!(USERS.read().await.userlist[&carol].active);
+
+      let mut wait = Some(Wait::new(DEFAULT_WAIT));
+      report_poll_reply(message("second", 2), &mut wait, &mut recent).await;
+      assert!(ERRORS
+        .read()
+        .await
+        .iter()
+        .any(|e| e == "[WAIT] carol: second"));
+    })
+  }
+
+  #[test]
+  fn wait_command() {
+    assert_eq!(parse_wait("/wait"), Some(DEFAULT_WAIT));
+    assert_eq!(parse_wait(" /wait 5 "), Some(Duration::from_secs(5)));
+    assert_eq!(parse_wait("/wait soon"), None);
+    assert_eq!(parse_wait("/wait 5 6"), None);
+    assert_eq!(parse_wait("/waiting"), None);
+
+    let mut inputbox = inputbox::IBox::new();
+    for c in "/wait x".chars() {
+      inputbox.enter_char(c);
+    }
+    assert!(submit(&mut inputbox).is_none());
+    assert_eq!(inputbox.message(), "/wait x");
+  }
+
+  #[test]
+  fn command_parsing() {
+    assert!(parse_command(" ").is_none());
+    assert!(parse_command("/wait x").is_none());
+    assert!(matches!(
+      parse_command("/wait 5"),
+      Some(Command::Wait { timeout }) if timeout == Duration::from_secs(5)
+    ));
+    assert!(matches!(parse_command(" /info "), Some(Command::Info)));
+    assert!(matches!(parse_command("/ping"), Some(Command::Ping)));
+    assert!(matches!(
+      parse_command("/waiting"),
+      Some(Command::SendMessage { message }) if message == "/waiting"
+    ));
+    assert!(matches!(
+      parse_command("/msg alice,bob  hello  there "),
+      Some(Command::MultiMessage { names, message })
+        if names == ["alice", "bob"] && message == "hello  there"
+    ));
+    assert!(parse_command("/msg alice").is_none());
+    assert!(parse_command("/msg alice  ").is_none());
+    assert!(matches!(
+      parse_command("/msgs alice hi"),
+      Some(Command::SendMessage { .. })
+    ));
+    assert!(matches!(
+      parse_command("/yes"),
+      Some(Command::Confirm { yes: true })
+    ));
+    assert!(matches!(
+      parse_command(" /no"),
+      Some(Command::Confirm { yes: false })
+    ));
+
+    async_std::task::block_on(async {
+      let (tx, rx) = async_std::channel::bounded::<Command>(16);
+      let input = async_std::io::Cursor::new("hi\n\n/wait x\n/info\n");
+      read_commands(input, tx).await.unwrap();
+      assert!(matches!(
+        rx.recv().await,
+        Ok(Command::SendMessage { message }) if message == "hi"
+      ));
+      assert!(matches!(rx.recv().await, Ok(Command::Info)));
+      assert!(matches!(rx.recv().await, Ok(Command::Quit)));
+      assert!(rx.recv().await.is_err());
+    })
+  }
+
+  #[test]
+  fn send_confirmation() {
+    // below the threshold, sent right away
+    assert!(confirm_send(1, 5, false));
+    assert!(confirm_send(5, 5, false));
+    // above it, only with --yes
+    assert!(confirm_send(6, 5, true));
+    assert!(!confirm_send(6, 5, false));
+
+    let waiting = || {
+      Some(Unconfirmed {
+        dest: vec![ClientId::default(); 6],
+        message: "hello".into(),
+      })
+    };
+    // declined, the message is dropped
+    let mut unconfirmed = waiting();
+    assert!(answer(&mut unconfirmed, false).is_none());
+    assert!(unconfirmed.is_none());
+    assert!(answer(&mut unconfirmed, true).is_none());
+    // confirmed
+    let mut unconfirmed = waiting();
+    let sent = answer(&mut unconfirmed, true).unwrap();
+    assert_eq!(sent.dest.len(), 6);
+    assert!(unconfirmed.is_none());
+  }
+
+  #[test]
+  fn line_editing() {
+    let ctrl = |c| KeyEvent::new(KeyCode::Char(c), KeyModifiers::CONTROL);
+    let mut inputbox = inputbox::IBox::new();
+    for line in ["one", "two words"] {
+      for c in line.chars() {
+        edit_line(&mut inputbox, KeyCode::Char(c).into());
+      }
+      assert!(submit(&mut inputbox).is_some());
+    }
+
+    for c in "new".chars() {
+      edit_line(&mut inputbox, KeyCode::Char(c).into());
+    }
+    edit_line(&mut inputbox, ctrl('p'));
+    assert_eq!(inputbox.message(), "two words");
+    edit_line(&mut inputbox, ctrl('w'));
+    assert_eq!(inputbox.message(), "two ");
+    edit_line(&mut inputbox, ctrl('p'));
+    edit_line(&mut inputbox, ctrl('p'));
+    assert_eq!(inputbox.message(), "one");
+    edit_line(&mut inputbox, ctrl('n'));
+    edit_line(&mut inputbox, ctrl('n'));
+    // back to the line being typed
+    assert_eq!(inputbox.message(), "new");
+
+    edit_line(&mut inputbox, ctrl('a'));
+    edit_line(&mut inputbox, ctrl('d'));
+    assert_eq!(inputbox.message(), "ew");
+    edit_line(&mut inputbox, KeyCode::Char('v').into());
+    edit_line(&mut inputbox, ctrl('k'));
+    assert_eq!(inputbox.message(), "v");
+    edit_line(&mut inputbox, KeyCode::End.into());
+    edit_line(&mut inputbox, ctrl('u'));
+    assert_eq!(inputbox.message(), "");
+  }
+
+  #[test]
+  fn info_command() {
+    let mut inputbox = inputbox::IBox::new();
+    for c in " /info ".chars() {
+      inputbox.enter_char(c);
+    }
+    assert!(matches!(submit(&mut inputbox), Some(Command::Info)));
+    assert_eq!(inputbox.message(), "");
+  }
+
+  // gives the scripted replies in order, then Nothing forever
+  struct ScriptedPolls {
+    replies: VecDeque<ClientPollReply>,
+    polls: usize,
+  }
+
+  #[async_trait]
+  impl PollSource for ScriptedPolls {
+    async fn poll(&mut self) -> anyhow::Result<ClientPollReply> {
+      self.polls += 1;
+      Ok(self.replies.pop_front().unwrap_or(ClientPollReply::Nothing))
+    }
+  }
+
+  #[test]
+  fn wait_termination() {
+    async_std::task::block_on(async {
+      let (tx, rx) = async_std::channel::bounded::<Command>(16);
+      let message = ClientPollReply::Message {
+        src: ClientId::default(),
+        srcsrv: None,
+        content: "hi".into(),
+        attachments: Vec::new(),
+        message_id: 0,
+      };
+
+      // a message arrives after a few empty polls
+      let mut source = ScriptedPolls {
+        replies: VecDeque::from([
+          ClientPollReply::Nothing,
+          ClientPollReply::Nothing,
+          message.clone(),
+        ]),
+        polls: 0,
+      };
+      let mut wait = Wait::new(Duration::from_secs(10));
+      let event = wait.next(&mut source, &rx).await.unwrap();
+      assert!(matches!(event, WaitEvent::Reply(reply) if reply == message));
+      assert_eq!(source.polls, 3);
+      assert_eq!(wait.delay, WAIT_DELAY * 4);
+
+      // nothing arrives before the deadline
+      let mut source = ScriptedPolls {
+        replies: VecDeque::new(),
+        polls: 0,
+      };
+      let start = Instant::now();
+      let mut wait = Wait::new(Duration::from_millis(200));
+      let event = wait.next(&mut source, &rx).await.unwrap();
+      assert!(matches!(event, WaitEvent::TimedOut));
+      assert!(start.elapsed() >= Duration::from_millis(200));
+      assert!(source.polls > 0);
+
+      // commands are not held back by the wait
+      tx.send(Command::ListUsers).await.unwrap();
+      let mut wait = Wait::new(Duration::from_secs(10));
+      let event = wait.next(&mut source, &rx).await.unwrap();
+      assert!(matches!(event, WaitEvent::Command(Command::ListUsers)));
+    })
+  }
+
+  #[test]
+  fn poller_shutdown() {
+    async_std::task::block_on(async {
+      let interval = Duration::from_millis(50);
+      let (tx, rx) = async_std::channel::bounded::<Command>(16);
+      let shutdown = Arc::new(AtomicBool::new(false));
+      let tpoll = async_std::task::spawn(poller(tx, shutdown.clone(), interval));
+      assert!(matches!(rx.recv().await, Ok(Command::Poll)));
+
+      shutdown.store(true, Ordering::Relaxed);
+      async_std::future::timeout(interval * 2, tpoll)
+        .await
+        .expect("the poller did not stop");
+    })
+  }
+
+  #[test]
+  fn outbox_retries_in_order() {
+    async_std::task::block_on(async {
+      let network = Network::with_transport(
+        ScriptedTransport::new(delivered).with_script([Step::Fail, Step::Fail]),
+        1,
+      );
+      let target = ClientId::default();
+      let mut client = Client::new(ClientId::default());
+      let mut outbox = Outbox::new(Duration::from_millis(100));
+      let mut expected = Vec::new();
+      for content in ["first", "second", "third"] {
+        let query = ClientQuery::Message(ClientMessage::Text {
+          dest: target,
+          content: content.into(),
+          attachments: Vec::new(),
+        });
+        expected.push(query.clone());
+        outbox
+          .send(&network, &mut client, vec![target], query)
+          .await
+          .unwrap();
+      }
+      assert_eq!(outbox.pending.len(), 3);
+      assert!(!outbox.is_due());
+      // a query sent while the messages wait takes a sequence id
+      let poll = client.sequence(ClientQuery::Poll);
+
+      // the delay doubles after each failure
+      while !outbox.pending.is_empty() {
+        async_std::task::sleep(Duration::from_millis(10)).await;
+        if outbox.is_due() {
+          outbox.flush(&network, &mut client).await.unwrap();
+        }
+      }
+      let sent = network.socket.sequences();
+      let contents: Vec<_> = sent.iter().map(|sq| sq.content.clone()).collect();
+      assert_eq!(contents, expected);
+      // the messages are sequenced when they are sent, after the query
+      assert!(sent[0].seqid > poll.seqid);
+      assert!(sent.windows(2).all(|w| w[0].seqid < w[1].seqid));
+    })
+  }
+
+  #[test]
+  fn multi_message_queued() {
+    async_std::task::block_on(async {
+      let network = Network::with_transport(
+        ScriptedTransport::new(delivered).with_script([Step::Fail]),
+        1,
+      );
+      let (bob, carol) = (ClientId::default(), ClientId::default());
+      let mut client = Client::new(ClientId::default());
+      let mut outbox = Outbox::new(Duration::from_millis(10));
+      let query = ClientQuery::Message(ClientMessage::Text {
+        dest: bob,
+        content: "first".into(),
+        attachments: Vec::new(),
+      });
+      outbox
+        .send(&network, &mut client, vec![bob], query)
+        .await
+        .unwrap();
+      // waits behind the message that could not be sent
+      send_multi(
+        &network,
+        &mut outbox,
+        &mut client,
+        vec![bob, carol],
+        "second".into(),
+      )
+      .await
+      .unwrap();
+      assert_eq!(outbox.pending.len(), 2);
+      assert_eq!(outbox.pending[1].0, [bob, carol]);
+      while !outbox.pending.is_empty() {
+        async_std::task::sleep(Duration::from_millis(10)).await;
+        if outbox.is_due() {
+          outbox.flush(&network, &mut client).await.unwrap();
+        }
+      }
+      let seqids: Vec<u128> = network
+        .socket
+        .sequences()
+        .iter()
+        .map(|sq| sq.seqid)
+        .collect();
+      // the failed attempt took the first sequence id
+      assert_eq!(seqids, [2, 3]);
+    })
+  }
+
+  #[test]
+  fn error_replies() {
+    async_std::task::block_on(async {
+      let network = Network::with_transport(
+        ScriptedTransport::new(delivered).with_script([
+          Step::Refuse(ClientError::ServerBusy),
+          Step::Answer,
+          Step::Refuse(ClientError::ProtocolError),
+        ]),
+        1,
+      );
+      let target = ClientId::default();
+      let mut client = Client::new(ClientId::default());
+      let mut outbox = Outbox::new(Duration::from_millis(10));
+      let query = ClientQuery::Message(ClientMessage::Text {
+        dest: target,
+        content: "refused".into(),
+        attachments: Vec::new(),
+      });
+
+      // a busy server did not receive the message, the ack status tells to send it again
+      outbox
+        .send(&network, &mut client, vec![target], query.clone())
+        .await
+        .unwrap();
+      assert_eq!(outbox.unacked.len(), 1);
+      assert_eq!(outbox.failures, 1);
+      assert!(ERRORS.read().await.contains(&format!(
+        "message to {} delayed, the server is busy",
+        target
+      )));
+
+      outbox.retransmit(&network, &mut client).await.unwrap();
+      assert_eq!(outbox.pending.len(), 1);
+
+      // the query is refused, and not sent anymore
+      while !outbox.pending.is_empty() {
+        async_std::task::sleep(Duration::from_millis(10)).await;
+        if outbox.is_due() {
+          outbox.flush(&network, &mut client).await.unwrap();
+        }
+      }
+      assert!(ERRORS
+        .read()
+        .await
+        .contains(&format!("message to {} refused: ProtocolError", target)));
+      let sent = network.socket.sequences();
+      assert_eq!(sent.len(), 3);
+      assert_eq!(sent[0].content, query);
+      assert_eq!(sent[1].content, ClientQuery::AckStatus);
+      assert_eq!(sent[2].content, query);
+
+      // the other queries see the error
+      network
+        .socket
+        .script
+        .lock()
+        .unwrap()
+        .push_back(Step::Refuse(ClientError::ServerBusy));
+      let rr = network
+        .query(
+          client.sequence(ClientQuery::ServerInfo),
+          decode::server_info,
+        )
+        .await
+        .unwrap_err();
+      assert_eq!(rr.downcast_ref(), Some(&ClientError::ServerBusy));
+    })
+  }
+
+  #[test]
+  fn lost_message_sent_again() {
+    async_std::task::block_on(async {
+      let network = Network::with_transport(
+        ScriptedTransport::new(delivered).with_script([Step::Answer, Step::Lose]),
+        1,
+      );
+      let target = ClientId::default();
+      let mut client = Client::new(ClientId::default());
+      let mut outbox = Outbox::new(Duration::from_millis(100));
+      outbox.reply_timeout = Duration::from_millis(50);
+      for content in ["first", "second", "third"] {
+        let query = ClientQuery::Message(ClientMessage::Text {
+          dest: target,
+          content: content.into(),
+          attachments: Vec::new(),
+        });
+        outbox
+          .send(&network, &mut client, vec![target], query)
+          .await
+          .unwrap();
+      }
+      assert_eq!(outbox.unacked.len(), 1);
+      // the lost message is not acknowledged by the ids the server accepted before it
+      outbox.acknowledge(1);
+      assert_eq!(outbox.unacked.len(), 1);
+      outbox.retransmit(&network, &mut client).await.unwrap();
+      assert!(outbox.unacked.is_empty() && outbox.pending.is_empty());
+      let sent = network.socket.sequences();
+      // the first two messages, the ack status query, then the lost message again, and the third
+      // one that waited behind it
+      let seqids: Vec<u128> = sent.iter().map(|sq| sq.seqid).collect();
+      assert_eq!(seqids, [1, 2, 3, 4, 5]);
+      assert_eq!(sent[2].content, ClientQuery::AckStatus);
+      assert_eq!(sent[3].content, sent[1].content);
+
+      // the messages the poll acknowledged need no ack status
+      let sq = client.sequence(ClientQuery::Poll);
+      outbox.unacked.push((vec![target], sq));
+      outbox.acknowledge(6);
+      assert!(outbox.unacked.is_empty());
+
+      // a message the server forgot is not sent again, it could be delivered twice
+      let sq = client.sequence(ClientQuery::Message(ClientMessage::Text {
+        dest: target,
+        content: "fourth".into(),
+        attachments: Vec::new(),
+      }));
+      outbox.unacked.push((vec![target], sq));
+      *network.socket.received.lock().unwrap() = AckStatus {
+        contiguous: 6,
+        forgotten: 100,
+        sparse: 0,
+      };
+      outbox.retransmit(&network, &mut client).await.unwrap();
+      assert!(outbox.unacked.is_empty() && outbox.pending.is_empty());
+      // only the ack status query
+      assert_eq!(network.socket.sequences().len(), 6);
+      assert!(ERRORS
+        .read()
+        .await
+        .iter()
+        .any(|e| e.contains("might not have been delivered")));
+    })
+  }
+
+  #[test]
+  fn temporary_errors() {
+    async_std::task::block_on(async {
+      let transport = ScriptedTransport::new(|sq| panic!("unexpected query {:?}", sq))
+        .with_script([Step::Refuse(ClientError::ServerBusy), Step::Lose]);
+      let network =
+        Network::with_transport(Box::new(transport) as AnyTransport, 1).with_retry(RetryPolicy {
+          attempts: 1,
+          timeout: Duration::from_millis(20),
+        });
+      let (tx, rx) = async_std::channel::unbounded();
+      let (event_tx, _event_rx) = async_std::channel::unbounded();
+      for cmd in [Command::Poll, Command::Info, Command::Quit] {
+        tx.send(cmd).await.unwrap();
+      }
+      // neither the busy server nor the lost reply end the network task
+      let client = Client::new(ClientId::default());
+      handle_network(client, network, event_tx, rx, 10, false)
+        .await
+        .unwrap();
+      let errors = ERRORS.read().await;
+      assert!(errors.contains(&"[POLL] ServerBusy".to_string()));
+      assert!(errors.contains(&"[INFO] no reply after 1 attempts".to_string()));
+    })
+  }
+
+  #[test]
+  fn flush_pipelined() {
+    async_std::task::block_on(async {
+      let delivered_contents = Arc::new(Mutex::new(Vec::new()));
+      let contents = delivered_contents.clone();
+      let network = Network::with_transport(
+        ScriptedTransport::new(move |sq| {
+          if let ClientQuery::Message(ClientMessage::Text { content, .. }) = &sq.content {
+            contents.lock().unwrap().push(content.clone());
+          }
+          delivered(sq)
+        })
+        .with_script([
+          Step::Answer,
+          Step::Refuse(ClientError::ServerBusy),
+          Step::Refuse(ClientError::ServerBusy),
+          Step::Lose,
+        ]),
+        3,
+      );
+      let bob = ClientId::default();
+      let mut client = Client::new(ClientId::default());
+      let mut outbox = Outbox::new(Duration::from_millis(10));
+      outbox.reply_timeout = Duration::from_millis(50);
+      let text = |i: usize| {
+        ClientQuery::Message(ClientMessage::Text {
+          dest: bob,
+          content: format!("message {}", i),
+          attachments: Vec::new(),
+        })
+      };
+      outbox.pending.extend((0..10).map(|i| (vec![bob], text(i))));
+      outbox.flush(&network, &mut client).await.unwrap();
+      assert_eq!(*network.socket.max_outstanding.lock().unwrap(), 3);
+      // nothing is sent past the first message the server was too busy for
+      assert_eq!(network.socket.sequences().len(), 4);
+      let unacked: Vec<u128> = outbox.unacked.iter().map(|(_, sq)| sq.seqid).collect();
+      assert_eq!(unacked, [2, 3, 4]);
+      assert!(!outbox.is_due());
+
+      // they are sent again in order, before the ones that were never sent
+      outbox.retransmit(&network, &mut client).await.unwrap();
+      let pending: Vec<&ClientQuery> = outbox.pending.iter().map(|(_, q)| q).collect();
+      let expected: Vec<ClientQuery> = (1..10).map(text).collect();
+      assert_eq!(pending, expected.iter().collect::<Vec<_>>());
+
+      while !outbox.pending.is_empty() {
+        async_std::task::sleep(Duration::from_millis(10)).await;
+        if outbox.is_due() {
+          outbox.flush(&network, &mut client).await.unwrap();
+        }
+      }
+      assert_eq!(*network.socket.max_outstanding.lock().unwrap(), 3);
+      let expected: Vec<String> = (0..10).map(|i| format!("message {}", i)).collect();
+      assert_eq!(*delivered_contents.lock().unwrap(), expected);
+      assert!(network.waiting.lock().unwrap().is_empty());
+    })
+  }
+
+  #[test]
+  fn max_inflight() {
+    async_std::task::block_on(async {
+      let network = Arc::new(Network::with_transport(
+        ScriptedTransport::new(|sq| match &sq.content {
+          ClientQuery::Message(ClientMessage::Text { content, .. }) => {
+            payload(content, |w, c| encode::string(w, c))
+          }
+          q => panic!("unexpected query {:?}", q),
+        })
+        .lifo(),
+        3,
+      ));
+      let mut client = Client::new(ClientId::default());
+      let mut tasks = Vec::new();
+      for i in 0..20 {
+        let content = format!("message {}", i);
+        let sq = client.sequence(ClientQuery::Message(ClientMessage::Text {
+          dest: ClientId::default(),
+          content: content.clone(),
+          attachments: Vec::new(),
+        }));
+        let network = network.clone();
+        tasks.push(async_std::task::spawn(async move {
+          let reply = network.query(sq, decode::string).await.unwrap();
+          assert_eq!(reply, content);
+        }));
+      }
+      for task in tasks {
+        task.await;
+      }
+      assert!(*network.socket.max_outstanding.lock().unwrap() <= 3);
+      assert!(network.waiting.lock().unwrap().is_empty());
+    })
+  }
 }
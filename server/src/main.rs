@@ -1,14 +1,29 @@
-use async_std::net::UdpSocket;
+use async_std::io::prelude::{ReadExt, WriteExt};
+use async_std::net::{TcpListener, TcpStream, UdpSocket};
 use async_std::sync::RwLock;
 use async_std::task;
-use chatproto::core::MessageServer;
+use chatproto::core::{MessageServer, MAX_CLIENTS, MAX_USER_PAGE};
 #[cfg(feature = "federation")]
-use chatproto::messages::ServerReply;
-use chatproto::messages::{ClientError, ClientQuery, Sequence, ServerId};
-use chatproto::netproto::{decode, encode};
-use std::io::Cursor;
-use std::net::IpAddr;
+use chatproto::messages::{AuthMessage, ServerMessage, ServerReply};
+use chatproto::messages::{
+  ClientError, ClientId, ClientLocation, ClientQuery, ClientReply, PollAck, Reply, Request,
+  Sequence, ServerId, ServerInfo, UserPage,
+};
+use chatproto::netproto::frame;
+#[cfg(feature = "federation")]
+use chatproto::netproto::FrameKind;
+use chatproto::netproto::{decode, encode, TransportKind, PROTOCOL_VERSION};
+use chatproto::ratelimit::{LogLimiter, LOG_WINDOW};
+use chatproto::reorder::ReorderBuffer;
+use chatproto::solutions::sample::{OverflowMode, Server};
+#[cfg(feature = "self-test")]
+use chatproto::testing::all_tests;
+use std::collections::HashMap;
+use std::io::{Cursor, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use structopt::StructOpt;
 
 #[derive(StructOpt)]
@@ -21,6 +36,11 @@ struct Opt {
   /// address to listen for clients on
   clisten: IpAddr,
 
+  #[structopt(long, default_value = "udp")]
+  /// how clients reach the server, udp, tcp, or chunked for udp with messages larger than a
+  /// datagram, other servers are always reached over udp
+  transport: TransportKind,
+
   #[structopt(long, default_value = "4667")]
   /// port to listen for servers on
   sport: u16,
@@ -28,65 +48,253 @@ struct Opt {
   #[structopt(long, default_value = "0.0.0.0")]
   /// address to listen for servers on
   slisten: IpAddr,
+
+  #[structopt(long, default_value = "300")]
+  /// messages older than this many seconds are rejected as possible replays
+  replay_window: u64,
+
+  #[structopt(long)]
+  /// reject messages that do not come from the address the client registered from
+  check_address: bool,
+
+  #[structopt(long)]
+  /// clients that have not sent anything for this many seconds are not listed anymore
+  liveness_window: Option<u64>,
+
+  #[structopt(long)]
+  /// keep the messages sent to full mailboxes in an overflow buffer instead of rejecting them
+  queue_overflow: bool,
+
+  #[structopt(long)]
+  /// send the messages addressed to the reserved echo client back to their sender
+  echo: bool,
+
+  #[structopt(long, default_value = "1024")]
+  /// over tcp, the number of client connections served at once, the others are closed right away
+  max_connections: usize,
+
+  #[structopt(long, default_value = "300")]
+  /// over tcp, client connections nothing is received from for this many seconds are closed
+  idle_timeout: u64,
+
+  #[structopt(long, default_value = "8192")]
+  /// size of the buffer client datagrams are received in, larger datagrams are dropped
+  /// over tcp, the largest message accepted from a client
+  recv_buffer: usize,
+
+  #[structopt(long)]
+  /// send read receipts to the senders of local messages, batched over this many milliseconds
+  receipt_window: Option<u64>,
+
+  #[structopt(long, default_value = "10")]
+  /// number of undecodable datagrams logged per second, the others are only counted
+  decode_log_limit: usize,
+
+  #[structopt(long)]
+  /// drop the queries of unknown senders before processing them, registrations excepted
+  strict: bool,
+
+  #[structopt(long)]
+  /// hold the queries that arrive ahead of their predecessors for up to this many milliseconds,
+  /// so that they are handled in order
+  reorder_grace: Option<u64>,
+
+  #[structopt(long)]
+  /// answer ServerBusy to the messages that could not be handled within this many milliseconds,
+  /// instead of stalling the other clients
+  lock_timeout: Option<u64>,
+
+  #[structopt(long)]
+  /// refuse to register the names starting with this prefix, whatever their case, can be repeated
+  reserved_prefix: Vec<String>,
+
+  #[structopt(long)]
+  /// run the test suite against the server implementation, and exit (needs the self-test feature)
+  self_test: bool,
+}
+
+#[cfg(feature = "federation")]
+#[derive(Debug, PartialEq)]
+enum ServerFrame {
+  Message(ServerMessage),
+  Auth(AuthMessage),
+  Ping,
+  Ack,
+}
+
+// decodes a frame received from another server, according to its kind
+#[cfg(feature = "federation")]
+fn read_server_frame(buf: &[u8], version: u8) -> anyhow::Result<ServerFrame> {
+  let mut cursor = Cursor::new(buf);
+  Ok(match decode::frame_kind(&mut cursor, version)? {
+    FrameKind::Server => ServerFrame::Message(decode::server_versioned(&mut cursor, version)?),
+    FrameKind::Auth => ServerFrame::Auth(decode::auth(&mut cursor)?),
+    FrameKind::Ping => ServerFrame::Ping,
+    FrameKind::Ack => ServerFrame::Ack,
+  })
+}
+
+// tells the peer that its server message was accepted
+#[cfg(feature = "federation")]
+fn ack_frame(version: u8) -> std::io::Result<Vec<u8>> {
+  let mut out = Vec::new();
+  encode::frame_kind(&mut out, FrameKind::Ack, version)?;
+  Ok(out)
+}
+
+// a server message, as sent to the next hop
+#[cfg(feature = "federation")]
+fn server_frame(msg: &ServerMessage, version: u8) -> std::io::Result<Vec<u8>> {
+  let mut out = Vec::new();
+  encode::frame_kind(&mut out, FrameKind::Server, version)?;
+  encode::server_versioned(&mut out, msg, version)?;
+  Ok(out)
 }
 
 #[cfg(feature = "federation")]
 async fn server_thread<S: MessageServer>(
   listen: IpAddr,
   port: u16,
+  decode_log_limit: usize,
   srv: &RwLock<S>,
 ) -> std::io::Result<()> {
   let socket = UdpSocket::bind((listen, port)).await?;
   log::info!("Listening for servers on {}", socket.local_addr()?);
   let mut buf = vec![0u8; 8192];
+  let mut decode_errors = LogLimiter::new(decode_log_limit, LOG_WINDOW);
+  // the address of each neighbour, learnt from the announces it relays, the last server of a route
+  // being the one it was received from, so that the messages to forward can be sent to it
+  let mut neighbours: HashMap<ServerId, SocketAddr> = HashMap::new();
   loop {
     let (n, peer) = socket.recv_from(&mut buf).await?;
-    let mut cursor = Cursor::new(buf[..n].to_vec());
-    match decode::server(&mut cursor) {
-      Err(rr) => log::error!("Could not decode server message from {}: {}", peer, rr),
-      Ok(msg) => match srv.write().await.handle_server_message(msg).await {
-        ServerReply::Outgoing(_) => todo!(),
-        ServerReply::EmptyRoute => todo!(),
-        ServerReply::Error(rr) => {
-          log::error!("Error occured when handling message from {}: {}", peer, rr)
+    match read_server_frame(&buf[..n], PROTOCOL_VERSION) {
+      Err(rr) => decode_errors.log("undecodable server frames", || {
+        log::error!("Could not decode server frame from {}: {}", peer, rr)
+      }),
+      Ok(ServerFrame::Ping) => log::debug!("ping from {}", peer),
+      Ok(ServerFrame::Ack) => log::debug!("message acknowledged by {}", peer),
+      Ok(ServerFrame::Auth(msg)) => {
+        log::warn!(
+          "ignoring {:?} from {}, servers are not authenticated",
+          msg,
+          peer
+        )
+      }
+      Ok(ServerFrame::Message(msg)) => {
+        let neighbour = match &msg {
+          ServerMessage::Announce { route, .. } => route.last().copied(),
+          _ => None,
+        };
+        match srv.read().await.handle_server_message(msg).await {
+          ServerReply::Outgoing(outgoing) => {
+            // only the neighbours relaying accepted announces are remembered
+            if let Some(neighbour) = neighbour {
+              neighbours.insert(neighbour, peer);
+            }
+            for out in outgoing {
+              let addr = match neighbours.get(&out.nexthop) {
+                Some(addr) => *addr,
+                None => {
+                  log::warn!("no address for {}, dropping a message to it", out.nexthop);
+                  continue;
+                }
+              };
+              let datagram = server_frame(&ServerMessage::Message(out.message), PROTOCOL_VERSION)?;
+              if let Err(rr) = socket.send_to(&datagram, addr).await {
+                log::error!("Could not forward a message to {}: {}", out.nexthop, rr);
+              }
+            }
+          }
+          ServerReply::EmptyRoute => {
+            log::warn!("dropping an announce with an empty route from {}", peer)
+          }
+          ServerReply::Error(rr) => {
+            log::error!("Error occured when handling message from {}: {}", peer, rr)
+          }
+          ServerReply::Ack => {
+            log::debug!("acknowledging the message from {}", peer);
+            socket.send_to(&ack_frame(PROTOCOL_VERSION)?, peer).await?;
+          }
         }
-      },
+      }
     }
   }
 }
 
+// checks the sequence of a registration, the client being unknown is expected
+async fn check_registration<S: MessageServer>(
+  srv: &S,
+  m: Sequence<ClientQuery>,
+  peer: Option<SocketAddr>,
+) -> anyhow::Result<()> {
+  match srv.handle_sequenced_message(m, peer).await {
+    Ok(_) => Ok(()),
+    Err(ClientError::UnknownClient) => Ok(()),
+    Err(rr) => Err(rr.into()),
+  }
+}
+
 async fn handle_client_query<S: MessageServer>(
   srv: &RwLock<S>,
   m: Sequence<ClientQuery>,
+  peer: Option<SocketAddr>,
 ) -> anyhow::Result<Vec<u8>> {
   log::debug!("received {:?}", m);
   let src = m.src;
 
-  let lock = srv.write().await;
+  // the server locks what it needs on its own, a write guard here would serialize every query
+  // and leave ServerBusy unreachable
+  let lock = srv.read().await;
 
   // handle register
   if let ClientQuery::Register(name) = &m.content {
     log::debug!("handle register message");
     let name = name.clone();
-    match lock.handle_sequenced_message(m).await {
-      Ok(_) => (),
-      Err(ClientError::UnknownClient) => (),
-      Err(rr) => {
-        anyhow::bail!("Error when handling register message: {}", rr);
-      }
+    let nonce = m.src;
+    check_registration(&*lock, m, peer).await?;
+    let id = lock.register_local_client_from(nonce, name).await?;
+    if let Some(peer) = peer {
+      lock.bind_address(id, peer).await;
     }
-    let id = lock.register_local_client(name).await;
     let mut ocurs = Cursor::new(Vec::new());
     encode::clientid(&mut ocurs, &id)?;
     return Ok(ocurs.into_inner());
   }
 
-  match lock.handle_sequenced_message(m).await? {
+  if let ClientQuery::RegisterAndMessage { name, message } = &m.content {
+    log::debug!("handle register and message");
+    let (name, message) = (name.clone(), message.clone());
+    let nonce = m.src;
+    check_registration(&*lock, m, peer).await?;
+    let (id, repl) = lock.register_and_message(nonce, name, message).await?;
+    if let Some(peer) = peer {
+      lock.bind_address(id, peer).await;
+    }
+    let mut ocurs = Cursor::new(Vec::new());
+    encode::clientid(&mut ocurs, &id)?;
+    encode::client_replies(&mut ocurs, &repl)?;
+    return Ok(ocurs.into_inner());
+  }
+
+  // liveness only, so that it costs nothing to the client
+  if let ClientQuery::Heartbeat = m.content {
+    if !lock.heartbeat(src, peer).await {
+      log::debug!("heartbeat from {} ignored", src);
+    }
+    let mut ocurs = Cursor::new(Vec::new());
+    encode::client_replies(&mut ocurs, &[ClientReply::Heartbeat])?;
+    return Ok(ocurs.into_inner());
+  }
+
+  match lock.handle_sequenced_message(m, peer).await? {
     ClientQuery::Poll => {
-      let repl = lock.client_poll(src).await;
+      let repl = PollAck {
+        reply: lock.client_poll(src).await,
+        contiguous_seqid: lock.ack_status(src).await.unwrap_or_default().contiguous,
+      };
       log::debug!(" -> poll {:?}", repl);
       let mut ocurs = Cursor::new(Vec::new());
-      encode::client_poll_reply(&mut ocurs, &repl)?;
+      encode::poll_ack(&mut ocurs, &repl)?;
       Ok(ocurs.into_inner())
     }
     ClientQuery::ListUsers => {
@@ -95,8 +303,30 @@ async fn handle_client_query<S: MessageServer>(
       encode::userlist(&mut ocurs, &repl)?;
       Ok(ocurs.into_inner())
     }
-    ClientQuery::Register(_) => {
-      anyhow::bail!("Unexpected register message from enrolled client")
+    ClientQuery::ListUsersPage { offset, limit } => {
+      let users = lock.list_users().await;
+      let repl = UserPage::slice(users, offset, limit.min(MAX_USER_PAGE));
+      let mut ocurs = Cursor::new(Vec::new());
+      encode::user_page(&mut ocurs, &repl)?;
+      Ok(ocurs.into_inner())
+    }
+    ClientQuery::Reachable => {
+      // capped like a page of users, so that the reply fits in a datagram, keeping the lowest ids
+      let mut reachable: Vec<_> = lock.reachable_clients().await.into_iter().collect();
+      reachable.sort_by_key(|(id, _)| *id);
+      reachable.truncate(MAX_USER_PAGE as usize);
+      let repl: HashMap<ClientId, ClientLocation> = reachable.into_iter().collect();
+      let mut ocurs = Cursor::new(Vec::new());
+      encode::reachable(&mut ocurs, &repl)?;
+      Ok(ocurs.into_inner())
+    }
+    ClientQuery::Register(_) | ClientQuery::RegisterAndMessage { .. } => {
+      log::debug!("unexpected register message from enrolled client {}", src);
+      Err(ClientError::ProtocolError.into())
+    }
+    ClientQuery::Heartbeat => {
+      log::debug!("unexpected sequenced heartbeat from {}", src);
+      Err(ClientError::ProtocolError.into())
     }
     ClientQuery::Message(msg) => {
       let repl = lock.handle_client_message(src, msg).await;
@@ -104,59 +334,1689 @@ async fn handle_client_query<S: MessageServer>(
       encode::client_replies(&mut ocurs, &repl)?;
       Ok(ocurs.into_inner())
     }
+    ClientQuery::Recall { message_id } => {
+      let found = lock.recall(src, message_id).await;
+      let mut ocurs = Cursor::new(Vec::new());
+      encode::bool(&mut ocurs, found)?;
+      Ok(ocurs.into_inner())
+    }
+    ClientQuery::Subscribe(topic) => {
+      let added = lock.subscribe(src, topic).await;
+      let mut ocurs = Cursor::new(Vec::new());
+      encode::bool(&mut ocurs, added)?;
+      Ok(ocurs.into_inner())
+    }
+    ClientQuery::HomeServer(client) => {
+      let server = lock.home_server(client).await;
+      let mut ocurs = Cursor::new(Vec::new());
+      encode::option_serverid(&mut ocurs, &server)?;
+      Ok(ocurs.into_inner())
+    }
+    ClientQuery::Unsubscribe(topic) => {
+      let removed = lock.unsubscribe(src, &topic).await;
+      let mut ocurs = Cursor::new(Vec::new());
+      encode::bool(&mut ocurs, removed)?;
+      Ok(ocurs.into_inner())
+    }
+    ClientQuery::SetOrder(order) => {
+      let set = lock.set_delivery_order(src, order).await;
+      let mut ocurs = Cursor::new(Vec::new());
+      encode::bool(&mut ocurs, set)?;
+      Ok(ocurs.into_inner())
+    }
+    ClientQuery::SetDelivery(enabled) => {
+      let set = lock.set_delivery(src, enabled).await;
+      let mut ocurs = Cursor::new(Vec::new());
+      encode::bool(&mut ocurs, set)?;
+      Ok(ocurs.into_inner())
+    }
+    ClientQuery::AckStatus => {
+      let status = lock.ack_status(src).await.unwrap_or_default();
+      let mut ocurs = Cursor::new(Vec::new());
+      encode::ack_status(&mut ocurs, &status)?;
+      Ok(ocurs.into_inner())
+    }
+    ClientQuery::ServerInfo => {
+      let info = ServerInfo {
+        server_id: lock.id(),
+        group_name: S::GROUP_NAME.to_string(),
+        version: PROTOCOL_VERSION,
+      };
+      let mut ocurs = Cursor::new(Vec::new());
+      encode::server_info(&mut ocurs, &info)?;
+      Ok(ocurs.into_inner())
+    }
+  }
+}
+
+// in strict mode, only registrations are processed for senders that are not local clients
+// there is no reply, so that probing for clients costs as little as possible
+async fn accept_sender<S: MessageServer>(
+  srv: &RwLock<S>,
+  strict: bool,
+  rq: &Request<ClientQuery>,
+) -> bool {
+  if !strict {
+    return true;
+  }
+  match rq.sequence.content {
+    ClientQuery::Register(_) | ClientQuery::RegisterAndMessage { .. } => true,
+    _ => srv
+      .read()
+      .await
+      .last_accepted_seqid(rq.sequence.src)
+      .await
+      .is_some(),
+  }
+}
+
+// the last reply sent to each client, along with the query it answers
+// a query whose reply was lost is sent again as it is, and its sequence id is not accepted anymore,
+// so the reply is sent again instead of handling it a second time
+#[derive(Default)]
+struct ReplyCache {
+  last: std::sync::Mutex<HashMap<ClientId, Answered>>,
+}
+
+// a query, and the payload of its reply
+type Answered = (Sequence<ClientQuery>, Vec<u8>);
+
+impl ReplyCache {
+  // the reply to this exact query, if it was the last one handled for its client
+  fn get(&self, sq: &Sequence<ClientQuery>) -> Option<Vec<u8>> {
+    match self.last.lock().unwrap().get(&sq.src) {
+      Some((cached, payload)) if cached == sq => Some(payload.clone()),
+      _ => None,
+    }
+  }
+
+  // registrations and heartbeats are not kept, the former are deduplicated by the server and the
+  // latter are not sequenced
+  fn insert(&self, sq: Sequence<ClientQuery>, payload: &[u8]) {
+    if matches!(
+      sq.content,
+      ClientQuery::Register(_) | ClientQuery::RegisterAndMessage { .. } | ClientQuery::Heartbeat
+    ) {
+      return;
+    }
+    let mut last = self.last.lock().unwrap();
+    if last.len() >= MAX_CLIENTS && !last.contains_key(&sq.src) {
+      if let Some(evicted) = last.keys().next().copied() {
+        last.remove(&evicted);
+      }
+    }
+    last.insert(sq.src, (sq, payload.to_vec()));
+  }
+}
+
+// handles the query, and wraps the reply so that it carries the request id
+async fn handle_client_request<S: MessageServer>(
+  srv: &RwLock<S>,
+  replies: &ReplyCache,
+  rq: Request<ClientQuery>,
+  peer: Option<SocketAddr>,
+) -> anyhow::Result<Vec<u8>> {
+  let payload = match replies.get(&rq.sequence) {
+    Some(payload) => {
+      log::debug!(
+        "query {} of {} sent again, so is its reply",
+        rq.sequence.seqid,
+        rq.sequence.src
+      );
+      payload
+    }
+    None => {
+      let sq = rq.sequence.clone();
+      match handle_client_query(srv, rq.sequence, peer).await {
+        Ok(payload) => {
+          replies.insert(sq, &payload);
+          payload
+        }
+        // not cached, the query is handled again when it is sent again
+        Err(rr) => return failure_reply(rq.request_id, rr),
+      }
+    }
+  };
+  let mut ocurs = Cursor::new(Vec::new());
+  encode::reply(
+    &mut ocurs,
+    &Reply {
+      request_id: rq.request_id,
+      payload,
+    },
+    |w, p| w.write_all(p),
+  )?;
+  Ok(ocurs.into_inner())
+}
+
+// replies to a query this server does not know about, for the given request id
+fn unsupported_reply(request_id: u64) -> anyhow::Result<Vec<u8>> {
+  error_reply(request_id, ClientError::ProtocolError)
+}
+
+// tells the client a query failed, for the given request id
+fn error_reply(request_id: u64, error: ClientError) -> anyhow::Result<Vec<u8>> {
+  let mut ocurs = Cursor::new(Vec::new());
+  encode::error_reply(
+    &mut ocurs,
+    &Reply {
+      request_id,
+      payload: error,
+    },
+  )?;
+  Ok(ocurs.into_inner())
+}
+
+// tells the client why its query failed, failures that are not a ClientError (the reply could not
+// be encoded) are logged, and reported as an InternalError
+fn failure_reply(request_id: u64, rr: anyhow::Error) -> anyhow::Result<Vec<u8>> {
+  let error = match rr.downcast::<ClientError>() {
+    Ok(error) => error,
+    Err(rr) => {
+      log::error!("could not handle request {}: {}", request_id, rr);
+      ClientError::InternalError
+    }
+  };
+  error_reply(request_id, error)
+}
+
+enum Datagram {
+  // the datagram filled the whole buffer, the OS might have dropped its end
+  Truncated,
+  Invalid(anyhow::Error),
+  // a well formed request, with a query this server does not support
+  Unsupported(u64),
+  Request(Request<ClientQuery>),
+}
+
+// decodes the first `n` bytes of the receive buffer
+fn read_datagram(buf: &[u8], n: usize) -> Datagram {
+  if n >= buf.len() {
+    return Datagram::Truncated;
+  }
+  read_request(&buf[..n])
+}
+
+// decodes a whole request, whatever the transport it came from
+fn read_request(buf: &[u8]) -> Datagram {
+  let mut cursor = Cursor::new(buf);
+  let query = |rd: &mut Cursor<Vec<u8>>| match decode::client_query(rd) {
+    Ok(q) => Ok(Some(q)),
+    Err(rr)
+      if matches!(
+        rr.downcast_ref(),
+        Some(decode::DecodeError::UnknownQuery(_))
+      ) =>
+    {
+      Ok(None)
+    }
+    Err(rr) => Err(rr),
+  };
+  match decode::request(&mut cursor, query) {
+    Ok(Request {
+      request_id,
+      sequence,
+    }) => match sequence.content {
+      None => Datagram::Unsupported(request_id),
+      Some(content) => Datagram::Request(Request {
+        request_id,
+        sequence: Sequence {
+          seqid: sequence.seqid,
+          src: sequence.src,
+          workproof: sequence.workproof,
+          timestamp: sequence.timestamp,
+          content,
+        },
+      }),
+    },
+    Err(rr) => Datagram::Invalid(rr),
+  }
+}
+
+// a request waiting in the reorder buffer, with the address it came from
+type Held = (Request<ClientQuery>, SocketAddr);
+
+// the requests that can be handled now, in order
+// only the requests of local clients are reordered, registrations are handled right away
+async fn reordered<S: MessageServer>(
+  srv: &RwLock<S>,
+  reorder: &mut Option<ReorderBuffer<Held>>,
+  rq: Request<ClientQuery>,
+  peer: SocketAddr,
+) -> Vec<Held> {
+  let reorder = match reorder {
+    Some(r) => r,
+    None => return vec![(rq, peer)],
+  };
+  let (src, seqid) = (rq.sequence.src, rq.sequence.seqid);
+  match srv.read().await.last_accepted_seqid(src).await {
+    Some(last) => reorder.push(src, seqid, last, (rq, peer), Instant::now()),
+    None => vec![(rq, peer)],
+  }
+}
+
+// how long the chunks of a request are kept while the others are missing
+const CHUNK_TIMEOUT: Duration = Duration::from_secs(5);
+
+// the chunks received from each client, and the id of the last reply split in chunks
+struct Chunking {
+  reassembler: frame::Reassembler,
+  last_id: u64,
+}
+
+impl Chunking {
+  fn new() -> Self {
+    Chunking {
+      reassembler: frame::Reassembler::new(CHUNK_TIMEOUT),
+      last_id: 0,
+    }
+  }
+
+  // decodes the request the first `n` bytes of the receive buffer complete, if any
+  fn receive(&mut self, buf: &[u8], n: usize, peer: SocketAddr) -> Option<Datagram> {
+    if n >= buf.len() {
+      return Some(Datagram::Truncated);
+    }
+    let now = Instant::now();
+    if self.reassembler.next_expiry().is_some_and(|e| e <= now) {
+      self.reassembler.expire(now);
+    }
+    let pushed =
+      frame::read_chunk(&buf[..n]).and_then(|chunk| self.reassembler.push(peer, chunk, now));
+    match pushed {
+      Ok(Some(payload)) => Some(read_request(&payload)),
+      Ok(None) => None,
+      Err(rr) => Some(Datagram::Invalid(rr)),
+    }
+  }
+
+  fn split(&mut self, msg: &[u8]) -> anyhow::Result<Vec<Vec<u8>>> {
+    self.last_id = self.last_id.wrapping_add(1);
+    frame::split(self.last_id, msg, frame::CHUNK_DATAGRAM_LEN)
+  }
+}
+
+// with chunking, the reply is sent in as many datagrams as needed
+async fn send_reply(
+  socket: &UdpSocket,
+  reply: anyhow::Result<Vec<u8>>,
+  peer: SocketAddr,
+  chunking: Option<&mut Chunking>,
+) {
+  let datagrams = reply.and_then(|msg| {
+    log::debug!("sending message {:?}", msg);
+    match chunking {
+      Some(chunking) => chunking.split(&msg),
+      None => Ok(vec![msg]),
+    }
+  });
+  match datagrams {
+    Ok(datagrams) => {
+      for datagram in datagrams {
+        if let Err(rr) = socket.send_to(&datagram, peer).await {
+          log::error!("Error when sending message to {}: {}", peer, rr);
+          return;
+        }
+      }
+    }
+    Err(rr) => log::error!("Error when handling message to {}: {}", peer, rr),
   }
 }
 
 async fn client_thread<S: MessageServer>(
-  listen: IpAddr,
-  port: u16,
+  listen: SocketAddr,
+  chunked: bool,
+  recv_buffer: usize,
+  decode_log_limit: usize,
+  strict: bool,
+  reorder_grace: Option<Duration>,
   srv: &RwLock<S>,
 ) -> anyhow::Result<()> {
-  let socket = UdpSocket::bind((listen, port)).await?;
+  let socket = UdpSocket::bind(listen).await?;
   log::info!("Listening for clients on {}", socket.local_addr()?);
-  let mut buf = vec![0u8; 8192];
+  let mut buf = vec![0u8; recv_buffer];
+  let mut decode_errors = LogLimiter::new(decode_log_limit, LOG_WINDOW);
+  let mut unknown_senders = LogLimiter::new(decode_log_limit, LOG_WINDOW);
+  let mut reorder = reorder_grace.map(ReorderBuffer::new);
+  let mut chunking = chunked.then(Chunking::new);
+  let replies = ReplyCache::default();
   loop {
-    let (n, peer) = socket.recv_from(&mut buf).await?;
-    let mut cursor = Cursor::new(buf[..n].to_vec());
-    match decode::sequence(&mut cursor, decode::client_query) {
-      Err(rr) => log::error!("Could not decode message from {}: {}", peer, rr),
-      Ok(m) => match handle_client_query(srv, m).await {
+    // the held requests must not wait for the next datagram once their grace period is over
+    let received = match reorder.as_ref().and_then(ReorderBuffer::next_expiry) {
+      Some(expiry) => {
+        let wait = expiry.saturating_duration_since(Instant::now());
+        async_std::future::timeout(wait, socket.recv_from(&mut buf))
+          .await
+          .ok()
+      }
+      None => Some(socket.recv_from(&mut buf).await),
+    };
+    if let Some(reorder) = &mut reorder {
+      for (rq, peer) in reorder.expired(Instant::now()) {
+        send_reply(
+          &socket,
+          handle_client_request(srv, &replies, rq, Some(peer)).await,
+          peer,
+          chunking.as_mut(),
+        )
+        .await;
+      }
+    }
+    let (n, peer) = match received {
+      Some(r) => r?,
+      None => continue,
+    };
+    let datagram = match &mut chunking {
+      Some(chunking) => match chunking.receive(&buf, n, peer) {
+        Some(datagram) => datagram,
+        None => continue,
+      },
+      None => read_datagram(&buf, n),
+    };
+    let reply = match datagram {
+      Datagram::Truncated => {
+        log::warn!(
+          "datagram from {} possibly truncated, increase --recv-buffer",
+          peer
+        );
+        continue;
+      }
+      Datagram::Invalid(rr) => {
+        decode_errors.log("undecodable messages", || {
+          log::error!("Could not decode message from {}: {}", peer, rr)
+        });
+        continue;
+      }
+      Datagram::Unsupported(request_id) => {
+        log::warn!("unsupported query from {}", peer);
+        unsupported_reply(request_id)
+      }
+      Datagram::Request(rq) if !accept_sender(srv, strict, &rq).await => {
+        unknown_senders.log("queries from unknown senders", || {
+          log::warn!(
+            "dropping a query from unknown sender {} at {}",
+            rq.sequence.src,
+            peer
+          )
+        });
+        continue;
+      }
+      Datagram::Request(rq) => {
+        for (rq, peer) in reordered(srv, &mut reorder, rq, peer).await {
+          send_reply(
+            &socket,
+            handle_client_request(srv, &replies, rq, Some(peer)).await,
+            peer,
+            chunking.as_mut(),
+          )
+          .await;
+        }
+        continue;
+      }
+    };
+    send_reply(&socket, reply, peer, chunking.as_mut()).await;
+  }
+}
+
+// bounds on the tcp connections of clients
+#[derive(Clone, Copy)]
+struct TcpLimits {
+  max_connections: usize,
+  // a connection nothing is received from for this long is closed, as is a connection a reply
+  // cannot be written to for this long
+  idle_timeout: Duration,
+}
+
+// time waited before accepting connections again, after accept failed
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+// only clients are served over tcp, server to server traffic (announces included) stays on udp
+async fn tcp_client_thread<S: MessageServer + Send + Sync + 'static>(
+  listen: SocketAddr,
+  limits: TcpLimits,
+  recv_buffer: usize,
+  decode_log_limit: usize,
+  strict: bool,
+  srv: Arc<RwLock<S>>,
+) -> anyhow::Result<()> {
+  let listener = TcpListener::bind(listen).await?;
+  log::info!("Listening for clients on {} (tcp)", listener.local_addr()?);
+  serve_tcp_clients(listener, limits, recv_buffer, decode_log_limit, strict, srv).await
+}
+
+// decrements the number of open connections when a connection is closed
+struct Connection(Arc<AtomicUsize>);
+
+impl Drop for Connection {
+  fn drop(&mut self) {
+    self.0.fetch_sub(1, Ordering::Relaxed);
+  }
+}
+
+// each connection is served by its own task, the requests of a connection are handled in order
+// so that, unlike with udp, they never need to be reordered
+// failing to accept a connection (when out of file descriptors, for instance) does not stop the
+// server, it waits a bit and tries again
+async fn serve_tcp_clients<S: MessageServer + Send + Sync + 'static>(
+  listener: TcpListener,
+  limits: TcpLimits,
+  recv_buffer: usize,
+  decode_log_limit: usize,
+  strict: bool,
+  srv: Arc<RwLock<S>>,
+) -> anyhow::Result<()> {
+  let open = Arc::new(AtomicUsize::new(0));
+  loop {
+    let (stream, peer) = match listener.accept().await {
+      Ok(accepted) => accepted,
+      Err(rr) => {
+        log::error!("could not accept a connection: {}", rr);
+        task::sleep(ACCEPT_BACKOFF).await;
+        continue;
+      }
+    };
+    if open.fetch_add(1, Ordering::Relaxed) >= limits.max_connections {
+      open.fetch_sub(1, Ordering::Relaxed);
+      log::warn!("too many connections, closing the one from {}", peer);
+      continue;
+    }
+    let connection = Connection(open.clone());
+    if let Err(rr) = stream.set_nodelay(true) {
+      log::warn!("could not disable nagle for {}: {}", peer, rr);
+    }
+    let srv = srv.clone();
+    task::spawn(async move {
+      let _connection = connection;
+      let served = tcp_connection(
+        stream,
+        peer,
+        limits.idle_timeout,
+        recv_buffer,
+        decode_log_limit,
+        strict,
+        &srv,
+      );
+      match served.await {
+        Ok(()) => log::debug!("{} disconnected", peer),
+        Err(rr) => log::warn!("closing the connection of {}: {}", peer, rr),
+      }
+    });
+  }
+}
+
+async fn tcp_connection<S: MessageServer>(
+  stream: TcpStream,
+  peer: SocketAddr,
+  idle_timeout: Duration,
+  recv_buffer: usize,
+  decode_log_limit: usize,
+  strict: bool,
+  srv: &RwLock<S>,
+) -> anyhow::Result<()> {
+  let mut buf = vec![0u8; recv_buffer];
+  // what was read from the stream and does not make a whole message yet
+  let mut pending = Vec::new();
+  let mut decode_errors = LogLimiter::new(decode_log_limit, LOG_WINDOW);
+  let mut unknown_senders = LogLimiter::new(decode_log_limit, LOG_WINDOW);
+  let replies = ReplyCache::default();
+  loop {
+    // a message that is too large leaves the stream out of sync, the connection is closed
+    while let Some((header, len)) = frame::delimited(&pending, recv_buffer)? {
+      let request = read_request(&pending[header..header + len]);
+      pending.drain(..header + len);
+      let reply = match request {
+        Datagram::Truncated => continue,
+        Datagram::Invalid(rr) => {
+          decode_errors.log("undecodable messages", || {
+            log::error!("Could not decode message from {}: {}", peer, rr)
+          });
+          continue;
+        }
+        Datagram::Unsupported(request_id) => {
+          log::warn!("unsupported query from {}", peer);
+          unsupported_reply(request_id)
+        }
+        Datagram::Request(rq) if !accept_sender(srv, strict, &rq).await => {
+          unknown_senders.log("queries from unknown senders", || {
+            log::warn!(
+              "dropping a query from unknown sender {} at {}",
+              rq.sequence.src,
+              peer
+            )
+          });
+          continue;
+        }
+        Datagram::Request(rq) => handle_client_request(srv, &replies, rq, Some(peer)).await,
+      };
+      match reply {
         Ok(msg) => {
           log::debug!("sending message {:?}", msg);
-          match socket.send_to(&msg, peer).await {
-            Ok(_) => (),
-            Err(rr) => log::error!("Error when sending message to {}: {}", peer, rr),
-          }
+          let delimited = frame::delimit(&msg);
+          let mut writer = &stream;
+          async_std::future::timeout(idle_timeout, writer.write_all(&delimited))
+            .await
+            .map_err(|_| anyhow::anyhow!("the reply could not be written"))??;
         }
         Err(rr) => log::error!("Error when handling message to {}: {}", peer, rr),
-      },
+      }
+    }
+    let n = match async_std::future::timeout(idle_timeout, (&stream).read(&mut buf)).await {
+      Ok(n) => n?,
+      Err(_) => {
+        log::debug!("closing the idle connection of {}", peer);
+        return Ok(());
+      }
+    };
+    if n == 0 {
+      return Ok(());
+    }
+    pending.extend_from_slice(&buf[..n]);
+  }
+}
+
+// operator commands, returns the text to display
+//  * system <text>: sends a notice to every local client
+//  * stats: displays the server metrics
+//  * reset: resets the cumulative counters of the metrics
+async fn handle_admin_command<S: MessageServer>(
+  srv: &RwLock<S>,
+  line: &str,
+) -> anyhow::Result<String> {
+  let (cmd, args) = line.split_once(' ').unwrap_or((line, ""));
+  match cmd {
+    "system" => {
+      if args.trim().is_empty() {
+        anyhow::bail!("usage: system <text>");
+      }
+      srv
+        .read()
+        .await
+        .broadcast_system(args.trim().to_string())
+        .await;
+      Ok("notice sent".to_string())
+    }
+    "stats" => {
+      let m = srv.read().await.metrics().await;
+      Ok(format!(
+        "uptime {}s, {} clients, {} delivered, {} errors, {} pending transfers",
+        m.uptime.as_secs(),
+        m.clients,
+        m.delivered,
+        m.errors,
+        m.pending_transfers
+      ))
+    }
+    "reset" => {
+      srv.read().await.reset_counters().await;
+      Ok("counters reset".to_string())
+    }
+    "top" => {
+      let n = match args.trim() {
+        "" => 10,
+        n => n
+          .parse()
+          .map_err(|_| anyhow::anyhow!("usage: top [count]"))?,
+      };
+      let top = srv.read().await.top_senders(n).await;
+      Ok(
+        top
+          .iter()
+          .map(|(client, sent)| format!("{} {}", client, sent))
+          .collect::<Vec<_>>()
+          .join("\n"),
+      )
+    }
+    _ => anyhow::bail!("unknown admin command {:?}", cmd),
+  }
+}
+
+// reads operator commands from the standard input
+async fn admin_thread<S: MessageServer>(srv: &RwLock<S>) -> anyhow::Result<()> {
+  let stdin = async_std::io::stdin();
+  let mut line = String::new();
+  loop {
+    line.clear();
+    if stdin.read_line(&mut line).await? == 0 {
+      return Ok(());
+    }
+    let line = line.trim();
+    if line.is_empty() {
+      continue;
+    }
+    match handle_admin_command(srv, line).await {
+      Ok(out) => println!("{}", out),
+      Err(rr) => println!("error: {}", rr),
     }
   }
 }
 
+// runs the test suite in process, the error being reported with the number of the failing stage
+#[cfg(feature = "self-test")]
+fn self_test<S: MessageServer>() -> Result<(), (usize, anyhow::Error)> {
+  let mut counter = 0;
+  task::block_on(all_tests::<S>(&mut counter)).map_err(|rr| (counter, rr))
+}
+
 fn main() {
   pretty_env_logger::init();
   let opt = Opt::from_args();
 
-  let server = chatproto::solutions::sample::Server::new(ServerId::default());
+  if opt.self_test {
+    #[cfg(feature = "self-test")]
+    {
+      match self_test::<Server>() {
+        Ok(()) => println!("self-test passed"),
+        Err((stage, rr)) => {
+          println!("self-test failed at stage {}: {:?}", stage, rr);
+          std::process::exit(1);
+        }
+      }
+      return;
+    }
+    #[cfg(not(feature = "self-test"))]
+    {
+      println!("self-test unavailable, the server was built without the self-test feature");
+      std::process::exit(1);
+    }
+  }
+
+  let mut server = Server::new(ServerId::default())
+    .with_replay_window(opt.replay_window.saturating_mul(1000))
+    .with_address_check(opt.check_address)
+    .with_echo(opt.echo)
+    .with_liveness_window(opt.liveness_window.map(Duration::from_secs))
+    .with_receipt_window(opt.receipt_window.map(Duration::from_millis))
+    .with_lock_timeout(opt.lock_timeout.map(Duration::from_millis))
+    .with_overflow_mode(if opt.queue_overflow {
+      OverflowMode::Queue
+    } else {
+      OverflowMode::Reject
+    });
+  if !opt.reserved_prefix.is_empty() {
+    let reserved: Vec<String> = opt
+      .reserved_prefix
+      .iter()
+      .map(|p| p.to_lowercase())
+      .collect();
+    server = server.with_name_policy(Box::new(move |name| {
+      let name = name.to_lowercase();
+      !reserved
+        .iter()
+        .any(|prefix| name.starts_with(prefix.as_str()))
+    }));
+  }
   let clock = Arc::new(RwLock::new(server));
+  let alock = clock.clone();
   #[cfg(feature = "federation")]
   let slock = clock.clone();
 
   task::block_on(async move {
     let cchild = task::spawn(async move {
-      if let Err(rr) = client_thread(opt.clisten, opt.cport, &clock).await {
+      let r = match opt.transport {
+        TransportKind::Udp | TransportKind::Chunked => {
+          client_thread(
+            SocketAddr::new(opt.clisten, opt.cport),
+            opt.transport == TransportKind::Chunked,
+            opt.recv_buffer,
+            opt.decode_log_limit,
+            opt.strict,
+            opt.reorder_grace.map(Duration::from_millis),
+            &clock,
+          )
+          .await
+        }
+        TransportKind::Tcp => {
+          if opt.reorder_grace.is_some() {
+            log::warn!("--reorder-grace has no effect over tcp, requests arrive in order");
+          }
+          let limits = TcpLimits {
+            max_connections: opt.max_connections,
+            idle_timeout: Duration::from_secs(opt.idle_timeout),
+          };
+          tcp_client_thread(
+            SocketAddr::new(opt.clisten, opt.cport),
+            limits,
+            opt.recv_buffer,
+            opt.decode_log_limit,
+            opt.strict,
+            clock,
+          )
+          .await
+        }
+      };
+      if let Err(rr) = r {
         log::error!("{}", rr)
       }
     });
     #[cfg(feature = "federation")]
     let schild = task::spawn(async move {
-      if let Err(rr) = server_thread(opt.slisten, opt.sport, &slock).await {
+      if let Err(rr) = server_thread(opt.slisten, opt.sport, opt.decode_log_limit, &slock).await {
+        log::error!("{}", rr)
+      }
+    });
+    let achild = task::spawn(async move {
+      if let Err(rr) = admin_thread(&alock).await {
         log::error!("{}", rr)
       }
     });
     cchild.await;
+    let _ = achild.cancel().await;
     #[cfg(feature = "federation")]
     let _ = schild.cancel().await;
   });
 }
+
+#[cfg(test)]
+mod test {
+  use chatproto::client::Client;
+  use chatproto::core::WORKPROOF_STRENGTH;
+  use chatproto::messages::{
+    ClientId, ClientLocation, ClientMessage, ClientPollReply, DeliveryOrder,
+  };
+  #[cfg(feature = "self-test")]
+  use chatproto::testing::Stub;
+  use chatproto::workproof::gen_workproof;
+
+  use super::*;
+
+  #[test]
+  fn truncated_datagram() {
+    let rq = Request {
+      request_id: 1,
+      sequence: Client::new(ClientId::default()).sequence(ClientQuery::Message(
+        ClientMessage::Text {
+          dest: ClientId::default(),
+          content: "x".repeat(100),
+          attachments: Vec::new(),
+        },
+      )),
+    };
+    let mut wr = Cursor::new(Vec::new());
+    encode::request(&mut wr, &rq, encode::client_query).unwrap();
+    let datagram = wr.into_inner();
+
+    let mut buf = vec![0u8; 64];
+    buf.copy_from_slice(&datagram[..64]);
+    assert!(matches!(read_datagram(&buf, 64), Datagram::Truncated));
+
+    let mut buf = vec![0u8; 8192];
+    buf[..datagram.len()].copy_from_slice(&datagram);
+    assert!(matches!(read_datagram(&buf, datagram.len()), Datagram::Request(r) if r == rq));
+    assert!(matches!(read_datagram(&buf, 64), Datagram::Invalid(_)));
+  }
+
+  #[test]
+  fn server_busy() {
+    let out = failure_reply(5, ClientError::ServerBusy.into()).unwrap();
+    let reply = decode::reply_header(&mut Cursor::new(out)).unwrap();
+    assert_eq!(reply.request_id, 5);
+    assert_eq!(reply.payload, Err(ClientError::ServerBusy));
+    // the other failures are told apart
+    let out = failure_reply(5, ClientError::UnknownClient.into()).unwrap();
+    let reply = decode::reply_header(&mut Cursor::new(out)).unwrap();
+    assert_eq!(reply.payload, Err(ClientError::UnknownClient));
+    let out = failure_reply(5, anyhow::anyhow!("could not encode")).unwrap();
+    let rr = decode::reply(&mut Cursor::new(out), |_| Ok(())).unwrap_err();
+    assert_eq!(rr.downcast_ref(), Some(&ClientError::InternalError));
+
+    // queries do not wait for the others to release the server
+    task::block_on(async {
+      let srv = RwLock::new(Server::new(ServerId::default()));
+      let c1 = srv
+        .read()
+        .await
+        .register_local_client("c1".into())
+        .await
+        .unwrap();
+      let mut client = Client::new(c1);
+      let _held = srv.read().await;
+      let rq = Request {
+        request_id: 1,
+        sequence: client.sequence(ClientQuery::Poll),
+      };
+      let replies = ReplyCache::default();
+      let handled = handle_client_request(&srv, &replies, rq, None);
+      let handled = async_std::future::timeout(Duration::from_secs(5), handled).await;
+      assert!(handled.unwrap().is_ok());
+    });
+  }
+
+  #[test]
+  fn chunked_requests() {
+    let rq = Request {
+      request_id: 1,
+      sequence: Client::new(ClientId::default()).sequence(ClientQuery::Message(
+        ClientMessage::Text {
+          dest: ClientId::default(),
+          content: "x".repeat(20000),
+          attachments: Vec::new(),
+        },
+      )),
+    };
+    let mut wr = Cursor::new(Vec::new());
+    encode::request(&mut wr, &rq, encode::client_query).unwrap();
+    let request = wr.into_inner();
+    let datagrams = frame::split(1, &request, frame::CHUNK_DATAGRAM_LEN).unwrap();
+    assert!(datagrams.len() > 1);
+
+    let peer = SocketAddr::from(([127, 0, 0, 1], 4000));
+    let mut chunking = Chunking::new();
+    let mut buf = vec![0u8; 8192];
+    let (last, first) = datagrams.split_last().unwrap();
+    for datagram in first {
+      buf[..datagram.len()].copy_from_slice(datagram);
+      assert!(chunking.receive(&buf, datagram.len(), peer).is_none());
+    }
+    buf[..last.len()].copy_from_slice(last);
+    let received = chunking.receive(&buf, last.len(), peer);
+    assert!(matches!(received, Some(Datagram::Request(r)) if r == rq));
+    assert!(matches!(
+      chunking.receive(&buf, 3, peer),
+      Some(Datagram::Invalid(_))
+    ));
+
+    // replies are split the same way
+    let datagrams = chunking.split(&request).unwrap();
+    assert!(datagrams
+      .iter()
+      .all(|d| d.len() <= frame::CHUNK_DATAGRAM_LEN));
+    let mut reassembler = frame::Reassembler::new(CHUNK_TIMEOUT);
+    let reassembled: Vec<_> = datagrams
+      .iter()
+      .filter_map(|d| {
+        let chunk = frame::read_chunk(d).unwrap();
+        reassembler.push(peer, chunk, Instant::now()).unwrap()
+      })
+      .collect();
+    assert_eq!(reassembled, [request]);
+  }
+
+  #[test]
+  fn unsupported_query() {
+    let rq = Request {
+      request_id: 77,
+      sequence: Client::new(ClientId::default()).sequence(ClientQuery::Poll),
+    };
+    let mut wr = Cursor::new(Vec::new());
+    // the query is replaced with a tag from the future
+    encode::request(&mut wr, &rq, |w, _| {
+      w.push(200);
+      Ok(())
+    })
+    .unwrap();
+    let datagram = wr.into_inner();
+    let mut buf = vec![0u8; 8192];
+    buf[..datagram.len()].copy_from_slice(&datagram);
+
+    let request_id = match read_datagram(&buf, datagram.len()) {
+      Datagram::Unsupported(request_id) => request_id,
+      _ => panic!("the query should be unsupported"),
+    };
+    let out = unsupported_reply(request_id).unwrap();
+    let reply = decode::reply_header(&mut Cursor::new(out)).unwrap();
+    assert_eq!(reply.request_id, 77);
+    assert_eq!(reply.payload, Err(ClientError::ProtocolError));
+  }
+
+  #[test]
+  fn tcp_clients() {
+    task::block_on(async {
+      let srv = Arc::new(RwLock::new(Server::new(ServerId::default())));
+      let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+      let addr = listener.local_addr().unwrap();
+      let limits = TcpLimits {
+        max_connections: 16,
+        idle_timeout: Duration::from_secs(60),
+      };
+      task::spawn(serve_tcp_clients(
+        listener,
+        limits,
+        8192,
+        10,
+        false,
+        srv.clone(),
+      ));
+
+      let mut stream = TcpStream::connect(addr).await.unwrap();
+      let mut sent = Vec::new();
+      for (request_id, name) in [(1, "alice"), (2, "bob")] {
+        // registrations are told apart by the temporary id they come from
+        let mut tempclient = Client::new(ClientId::from(request_id as u128));
+        let rq = Request {
+          request_id,
+          sequence: tempclient.sequence(ClientQuery::Register(name.into())),
+        };
+        let mut wr = Cursor::new(Vec::new());
+        encode::request(&mut wr, &rq, encode::client_query).unwrap();
+        sent.extend(frame::delimit(wr.get_ref()));
+      }
+      // both requests in one write, the first one split across two
+      stream.write_all(&sent[..3]).await.unwrap();
+      stream.flush().await.unwrap();
+      task::sleep(Duration::from_millis(10)).await;
+      stream.write_all(&sent[3..]).await.unwrap();
+
+      let mut pending = Vec::new();
+      let mut buf = [0u8; 512];
+      for request_id in [1, 2] {
+        let (header, len) = loop {
+          if let Some(found) = frame::delimited(&pending, 8192).unwrap() {
+            break found;
+          }
+          let n = stream.read(&mut buf).await.unwrap();
+          assert!(n > 0);
+          pending.extend_from_slice(&buf[..n]);
+        };
+        let msg = pending[header..header + len].to_vec();
+        pending.drain(..header + len);
+        let reply = decode::reply(&mut Cursor::new(msg), decode::clientid).unwrap();
+        assert_eq!(reply.request_id, request_id);
+      }
+      assert_eq!(srv.read().await.list_users().await.len(), 2);
+
+      // a message larger than the limit closes the connection
+      stream
+        .write_all(&frame::delimit(&vec![0; 9000]))
+        .await
+        .unwrap();
+      let n = async_std::future::timeout(Duration::from_secs(5), stream.read(&mut buf))
+        .await
+        .unwrap()
+        .unwrap_or(0);
+      assert_eq!(n, 0);
+    })
+  }
+
+  #[test]
+  fn tcp_limits() {
+    task::block_on(async {
+      let srv = Arc::new(RwLock::new(Server::new(ServerId::default())));
+      let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+      let addr = listener.local_addr().unwrap();
+      let limits = TcpLimits {
+        max_connections: 1,
+        idle_timeout: Duration::from_millis(200),
+      };
+      task::spawn(serve_tcp_clients(listener, limits, 8192, 10, false, srv));
+      let closed = |mut stream: TcpStream| async move {
+        let mut buf = [0u8; 16];
+        let read = async_std::future::timeout(Duration::from_secs(5), stream.read(&mut buf));
+        read.await.unwrap().unwrap_or(0) == 0
+      };
+
+      let first = TcpStream::connect(addr).await.unwrap();
+      task::sleep(Duration::from_millis(20)).await;
+      // past the limit, the connection is closed right away
+      let started = Instant::now();
+      assert!(closed(TcpStream::connect(addr).await.unwrap()).await);
+      assert!(started.elapsed() < limits.idle_timeout);
+      // the first one is closed once idle, making room for another one
+      assert!(closed(first).await);
+      task::sleep(Duration::from_millis(20)).await;
+      let started = Instant::now();
+      assert!(closed(TcpStream::connect(addr).await.unwrap()).await);
+      assert!(started.elapsed() >= limits.idle_timeout);
+    })
+  }
+
+  #[test]
+  fn reply_carries_request_id() {
+    task::block_on(async {
+      let srv = RwLock::new(Server::new(ServerId::default()));
+      let tempid = ClientId::default();
+      let workproof = gen_workproof((&tempid).into(), WORKPROOF_STRENGTH, u128::MAX).unwrap();
+      let rq = Request {
+        request_id: 4242,
+        sequence: Sequence {
+          seqid: 0,
+          src: tempid,
+          workproof,
+          timestamp: None,
+          content: ClientQuery::Register("bob".into()),
+        },
+      };
+      let out = handle_client_request(&srv, &ReplyCache::default(), rq, None)
+        .await
+        .unwrap();
+      let reply = decode::reply(&mut Cursor::new(out), decode::clientid).unwrap();
+      assert_eq!(reply.request_id, 4242);
+    })
+  }
+
+  // the reply to a query is lost, the client sends the same datagram again
+  #[test]
+  fn lost_reply() {
+    task::block_on(async {
+      let srv = RwLock::new(Server::new(ServerId::default()));
+      let replies = ReplyCache::default();
+      let (c1, c2) = {
+        let lock = srv.read().await;
+        (
+          lock.register_local_client("c1".into()).await.unwrap(),
+          lock.register_local_client("c2".into()).await.unwrap(),
+        )
+      };
+      let (mut client1, mut client2) = (Client::new(c1), Client::new(c2));
+      let message = Request {
+        request_id: 1,
+        sequence: client1.sequence(ClientQuery::Message(ClientMessage::Text {
+          dest: c2,
+          content: "once".into(),
+          attachments: Vec::new(),
+        })),
+      };
+      let poll = Request {
+        request_id: 1,
+        sequence: client2.sequence(ClientQuery::Poll),
+      };
+      for rq in [message, poll] {
+        let first = handle_client_request(&srv, &replies, rq.clone(), None)
+          .await
+          .unwrap();
+        let again = handle_client_request(&srv, &replies, rq, None)
+          .await
+          .unwrap();
+        assert_eq!(first, again);
+      }
+      // the message was delivered, and polled, once
+      assert_eq!(
+        srv.read().await.client_poll(c2).await,
+        ClientPollReply::Nothing
+      );
+
+      // a query that merely reuses the sequence id is not answered from the cache
+      let mut forged = Request {
+        request_id: 2,
+        sequence: Client::new(c2).sequence(ClientQuery::ListUsers),
+      };
+      forged.sequence.workproof = 0;
+      let out = handle_client_request(&srv, &replies, forged, None)
+        .await
+        .unwrap();
+      let reply = decode::reply_header(&mut Cursor::new(out)).unwrap();
+      assert!(reply.payload.is_err());
+    })
+  }
+
+  #[test]
+  fn register_retransmit() {
+    task::block_on(async {
+      let srv = RwLock::new(Server::new(ServerId::default()));
+      let mut tempclient = Client::new(ClientId::default());
+      let rq = Request {
+        request_id: 1,
+        sequence: tempclient.sequence(ClientQuery::Register("bob".into())),
+      };
+      let mut ids = Vec::new();
+      for _ in 0..2 {
+        let out = handle_client_request(&srv, &ReplyCache::default(), rq.clone(), None)
+          .await
+          .unwrap();
+        ids.push(
+          decode::reply(&mut Cursor::new(out), decode::clientid)
+            .unwrap()
+            .payload,
+        );
+      }
+      assert_eq!(ids[0], ids[1]);
+      assert_eq!(srv.read().await.list_users().await.len(), 1);
+    })
+  }
+
+  #[test]
+  fn admin_system_notice() {
+    task::block_on(async {
+      let srv = RwLock::new(Server::new(ServerId::default()));
+      let c1 = srv
+        .read()
+        .await
+        .register_local_client("user 1".into())
+        .await
+        .unwrap();
+      assert!(handle_admin_command(&srv, "system").await.is_err());
+      handle_admin_command(&srv, "system back in 5 minutes")
+        .await
+        .unwrap();
+      assert_eq!(
+        srv.read().await.client_poll(c1).await,
+        ClientPollReply::System {
+          text: "back in 5 minutes".into()
+        }
+      );
+    })
+  }
+
+  // the features of the current protocol version are used by the datagrams the server reads and
+  // writes, and not only by the versioned codecs
+  #[test]
+  fn protocol_version_features() {
+    task::block_on(async {
+      let srv = RwLock::new(Server::new(ServerId::default()));
+      // long names, so that the user list is compressed
+      let mut c1 = ClientId::default();
+      for i in 0..64 {
+        c1 = srv
+          .read()
+          .await
+          .register_local_client(format!("a rather long user name {:02}", i))
+          .await
+          .unwrap();
+      }
+      let mut client = Client::new(c1);
+      async fn exchange<S: MessageServer>(
+        srv: &RwLock<S>,
+        rq: Request<ClientQuery>,
+      ) -> Cursor<Vec<u8>> {
+        let mut wr = Cursor::new(Vec::new());
+        encode::request(&mut wr, &rq, encode::client_query).unwrap();
+        let datagram = wr.into_inner();
+        // the sequence is checksummed
+        let mut corrupted = datagram.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        assert!(matches!(read_request(&corrupted), Datagram::Invalid(_)));
+        let rq = match read_request(&datagram) {
+          Datagram::Request(rq) => rq,
+          _ => panic!("the request should be valid"),
+        };
+        let mut rd = Cursor::new(
+          handle_client_request(srv, &ReplyCache::default(), rq, None)
+            .await
+            .unwrap(),
+        );
+        decode::reply(&mut rd, |_| Ok(())).unwrap();
+        rd
+      }
+
+      let attachments = vec![("reply-to".to_string(), "42".to_string())];
+      let query = ClientQuery::Message(ClientMessage::Text {
+        dest: c1,
+        content: "hi".into(),
+        attachments: attachments.clone(),
+      });
+      let rq = Request {
+        request_id: 1,
+        sequence: client.sequence(query),
+      };
+      let replies = decode::client_replies(&mut exchange(&srv, rq).await).unwrap();
+      let message_id = match replies[..] {
+        [ClientReply::Delivered(id)] => id,
+        _ => panic!("unexpected {:?}", replies),
+      };
+      let rq = Request {
+        request_id: 2,
+        sequence: client.sequence(ClientQuery::Poll),
+      };
+      let ack = decode::poll_ack(&mut exchange(&srv, rq).await).unwrap();
+      assert_eq!(
+        ack.reply,
+        ClientPollReply::Message {
+          src: c1,
+          srcsrv: None,
+          content: "hi".into(),
+          attachments,
+          message_id,
+        }
+      );
+
+      let rq = Request {
+        request_id: 3,
+        sequence: client.sequence(ClientQuery::ListUsers),
+      };
+      let mut rd = exchange(&srv, rq).await;
+      // the compression flag
+      assert_eq!(rd.get_ref()[rd.position() as usize], 1);
+      assert_eq!(decode::userlist(&mut rd).unwrap().len(), 64);
+    })
+  }
+
+  // the query goes through the same decoding and dispatch as in client_thread, and the reply
+  // must be exactly what the encoder for that query produces
+  #[test]
+  fn query_dispatch_matrix() {
+    task::block_on(async {
+      let srv = RwLock::new(Server::new(ServerId::from(42)));
+      let c1 = srv
+        .read()
+        .await
+        .register_local_client("user 1".into())
+        .await
+        .unwrap();
+      let mut client = Client::new(c1);
+      let mut newcomer = Client::new(ClientId::default());
+
+      async fn dispatch<S: MessageServer>(
+        srv: &RwLock<S>,
+        client: &mut Client,
+        query: ClientQuery,
+      ) -> Cursor<Vec<u8>> {
+        let rq = Request {
+          request_id: 1,
+          sequence: client.sequence(query),
+        };
+        let mut wr = Cursor::new(Vec::new());
+        encode::request(&mut wr, &rq, encode::client_query).unwrap();
+        let rq = decode::request(&mut Cursor::new(wr.into_inner()), decode::client_query).unwrap();
+        let out = handle_client_request(srv, &ReplyCache::default(), rq, None)
+          .await
+          .unwrap();
+        let mut rd = Cursor::new(out);
+        decode::reply(&mut rd, |_| Ok(())).unwrap();
+        rd
+      }
+      fn finish<A, DEC>(mut rd: Cursor<Vec<u8>>, d: DEC) -> A
+      where
+        DEC: FnOnce(&mut Cursor<Vec<u8>>) -> anyhow::Result<A>,
+      {
+        let payload = d(&mut rd).unwrap();
+        assert_eq!(rd.position() as usize, rd.get_ref().len(), "trailing bytes");
+        payload
+      }
+
+      let rd = dispatch(&srv, &mut newcomer, ClientQuery::Register("bob".into())).await;
+      let bob = finish(rd, decode::clientid);
+      assert_ne!(bob, c1);
+
+      let query = ClientQuery::Message(ClientMessage::Text {
+        dest: c1,
+        content: "hi".into(),
+        attachments: Vec::new(),
+      });
+      let rd = dispatch(&srv, &mut client, query).await;
+      let replies = finish(rd, decode::client_replies);
+      let message_id = match replies[..] {
+        [ClientReply::Delivered(id)] => id,
+        _ => panic!("unexpected {:?}", replies),
+      };
+
+      let rd = dispatch(&srv, &mut client, ClientQuery::Poll).await;
+      let ack = finish(rd, decode::poll_ack);
+      assert_eq!(
+        ack.reply,
+        ClientPollReply::Message {
+          src: c1,
+          srcsrv: None,
+          content: "hi".into(),
+          attachments: Vec::new(),
+          message_id,
+        }
+      );
+      // the message, then the poll itself
+      assert_eq!(ack.contiguous_seqid, 2);
+
+      let rd = dispatch(&srv, &mut client, ClientQuery::ListUsers).await;
+      let users = finish(rd, decode::userlist);
+      assert_eq!(users.len(), 2);
+      assert_eq!(users.get(&bob).map(String::as_str), Some("bob"));
+
+      let query = ClientQuery::ListUsersPage {
+        offset: 1,
+        limit: 10,
+      };
+      let rd = dispatch(&srv, &mut client, query).await;
+      let page = finish(rd, decode::user_page);
+      assert_eq!((page.users.len(), page.total, page.has_more), (1, 2, false));
+
+      let rd = dispatch(&srv, &mut client, ClientQuery::Reachable).await;
+      let reachable = finish(rd, decode::reachable);
+      assert_eq!(reachable.get(&bob), Some(&ClientLocation::Local));
+
+      let query = ClientQuery::Recall { message_id: 12345 };
+      let rd = dispatch(&srv, &mut client, query).await;
+      assert!(!finish(rd, decode::bool));
+
+      let query = ClientQuery::Subscribe("news".into());
+      let rd = dispatch(&srv, &mut client, query).await;
+      assert!(finish(rd, decode::bool));
+
+      let query = ClientQuery::Unsubscribe("news".into());
+      let rd = dispatch(&srv, &mut client, query).await;
+      assert!(finish(rd, decode::bool));
+
+      let rd = dispatch(&srv, &mut client, ClientQuery::HomeServer(bob)).await;
+      assert_eq!(finish(rd, decode::option_serverid), None);
+
+      let query = ClientQuery::SetOrder(DeliveryOrder::Lifo);
+      let rd = dispatch(&srv, &mut client, query).await;
+      assert!(finish(rd, decode::bool));
+
+      let rd = dispatch(&srv, &mut client, ClientQuery::SetDelivery(true)).await;
+      assert!(finish(rd, decode::bool));
+
+      let rd = dispatch(&srv, &mut client, ClientQuery::AckStatus).await;
+      let status = finish(rd, decode::ack_status);
+      assert_eq!(status.contiguous, client.sequence(()).seqid - 1);
+      // the sequence that was just skipped holds the acknowledgement of the poll back
+      let rd = dispatch(&srv, &mut client, ClientQuery::Poll).await;
+      assert_eq!(
+        finish(rd, decode::poll_ack).contiguous_seqid,
+        status.contiguous
+      );
+
+      let rd = dispatch(&srv, &mut client, ClientQuery::ServerInfo).await;
+      let info = finish(rd, decode::server_info);
+      assert_eq!(info.server_id, ServerId::from(42));
+      assert_eq!(info.group_name, Server::GROUP_NAME);
+      assert_eq!(info.version, PROTOCOL_VERSION);
+
+      let rd = dispatch(&srv, &mut client, ClientQuery::Heartbeat).await;
+      let replies = finish(rd, decode::client_replies);
+      assert_eq!(replies, [ClientReply::Heartbeat]);
+
+      let query = ClientQuery::RegisterAndMessage {
+        name: "bot".into(),
+        message: ClientMessage::Text {
+          dest: bob,
+          content: "one shot".into(),
+          attachments: Vec::new(),
+        },
+      };
+      let rd = dispatch(&srv, &mut Client::new(ClientId::default()), query).await;
+      let (bot, replies) = finish(rd, |rd| {
+        Ok((decode::clientid(rd)?, decode::client_replies(rd)?))
+      });
+      let message_id = match replies[..] {
+        [ClientReply::Delivered(id)] => id,
+        _ => panic!("unexpected {:?}", replies),
+      };
+      let reply = srv.read().await.client_poll(bob).await;
+      assert_eq!(
+        reply,
+        ClientPollReply::Message {
+          src: bot,
+          srcsrv: None,
+          content: "one shot".into(),
+          attachments: Vec::new(),
+          message_id,
+        }
+      );
+    })
+  }
+
+  #[test]
+  fn paged_user_list() {
+    task::block_on(async {
+      let srv = RwLock::new(Server::new(ServerId::from(42)));
+      let names: Vec<String> = (0..500).map(|i| format!("user {}", i)).collect();
+      let ids = srv.read().await.register_local_clients(names).await;
+      let mut client = Client::new(*ids[0].as_ref().unwrap());
+
+      let mut pages = Vec::new();
+      loop {
+        let query = ClientQuery::ListUsersPage {
+          offset: pages.iter().map(|p: &UserPage| p.users.len() as u64).sum(),
+          // more than the server sends at once
+          limit: 1000,
+        };
+        let rq = Request {
+          request_id: 1,
+          sequence: client.sequence(query),
+        };
+        let out = handle_client_request(&srv, &ReplyCache::default(), rq, None)
+          .await
+          .unwrap();
+        // fits in the receive buffer
+        assert!(out.len() <= 8192);
+        let mut rd = Cursor::new(out);
+        decode::reply(&mut rd, |_| Ok(())).unwrap();
+        let page = decode::user_page(&mut rd).unwrap();
+        assert_eq!(page.total, 500);
+        assert!(page.users.len() as u64 <= MAX_USER_PAGE);
+        let has_more = page.has_more;
+        pages.push(page);
+        if !has_more {
+          break;
+        }
+      }
+      let listed: Vec<ClientId> = pages
+        .iter()
+        .flat_map(|p| p.users.iter().map(|(id, _)| *id))
+        .collect();
+      let mut expected: Vec<ClientId> = ids.into_iter().map(Result::unwrap).collect();
+      expected.sort();
+      // sorted, without duplicates nor gaps
+      assert_eq!(listed, expected);
+    })
+  }
+
+  #[test]
+  fn capped_reachable() {
+    task::block_on(async {
+      let srv = RwLock::new(Server::new(ServerId::from(42)));
+      let names: Vec<String> = (0..500).map(|i| format!("user {}", i)).collect();
+      let ids = srv.read().await.register_local_clients(names).await;
+      let mut client = Client::new(*ids[0].as_ref().unwrap());
+
+      let rq = Request {
+        request_id: 1,
+        sequence: client.sequence(ClientQuery::Reachable),
+      };
+      let out = handle_client_request(&srv, &ReplyCache::default(), rq, None)
+        .await
+        .unwrap();
+      // fits in the receive buffer
+      assert!(out.len() <= 8192);
+      let mut rd = Cursor::new(out);
+      decode::reply(&mut rd, |_| Ok(())).unwrap();
+      let reachable = decode::reachable(&mut rd).unwrap();
+      let mut expected: Vec<ClientId> = ids.into_iter().map(Result::unwrap).collect();
+      expected.sort();
+      expected.truncate(MAX_USER_PAGE as usize);
+      let mut listed: Vec<ClientId> = reachable.into_keys().collect();
+      listed.sort();
+      // the lowest ids
+      assert_eq!(listed, expected);
+    })
+  }
+
+  #[cfg(feature = "federation")]
+  #[test]
+  fn server_frames() {
+    use chatproto::netproto::FRAME_KIND_VERSION;
+
+    // the current version checksums server messages, the older ones do not all do
+    let server = |version| {
+      move |w: &mut Vec<u8>, msg: &ServerMessage| encode::server_versioned(w, msg, version).unwrap()
+    };
+    let msg = ServerMessage::Announce {
+      route: vec![ServerId::default()],
+      clients: Default::default(),
+    };
+    let auth = AuthMessage::Hello {
+      user: ClientId::default(),
+      nonce: chatproto::messages::Nonce::from_bytes([1; 8]),
+    };
+    let frame = |kind, payload: &dyn Fn(&mut Vec<u8>)| {
+      let mut wr = Vec::new();
+      encode::frame_kind(&mut wr, kind, PROTOCOL_VERSION).unwrap();
+      payload(&mut wr);
+      wr
+    };
+
+    let buf = frame(FrameKind::Server, &|w| server(PROTOCOL_VERSION)(w, &msg));
+    let decoded = read_server_frame(&buf, PROTOCOL_VERSION).unwrap();
+    assert_eq!(decoded, ServerFrame::Message(msg.clone()));
+    let buf = frame(FrameKind::Auth, &|w| encode::auth(w, &auth).unwrap());
+    let decoded = read_server_frame(&buf, PROTOCOL_VERSION).unwrap();
+    assert_eq!(decoded, ServerFrame::Auth(auth.clone()));
+    let buf = frame(FrameKind::Ping, &|_| ());
+    let decoded = read_server_frame(&buf, PROTOCOL_VERSION).unwrap();
+    assert_eq!(decoded, ServerFrame::Ping);
+    let buf = ack_frame(PROTOCOL_VERSION).unwrap();
+    let decoded = read_server_frame(&buf, PROTOCOL_VERSION).unwrap();
+    assert_eq!(decoded, ServerFrame::Ack);
+    // a corrupted server message is rejected
+    let mut buf = frame(FrameKind::Server, &|w| server(PROTOCOL_VERSION)(w, &msg));
+    *buf.last_mut().unwrap() ^= 1;
+    assert!(read_server_frame(&buf, PROTOCOL_VERSION).is_err());
+
+    // older peers send bare server messages
+    let mut buf = Vec::new();
+    server(FRAME_KIND_VERSION - 1)(&mut buf, &msg);
+    let decoded = read_server_frame(&buf, FRAME_KIND_VERSION - 1).unwrap();
+    assert_eq!(decoded, ServerFrame::Message(msg));
+  }
+
+  #[cfg(feature = "federation")]
+  #[test]
+  fn server_ack() {
+    use chatproto::messages::FullyQualifiedMessage;
+
+    task::block_on(async {
+      let me = ServerId::from(42);
+      let srv: &'static RwLock<Server> = Box::leak(Box::new(RwLock::new(Server::new(me))));
+      let bob = srv
+        .read()
+        .await
+        .register_local_client("bob".into())
+        .await
+        .unwrap();
+      let port = std::net::UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+      let listen = IpAddr::from([127, 0, 0, 1]);
+      task::spawn(async move { server_thread(listen, port, 10, srv).await });
+
+      let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+      let msg = ServerMessage::Message(FullyQualifiedMessage {
+        src: ClientId::default(),
+        srcsrv: ServerId::default(),
+        dsts: vec![(bob, me)],
+        content: "hello".into(),
+      });
+      let mut wr = Vec::new();
+      encode::frame_kind(&mut wr, FrameKind::Server, PROTOCOL_VERSION).unwrap();
+      encode::server_versioned(&mut wr, &msg, PROTOCOL_VERSION).unwrap();
+      let mut buf = vec![0u8; 8192];
+      // the server might not be listening yet
+      let mut received = None;
+      for _ in 0..50 {
+        peer.send_to(&wr, (listen, port)).await.unwrap();
+        let reply = async_std::future::timeout(Duration::from_millis(100), peer.recv(&mut buf));
+        if let Ok(n) = reply.await {
+          received = Some(n.unwrap());
+          break;
+        }
+      }
+      let n = received.expect("no reply from the server");
+      assert_eq!(
+        read_server_frame(&buf[..n], PROTOCOL_VERSION).unwrap(),
+        ServerFrame::Ack
+      );
+    })
+  }
+
+  #[cfg(feature = "federation")]
+  #[test]
+  fn server_forwarding() {
+    use chatproto::messages::FullyQualifiedMessage;
+
+    task::block_on(async {
+      let me = ServerId::from(42);
+      let srv: &'static RwLock<Server> = Box::leak(Box::new(RwLock::new(Server::new(me))));
+      let remote_server = ServerId::from(43);
+      let remote = ClientId::default();
+      let announce = ServerMessage::Announce {
+        route: vec![remote_server],
+        clients: HashMap::from([(remote, "remote".to_string())]),
+      };
+      let bob = {
+        let lock = srv.read().await;
+        let bob = lock.register_local_client("bob".into()).await.unwrap();
+        lock.handle_server_message(announce.clone()).await;
+        // the message is held until the route comes back
+        assert!(lock.prune_route(remote_server).await);
+        let msg = ClientMessage::Text {
+          dest: remote,
+          content: "hello".into(),
+          attachments: Vec::new(),
+        };
+        assert!(matches!(
+          lock.handle_client_message(bob, msg).await[..],
+          [ClientReply::Delayed(_)]
+        ));
+        bob
+      };
+      let port = std::net::UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+      let listen = IpAddr::from([127, 0, 0, 1]);
+      task::spawn(async move { server_thread(listen, port, 10, srv).await });
+
+      let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+      let empty = server_frame(
+        &ServerMessage::Announce {
+          route: Vec::new(),
+          clients: HashMap::new(),
+        },
+        PROTOCOL_VERSION,
+      )
+      .unwrap();
+      let announce = server_frame(&announce, PROTOCOL_VERSION).unwrap();
+      let mut buf = vec![0u8; 8192];
+      // the server might not be listening yet, an empty route does not stop it
+      let mut received = None;
+      for _ in 0..50 {
+        peer.send_to(&empty, (listen, port)).await.unwrap();
+        peer.send_to(&announce, (listen, port)).await.unwrap();
+        let reply = async_std::future::timeout(Duration::from_millis(100), peer.recv(&mut buf));
+        if let Ok(n) = reply.await {
+          received = Some(n.unwrap());
+          break;
+        }
+      }
+      let n = received.expect("the held message was not forwarded");
+      match read_server_frame(&buf[..n], PROTOCOL_VERSION).unwrap() {
+        ServerFrame::Message(ServerMessage::Message(FullyQualifiedMessage {
+          src,
+          dsts,
+          content,
+          ..
+        })) => {
+          assert_eq!(src, bob);
+          assert_eq!(dsts, [(remote, remote_server)]);
+          assert_eq!(content, "hello");
+        }
+        f => panic!("unexpected frame {:?}", f),
+      }
+    })
+  }
+
+  #[test]
+  fn strict_mode() {
+    task::block_on(async {
+      let srv = RwLock::new(Server::new(ServerId::default()));
+      let mut client = Client::new(ClientId::default());
+      let request = |client: &mut Client, query| Request {
+        request_id: 1,
+        sequence: client.sequence(query),
+      };
+      let poll = request(&mut client, ClientQuery::Poll);
+      assert!(!accept_sender(&srv, true, &poll).await);
+      assert!(accept_sender(&srv, false, &poll).await);
+
+      let register = request(&mut client, ClientQuery::Register("bob".into()));
+      assert!(accept_sender(&srv, true, &register).await);
+      let out = handle_client_request(&srv, &ReplyCache::default(), register, None)
+        .await
+        .unwrap();
+      let bob = decode::reply(&mut Cursor::new(out), decode::clientid)
+        .unwrap()
+        .payload;
+      let poll = request(&mut Client::new(bob), ClientQuery::Poll);
+      assert!(accept_sender(&srv, true, &poll).await);
+    })
+  }
+
+  #[cfg(feature = "self-test")]
+  #[test]
+  fn self_test_stages() {
+    assert!(self_test::<Server>().is_ok());
+    let (stage, rr) = self_test::<Stub>().unwrap_err();
+    assert_eq!(stage, 0);
+    assert!(format!("{:?}", rr).contains("sequence_correct"));
+  }
+}